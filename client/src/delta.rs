@@ -0,0 +1,207 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Deltas of wasm modules against a module uploaded previously, in the format the instance
+//! applies them in:
+//!
+//! - `0x00 <offset> <length>` copies `length` bytes at `offset` of the base module,
+//! - `0x01 <length> <bytes>` inserts the `length` bytes following the instruction,
+//!
+//! where all integers are unsigned LEB128.
+//!
+//! The base is indexed in blocks, and the blocks found in the new module are copied, extended
+//! as far as the modules keep matching. Everything else is inserted.
+
+use std::collections::HashMap;
+
+/// Instruction copying a range of the base module.
+const COPY: u8 = 0x00;
+
+/// Instruction inserting literal bytes.
+const INSERT: u8 = 0x01;
+
+/// Size in bytes of the blocks of the base which are looked for in the new module.
+const BLOCK: usize = 32;
+
+/// Multiplier of the rolling hash.
+const PRIME: u32 = 0x0100_0193;
+
+fn leb128(out: &mut Vec<u8>, mut value: usize) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+/// Rolling hash of a window of `BLOCK` bytes.
+#[derive(Copy, Clone, Debug)]
+struct Hash {
+    value: u32,
+    /// `PRIME` to the power of `BLOCK - 1`, to remove the byte leaving the window.
+    leading: u32,
+}
+
+impl Hash {
+    fn new(window: &[u8]) -> Self {
+        Self {
+            value: window
+                .iter()
+                .fold(0u32, |h, &b| h.wrapping_mul(PRIME).wrapping_add(b.into())),
+            leading: (1..BLOCK).fold(1u32, |p, _| p.wrapping_mul(PRIME)),
+        }
+    }
+
+    /// Moves the window by one byte, from `out` to `into`.
+    fn roll(&mut self, out: u8, into: u8) {
+        self.value = self
+            .value
+            .wrapping_sub(u32::from(out).wrapping_mul(self.leading))
+            .wrapping_mul(PRIME)
+            .wrapping_add(into.into());
+    }
+}
+
+/// Appends the instruction inserting `bytes`, unless there are none.
+fn insert(out: &mut Vec<u8>, bytes: &[u8]) {
+    if !bytes.is_empty() {
+        out.push(INSERT);
+        leb128(out, bytes.len());
+        out.extend_from_slice(bytes);
+    }
+}
+
+/// Returns the delta building `wasm` from `base`.
+pub(crate) fn diff(base: &[u8], wasm: &[u8]) -> Vec<u8> {
+    let mut blocks = HashMap::new();
+    for (i, block) in base.chunks_exact(BLOCK).enumerate() {
+        _ = blocks.entry(Hash::new(block).value).or_insert(i * BLOCK);
+    }
+
+    let mut out = Vec::new();
+    // Start of the bytes not covered by an instruction yet.
+    let mut pending = 0;
+    let mut pos = 0;
+    let mut hash = None;
+    while pos + BLOCK <= wasm.len() {
+        let h = *hash.get_or_insert_with(|| Hash::new(&wasm[pos..pos + BLOCK]));
+        let found = blocks
+            .get(&h.value)
+            .copied()
+            .filter(|&offset| base[offset..offset + BLOCK] == wasm[pos..pos + BLOCK]);
+        if let Some(offset) = found {
+            let len = base[offset..]
+                .iter()
+                .zip(&wasm[pos..])
+                .take_while(|(a, b)| a == b)
+                .count();
+            insert(&mut out, &wasm[pending..pos]);
+            out.push(COPY);
+            leb128(&mut out, offset);
+            leb128(&mut out, len);
+            pos += len;
+            pending = pos;
+            hash = None;
+        } else {
+            if let Some(h) = &mut hash {
+                if let Some(&into) = wasm.get(pos + BLOCK) {
+                    h.roll(wasm[pos], into);
+                }
+            }
+            pos += 1;
+        }
+    }
+    insert(&mut out, &wasm[pending..]);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Applies `delta` to `base` the way the instance does.
+    fn apply(base: &[u8], delta: &[u8]) -> Vec<u8> {
+        fn read(data: &[u8], pos: &mut usize) -> usize {
+            let mut value = 0;
+            for shift in (0..).step_by(7) {
+                let byte = data[*pos];
+                *pos += 1;
+                value |= usize::from(byte & 0x7f) << shift;
+                if byte & 0x80 == 0 {
+                    break;
+                }
+            }
+            value
+        }
+
+        let mut out = vec![];
+        let mut pos = 0;
+        while pos < delta.len() {
+            pos += 1;
+            match delta[pos - 1] {
+                COPY => {
+                    let offset = read(delta, &mut pos);
+                    let len = read(delta, &mut pos);
+                    out.extend_from_slice(&base[offset..offset + len]);
+                }
+                INSERT => {
+                    let len = read(delta, &mut pos);
+                    out.extend_from_slice(&delta[pos..pos + len]);
+                    pos += len;
+                }
+                op => panic!("unknown instruction {op}"),
+            }
+        }
+        out
+    }
+
+    fn module(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn deltas_rebuild_the_module() {
+        let base = module(10_000, 1);
+        let mut wasm = base.clone();
+        wasm[5_000] ^= 0xff;
+        _ = wasm.splice(100..100, module(77, 2));
+        wasm.truncate(9_000);
+        wasm.extend(module(500, 3));
+
+        let delta = diff(&base, &wasm);
+        assert_eq!(apply(&base, &delta), wasm);
+        assert!(delta.len() < 1_000, "{}", delta.len());
+    }
+
+    #[test]
+    fn unrelated_modules_are_inserted() {
+        for (base, wasm) in [
+            (module(1_000, 1), module(1_000, 2)),
+            (vec![], module(100, 2)),
+            (module(100, 1), vec![]),
+            (module(10, 1), module(10, 1)),
+        ] {
+            assert_eq!(apply(&base, &diff(&base, &wasm)), wasm);
+        }
+    }
+
+    #[test]
+    fn lengths_are_leb128() {
+        let mut out = vec![];
+        leb128(&mut out, 0);
+        leb128(&mut out, 127);
+        leb128(&mut out, 128);
+        leb128(&mut out, 624_485);
+        assert_eq!(out, [0x00, 0x7f, 0x80, 0x01, 0xe5, 0x8e, 0x26]);
+    }
+}
@@ -0,0 +1,465 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Client of the `/api/v1` endpoints of a benefice instance.
+//!
+//! Requests are authenticated with a personal access token, which can be minted by a logged in
+//! user at `/me/tokens`, or obtained by logging in with [`Client::login`]. Each user runs at
+//! most one job at a time, submitting a new one kills the job running before.
+//!
+//! Rebuilt workloads can be uploaded as a delta against the module uploaded before with
+//! [`Client::submit_delta`], which only transfers the changes.
+
+#![forbid(unsafe_code)]
+#![deny(
+    clippy::all,
+    absolute_paths_not_starting_with_crate,
+    deprecated_in_future,
+    missing_copy_implementations,
+    missing_debug_implementations,
+    noop_method_call,
+    rust_2018_compatibility,
+    rust_2018_idioms,
+    rust_2021_compatibility,
+    single_use_lifetimes,
+    trivial_bounds,
+    trivial_casts,
+    trivial_numeric_casts,
+    unreachable_code,
+    unreachable_patterns,
+    unreachable_pub,
+    unstable_features,
+    unused,
+    unused_import_braces,
+    unused_lifetimes,
+    unused_results,
+    variant_size_differences
+)]
+
+mod delta;
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::{bail, Context as _};
+use reqwest::multipart::{Form, Part};
+use reqwest::{RequestBuilder, Response, StatusCode};
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tokio::time::sleep;
+
+pub use reqwest::Url;
+
+/// Interval between output polls while watching a job.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Header holding the offset of the first byte of a chunk of output.
+const OFFSET_HEADER: &str = "x-output-offset";
+
+/// Header holding the offset to read the next chunk of output from.
+const NEXT_HEADER: &str = "x-output-next";
+
+/// Name and description of a job, to tell it apart from other jobs.
+#[derive(Clone, Debug, Default)]
+pub struct Metadata {
+    pub name: Option<String>,
+    pub description: Option<String>,
+}
+
+/// A job which was started.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Job {
+    pub id: String,
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Host port -> (Workload port, URL of the port)
+    #[serde(default)]
+    pub ports: HashMap<u16, (u16, String)>,
+}
+
+/// State of a job.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    Running,
+    /// Paused after exceeding its output rate, until it is resumed.
+    Paused,
+    /// Exited, while its output may still be read.
+    Exited,
+    /// Ended and removed, only its outcome is known.
+    Ended,
+}
+
+/// How a job ended.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Outcome {
+    /// Why the job ended, e.g. `finished` or `timeout`.
+    pub reason: String,
+    /// Exit code of the workload, if it exited on its own.
+    pub code: Option<i32>,
+    /// Signal which terminated the workload, if any.
+    pub signal: Option<i32>,
+}
+
+/// Stage of the lifecycle of the Keep of a job, as recognized in the log of Enarx.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Annotation {
+    /// The stage, e.g. `attested` or `listening`.
+    pub stage: String,
+    /// Milliseconds since the job started.
+    pub at: u64,
+    /// Details of the stage, e.g. the address the workload listens on.
+    #[serde(default)]
+    pub detail: Option<String>,
+}
+
+/// Status of a job.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Status {
+    pub id: String,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub description: Option<String>,
+    pub state: State,
+    pub running: bool,
+    /// Exit code of the workload, once it exited.
+    pub code: Option<i32>,
+    pub outcome: Option<Outcome>,
+    /// Seconds until the job times out, while it is running.
+    #[serde(default)]
+    pub remaining: Option<u64>,
+    /// Stages the Keep of the job went through so far.
+    #[serde(default)]
+    pub annotations: Vec<Annotation>,
+}
+
+/// A recent run of a job.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Run {
+    pub id: String,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Drawbridge slug of the workload, `None` for uploaded workloads.
+    pub slug: Option<String>,
+    /// When the run started in seconds since the Unix epoch.
+    pub started: u64,
+    /// Whether the files of the uploaded workload can be downloaded.
+    pub retained: bool,
+    /// How the job ended, `None` while it is running.
+    pub outcome: Option<Outcome>,
+}
+
+/// Output stream of a job.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+impl Stream {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Stdout => "stdout",
+            Self::Stderr => "stderr",
+        }
+    }
+}
+
+/// Chunk of output of a job.
+#[derive(Clone, Debug)]
+pub struct Chunk {
+    /// Offset of the first byte of `data` in the stream, which is later than the requested
+    /// one if the output in between is no longer buffered.
+    pub offset: u64,
+    pub data: Vec<u8>,
+    /// Offset to read the next chunk from.
+    pub next: u64,
+}
+
+/// Code the user enters at the OpenID Connect provider to log in a client.
+#[derive(Clone, Debug, Deserialize)]
+pub struct DeviceCode {
+    /// Secret the client polls for its token with.
+    device_code: String,
+    pub user_code: String,
+    /// Page of the provider at which the code is entered.
+    pub verification_uri: String,
+    /// Page of the provider with the code filled in, if the provider has one.
+    #[serde(default)]
+    pub verification_uri_complete: Option<String>,
+    /// Seconds until the code expires.
+    pub expires_in: u64,
+    /// Seconds to wait between polls.
+    #[serde(default)]
+    interval: u64,
+}
+
+#[derive(Deserialize)]
+struct DeviceToken {
+    token: String,
+}
+
+/// Client of a benefice instance.
+#[derive(Clone, Debug)]
+pub struct Client {
+    http: reqwest::Client,
+    url: Url,
+    token: String,
+}
+
+impl Client {
+    /// Creates a client of the instance at `url`, authenticated with the personal access
+    /// `token`.
+    pub fn new(url: Url, token: impl Into<String>) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            url,
+            token: token.into(),
+        }
+    }
+
+    /// Logs in to the instance at `url` with the device authorization flow, showing the code
+    /// the user enters at the OpenID Connect provider with `prompt`. The client is
+    /// authenticated with a personal access token labelled `name`.
+    pub async fn login(
+        url: Url,
+        name: &str,
+        prompt: impl FnOnce(&DeviceCode),
+    ) -> anyhow::Result<Self> {
+        let http = reqwest::Client::new();
+        let start = url.join("/device").context("invalid endpoint `/device`")?;
+        let resp = http
+            .post(start)
+            .json(&json!({ "name": name }))
+            .send()
+            .await
+            .context("failed to send request")?;
+        if !resp.status().is_success() {
+            let status = resp.status();
+            bail!("failed to start login: {status}: {}", resp.text().await?);
+        }
+        let code: DeviceCode = resp.json().await.context("failed to decode device code")?;
+        prompt(&code);
+
+        let poll = url
+            .join("/device/token")
+            .context("invalid endpoint `/device/token`")?;
+        let interval = Duration::from_secs(code.interval.max(1));
+        loop {
+            sleep(interval).await;
+            let resp = http
+                .post(poll.clone())
+                .json(&json!({ "device_code": code.device_code }))
+                .send()
+                .await
+                .context("failed to send request")?;
+            match resp.status() {
+                StatusCode::ACCEPTED => continue,
+                status if status.is_success() => {
+                    let DeviceToken { token } =
+                        resp.json().await.context("failed to decode token")?;
+                    return Ok(Self { http, url, token });
+                }
+                status => bail!("failed to log in: {status}: {}", resp.text().await?),
+            }
+        }
+    }
+
+    /// Returns the personal access token the client is authenticated with, e.g. to store it
+    /// after logging in.
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    fn endpoint(&self, path: &str) -> anyhow::Result<Url> {
+        self.url
+            .join(path)
+            .with_context(|| format!("invalid endpoint `{path}`"))
+    }
+
+    async fn send(&self, req: RequestBuilder) -> anyhow::Result<Response> {
+        req.bearer_auth(&self.token)
+            .send()
+            .await
+            .context("failed to send request")
+    }
+
+    /// Uploads and runs a workload, the WebAssembly module `wasm` with the Enarx.toml `conf`.
+    pub async fn submit(&self, wasm: Vec<u8>, conf: String, meta: Metadata) -> anyhow::Result<Job> {
+        let form = Form::new().part(
+            "wasm",
+            Part::bytes(wasm)
+                .file_name("main.wasm")
+                .mime_str("application/wasm")?,
+        );
+        started(self.upload(form, conf, meta).await?).await
+    }
+
+    /// Uploads and runs a workload like [`Client::submit`], but only uploads the changes of
+    /// `wasm` against `base`, a module the user uploaded recently. The module is uploaded in
+    /// full instead if the delta is not smaller, or if the instance no longer keeps `base`.
+    pub async fn submit_delta(
+        &self,
+        base: &[u8],
+        wasm: Vec<u8>,
+        conf: String,
+        meta: Metadata,
+    ) -> anyhow::Result<Job> {
+        let delta = delta::diff(base, &wasm);
+        if delta.len() >= wasm.len() {
+            return self.submit(wasm, conf, meta).await;
+        }
+        let form = Form::new()
+            .text("wasm_base", format!("{:x}", Sha256::digest(base)))
+            .part("wasm_delta", Part::bytes(delta));
+        let resp = self.upload(form, conf.clone(), meta.clone()).await?;
+        if resp.status() == StatusCode::CONFLICT {
+            return self.submit(wasm, conf, meta).await;
+        }
+        started(resp).await
+    }
+
+    /// Sends the upload of a workload, with the module in `form`.
+    async fn upload(&self, form: Form, conf: String, meta: Metadata) -> anyhow::Result<Response> {
+        let mut form = form.text("workloadType", "upload").text("toml", conf);
+        if let Some(name) = meta.name {
+            form = form.text("name", name);
+        }
+        if let Some(description) = meta.description {
+            form = form.text("description", description);
+        }
+
+        let req = self
+            .http
+            .post(self.endpoint("/api/v1/jobs")?)
+            .multipart(form);
+        self.send(req).await
+    }
+
+    /// Runs a workload from Drawbridge by its `slug`, for example `user/my-repo:0.1.0`.
+    pub async fn deploy(&self, slug: &str, meta: Metadata) -> anyhow::Result<Job> {
+        let req = self
+            .http
+            .post(self.endpoint("/api/v1/deploy")?)
+            .json(&json!({
+                "slug": slug,
+                "name": meta.name,
+                "description": meta.description,
+            }));
+        started(self.send(req).await?).await
+    }
+
+    /// Returns the status of the job with `id`, or `None` if it is unknown.
+    pub async fn status(&self, id: &str) -> anyhow::Result<Option<Status>> {
+        let req = self.http.get(self.endpoint(&format!("/api/v1/jobs/{id}"))?);
+        let resp = self.send(req).await?;
+        if resp.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        resp.error_for_status()?
+            .json()
+            .await
+            .map(Some)
+            .context("failed to decode job status")
+    }
+
+    /// Reads a chunk of `stream` of the job with `id` from `offset`, or after the output read
+    /// last. Returns `None` once the job was removed, e.g. because it was killed.
+    pub async fn read(
+        &self,
+        id: &str,
+        stream: Stream,
+        offset: Option<u64>,
+    ) -> anyhow::Result<Option<Chunk>> {
+        let path = format!("/api/v1/jobs/{id}/{}", stream.as_str());
+        let mut req = self.http.get(self.endpoint(&path)?);
+        if let Some(offset) = offset {
+            req = req.query(&[("offset", offset)]);
+        }
+        let resp = self.send(req).await?;
+        if resp.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let resp = resp.error_for_status()?;
+        let header = |name: &str| -> Option<u64> {
+            resp.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok()?.parse().ok())
+        };
+        let (offset, next) = (header(OFFSET_HEADER), header(NEXT_HEADER));
+        let data = resp.bytes().await?.to_vec();
+        let offset = offset.unwrap_or_default();
+        Ok(Some(Chunk {
+            offset,
+            next: next.unwrap_or(offset + data.len() as u64),
+            data,
+        }))
+    }
+
+    /// Kills the running job, if any.
+    pub async fn kill(&self) -> anyhow::Result<()> {
+        let req = self.http.delete(self.endpoint("/api/v1/jobs")?);
+        _ = self.send(req).await?.error_for_status()?;
+        Ok(())
+    }
+
+    /// Returns the recent runs, newest first.
+    pub async fn history(&self) -> anyhow::Result<Vec<Run>> {
+        let req = self.http.get(self.endpoint("/api/v1/jobs")?);
+        self.send(req)
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+            .context("failed to decode run history")
+    }
+
+    /// Passes the output of the job with `id` to `output` until the job exits, returning its
+    /// exit code, or `None` if the job was killed, e.g. because it timed out.
+    pub async fn watch(
+        &self,
+        id: &str,
+        mut output: impl FnMut(Stream, &[u8]) -> anyhow::Result<()>,
+    ) -> anyhow::Result<Option<i32>> {
+        let mut offsets = [(Stream::Stdout, None), (Stream::Stderr, None)];
+        loop {
+            // Query the status before reading, so that no output is lost
+            // between the last read and the workload exiting.
+            let status = match self.status(id).await? {
+                // The job was removed along with its output.
+                Some(status) if status.state == State::Ended => return Ok(status.code),
+                Some(status) => status,
+                None => return Ok(None),
+            };
+
+            let mut drained = true;
+            for (stream, offset) in &mut offsets {
+                let chunk = match self.read(id, *stream, *offset).await? {
+                    Some(chunk) => chunk,
+                    None => return Ok(None),
+                };
+                output(*stream, &chunk.data)?;
+                drained &= chunk.data.is_empty();
+                *offset = Some(chunk.next);
+            }
+
+            if !status.running && drained {
+                return Ok(Some(status.code.unwrap_or(1)));
+            }
+            sleep(POLL_INTERVAL).await;
+        }
+    }
+}
+
+/// Decodes the job started by a request.
+async fn started(resp: Response) -> anyhow::Result<Job> {
+    if !resp.status().is_success() {
+        let status = resp.status();
+        bail!("failed to start workload: {status}: {}", resp.text().await?);
+    }
+    resp.json().await.context("failed to decode job")
+}
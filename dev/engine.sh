@@ -0,0 +1,79 @@
+#!/bin/sh
+# SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+# SPDX-License-Identifier: AGPL-3.0-only
+#
+# Mock OCI container engine of the development profile, standing in for `docker` running Enarx.
+# Jobs print a banner and echo their standard input instead of running their workload.
+# Only the commands and options benefice uses are supported.
+
+set -e
+
+jobs="$(dirname "$0")/jobs"
+
+# Sends `signal` to the job named `name`, if it is still running.
+signal() {
+    if [ -f "$jobs/$2.pid" ]; then
+        kill "-$1" "$(cat "$jobs/$2.pid")" 2>/dev/null || true
+    fi
+}
+
+case "$1" in
+--version)
+    echo "benefice mock container engine"
+    ;;
+run)
+    # Skip the options of the container, up to the Enarx command line.
+    name=
+    while [ $# -gt 0 ] && [ "$1" != enarx ]; do
+        if [ "$1" = --name ]; then
+            name=$2
+        fi
+        shift
+    done
+    shift
+    case "$1" in
+    --version)
+        echo "enarx 0.0.0 (mock)"
+        ;;
+    run | deploy)
+        if [ -n "$name" ]; then
+            mkdir -p "$jobs"
+            echo $$ >"$jobs/$name.pid"
+        fi
+        echo "mock executor: not running the workload, echoing standard input instead"
+        echo "mock executor: started job $name" >&2
+        exec cat
+        ;;
+    *)
+        echo "mock executor: unsupported Enarx command $1" >&2
+        exit 1
+        ;;
+    esac
+    ;;
+inspect)
+    # Containers are removed along with their jobs, so only the state of jobs is known.
+    case "$3" in
+    *OOMKilled*)
+        echo "false 0"
+        ;;
+    *)
+        [ -f "$jobs/$4.pid" ]
+        ;;
+    esac
+    ;;
+pause)
+    signal STOP "$2"
+    ;;
+unpause)
+    signal CONT "$2"
+    ;;
+rm)
+    signal CONT "$3"
+    signal TERM "$3"
+    rm -f "$jobs/$3.pid"
+    ;;
+*)
+    echo "mock container engine: unsupported command $1" >&2
+    exit 1
+    ;;
+esac
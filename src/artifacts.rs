@@ -0,0 +1,120 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Artifacts written by workloads to the directory declared as `artifacts` in their Enarx.toml,
+//! so that demos can produce downloadable results rather than only output.
+//!
+//! The artifacts are copied out of the container of a job as a gzipped tarball once it has
+//! exited, until the job is reaped or replaced.
+
+use crate::auth::User;
+use crate::JOBS;
+
+use std::ffi::OsStr;
+use std::io::Write as _;
+use std::process::Stdio;
+
+use axum::extract::Path as AxumPath;
+use axum::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use humansize::{file_size_opts as options, FileSize};
+use tokio::io::AsyncReadExt;
+use tokio::process::Command;
+use tracing::{debug, error};
+
+/// Copies the directory `dir` out of the container of the job with `id` as a gzipped tarball,
+/// rejecting it if the tarball exceeds `limit` bytes before compression.
+pub(crate) async fn archive(
+    oci_command: &OsStr,
+    id: &str,
+    dir: &str,
+    limit: u64,
+) -> Result<Vec<u8>, Response> {
+    let failed = |e: std::io::Error| {
+        error!(error = ?e, job_id = id, "failed to copy artifacts");
+        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    };
+
+    // The container engine writes a tarball of the directory to its standard output.
+    let mut cp = Command::new(oci_command)
+        .args(["cp", &format!("{id}:{dir}"), "-"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(failed)?;
+    let mut tar = vec![];
+    let stdout = cp.stdout.take().expect("stdout is piped");
+    _ = stdout
+        .take(limit + 1)
+        .read_to_end(&mut tar)
+        .await
+        .map_err(failed)?;
+    if tar.len() as u64 > limit {
+        let limit = limit
+            .file_size(options::CONVENTIONAL)
+            .unwrap_or_else(|_| format!("{limit} B"));
+        return Err((
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("The artifacts exceed the limit of {limit}"),
+        )
+            .into_response());
+    }
+
+    let out = cp.wait_with_output().await.map_err(failed)?;
+    if !out.status.success() {
+        debug!(
+            job_id = id,
+            stderr = %String::from_utf8_lossy(&out.stderr),
+            "failed to copy artifacts"
+        );
+        return Err((
+            StatusCode::NOT_FOUND,
+            "The workload did not write any artifacts",
+        )
+            .into_response());
+    }
+
+    tokio::task::spawn_blocking(move || {
+        let mut gz = GzEncoder::new(vec![], Compression::default());
+        gz.write_all(&tar)?;
+        gz.finish()
+    })
+    .await
+    .map_err(std::io::Error::from)
+    .and_then(|archive| archive)
+    .map_err(failed)
+}
+
+/// Downloads the artifacts of the job of the user with `id`, once it has exited.
+pub(crate) async fn download(
+    AxumPath(id): AxumPath<String>,
+    user: User,
+    limit: u64,
+) -> Result<Response, Response> {
+    let jobs = JOBS.read().await;
+    let mut job = match jobs.get(&user) {
+        Some(job) => job.write().await,
+        None => return Err(StatusCode::NOT_FOUND.into_response()),
+    };
+    if job.id != id {
+        return Err(StatusCode::NOT_FOUND.into_response());
+    }
+
+    let archive = job.archive_artifacts(limit).await?;
+    Ok((
+        [
+            (CONTENT_TYPE, "application/gzip".to_string()),
+            (
+                CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{id}-artifacts.tar.gz\""),
+            ),
+        ],
+        archive,
+    )
+        .into_response())
+}
@@ -0,0 +1,361 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Append-only audit trail of logins, submitted jobs, how they ended, API token changes and
+//! security-relevant events.
+//!
+//! Events are appended to the audit log file as JSON lines, if one is configured.
+//! Security-relevant events are posted to the security webhook as well.
+//! Administrators can export them page by page, e.g. to handle abuse reports.
+
+use crate::auth::Admin;
+
+use std::borrow::Cow;
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context as _;
+use axum::extract::Query;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use chrono::{DateTime, SecondsFormat, Utc};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::Mutex;
+use tracing::{error, info};
+
+/// Default number of events per page of an export.
+const DEFAULT_PAGE_SIZE: usize = 1000;
+
+/// Maximum number of events per page of an export.
+const MAX_PAGE_SIZE: usize = 10_000;
+
+/// The audit log, set by `init`.
+static LOG: OnceCell<Log> = OnceCell::new();
+
+#[derive(Debug)]
+struct Log {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+/// What happened.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Action {
+    /// A user logged in.
+    Login,
+    /// A user submitted a job.
+    Submit,
+    /// A job was killed before its deadline, by its owner or because it was replaced.
+    Kill,
+    /// A job was killed after its deadline.
+    Timeout,
+    /// A user created an API token.
+    TokenCreate,
+    /// A user revoked an API token.
+    TokenRevoke,
+    /// A user shared the output of their job.
+    Share,
+    /// A user submitted a banned workload, or one similar to a banned workload.
+    DenylistHit,
+    /// A banned user, or a user of a banned network, attempted to submit a workload.
+    BanHit,
+    /// A user repeatedly exceeded their quotas.
+    QuotaExceeded,
+    /// An administrator changed the state of this instance.
+    AdminAction,
+    /// A user who is not an administrator attempted to administer this instance.
+    AdminDenied,
+}
+
+impl Action {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Login => "login",
+            Self::Submit => "submit",
+            Self::Kill => "kill",
+            Self::Timeout => "timeout",
+            Self::TokenCreate => "token-create",
+            Self::TokenRevoke => "token-revoke",
+            Self::Share => "share",
+            Self::DenylistHit => "denylist-hit",
+            Self::BanHit => "ban-hit",
+            Self::QuotaExceeded => "quota-exceeded",
+            Self::AdminAction => "admin-action",
+            Self::AdminDenied => "admin-denied",
+        }
+    }
+
+    /// Returns whether the operators are alerted of the action.
+    fn is_security(self) -> bool {
+        matches!(
+            self,
+            Self::DenylistHit
+                | Self::BanHit
+                | Self::QuotaExceeded
+                | Self::AdminAction
+                | Self::AdminDenied
+        )
+    }
+}
+
+/// An entry of the audit log.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct Event {
+    /// RFC 3339 timestamp.
+    time: String,
+    /// GitHub user ID of the user who acted, or whose job it is.
+    user: u64,
+    action: Action,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    job: Option<String>,
+    /// Name given to the job by its owner.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+    /// SHA-256 digest of the wasm of an uploaded workload.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+    /// Host ports of the job.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    ports: Vec<u16>,
+    /// Details of a security-relevant event, e.g. the exceeded quota.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+}
+
+impl Event {
+    pub(crate) fn new(user: u64, action: Action) -> Self {
+        Self {
+            time: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            user,
+            action,
+            job: None,
+            name: None,
+            token: None,
+            sha256: None,
+            ports: vec![],
+            detail: None,
+        }
+    }
+
+    pub(crate) fn job(mut self, id: impl Into<String>) -> Self {
+        self.job = Some(id.into());
+        self
+    }
+
+    pub(crate) fn name(mut self, name: Option<String>) -> Self {
+        self.name = name;
+        self
+    }
+
+    pub(crate) fn token(mut self, id: impl ToString) -> Self {
+        self.token = Some(id.to_string());
+        self
+    }
+
+    pub(crate) fn sha256(mut self, sha256: Option<String>) -> Self {
+        self.sha256 = sha256;
+        self
+    }
+
+    pub(crate) fn ports(mut self, ports: impl IntoIterator<Item = u16>) -> Self {
+        self.ports = ports.into_iter().collect();
+        self.ports.sort_unstable();
+        self
+    }
+
+    pub(crate) fn detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+
+    /// Appends the event to the audit log.
+    pub(crate) async fn record(self) {
+        info!(
+            user = self.user,
+            action = ?self.action,
+            job_id = self.job.as_deref(),
+            "audit event"
+        );
+        if self.action.is_security() {
+            crate::security::alert(&self);
+        }
+        let log = match LOG.get() {
+            Some(log) => log,
+            None => return,
+        };
+        let mut line = match serde_json::to_vec(&self) {
+            Ok(line) => line,
+            Err(e) => {
+                error!(error = ?e, "failed to encode audit event");
+                return;
+            }
+        };
+        line.push(b'\n');
+
+        let mut file = log.file.lock().await;
+        let result = async {
+            file.write_all(&line).await?;
+            file.flush().await
+        };
+        if let Err(e) = result.await {
+            error!(error = ?e, "failed to append to the audit log");
+        }
+    }
+}
+
+/// Opens the audit log at `path` on startup, creating it if needed.
+pub(crate) async fn init(path: impl AsRef<Path>) -> anyhow::Result<()> {
+    let path = path.as_ref();
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .with_context(|| format!("failed to open audit log `{}`", path.display()))?;
+    LOG.set(Log {
+        path: path.into(),
+        file: Mutex::new(file),
+    })
+    .map_err(|_| anyhow::anyhow!("audit log already initialized"))
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Format {
+    #[default]
+    Json,
+    Csv,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub(crate) struct ExportQuery {
+    /// Only events at or after this RFC 3339 timestamp.
+    since: Option<String>,
+    format: Format,
+    /// Number of events per page.
+    limit: Option<usize>,
+    /// Position in the audit log to continue from, as returned with the previous page.
+    cursor: u64,
+}
+
+/// Quotes a free-form CSV `field` if needed.
+fn quote(field: Option<&str>) -> Cow<'_, str> {
+    match field {
+        Some(field) if field.contains(&[',', '"', '\n', '\r'][..]) => {
+            format!("\"{}\"", field.replace('"', "\"\"")).into()
+        }
+        Some(field) => field.into(),
+        None => "".into(),
+    }
+}
+
+/// Formats events as CSV, with a header row.
+fn csv(events: &[Event]) -> String {
+    let mut out = String::from("time,user,action,job,token,sha256,ports,detail,name\n");
+    for event in events {
+        let ports: Vec<_> = event.ports.iter().map(u16::to_string).collect();
+        _ = writeln!(
+            out,
+            "{},{},{},{},{},{},{},{},{}",
+            event.time,
+            event.user,
+            event.action.as_str(),
+            event.job.as_deref().unwrap_or_default(),
+            event.token.as_deref().unwrap_or_default(),
+            event.sha256.as_deref().unwrap_or_default(),
+            ports.join(" "),
+            quote(event.detail.as_deref()),
+            quote(event.name.as_deref()),
+        );
+    }
+    out
+}
+
+/// Exports a page of the events of the audit log, as JSON or CSV.
+///
+/// The cursor of the next page is returned in the `next` field of JSON pages and in the
+/// `X-Next-Cursor` header of both formats, if there are more events.
+pub(crate) async fn export(
+    _: Admin,
+    Query(query): Query<ExportQuery>,
+) -> Result<Response, Response> {
+    let log = LOG.get().ok_or_else(|| {
+        (
+            StatusCode::NOT_FOUND,
+            "No audit log is configured on this instance",
+        )
+            .into_response()
+    })?;
+    let since = match &query.since {
+        Some(since) => Some(DateTime::parse_from_rfc3339(since).map_err(|_| {
+            (
+                StatusCode::BAD_REQUEST,
+                "`since` must be an RFC 3339 timestamp",
+            )
+                .into_response()
+        })?),
+        None => None,
+    };
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let failed = |e: std::io::Error| {
+        error!(error = ?e, "failed to read the audit log");
+        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    };
+
+    let file = File::open(&log.path).await.map_err(failed)?;
+    let mut lines = BufReader::new(file).lines();
+    let mut events = vec![];
+    let mut position = 0;
+    let mut next = None;
+    while let Some(line) = lines.next_line().await.map_err(failed)? {
+        position += 1;
+        if position <= query.cursor {
+            continue;
+        }
+        let event: Event = match serde_json::from_str(&line) {
+            Ok(event) => event,
+            Err(e) => {
+                error!(error = ?e, line = position, "skipping invalid audit log entry");
+                continue;
+            }
+        };
+        if let Some(since) = since {
+            match DateTime::parse_from_rfc3339(&event.time) {
+                Ok(time) if time >= since => {}
+                _ => continue,
+            }
+        }
+        if events.len() == limit {
+            // The next page starts with this event.
+            next = Some(position - 1);
+            break;
+        }
+        events.push(event);
+    }
+
+    let mut resp = match query.format {
+        Format::Json => Json(json!({
+            "events": events,
+            "next": next,
+        }))
+        .into_response(),
+        Format::Csv => ([(CONTENT_TYPE, "text/csv")], csv(&events)).into_response(),
+    };
+    if let Some(next) = next {
+        _ = resp.headers_mut().insert("x-next-cursor", next.into());
+    }
+    Ok(resp)
+}
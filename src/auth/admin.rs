@@ -0,0 +1,73 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use super::token::{self, Scope};
+use super::{Config, User};
+use crate::audit;
+
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use axum::async_trait;
+use axum::extract::{FromRequest, RequestParts};
+use axum::http::{Method, StatusCode};
+
+/// A user who is allowed to administer this instance, authenticated
+/// either by a personal access token with the `admin` scope or by the session cookie.
+///
+/// Requests other than reads are recorded as administrative actions, and requests of users
+/// who are not administrators as denied attempts.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Admin(pub(crate) User);
+
+/// Whether the user of a request is an administrator, for routes which are not reserved to
+/// administrators. Unlike `Admin`, it records no events.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct IsAdmin(pub(crate) bool);
+
+/// Authenticates the user of a request, returning whether they are an administrator.
+async fn authenticate<B: Send>(req: &mut RequestParts<B>) -> Result<(User, bool), StatusCode> {
+    // Get the configuration.
+    let config = req.extensions().get::<Arc<Config>>().cloned().unwrap();
+
+    let user = match token::authenticate(req, Scope::Admin).await? {
+        Some(user) => user,
+        None => User::from_request(req)
+            .await
+            .map_err(|_| StatusCode::UNAUTHORIZED)?,
+    };
+    Ok((user, config.is_admin(&user)))
+}
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for Admin {
+    type Rejection = StatusCode;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let (user, admin) = authenticate(req).await?;
+        let request = format!("{} {}", req.method(), req.uri().path());
+        if !admin {
+            audit::Event::new(user.uid(), audit::Action::AdminDenied)
+                .detail(request)
+                .record()
+                .await;
+            return Err(StatusCode::FORBIDDEN);
+        }
+        if ![Method::GET, Method::HEAD].contains(req.method()) {
+            audit::Event::new(user.uid(), audit::Action::AdminAction)
+                .detail(request)
+                .record()
+                .await;
+        }
+        Ok(Self(user))
+    }
+}
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for IsAdmin {
+    type Rejection = Infallible;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        Ok(Self(matches!(authenticate(req).await, Ok((_, true)))))
+    }
+}
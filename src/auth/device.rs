@@ -0,0 +1,216 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Device authorization flow, which logs in headless clients such as the CLI.
+//!
+//! A client starts a login with `POST /device` and shows the user the code to enter at the
+//! OpenID Connect provider. Meanwhile, this server polls the provider until the user has logged
+//! in, and mints a personal access token for them. The client polls `POST /device/token` with
+//! the device code it was given until it receives the token, which is only handed out once.
+
+use super::provider::PRIMARY;
+use super::token::{self, Scope};
+use super::{identify, Config, User};
+use crate::audit;
+use crate::denylist::digest;
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::extract::Extension;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use base64::write::EncoderStringWriter;
+use base64::URL_SAFE_NO_PAD;
+use once_cell::sync::Lazy;
+use openidconnect::core::CoreDeviceAuthorizationResponse;
+use openidconnect::reqwest::async_http_client;
+use rand::RngCore;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::RwLock;
+use tokio::time::sleep;
+use tracing::{error, info, warn};
+
+/// Name of tokens minted for clients which do not name them.
+const DEFAULT_NAME: &str = "device login";
+
+/// Logins in progress, keyed by the digest of their device code.
+static LOGINS: Lazy<RwLock<HashMap<String, Login>>> = Lazy::new(Default::default);
+
+enum State {
+    /// The user has yet to log in with the provider.
+    Pending,
+    /// The user logged in, and this token was minted for them.
+    Done { token: String, expires: SystemTime },
+    /// The login failed for this reason.
+    Failed(&'static str),
+}
+
+struct Login {
+    state: State,
+    /// When the login is forgotten, whether the client fetched its outcome or not.
+    expires: SystemTime,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub(super) struct StartRequest {
+    /// Name of the provider to log in with, the primary one if not given.
+    provider: Option<String>,
+    /// Label of the token minted for the client.
+    name: String,
+}
+
+/// Starts the login of a client, returning the code the user enters at the provider.
+pub(super) async fn start(
+    Extension(config): Extension<Arc<Config>>,
+    request: Option<Json<StartRequest>>,
+) -> Result<impl IntoResponse, Response> {
+    let request = request.map(|Json(request)| request).unwrap_or_default();
+    let name = request.provider.as_deref().unwrap_or(PRIMARY);
+    let provider = match config.provider(name) {
+        Some(provider) if provider.device => provider,
+        Some(_) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "This OpenID Connect provider does not support device logins",
+            )
+                .into_response())
+        }
+        None => return Err(StatusCode::NOT_FOUND.into_response()),
+    };
+
+    let details: CoreDeviceAuthorizationResponse = match provider.client.exchange_device_code() {
+        Ok(request) => request
+            .request_async(async_http_client)
+            .await
+            .map_err(|e| {
+                error!(provider = name, error = ?e, "failed to start device login");
+                StatusCode::BAD_GATEWAY.into_response()
+            })?,
+        Err(e) => {
+            error!(provider = name, error = ?e, "device login is misconfigured");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+        }
+    };
+
+    let mut code = [0; 32];
+    rand::thread_rng().fill_bytes(&mut code);
+    let mut b64 = EncoderStringWriter::new(URL_SAFE_NO_PAD);
+    b64.write_all(&code).unwrap();
+    let code = b64.into_inner();
+
+    let now = SystemTime::now();
+    {
+        let mut logins = LOGINS.write().await;
+        logins.retain(|_, login| login.expires > now);
+        _ = logins.insert(
+            digest(&code),
+            Login {
+                state: State::Pending,
+                expires: now + details.expires_in(),
+            },
+        );
+    }
+
+    let response = json!({
+        "device_code": code,
+        "user_code": details.user_code().secret(),
+        "verification_uri": details.verification_uri().as_str(),
+        "verification_uri_complete": details
+            .verification_uri_complete()
+            .map(|uri| uri.secret()),
+        "expires_in": details.expires_in().as_secs(),
+        "interval": details.interval().as_secs(),
+    });
+    let label = match request.name.trim() {
+        "" => DEFAULT_NAME.into(),
+        label => label.to_string(),
+    };
+    let provider = provider.name.clone();
+    _ = tokio::spawn(async move {
+        let state = match poll(&config, &provider, &details, &label).await {
+            Ok((token, expires)) => State::Done { token, expires },
+            Err(reason) => State::Failed(reason),
+        };
+        if let Some(login) = LOGINS.write().await.get_mut(&digest(&code)) {
+            login.state = state;
+        }
+    });
+    Ok(Json(response))
+}
+
+/// Polls `provider` until the user logged in, and mints a token named `label` for them,
+/// returning its secret and expiry.
+async fn poll(
+    config: &Config,
+    provider: &str,
+    details: &CoreDeviceAuthorizationResponse,
+    label: &str,
+) -> Result<(String, SystemTime), &'static str> {
+    let provider = config
+        .provider(provider)
+        .ok_or("The OpenID Connect provider is gone")?;
+    let token = provider
+        .client
+        .exchange_device_access_token(details)
+        .request_async(async_http_client, sleep, Some(details.expires_in()))
+        .await
+        .map_err(|e| {
+            warn!(provider = %provider.name, error = ?e, "device login failed");
+            "The login was denied or has expired"
+        })?;
+    // The device authorization flow asks for no nonce.
+    let (uid, roles) = identify(config, provider, &token, None)
+        .await
+        .map_err(|(_, reason)| reason)?;
+
+    audit::Event::new(uid, audit::Action::Login)
+        .detail(format!("{} (device)", provider.name))
+        .record()
+        .await;
+    let validity = Duration::from_secs(token::DEFAULT_VALIDITY_DAYS * 24 * 60 * 60);
+    let (id, secret) = token::mint(
+        User::new(uid, roles, None),
+        label,
+        [Scope::Submit, Scope::Read].into(),
+        None,
+        validity,
+    )
+    .await;
+    info!(uid, token_id = %id, "logged in device");
+    Ok((secret, SystemTime::now() + validity))
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct TokenRequest {
+    device_code: String,
+}
+
+/// Returns the token of a client once the user logged in, which is only returned once.
+pub(super) async fn claim(Json(TokenRequest { device_code }): Json<TokenRequest>) -> Response {
+    let key = digest(&device_code);
+    let mut logins = LOGINS.write().await;
+    let now = SystemTime::now();
+    match logins.get(&key) {
+        Some(login) if login.expires > now => {}
+        _ => return (StatusCode::NOT_FOUND, "No such device login").into_response(),
+    }
+    if matches!(logins[&key].state, State::Pending) {
+        return (StatusCode::ACCEPTED, Json(json!({ "status": "pending" }))).into_response();
+    }
+
+    match logins.remove(&key).map(|login| login.state) {
+        Some(State::Done { token, expires }) => Json(json!({
+            "token": token,
+            "expires": expires.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+        }))
+        .into_response(),
+        Some(State::Failed(reason)) => (StatusCode::FORBIDDEN, reason).into_response(),
+        Some(State::Pending) | None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
@@ -0,0 +1,85 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Logins in progress, whose state is sealed in a cookie until the provider redirects the
+//! user back.
+//!
+//! The state binds the redirect back to the browser which started the login: the CSRF token
+//! must match the `state` the provider passes back, the PKCE verifier proves to the
+//! provider that the authorization code is exchanged by whoever requested it, and the nonce
+//! must match the one in the ID token the code is exchanged for.
+
+use super::Config;
+
+use std::time::{Duration, SystemTime};
+
+use axum_extra::extract::cookie::{Cookie, SameSite};
+use axum_extra::extract::CookieJar;
+use openidconnect::{CsrfToken, Nonce, PkceCodeVerifier};
+use serde::{Deserialize, Serialize};
+
+const COOKIE_NAME: &str = "OIDC_FLOW";
+
+/// How long users have to log in with the provider.
+const MAX_AGE: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Deserialize, Serialize)]
+struct Flow {
+    time: SystemTime,
+    /// Name of the provider the user logs in with.
+    provider: String,
+    state: String,
+    nonce: String,
+    verifier: String,
+}
+
+/// Remembers a login with `provider` which passes `state` back, whose code is exchanged with
+/// `verifier` for an ID token carrying `nonce`.
+pub(super) fn start(
+    config: &Config,
+    jar: CookieJar,
+    provider: &str,
+    state: CsrfToken,
+    nonce: Nonce,
+    verifier: PkceCodeVerifier,
+) -> CookieJar {
+    let flow = Flow {
+        time: SystemTime::now(),
+        provider: provider.into(),
+        state: state.secret().clone(),
+        nonce: nonce.secret().clone(),
+        verifier: verifier.secret().clone(),
+    };
+    let value = config.key.seal(&serde_json::to_vec(&flow).unwrap());
+    jar.add(
+        Cookie::build(COOKIE_NAME, value)
+            .path("/")
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .finish(),
+    )
+}
+
+/// Ends the login with `provider`, returning the PKCE verifier and the nonce of the ID token
+/// if it was started by this browser, passed `state` back and has not expired.
+pub(super) fn finish(
+    config: &Config,
+    jar: CookieJar,
+    provider: &str,
+    state: &str,
+) -> (CookieJar, Option<(PkceCodeVerifier, Nonce)>) {
+    let flow = jar
+        .get(COOKIE_NAME)
+        .and_then(|cookie| config.key.open(cookie.value()))
+        .and_then(|plaintext| serde_json::from_slice::<Flow>(&plaintext).ok());
+    let jar = jar.remove(Cookie::build(COOKIE_NAME, "").path("/").finish());
+
+    let verifier = flow
+        .filter(|flow| {
+            flow.provider == provider
+                && flow.state == state
+                && flow.time + MAX_AGE > SystemTime::now()
+        })
+        .map(|flow| (PkceCodeVerifier::new(flow.verifier), Nonce::new(flow.nonce)));
+    (jar, verifier)
+}
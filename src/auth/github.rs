@@ -0,0 +1,370 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Stars of GitHub repositories, which grant the `starred` role, and membership of GitHub
+//! organizations and teams, which grants the `member` role.
+//!
+//! Users are looked up by their GitHub user ID via the GitHub REST API when they log in.
+//! Private membership of organizations and membership of teams are only visible with the
+//! token of a member of the organization, given with `--github-token`.
+//!
+//! Lookups are cached for `--github-cache-ttl`, so that users logging in repeatedly do not
+//! exhaust the rate limit of the API. Lookups older than that are still used, and refreshed in
+//! the background. While GitHub rate-limits this instance, nothing is looked up, and users
+//! whose lookups are not cached get the default tier.
+//!
+//! Stars of `enarx/enarx` are told by the `has_starred_enarx` claim of the identity provider,
+//! so they are only looked up if the claim is missing.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display};
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, ensure, Context as _};
+use once_cell::sync::Lazy;
+use reqwest::header::{ACCEPT, RETRY_AFTER};
+use reqwest::{RequestBuilder, Response, StatusCode};
+use serde::Deserialize;
+use tokio::sync::RwLock;
+use tracing::{debug, info, warn};
+
+/// Root of the GitHub REST API.
+const API: &str = "https://api.github.com";
+
+/// Longest time a lookup is used for while it cannot be refreshed.
+const MAX_STALENESS: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Time to wait after being rate-limited if GitHub does not tell when to retry.
+const RATE_LIMIT_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Repository whose stars the identity provider tells with the `has_starred_enarx` claim.
+pub(crate) const ENARX: &str = "enarx/enarx";
+
+/// Most pages of starred repositories of a user which are looked through.
+const MAX_STAR_PAGES: usize = 10;
+
+/// GitHub rejects requests without a user agent.
+const USER_AGENT: &str = concat!("benefice/", env!("CARGO_PKG_VERSION"));
+
+/// Outcome of each lookup of a user, by their GitHub user ID, and when it was looked up.
+static CACHE: Lazy<RwLock<HashMap<(u64, Lookup), (Instant, bool)>>> = Lazy::new(Default::default);
+
+/// Lookups being refreshed in the background.
+static REFRESHING: Lazy<RwLock<HashSet<(u64, Lookup)>>> = Lazy::new(Default::default);
+
+/// Time until which GitHub rate-limits this instance.
+static RATE_LIMITED: Lazy<RwLock<Option<Instant>>> = Lazy::new(Default::default);
+
+/// GitHub rate-limited a request.
+#[derive(Copy, Clone, Debug)]
+struct RateLimited;
+
+impl Display for RateLimited {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("rate-limited by GitHub")
+    }
+}
+
+impl std::error::Error for RateLimited {}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+enum Lookup {
+    Member,
+    Star,
+}
+
+/// GitHub repository, e.g. `enarx/enarx`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Repo(String);
+
+impl Repo {
+    pub(crate) fn url(&self) -> String {
+        format!("https://github.com/{}", self.0)
+    }
+
+    fn is(&self, full_name: &str) -> bool {
+        self.0.eq_ignore_ascii_case(full_name)
+    }
+}
+
+impl FromStr for Repo {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        match s.split_once('/') {
+            Some((owner, name)) if valid_name(owner) && valid_name(name) => Ok(Self(s.into())),
+            _ => bail!("`{s}` is not a GitHub repository of the form `<owner>/<name>`"),
+        }
+    }
+}
+
+impl Display for Repo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Returns whether `name` is a valid name of a GitHub account, team or repository.
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+/// GitHub organization, or team of one, e.g. `enarx` or `enarx/maintainers`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Org {
+    org: String,
+    /// Slug of the team, if only its members count.
+    team: Option<String>,
+}
+
+impl FromStr for Org {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (org, team) = match s.trim().split_once('/') {
+            Some((org, team)) => (org, Some(team)),
+            None => (s.trim(), None),
+        };
+        ensure!(valid_name(org), "invalid GitHub organization `{org}`");
+        if let Some(team) = team {
+            ensure!(valid_name(team), "invalid GitHub team `{team}`");
+        }
+        Ok(Self {
+            org: org.into(),
+            team: team.map(Into::into),
+        })
+    }
+}
+
+impl Display for Org {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.team {
+            Some(team) => write!(f, "{}/{team}", self.org),
+            None => f.write_str(&self.org),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Account {
+    login: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TeamMembership {
+    state: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct StarredRepo {
+    full_name: String,
+}
+
+/// Repositories whose stargazers get the `starred` role, and organizations and teams whose
+/// members get the `member` role.
+#[derive(Clone, Default)]
+pub(super) struct Github {
+    pub(super) star_repos: Vec<Repo>,
+    pub(super) orgs: Vec<Org>,
+    /// Token used to see private membership and membership of teams.
+    pub(super) token: Option<String>,
+    /// How long lookups are used for before they are refreshed.
+    pub(super) cache_ttl: Duration,
+}
+
+impl Github {
+    /// Returns whether the user with the GitHub user ID `uid` has starred any of the
+    /// repositories, given whether they have starred `enarx/enarx` according to the identity
+    /// provider. Users whose stars cannot be looked up have not starred any.
+    pub(super) async fn has_starred(&self, uid: u64, has_starred_enarx: Option<bool>) -> bool {
+        let claimed = self.star_repos.iter().any(|repo| repo.is(ENARX));
+        if claimed && has_starred_enarx == Some(true) {
+            return true;
+        }
+        // The claim tells whether the user has starred `enarx/enarx` already.
+        let unclaimed = self
+            .star_repos
+            .iter()
+            .any(|repo| has_starred_enarx.is_none() || !repo.is(ENARX));
+        unclaimed && self.cached(uid, Lookup::Star).await
+    }
+
+    /// Returns whether the user with the GitHub user ID `uid` is a member of any of the
+    /// organizations. Users whose membership cannot be looked up are not members.
+    pub(super) async fn is_member(&self, uid: u64) -> bool {
+        !self.orgs.is_empty() && self.cached(uid, Lookup::Member).await
+    }
+
+    /// Returns the outcome of `lookup` for the user with `uid`, from the cache if it has been
+    /// looked up before.
+    async fn cached(&self, uid: u64, lookup: Lookup) -> bool {
+        let cached = CACHE.read().await.get(&(uid, lookup)).copied();
+        match cached {
+            Some((checked, found)) if checked.elapsed() < self.cache_ttl => found,
+            Some((checked, found)) if checked.elapsed() < MAX_STALENESS => {
+                if REFRESHING.write().await.insert((uid, lookup)) {
+                    let github = self.clone();
+                    _ = tokio::spawn(async move {
+                        _ = github.refresh(uid, lookup).await;
+                        _ = REFRESHING.write().await.remove(&(uid, lookup));
+                    });
+                }
+                found
+            }
+            _ => self.refresh(uid, lookup).await.unwrap_or(false),
+        }
+    }
+
+    /// Looks up `lookup` for the user with `uid` and caches the outcome, unless GitHub
+    /// rate-limits this instance or the lookup fails.
+    async fn refresh(&self, uid: u64, lookup: Lookup) -> Option<bool> {
+        if matches!(*RATE_LIMITED.read().await, Some(until) if until > Instant::now()) {
+            debug!(uid, ?lookup, "skipping GitHub lookup while rate-limited");
+            return None;
+        }
+
+        let result = match lookup {
+            Lookup::Member => self.lookup_member(uid).await,
+            Lookup::Star => self.lookup_star(uid).await,
+        };
+        match result {
+            Ok(found) => {
+                debug!(uid, ?lookup, found, "looked up GitHub account");
+                _ = CACHE
+                    .write()
+                    .await
+                    .insert((uid, lookup), (Instant::now(), found));
+                Some(found)
+            }
+            Err(e) if e.is::<RateLimited>() => {
+                info!(uid, ?lookup, "rate-limited by GitHub, skipping lookup");
+                None
+            }
+            Err(e) => {
+                warn!(uid, ?lookup, error = ?e, "failed to look up GitHub account");
+                None
+            }
+        }
+    }
+
+    fn get(&self, http: &reqwest::Client, path: &str) -> RequestBuilder {
+        let request = http
+            .get(format!("{API}{path}"))
+            .header(ACCEPT, "application/vnd.github+json");
+        match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+
+    /// Returns an HTTP client for the API and the login of the user with `uid`.
+    async fn login(&self, uid: u64) -> anyhow::Result<(reqwest::Client, String)> {
+        let http = reqwest::Client::builder()
+            .user_agent(USER_AGENT)
+            .build()
+            .context("failed to build HTTP client")?;
+        let Account { login } = send(self.get(&http, &format!("/user/{uid}")))
+            .await?
+            .error_for_status()
+            .context("failed to fetch GitHub account")?
+            .json()
+            .await
+            .context("failed to decode GitHub account")?;
+        Ok((http, login))
+    }
+
+    async fn lookup_star(&self, uid: u64) -> anyhow::Result<bool> {
+        let (http, login) = self.login(uid).await?;
+        for page in 1..=MAX_STAR_PAGES {
+            let path = format!("/users/{login}/starred?per_page=100&page={page}");
+            let starred: Vec<StarredRepo> = send(self.get(&http, &path))
+                .await?
+                .error_for_status()
+                .context("failed to fetch starred repositories")?
+                .json()
+                .await
+                .context("failed to decode starred repositories")?;
+            if starred.iter().any(|starred| {
+                self.star_repos
+                    .iter()
+                    .any(|repo| repo.is(&starred.full_name))
+            }) {
+                return Ok(true);
+            }
+            if starred.len() < 100 {
+                break;
+            }
+        }
+        Ok(false)
+    }
+
+    async fn lookup_member(&self, uid: u64) -> anyhow::Result<bool> {
+        let (http, login) = self.login(uid).await?;
+        for org in &self.orgs {
+            let path = match &org.team {
+                Some(team) => format!("/orgs/{}/teams/{team}/memberships/{login}", org.org),
+                // Without a token of a member, this redirects to the public members.
+                None => format!("/orgs/{}/members/{login}", org.org),
+            };
+            let resp = send(self.get(&http, &path))
+                .await
+                .with_context(|| format!("failed to fetch membership of `{org}`"))?;
+            let member = match resp.status() {
+                StatusCode::NOT_FOUND => false,
+                StatusCode::NO_CONTENT if org.team.is_none() => true,
+                StatusCode::OK if org.team.is_some() => {
+                    let TeamMembership { state } = resp
+                        .json()
+                        .await
+                        .with_context(|| format!("failed to decode membership of `{org}`"))?;
+                    state == "active"
+                }
+                status => bail!("unexpected status {status} fetching membership of `{org}`"),
+            };
+            if member {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+/// Sends `request`, backing off from the API if GitHub rate-limits it.
+async fn send(request: RequestBuilder) -> anyhow::Result<Response> {
+    let resp = request.send().await?;
+    let headers = resp.headers();
+    let exhausted = headers
+        .get("x-ratelimit-remaining")
+        .map_or(false, |remaining| remaining == "0");
+    let limited = resp.status() == StatusCode::TOO_MANY_REQUESTS
+        || (resp.status() == StatusCode::FORBIDDEN && exhausted);
+    if !limited {
+        return Ok(resp);
+    }
+
+    let header = |name: &str| headers.get(name)?.to_str().ok()?.parse::<u64>().ok();
+    let wait = match (header(RETRY_AFTER.as_str()), header("x-ratelimit-reset")) {
+        (Some(secs), _) => Duration::from_secs(secs),
+        (None, Some(reset)) => UNIX_EPOCH
+            .checked_add(Duration::from_secs(reset))
+            .and_then(|reset| reset.duration_since(SystemTime::now()).ok())
+            .unwrap_or(RATE_LIMIT_BACKOFF),
+        (None, None) => RATE_LIMIT_BACKOFF,
+    };
+    warn!(wait = wait.as_secs(), "rate-limited by GitHub, backing off");
+    *RATE_LIMITED.write().await = Some(Instant::now() + wait);
+    Err(RateLimited.into())
+}
+
+/// Forgets lookups which were made too long ago to be used.
+pub(crate) async fn gc() {
+    CACHE
+        .write()
+        .await
+        .retain(|_, (checked, _)| checked.elapsed() < MAX_STALENESS);
+}
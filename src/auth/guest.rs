@@ -0,0 +1,138 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Time-boxed guest accounts, which let people without an account run workloads.
+//!
+//! Administrators mint guest links embedding the tier and expiry of the guest account,
+//! encrypted with the session key. Opening a link starts a session lasting until the expiry.
+
+use super::{Admin, Config, Role, Roles, User};
+use crate::redirect;
+
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::extract::{Extension, Path};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{debug, error, info};
+
+/// Guests are assigned user IDs from this value upwards, so that they never clash with GitHub.
+pub(super) const GUEST_UID_MIN: u64 = 1 << 62;
+
+/// Longest time a guest account may be valid for.
+const MAX_VALIDITY: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Limits the guest is subject to.
+#[derive(Copy, Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Tier {
+    #[default]
+    Default,
+    /// The limits of users who have starred the Enarx repository.
+    Starred,
+}
+
+/// Contents of a guest link.
+#[derive(Debug, Deserialize, Serialize)]
+struct Grant {
+    uid: u64,
+    tier: Tier,
+    expires: SystemTime,
+}
+
+fn default_hours() -> u64 {
+    24
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct MintRequest {
+    #[serde(default)]
+    tier: Tier,
+    /// Number of hours the guest account is valid for.
+    #[serde(default = "default_hours")]
+    hours: u64,
+}
+
+/// Mints a guest link on behalf of an administrator.
+pub(super) async fn mint(
+    Admin(admin): Admin,
+    Extension(config): Extension<Arc<Config>>,
+    Json(MintRequest { tier, hours }): Json<MintRequest>,
+) -> Result<impl IntoResponse, Response> {
+    let validity = Duration::from_secs(hours.saturating_mul(60 * 60));
+    if validity.is_zero() || validity > MAX_VALIDITY {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!(
+                "Guest accounts must be valid for between 1 and {} hours",
+                MAX_VALIDITY.as_secs() / 60 / 60
+            ),
+        )
+            .into_response());
+    }
+
+    let grant = Grant {
+        uid: GUEST_UID_MIN | (rand::thread_rng().next_u64() & (GUEST_UID_MIN - 1)),
+        tier,
+        expires: SystemTime::now() + validity,
+    };
+    let token = config.key.seal(&serde_json::to_vec(&grant).unwrap());
+    let link = config
+        .server
+        .join(&format!("/guest/{token}"))
+        .map_err(|e| {
+            error!(error = ?e, "failed to build guest link");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        })?;
+    info!(%admin, guest = grant.uid, ?tier, hours, "minted guest link");
+
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({
+            "uid": grant.uid,
+            "tier": tier,
+            "link": link.as_str(),
+            "expires": grant
+                .expires
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        })),
+    ))
+}
+
+/// Starts the session of a guest who opened a guest link.
+pub(super) async fn redeem(
+    Path(token): Path<String>,
+    Extension(config): Extension<Arc<Config>>,
+) -> Response {
+    let grant = config
+        .key
+        .open(&token)
+        .and_then(|plaintext| serde_json::from_slice::<Grant>(&plaintext).ok());
+    match grant {
+        Some(grant) if grant.expires > SystemTime::now() => {
+            info!(guest = grant.uid, tier = ?grant.tier, "guest signed in");
+            let mut roles = Roles::default();
+            roles.insert(Role::Default);
+            if matches!(grant.tier, Tier::Starred) {
+                roles.insert(Role::Starred);
+            }
+            let cookie = User::create_guest(&config, grant.uid, roles, grant.expires);
+            ([cookie], redirect::to("/")).into_response()
+        }
+        grant => {
+            debug!(expired = grant.is_some(), "rejecting guest link");
+            (
+                StatusCode::FORBIDDEN,
+                "This guest link is invalid or has expired",
+            )
+                .into_response()
+        }
+    }
+}
@@ -3,15 +3,28 @@
 
 use std::{
     fmt::Debug,
+    io::{Cursor, Read, Write},
     ops::{Deref, DerefMut},
 };
 
-use aes_gcm::{Aes128Gcm, NewAead};
+use aes_gcm::aead::Aead;
+use aes_gcm::{Aes128Gcm, NewAead, Nonce};
+use base64::read::DecoderReader;
+use base64::write::EncoderStringWriter;
+use base64::URL_SAFE_NO_PAD;
+use hkdf::Hkdf;
 use rand::RngCore;
+use sha2::Sha256;
 use zeroize::Zeroize;
 
 type KeySize = <Aes128Gcm as NewAead>::KeySize;
 
+/// Salt of the keys derived from secrets with HKDF-SHA256.
+const DERIVE_SALT: &[u8] = b"benefice";
+
+/// Context the keys derived from secrets are bound to.
+const DERIVE_INFO: &[u8] = b"benefice cookie key";
+
 #[derive(Clone)]
 pub struct Key(aes_gcm::Key<KeySize>);
 
@@ -49,3 +62,66 @@ impl DerefMut for Key {
         &mut self.0
     }
 }
+
+impl Key {
+    /// Derives a key from `secret` with HKDF-SHA256, which yields the same key across restarts.
+    pub(crate) fn derive(secret: &str) -> Self {
+        let mut key = aes_gcm::Key::<KeySize>::default();
+        Hkdf::<Sha256>::new(Some(DERIVE_SALT), secret.as_bytes())
+            .expand(DERIVE_INFO, &mut key)
+            .expect("key is shorter than the maximum output of HKDF-SHA256");
+        Self(key)
+    }
+
+    /// Encrypts `plaintext`, returning the nonce and ciphertext encoded as URL-safe base64.
+    pub(super) fn seal(&self, plaintext: &[u8]) -> String {
+        // Generate the nonce.
+        let mut rng = rand::thread_rng();
+        let mut nonce = Nonce::default();
+        rng.fill_bytes(&mut nonce);
+
+        // Do the encryption.
+        let aes = Aes128Gcm::new(self);
+        let ciphertext = aes.encrypt(&nonce, plaintext).unwrap();
+
+        // Encode the results.
+        let mut b64 = EncoderStringWriter::new(URL_SAFE_NO_PAD);
+        b64.write_all(&nonce).unwrap();
+        b64.write_all(&ciphertext).unwrap();
+        b64.into_inner()
+    }
+
+    /// Decrypts a value sealed by [`Key::seal`], returning `None` if it was not sealed by this key.
+    pub(super) fn open(&self, sealed: &str) -> Option<Vec<u8>> {
+        // Decode the input.
+        let mut cur = Cursor::new(sealed.as_bytes());
+        let mut b64 = DecoderReader::new(&mut cur, URL_SAFE_NO_PAD);
+
+        // Read the nonce.
+        let mut nonce = Nonce::default();
+        b64.read_exact(&mut nonce).ok()?;
+
+        // Read the ciphertext.
+        let mut ciphertext = Vec::new();
+        let _ = b64.read_to_end(&mut ciphertext).ok()?;
+
+        // Decrypt the ciphertext.
+        let aes = Aes128Gcm::new(self);
+        aes.decrypt(&nonce, &*ciphertext).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn derived_keys_survive_restarts() {
+        let sealed = Key::derive("secret").seal(b"session");
+        assert_eq!(
+            Key::derive("secret").open(&sealed).as_deref(),
+            Some(&b"session"[..])
+        );
+        assert_eq!(Key::derive("other secret").open(&sealed), None);
+    }
+}
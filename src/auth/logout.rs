@@ -0,0 +1,97 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! OpenID Connect back-channel logout.
+
+use super::{session, user, Config};
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use axum::extract::{Extension, Form};
+use axum::http::StatusCode;
+use openidconnect::core::{
+    CoreGenderClaim, CoreJsonWebKeyType, CoreJweContentEncryptionAlgorithm, CoreJwsSigningAlgorithm,
+};
+use openidconnect::Nonce;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+/// Event identifying a logout token.
+const LOGOUT_EVENT: &str = "http://schemas.openid.net/event/backchannel-logout";
+
+#[derive(Debug, Deserialize, Serialize)]
+struct LogoutClaims {
+    events: HashMap<String, serde_json::Value>,
+}
+
+impl openidconnect::AdditionalClaims for LogoutClaims {}
+
+type LogoutToken = openidconnect::IdToken<
+    LogoutClaims,
+    CoreGenderClaim,
+    CoreJweContentEncryptionAlgorithm,
+    CoreJwsSigningAlgorithm,
+    CoreJsonWebKeyType,
+>;
+
+#[derive(Debug, Deserialize)]
+pub(super) struct LogoutRequest {
+    logout_token: String,
+}
+
+/// Logs out a user on behalf of the identity provider, by revoking all their sessions.
+pub(super) async fn backchannel(
+    Extension(config): Extension<Arc<Config>>,
+    Form(LogoutRequest { logout_token }): Form<LogoutRequest>,
+) -> StatusCode {
+    let token: LogoutToken = match serde_json::from_value(logout_token.into()) {
+        Ok(token) => token,
+        Err(e) => {
+            warn!(error = ?e, "malformed logout token");
+            return StatusCode::BAD_REQUEST;
+        }
+    };
+
+    // The token is verified by the provider which issued it.
+    let verified = config.providers.iter().find_map(|provider| {
+        // Logout tokens are never issued with a nonce.
+        let claims = token
+            .claims(
+                &provider.client.id_token_verifier(),
+                |nonce: Option<&Nonce>| {
+                    nonce.map_or(Ok(()), |_| Err("logout token contains a nonce".to_string()))
+                },
+            )
+            .ok()?;
+        Some((provider, claims))
+    });
+    let (provider, claims) = match verified {
+        Some(verified) => verified,
+        None if config.providers.is_empty() => return StatusCode::NOT_FOUND,
+        None => {
+            warn!("invalid logout token");
+            return StatusCode::BAD_REQUEST;
+        }
+    };
+    if !claims.additional_claims().events.contains_key(LOGOUT_EVENT) {
+        warn!("logout token is missing the logout event");
+        return StatusCode::BAD_REQUEST;
+    }
+
+    let uid = match provider.uid(claims.subject()) {
+        Some(uid) => uid,
+        None => return StatusCode::BAD_REQUEST,
+    };
+
+    let ended = session::end_user(uid).await;
+    info!(
+        uid,
+        ended, "revoking sessions on behalf of the identity provider"
+    );
+    user::revoke(uid, config.ttl).await;
+    if config.kill_on_logout {
+        crate::kill_job_of(uid).await;
+    }
+    StatusCode::OK
+}
@@ -1,43 +1,82 @@
 // SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
 // SPDX-License-Identifier: AGPL-3.0-only
 
+mod admin;
+mod device;
+mod flow;
+pub(crate) mod github;
+mod guest;
 mod key;
+mod logout;
+mod provider;
+mod refresh;
+mod role;
+mod session;
+mod share;
+pub(crate) mod token;
 mod user;
 
+pub(crate) use self::admin::{Admin, IsAdmin};
 pub(crate) use self::key::Key;
+pub(crate) use self::provider::{Providers, Subjects};
+pub(crate) use self::role::{GroupRole, Role, Roles};
+pub(crate) use self::session::SessionStore;
+pub(crate) use self::token::ApiUser;
 pub(crate) use self::user::User;
-pub(crate) use openidconnect::url::Url;
+pub(crate) use openidconnect::url::{Host, Url};
 
-use crate::last_page;
+use self::provider::Provider;
+use crate::templates::{HtmlTemplate, LoginTemplate};
+use crate::{audit, dev, redirect, settings};
 
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 use std::time::Duration;
 
-use axum::extract::{Extension, Query};
+use axum::extract::{Extension, Path, Query};
+use axum::headers::UserAgent;
 use axum::http::StatusCode;
-use axum::response::{IntoResponse, Redirect};
-use axum::routing::get;
-use axum::Router;
+use axum::response::{IntoResponse, Redirect, Response};
+use axum::routing::{delete, get, post};
+use axum::{middleware, Router, TypedHeader};
 use axum_extra::extract::CookieJar;
 
-use openidconnect::core::{CoreProviderMetadata, CoreResponseType, CoreUserInfoClaims};
+use openidconnect::core::{CoreResponseType, CoreUserInfoClaims};
 use openidconnect::reqwest::async_http_client;
 use openidconnect::{
-    AuthType, AuthenticationFlow, AuthorizationCode, ClientId, ClientSecret, CsrfToken, IssuerUrl,
-    Nonce, OAuth2TokenResponse, RedirectUrl,
+    AuthenticationFlow, AuthorizationCode, CsrfToken, Nonce, OAuth2TokenResponse,
+    PkceCodeChallenge, Scope,
 };
 
-use anyhow::{Context as _, Error};
+use anyhow::Error;
 use serde::{Deserialize, Serialize};
-use tracing::error;
+use tracing::{error, warn};
 
-#[derive(Deserialize, Serialize, Debug)]
+/// Scope asking providers for a refresh token, with which sessions are renewed.
+const OFFLINE_ACCESS: &str = "offline_access";
+
+#[derive(Clone, Deserialize, Serialize, Debug)]
 struct EnarxClaims {
     has_starred_enarx: Option<bool>,
+    /// Other claims, among which the one listing the groups of the user.
+    #[serde(flatten)]
+    other: HashMap<String, serde_json::Value>,
 }
 
 impl openidconnect::AdditionalClaims for EnarxClaims {}
 
+type TokenResponse = openidconnect::StandardTokenResponse<
+    openidconnect::IdTokenFields<
+        EnarxClaims,
+        openidconnect::EmptyExtraTokenFields,
+        openidconnect::core::CoreGenderClaim,
+        openidconnect::core::CoreJweContentEncryptionAlgorithm,
+        openidconnect::core::CoreJwsSigningAlgorithm,
+        openidconnect::core::CoreJsonWebKeyType,
+    >,
+    openidconnect::core::CoreTokenType,
+>;
+
 type OIDCClient = openidconnect::Client<
     EnarxClaims,
     openidconnect::core::CoreAuthDisplay,
@@ -49,17 +88,7 @@ type OIDCClient = openidconnect::Client<
     openidconnect::core::CoreJsonWebKey,
     openidconnect::core::CoreAuthPrompt,
     openidconnect::StandardErrorResponse<openidconnect::core::CoreErrorResponseType>,
-    openidconnect::StandardTokenResponse<
-        openidconnect::IdTokenFields<
-            EnarxClaims,
-            openidconnect::EmptyExtraTokenFields,
-            openidconnect::core::CoreGenderClaim,
-            openidconnect::core::CoreJweContentEncryptionAlgorithm,
-            openidconnect::core::CoreJwsSigningAlgorithm,
-            openidconnect::core::CoreJsonWebKeyType,
-        >,
-        openidconnect::core::CoreTokenType,
-    >,
+    TokenResponse,
     openidconnect::core::CoreTokenType,
     openidconnect::core::CoreTokenIntrospectionResponse,
     openidconnect::core::CoreRevocableToken,
@@ -67,14 +96,84 @@ type OIDCClient = openidconnect::Client<
 >;
 
 struct Config {
-    oidc: OIDCClient,
+    /// The OpenID Connect providers, the primary one first, or none if users log in with the
+    /// development profile.
+    providers: Vec<Provider>,
+    server: Url,
     ttl: Duration,
     key: Key,
+    admins: HashSet<u64>,
+    /// Name of the claim listing the groups of a user.
+    roles_claim: String,
+    /// Roles granted to the members of groups.
+    role_groups: Vec<GroupRole>,
+    /// GitHub repositories and organizations granting roles.
+    github: github::Github,
+    kill_on_logout: bool,
+}
+
+impl Config {
+    /// Returns whether `user` administers this instance, either by their role or because
+    /// they are listed as an administrator.
+    fn is_admin(&self, user: &User) -> bool {
+        user.roles().contains(Role::Admin) || self.admins.contains(&user.uid())
+    }
+
+    /// Returns the provider named `name`.
+    fn provider(&self, name: &str) -> Option<&Provider> {
+        self.providers.iter().find(|provider| provider.name == name)
+    }
+
+    /// Returns the roles of the user with `uid` of `provider`, given the `claims` of their ID
+    /// token. Only users of the primary provider are GitHub accounts, which may be granted
+    /// roles by their stars and membership on GitHub.
+    async fn roles(&self, provider: &Provider, uid: u64, claims: Option<&EnarxClaims>) -> Roles {
+        let github = provider.is_primary();
+        let has_starred_enarx = match claims.map(|claims| claims.has_starred_enarx) {
+            None => None,
+            Some(None) if github => {
+                error!("No has_starred_enarx claim found in id token");
+                None
+            }
+            Some(val) => val,
+        };
+        let starred = github && self.github.has_starred(uid, has_starred_enarx).await;
+        let groups = role::groups(claims.and_then(|claims| claims.other.get(&self.roles_claim)));
+        let mut roles = role::derive(starred, &groups, &self.role_groups);
+        if github && self.admins.contains(&uid) {
+            roles.insert(Role::Admin);
+        }
+        if github && self.github.is_member(uid).await {
+            roles.insert(Role::Member);
+        }
+        roles
+    }
+
+    /// Returns the roles the user with `uid` has now, without the claims of a login: stars and
+    /// membership on GitHub and the administrators. Roles granted by the groups of a provider
+    /// and by guest links are only known when the user logs in, so they are not included.
+    async fn current_roles(&self, uid: u64) -> Roles {
+        if uid == dev::UID {
+            return dev::roles();
+        }
+        // Users of other providers and guests have IDs beyond the ones GitHub assigns.
+        let github = uid < guest::GUEST_UID_MIN;
+        let starred = github && self.github.has_starred(uid, None).await;
+        let mut roles = role::derive(starred, &[], &self.role_groups);
+        if github && self.admins.contains(&uid) {
+            roles.insert(Role::Admin);
+        }
+        if github && self.github.is_member(uid).await {
+            roles.insert(Role::Member);
+        }
+        roles
+    }
 }
 
 #[derive(Debug, Deserialize)]
 struct AuthRequest {
     code: String,
+    state: String,
 }
 
 fn ice<E: std::fmt::Debug>(info: &'static str) -> impl Fn(E) -> (StatusCode, &'static str) {
@@ -84,84 +183,224 @@ fn ice<E: std::fmt::Debug>(info: &'static str) -> impl Fn(E) -> (StatusCode, &'s
     }
 }
 
-fn accept_any_nonce(_: Option<&openidconnect::Nonce>) -> Result<(), String> {
-    Ok(())
+/// User agent of a request, which describes the sessions it starts.
+type Agent = Option<TypedHeader<UserAgent>>;
+
+fn agent_of(agent: Agent) -> Option<String> {
+    agent.map(|TypedHeader(agent)| agent.to_string())
 }
 
+/// Logs in a user redirected back by the primary provider.
 async fn authorized(
-    Query(AuthRequest { code, .. }): Query<AuthRequest>,
+    query: Query<AuthRequest>,
+    Extension(config): Extension<Arc<Config>>,
+    jar: CookieJar,
+    agent: Agent,
+) -> Result<Response, (StatusCode, &'static str)> {
+    authorize(provider::PRIMARY, query, &config, jar, agent).await
+}
+
+/// Logs in a user redirected back by the provider named `name`.
+async fn authorized_by(
+    Path(name): Path<String>,
+    query: Query<AuthRequest>,
     Extension(config): Extension<Arc<Config>>,
     jar: CookieJar,
-) -> impl IntoResponse {
+    agent: Agent,
+) -> Result<Response, (StatusCode, &'static str)> {
+    authorize(&name, query, &config, jar, agent).await
+}
+
+async fn authorize(
+    name: &str,
+    Query(AuthRequest { code, state }): Query<AuthRequest>,
+    config: &Config,
+    jar: CookieJar,
+    agent: Agent,
+) -> Result<Response, (StatusCode, &'static str)> {
+    let provider = config
+        .provider(name)
+        .ok_or_else(|| ice("OpenID Connect provider is unknown")(name))?;
+    let oidc = &provider.client;
+
+    // Check that the login was started by this browser.
+    let (jar, flow) = flow::finish(config, jar, name, &state);
+    let (verifier, nonce) = flow.ok_or_else(|| {
+        warn!(
+            provider = name,
+            "login state is missing, invalid or expired"
+        );
+        (
+            StatusCode::BAD_REQUEST,
+            "The login has expired or was started elsewhere, please log in again",
+        )
+    })?;
+
     // Get the OIDC token.
-    let token = config
-        .oidc
+    let token = oidc
         .exchange_code(AuthorizationCode::new(code))
+        .set_pkce_verifier(verifier)
         .request_async(async_http_client)
         .await
         .map_err(ice("error constructing request token"))?;
 
-    let has_starred_enarx = match token.extra_fields().id_token() {
+    let (uid, roles) = identify(config, provider, &token, Some(&nonce)).await?;
+    let id = session::start(config, uid, Some(provider), agent_of(agent), Some(&token))
+        .await
+        .map_err(ice("error starting session"))?;
+    let session_cookie = User::create(config, uid, roles, id);
+    audit::Event::new(uid, audit::Action::Login)
+        .detail(provider.name.clone())
+        .record()
+        .await;
+    let back = redirect::back(&jar);
+    Ok(([session_cookie], jar, back).into_response())
+}
+
+/// Identifies the user `provider` issued `token` to, returning their ID and roles.
+/// The ID token must carry `nonce` if the login asked for one, and no nonce otherwise.
+async fn identify(
+    config: &Config,
+    provider: &Provider,
+    token: &TokenResponse,
+    nonce: Option<&Nonce>,
+) -> Result<(u64, Roles), (StatusCode, &'static str)> {
+    let oidc = &provider.client;
+    let verifier = oidc.id_token_verifier();
+    let id_claims = match token.extra_fields().id_token() {
+        None if nonce.is_some() => return Err(ice("no id token found in response")(())),
         None => {
             error!("No id token found in response");
-            false
+            None
         }
         Some(id_token) => {
-            match id_token.claims(&config.oidc.id_token_verifier(), accept_any_nonce) {
+            let claims = match nonce {
+                Some(nonce) => id_token.claims(&verifier, nonce),
+                None => id_token.claims(&verifier, |nonce: Option<&Nonce>| {
+                    nonce.map_or(Ok(()), |_| Err("unexpected nonce".to_string()))
+                }),
+            };
+            match claims {
                 Err(e) => {
-                    error!(error = ?e, "failed to verify claims");
-                    false
+                    warn!(provider = %provider.name, error = ?e, "failed to verify claims");
+                    return Err((
+                        StatusCode::BAD_REQUEST,
+                        "The login could not be verified, please log in again",
+                    ));
                 }
-                Ok(claims) => match claims.additional_claims().has_starred_enarx {
-                    None => {
-                        error!("No has_starred_enarx claim found in id token");
-                        false
-                    }
-                    Some(val) => val,
-                },
+                Ok(claims) => Some(claims.additional_claims().clone()),
             }
         }
     };
 
     // Get the OIDC claims from the User Info endpoint.
-    let claims: CoreUserInfoClaims = config
-        .oidc
+    let claims: CoreUserInfoClaims = oidc
         .user_info(token.access_token().clone(), None)
         .map_err(ice("error constructing user info request"))?
         .request_async(async_http_client)
         .await
         .map_err(ice("error fetching claims"))?;
 
-    // Get the GitHub user identifier.
-    match claims.subject().split_once('|') {
-        Some(("github", uid)) => {
-            let session_cookie = User::create(
-                &config,
-                uid.parse().map_err(ice("invalid uid"))?,
-                has_starred_enarx,
-            );
-            let redirect_path = last_page(&jar).await.unwrap_or("/");
-            Ok(([session_cookie], Redirect::to(redirect_path)).into_response())
-        }
-        _ => Err(ice("invalid user type")("unknown user type")),
-    }
+    // Get the user identifier, which is the GitHub user ID of users of the primary provider.
+    let uid = provider
+        .uid(claims.subject())
+        .ok_or_else(|| ice("invalid user type")(claims.subject().as_str()))?;
+    let roles = config.roles(provider, uid, id_claims.as_ref()).await;
+    Ok((uid, roles))
 }
 
-// TODO: invalidate the session on the remote server properly
-async fn logout(jar: CookieJar) -> impl IntoResponse {
+/// Ends the session of the user, revoking its refresh token at the provider, and logs them
+/// out of the provider if it supports logging out on behalf of this server.
+async fn logout(
+    user: Option<User>,
+    Extension(config): Extension<Arc<Config>>,
+    jar: CookieJar,
+) -> Response {
     let session_cookie = User::clear();
-    let redirect_path = last_page(&jar).await.unwrap_or("/");
-    ([session_cookie], Redirect::to(redirect_path)).into_response()
+    let ended = match user.and_then(|user| user.session()) {
+        Some(id) => session::end(id).await,
+        None => None,
+    };
+    let ended = match ended {
+        Some(ended) => ended,
+        None => return ([session_cookie], redirect::back(&jar)).into_response(),
+    };
+
+    let provider = ended
+        .provider
+        .as_deref()
+        .and_then(|name| config.provider(name));
+    let logout_url = provider
+        .and_then(|provider| provider.logout_url(ended.id_token.as_deref(), &config.server));
+    session::revoke(&config, ended).await;
+    match logout_url {
+        Some(url) => ([session_cookie], Redirect::to(url.as_str())).into_response(),
+        None => ([session_cookie], redirect::back(&jar)).into_response(),
+    }
+}
+
+/// Logs in with the only provider, or lets the user choose one.
+async fn login(
+    Extension(config): Extension<Arc<Config>>,
+    jar: CookieJar,
+    agent: Agent,
+) -> Response {
+    match config.providers.as_slice() {
+        [] => dev_login(&config, &jar, agent).await,
+        [provider] => redirect_to(&config, provider, jar),
+        providers => HtmlTemplate(LoginTemplate {
+            demo_fqdn: settings::other().demo_fqdn,
+            user: false,
+            providers: providers
+                .iter()
+                .map(|provider| (provider.name.clone(), provider.title.clone()))
+                .collect(),
+        })
+        .into_response(),
+    }
 }
 
-async fn login(Extension(config): Extension<Arc<Config>>) -> impl IntoResponse {
-    let request = config.oidc.authorize_url(
-        AuthenticationFlow::<CoreResponseType>::AuthorizationCode,
-        CsrfToken::new_random,
-        Nonce::new_random,
-    );
+/// Logs in with the provider named `name`.
+async fn login_with(
+    Path(name): Path<String>,
+    Extension(config): Extension<Arc<Config>>,
+    jar: CookieJar,
+) -> Response {
+    match config.provider(&name) {
+        Some(provider) => redirect_to(&config, provider, jar),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
 
-    Redirect::temporary(request.url().0.as_str())
+/// Redirects the user to log in with `provider`, asking for a refresh token.
+fn redirect_to(config: &Config, provider: &Provider, jar: CookieJar) -> Response {
+    let (challenge, verifier) = PkceCodeChallenge::new_random_sha256();
+    let (url, state, nonce) = provider
+        .client
+        .authorize_url(
+            AuthenticationFlow::<CoreResponseType>::AuthorizationCode,
+            CsrfToken::new_random,
+            Nonce::new_random,
+        )
+        .add_scope(Scope::new(OFFLINE_ACCESS.into()))
+        .set_pkce_challenge(challenge)
+        .url();
+    let jar = flow::start(config, jar, &provider.name, state, nonce, verifier);
+    (jar, Redirect::temporary(url.as_str())).into_response()
+}
+
+/// Logs in as the local administrator of the development profile.
+async fn dev_login(config: &Config, jar: &CookieJar, agent: Agent) -> Response {
+    let roles = dev::roles();
+    let id = match session::start(config, dev::UID, None, agent_of(agent), None).await {
+        Ok(id) => id,
+        Err(e) => return ice("error starting session")(e).into_response(),
+    };
+    let session_cookie = User::create(config, dev::UID, roles, id);
+    audit::Event::new(dev::UID, audit::Action::Login)
+        .record()
+        .await;
+    ([session_cookie], redirect::back(jar)).into_response()
 }
 
 pub(crate) struct Oidc {
@@ -171,31 +410,96 @@ pub(crate) struct Oidc {
     pub(crate) secret: Option<String>,
     pub(crate) session_ttl: Duration,
     pub(crate) session_key: Key,
+    /// Where sessions are kept.
+    pub(crate) session_store: SessionStore,
+    /// GitHub user IDs of the administrators.
+    pub(crate) admins: HashSet<u64>,
+    /// Name of the claim of the ID token listing the groups of a user.
+    pub(crate) roles_claim: String,
+    /// Roles granted to the members of groups.
+    pub(crate) role_groups: Vec<GroupRole>,
+    /// OpenID Connect providers besides the primary one.
+    pub(crate) providers: Vec<provider::ProviderConfig>,
+    /// GitHub repositories whose stargazers get the `starred` role.
+    pub(crate) star_repos: Vec<github::Repo>,
+    /// GitHub organizations and teams whose members get the `member` role.
+    pub(crate) github_orgs: Vec<github::Org>,
+    /// GitHub token used to see private membership of organizations and teams.
+    pub(crate) github_token: Option<String>,
+    /// How long lookups of stars and membership on GitHub are used for before they are
+    /// refreshed.
+    pub(crate) github_cache_ttl: Duration,
+    /// Whether to kill the job of a user logged out by the identity provider.
+    pub(crate) kill_on_logout: bool,
+    /// Whether users log in as the local administrator of the development profile instead.
+    pub(crate) dev: bool,
 }
 
 impl Oidc {
-    pub(crate) async fn routes(self, router: Router) -> Result<Router, Error> {
-        let redir = RedirectUrl::from_url(self.server.join("/authorized").unwrap());
-        let secret = self.secret.map(ClientSecret::new);
-        let url = IssuerUrl::from_url(self.issuer);
-        let id = ClientId::new(self.client);
+    /// Checks that the metadata of the OpenID Connect providers can be fetched.
+    pub(crate) async fn discover(&self) -> Result<(), Error> {
+        provider::discover(&self.issuer).await?;
+        for provider in &self.providers {
+            provider::discover(provider.issuer()).await?;
+        }
+        Ok(())
+    }
 
-        let metadata = CoreProviderMetadata::discover_async(url, async_http_client)
-            .await
-            .with_context(|| "unable to fetch OIDC provider metadata")?;
+    /// Returns the resolver of the subjects of the users of the OpenID Connect providers.
+    pub(crate) fn subjects(&self) -> Subjects {
+        Subjects::new(&self.providers)
+    }
 
-        let oidc = OIDCClient::from_provider_metadata(metadata, id, secret)
-            .set_redirect_uri(redir)
-            .set_auth_type(AuthType::RequestBody);
+    pub(crate) async fn routes(self, router: Router) -> Result<Router, Error> {
+        let mut providers = vec![];
+        if !self.dev {
+            providers.push(
+                Provider::primary(&self.server, self.issuer, self.client, self.secret).await?,
+            );
+            for provider in self.providers {
+                providers.push(Provider::other(&self.server, provider).await?);
+            }
+        }
+        session::init(self.session_store).await?;
+        let server = self.server;
 
         Ok(router
             .route("/authorized", get(authorized))
+            .route("/authorized/:provider", get(authorized_by))
             .route("/logout", get(logout))
+            .route("/backchannel-logout", post(logout::backchannel))
             .route("/login", get(login))
+            .route("/login/:provider", get(login_with))
+            .route("/device", post(device::start))
+            .route("/device/token", post(device::claim))
+            .route("/tokens", get(token::list).post(token::create))
+            .route("/tokens/:id", delete(token::revoke))
+            .route(
+                "/sessions",
+                get(session::list).delete(session::delete_others),
+            )
+            .route("/sessions/:id", delete(session::delete))
+            .route("/guest/:token", get(guest::redeem))
+            .route("/admin/guests", post(guest::mint))
+            .route("/jobs/:id/share", post(share::mint))
+            .route("/share/:token", get(share::view))
+            .route("/share/:token/:stream", get(share::output))
+            .layer(middleware::from_fn(refresh::renew))
             .layer(Extension(Arc::new(Config {
-                oidc,
+                providers,
+                server,
                 key: self.session_key,
                 ttl: self.session_ttl,
+                admins: self.admins,
+                roles_claim: self.roles_claim,
+                role_groups: self.role_groups,
+                github: github::Github {
+                    star_repos: self.star_repos,
+                    orgs: self.github_orgs,
+                    token: self.github_token,
+                    cache_ttl: self.github_cache_ttl,
+                },
+                kill_on_logout: self.kill_on_logout,
             }))))
     }
 }
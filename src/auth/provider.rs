@@ -0,0 +1,350 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! OpenID Connect providers users can log in with.
+//!
+//! The provider given with `--oidc-issuer` and `--oidc-client` is the primary one, whose
+//! subjects are GitHub accounts, e.g. `github|1234`, and whose users are identified by their
+//! GitHub user ID. Further providers given with `--oidc-providers` have users of their own,
+//! whose IDs are derived from the issuer and their subject, so that users of different
+//! providers never share an ID. Operators name these users by their subject prefixed with the
+//! name of their provider, e.g. `keycloak:f81d4fae`.
+//!
+//! Providers which advertise a device authorization endpoint also let headless clients log
+//! in with the device authorization flow. Logging out revokes the refresh token of the session
+//! at providers which advertise a revocation endpoint, and logs users out of providers which
+//! advertise an end session endpoint.
+
+use super::OIDCClient;
+use crate::secret::SecretFile;
+
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::{ensure, Context as _};
+use openidconnect::core::{
+    CoreAuthDisplay, CoreClaimName, CoreClaimType, CoreClientAuthMethod, CoreGrantType,
+    CoreJsonWebKey, CoreJsonWebKeyType, CoreJsonWebKeyUse, CoreJweContentEncryptionAlgorithm,
+    CoreJweKeyManagementAlgorithm, CoreJwsSigningAlgorithm, CoreResponseMode, CoreResponseType,
+    CoreRevocableToken, CoreSubjectIdentifierType,
+};
+use openidconnect::reqwest::async_http_client;
+use openidconnect::url::Url;
+use openidconnect::{
+    AdditionalProviderMetadata, AuthType, ClientId, ClientSecret, DeviceAuthorizationUrl,
+    IssuerUrl, RedirectUrl, RefreshToken, RevocationUrl,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+/// Name of the primary provider in URLs.
+pub(super) const PRIMARY: &str = "default";
+
+/// Users of providers other than the primary one have IDs with this bit set.
+const NAMESPACED_UID_MIN: u64 = 1 << 63;
+
+/// Metadata of providers beyond the core one, which this server makes use of.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct ExtraMetadata {
+    device_authorization_endpoint: Option<DeviceAuthorizationUrl>,
+    revocation_endpoint: Option<RevocationUrl>,
+    end_session_endpoint: Option<Url>,
+}
+
+impl AdditionalProviderMetadata for ExtraMetadata {}
+
+type ProviderMetadata = openidconnect::ProviderMetadata<
+    ExtraMetadata,
+    CoreAuthDisplay,
+    CoreClientAuthMethod,
+    CoreClaimName,
+    CoreClaimType,
+    CoreGrantType,
+    CoreJweContentEncryptionAlgorithm,
+    CoreJweKeyManagementAlgorithm,
+    CoreJwsSigningAlgorithm,
+    CoreJsonWebKeyType,
+    CoreJsonWebKeyUse,
+    CoreJsonWebKey,
+    CoreResponseMode,
+    CoreResponseType,
+    CoreSubjectIdentifierType,
+>;
+
+/// Provider besides the primary one, as configured.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ProviderConfig {
+    /// Name of the provider in URLs, e.g. `keycloak`.
+    name: String,
+    /// Label of the provider on the login page, its name if not given.
+    title: Option<String>,
+    issuer: Url,
+    client: String,
+    /// Path to a file containing the client secret.
+    secret: Option<PathBuf>,
+}
+
+impl ProviderConfig {
+    pub(super) fn issuer(&self) -> &Url {
+        &self.issuer
+    }
+}
+
+/// Providers besides the primary one.
+///
+/// For example:
+/// ```toml
+/// [[providers]]
+/// name = "keycloak"
+/// title = "Corporate account"
+/// issuer = "https://keycloak.example.com/realms/corp"
+/// client = "benefice"
+/// secret = "/run/secrets/keycloak"
+/// ```
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Providers {
+    pub(crate) providers: Vec<ProviderConfig>,
+}
+
+impl FromStr for Providers {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let providers: Self = toml::from_str(s).context("invalid OIDC providers")?;
+        let mut names = HashSet::from([PRIMARY]);
+        for provider in &providers.providers {
+            let name = provider.name.as_str();
+            ensure!(
+                !name.is_empty()
+                    && name
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'),
+                "invalid OIDC provider name `{name}`"
+            );
+            ensure!(
+                names.insert(name),
+                "OIDC provider `{name}` is defined twice"
+            );
+        }
+        Ok(providers)
+    }
+}
+
+/// Provider users can log in with.
+pub(super) struct Provider {
+    pub(super) name: String,
+    pub(super) title: String,
+    issuer: Url,
+    client_id: String,
+    pub(super) client: OIDCClient,
+    /// Whether the provider supports the device authorization flow.
+    pub(super) device: bool,
+    /// Page at which users log out of the provider, if it has one.
+    end_session: Option<Url>,
+}
+
+impl Provider {
+    /// Discovers the provider at `issuer`, which redirects users back to `redirect`.
+    async fn discover(
+        name: String,
+        title: String,
+        issuer: Url,
+        client: String,
+        secret: Option<String>,
+        redirect: Url,
+    ) -> anyhow::Result<Self> {
+        let metadata = ProviderMetadata::discover_async(
+            IssuerUrl::from_url(issuer.clone()),
+            async_http_client,
+        )
+        .await
+        .with_context(|| format!("unable to fetch metadata of OIDC provider `{name}`"))?;
+        let extra = metadata.additional_metadata().clone();
+        let client_id = client;
+        let mut client = OIDCClient::from_provider_metadata(
+            metadata,
+            ClientId::new(client_id.clone()),
+            secret.map(ClientSecret::new),
+        )
+        .set_redirect_uri(RedirectUrl::from_url(redirect))
+        .set_auth_type(AuthType::RequestBody);
+        if let Some(url) = &extra.device_authorization_endpoint {
+            client = client.set_device_authorization_uri(url.clone());
+        }
+        if let Some(url) = extra.revocation_endpoint {
+            client = client.set_revocation_uri(url);
+        }
+        Ok(Self {
+            name,
+            title,
+            issuer,
+            client_id,
+            client,
+            device: extra.device_authorization_endpoint.is_some(),
+            end_session: extra.end_session_endpoint,
+        })
+    }
+
+    /// Discovers the primary provider.
+    pub(super) async fn primary(
+        server: &Url,
+        issuer: Url,
+        client: String,
+        secret: Option<String>,
+    ) -> anyhow::Result<Self> {
+        let title = issuer.host_str().unwrap_or(PRIMARY).to_string();
+        let redirect = server.join("/authorized")?;
+        Self::discover(PRIMARY.into(), title, issuer, client, secret, redirect).await
+    }
+
+    /// Discovers a provider besides the primary one.
+    pub(super) async fn other(server: &Url, config: ProviderConfig) -> anyhow::Result<Self> {
+        let secret = match &config.secret {
+            Some(path) => Some(path.to_string_lossy().parse::<SecretFile<String>>()?.into()),
+            None => None,
+        };
+        let redirect = server.join(&format!("/authorized/{}", config.name))?;
+        let title = config.title.unwrap_or_else(|| config.name.clone());
+        Self::discover(
+            config.name,
+            title,
+            config.issuer,
+            config.client,
+            secret,
+            redirect,
+        )
+        .await
+    }
+
+    /// Returns whether this is the primary provider, whose users are GitHub accounts.
+    pub(super) fn is_primary(&self) -> bool {
+        self.name == PRIMARY
+    }
+
+    /// Revokes the refresh `token`, if the provider supports revocation.
+    pub(super) async fn revoke(&self, token: RefreshToken) {
+        let request = match self
+            .client
+            .revoke_token(CoreRevocableToken::RefreshToken(token))
+        {
+            Ok(request) => request,
+            Err(_) => return,
+        };
+        if let Err(e) = request.request_async(async_http_client).await {
+            warn!(provider = %self.name, error = ?e, "failed to revoke refresh token");
+        }
+    }
+
+    /// Returns the page at which the user with `id_token` logs out of the provider, which
+    /// redirects them back to `target`, if the provider has one.
+    pub(super) fn logout_url(&self, id_token: Option<&str>, target: &Url) -> Option<Url> {
+        let mut url = self.end_session.clone()?;
+        {
+            let mut query = url.query_pairs_mut();
+            _ = query.append_pair("client_id", &self.client_id);
+            if let Some(id_token) = id_token {
+                _ = query.append_pair("id_token_hint", id_token);
+            }
+            _ = query.append_pair("post_logout_redirect_uri", target.as_str());
+        }
+        Some(url)
+    }
+
+    /// Returns the ID of the user with `subject`, if it is a valid subject of this provider.
+    pub(super) fn uid(&self, subject: &str) -> Option<u64> {
+        if self.is_primary() {
+            github_uid(subject)
+        } else {
+            Some(namespaced_uid(&self.issuer, subject))
+        }
+    }
+}
+
+/// Returns the GitHub user ID of the user with `subject` of the primary provider.
+fn github_uid(subject: &str) -> Option<u64> {
+    match subject.split_once('|') {
+        Some(("github", uid)) => uid.parse().ok(),
+        _ => None,
+    }
+}
+
+/// Returns the ID of the user with `subject` of the provider at `issuer`, which is not the
+/// primary one.
+fn namespaced_uid(issuer: &Url, subject: &str) -> u64 {
+    let digest = Sha256::new()
+        .chain_update(issuer.as_str())
+        .chain_update([0u8])
+        .chain_update(subject)
+        .finalize();
+    let mut hash = [0; 8];
+    hash.copy_from_slice(&digest[..8]);
+    NAMESPACED_UID_MIN | (u64::from_be_bytes(hash) & (NAMESPACED_UID_MIN - 1))
+}
+
+/// Resolves the subjects of users of all providers to their IDs.
+///
+/// Subjects of the primary provider are GitHub accounts, e.g. `github|1234`. Subjects of other
+/// providers are prefixed with the name of their provider, e.g. `keycloak:f81d4fae`.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Subjects {
+    /// Issuers of the providers besides the primary one, by their name.
+    issuers: HashMap<String, Url>,
+}
+
+impl Subjects {
+    pub(crate) fn new<'a>(providers: impl IntoIterator<Item = &'a ProviderConfig>) -> Self {
+        let issuers = providers
+            .into_iter()
+            .map(|provider| (provider.name.clone(), provider.issuer.clone()))
+            .collect();
+        Self { issuers }
+    }
+
+    /// Returns the ID of the user with `subject`, if it names a user of a known provider.
+    pub(crate) fn uid(&self, subject: &str) -> Option<u64> {
+        match subject.split_once(':') {
+            Some((_, "")) => None,
+            Some((name, subject)) => Some(namespaced_uid(self.issuers.get(name)?, subject)),
+            None => github_uid(subject),
+        }
+    }
+}
+
+/// Checks that the metadata of the provider at `issuer` can be fetched.
+pub(super) async fn discover(issuer: &Url) -> anyhow::Result<()> {
+    let url = IssuerUrl::from_url(issuer.clone());
+    _ = ProviderMetadata::discover_async(url, async_http_client)
+        .await
+        .with_context(|| format!("unable to fetch metadata of OIDC provider {issuer}"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subjects_resolve_to_the_ids_users_log_in_with() {
+        let providers: Providers = r#"
+            [[providers]]
+            name = "keycloak"
+            issuer = "https://keycloak.example.com/realms/corp"
+            client = "benefice"
+        "#
+        .parse()
+        .unwrap();
+        let subjects = Subjects::new(&providers.providers);
+
+        assert_eq!(subjects.uid("github|1234"), Some(1234));
+        assert_eq!(
+            subjects.uid("keycloak:f81d4fae"),
+            Some(namespaced_uid(providers.providers[0].issuer(), "f81d4fae"))
+        );
+        assert!(subjects.uid("keycloak:f81d4fae").unwrap() >= NAMESPACED_UID_MIN);
+        assert_eq!(subjects.uid("okta:f81d4fae"), None);
+    }
+}
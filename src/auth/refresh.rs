@@ -0,0 +1,106 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Renewal of sessions with the refresh tokens of OpenID Connect providers.
+//!
+//! Refresh tokens never leave this server: they are kept with the session they were issued
+//! for, which the session cookie names. Once half the TTL of a session has passed, the
+//! next request exchanges the refresh token and gets a renewed session cookie, so that users
+//! watching a long workload are not logged out while the provider still lets them in. If the
+//! provider rejects the refresh token, the session ends and pages redirect to log in again.
+
+use super::{session, Config, User};
+use crate::redirect;
+
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use anyhow::Context as _;
+use axum::body::Body;
+use axum::http::{header::UPGRADE, Method, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Redirect, Response};
+use axum_extra::extract::CookieJar;
+use openidconnect::reqwest::async_http_client;
+use openidconnect::OAuth2TokenResponse;
+use tracing::{debug, warn};
+
+/// Exchanges the refresh token of the session `id` of `user` for a new one, returning whether
+/// there was one to exchange. Requests racing to renew the same session find the token taken,
+/// and leave the renewal to the first one.
+async fn refresh(config: &Config, user: &User, id: u64) -> anyhow::Result<bool> {
+    let (provider, token) = match session::take_refresh(id, user, config.ttl).await {
+        Some(taken) => taken,
+        None => return Ok(false),
+    };
+
+    let provider = config
+        .provider(&provider)
+        .with_context(|| format!("OIDC provider `{provider}` is gone"))?;
+    let response = provider
+        .client
+        .exchange_refresh_token(&token)
+        .request_async(async_http_client)
+        .await
+        .context("failed to exchange refresh token")?;
+
+    // Providers may rotate refresh tokens, or keep using the same one.
+    let token = response.refresh_token().cloned().unwrap_or(token);
+    Ok(session::renewed(id, token, config.ttl).await)
+}
+
+/// Renews the session of a request once half its TTL has passed, if it can be refreshed.
+pub(crate) async fn renew(req: Request<Body>, next: Next<Body>) -> Response {
+    let config = match req.extensions().get::<Arc<Config>>() {
+        Some(config) => config.clone(),
+        None => return next.run(req).await,
+    };
+    let user = match User::from_headers(&config, req.headers()) {
+        Some(user) => user,
+        None => return next.run(req).await,
+    };
+    let id = match user.session() {
+        Some(id) => id,
+        None => return next.run(req).await,
+    };
+    let age = SystemTime::now()
+        .duration_since(user.issued())
+        .unwrap_or_default();
+    if age < config.ttl / 2 || age >= config.ttl || user.is_revoked().await {
+        return next.run(req).await;
+    }
+
+    match refresh(&config, &user, id).await {
+        Ok(false) => next.run(req).await,
+        Ok(true) => {
+            debug!(uid = user.uid(), "renewed session");
+            let (name, value) = user.renew(&config);
+            let mut res = next.run(req).await;
+            _ = res.headers_mut().append(name, value);
+            res
+        }
+        Err(e) => {
+            warn!(uid = user.uid(), error = ?e, "failed to refresh session, logging out");
+            _ = session::end(id).await;
+            relogin(&req)
+        }
+    }
+}
+
+/// Ends the session of `req`, sending users back to the login page if they requested one.
+fn relogin(req: &Request<Body>) -> Response {
+    let path = req.uri().path();
+    let page = req.method() == Method::GET
+        && !path.starts_with("/api/")
+        && !req.headers().contains_key(UPGRADE);
+    if !page {
+        return ([User::clear()], StatusCode::UNAUTHORIZED).into_response();
+    }
+
+    let target = req
+        .uri()
+        .path_and_query()
+        .map_or(path, |target| target.as_str());
+    let jar = redirect::remember(CookieJar::default(), target);
+    ([User::clear()], jar, Redirect::to("/login")).into_response()
+}
@@ -0,0 +1,173 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Roles of users, which decide what they may do on this instance and which tier of limits
+//! applies to them.
+//!
+//! Roles are derived when users log in. Stars of the repositories given with `--star-repo`
+//! grant the `starred` role, and membership of the GitHub organizations given with
+//! `--github-org` grants the `member` role. The groups listed in the claim of the OpenID
+//! Connect provider chosen with `--oidc-roles-claim` grant the roles they are mapped to with
+//! `--oidc-role`. Every user has the `default` role.
+
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use anyhow::{ensure, Context as _};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Role {
+    /// Administrators of this instance.
+    Admin,
+    /// Users who have starred any of the repositories given with `--star-repo`.
+    Starred,
+    /// Members of the GitHub organizations or teams given with `--github-org`.
+    Member,
+    /// Every user.
+    Default,
+    /// Users who are banned from submitting workloads.
+    Banned,
+}
+
+impl Role {
+    const ALL: [Self; 5] = [
+        Self::Admin,
+        Self::Starred,
+        Self::Member,
+        Self::Default,
+        Self::Banned,
+    ];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Admin => "admin",
+            Self::Starred => "starred",
+            Self::Member => "member",
+            Self::Default => "default",
+            Self::Banned => "banned",
+        }
+    }
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+impl Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Set of roles, which is `Copy` so that users are.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(from = "Vec<Role>", into = "Vec<Role>")]
+pub(crate) struct Roles(u8);
+
+impl Roles {
+    pub(crate) fn contains(self, role: Role) -> bool {
+        self.0 & role.bit() != 0
+    }
+
+    pub(crate) fn insert(&mut self, role: Role) {
+        self.0 |= role.bit();
+    }
+
+    pub(crate) fn iter(self) -> impl Iterator<Item = Role> {
+        Role::ALL
+            .into_iter()
+            .filter(move |role| self.contains(*role))
+    }
+
+    /// Returns whether any role is in both sets.
+    pub(crate) fn intersects(self, other: Self) -> bool {
+        self.0 & other.0 != 0
+    }
+}
+
+impl FromIterator<Role> for Roles {
+    fn from_iter<I: IntoIterator<Item = Role>>(roles: I) -> Self {
+        let mut set = Self::default();
+        for role in roles {
+            set.insert(role);
+        }
+        set
+    }
+}
+
+impl From<Vec<Role>> for Roles {
+    fn from(roles: Vec<Role>) -> Self {
+        roles.into_iter().collect()
+    }
+}
+
+impl From<Roles> for Vec<Role> {
+    fn from(roles: Roles) -> Self {
+        roles.iter().collect()
+    }
+}
+
+impl Display for Roles {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let roles: Vec<_> = self.iter().map(Role::as_str).collect();
+        f.write_str(&roles.join(", "))
+    }
+}
+
+/// Mapping of a group of the OpenID Connect provider to a role, e.g. `admin=benefice-admins`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct GroupRole {
+    pub(crate) role: Role,
+    pub(crate) group: String,
+}
+
+impl FromStr for GroupRole {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (role, group) = s
+            .split_once('=')
+            .with_context(|| format!("`{s}` is not of the form `<role>=<group>`"))?;
+        let role = Role::ALL
+            .into_iter()
+            .find(|known| known.as_str() == role.trim())
+            .with_context(|| format!("unknown role `{role}`"))?;
+        let group = group.trim();
+        ensure!(!group.is_empty(), "no group is mapped to `{role}`");
+        Ok(Self {
+            role,
+            group: group.into(),
+        })
+    }
+}
+
+/// Returns the groups listed in the claim `value`, which is either a list of groups or a
+/// single one.
+pub(super) fn groups(value: Option<&serde_json::Value>) -> Vec<String> {
+    match value {
+        Some(serde_json::Value::String(group)) => vec![group.clone()],
+        Some(serde_json::Value::Array(groups)) => groups
+            .iter()
+            .filter_map(|group| group.as_str().map(str::to_string))
+            .collect(),
+        _ => vec![],
+    }
+}
+
+/// Derives the roles of a user who is a member of `groups`.
+pub(super) fn derive(starred: bool, groups: &[String], mapping: &[GroupRole]) -> Roles {
+    let mut roles = Roles::default();
+    roles.insert(Role::Default);
+    if starred {
+        roles.insert(Role::Starred);
+    }
+    for GroupRole { role, group } in mapping {
+        if groups.contains(group) {
+            roles.insert(*role);
+        }
+    }
+    roles
+}
@@ -0,0 +1,402 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Sessions of users logged in with a browser, which they can list and end.
+//!
+//! Session cookies name the session they belong to, and are only accepted while it is active.
+//! Sessions keep the tokens the provider issued at login: the refresh token, with which the
+//! session is renewed and which is revoked at the provider when the session ends, and the ID
+//! token, which is passed to the provider as a hint when the user logs out.
+//!
+//! Sessions are kept in memory unless another store is given with `--session-store`: Redis
+//! with `redis://<host>[:<port>][/<db>]` in instances built with the `redis` feature, or an
+//! SQLite database with `sqlite:<path>` in instances built with the `sqlite` feature. Sessions
+//! kept there survive restarts, as long as the session cookies can still be decrypted with the
+//! key given with `--session-key`.
+//!
+//! Instances given a `--cookie-secret` keep no sessions at all: the session cookie, encrypted
+//! with a key derived from the secret, is all there is to a session, so that sessions survive
+//! restarts without a store. Such sessions cannot be listed, ended one by one or renewed with
+//! refresh tokens, and expire after the session TTL. Revocations of all sessions of a user are
+//! kept in memory only.
+
+#[cfg(feature = "redis")]
+mod redis;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+use super::{Config, Provider, TokenResponse, User};
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::bail;
+use axum::async_trait;
+use axum::extract::{Extension, Path};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use once_cell::sync::{Lazy, OnceCell};
+use openidconnect::{OAuth2TokenResponse, RefreshToken};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::{Mutex, RwLock};
+use tracing::{error, info};
+
+/// Configured session store, none if sessions are only kept in their cookies.
+static STORE: OnceCell<Option<Box<dyn Store>>> = OnceCell::new();
+
+/// Held while a session is read and written back, so that no update is lost.
+static UPDATE: Lazy<Mutex<()>> = Lazy::new(Default::default);
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(super) struct Session {
+    uid: u64,
+    /// Name of the provider the user logged in with, if any.
+    pub(super) provider: Option<String>,
+    refresh: Option<RefreshToken>,
+    pub(super) id_token: Option<String>,
+    /// User agent of the browser the user logged in with.
+    agent: Option<String>,
+    created: SystemTime,
+    /// When the latest session cookie was issued.
+    issued: SystemTime,
+}
+
+/// Where sessions are kept.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) enum SessionStore {
+    #[default]
+    Memory,
+    /// Redis server at this URL.
+    Redis(String),
+    /// SQLite database at this path.
+    Sqlite(PathBuf),
+    /// No store, sessions are only kept in their cookies, which is chosen with
+    /// `--cookie-secret`.
+    Cookie,
+}
+
+impl FromStr for SessionStore {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "memory" {
+            Ok(Self::Memory)
+        } else if s.starts_with("redis://") || s.starts_with("rediss://") {
+            Ok(Self::Redis(s.into()))
+        } else if let Some(path) = s.strip_prefix("sqlite:") {
+            let path = path.strip_prefix("//").unwrap_or(path);
+            if path.is_empty() {
+                bail!("`{s}` names no SQLite database");
+            }
+            Ok(Self::Sqlite(path.into()))
+        } else {
+            bail!("unknown session store `{s}`, expected `memory`, `redis://...` or `sqlite:...`")
+        }
+    }
+}
+
+/// Backend sessions are kept in.
+#[async_trait]
+trait Store: Send + Sync {
+    /// Returns the session `id`, unless it does not exist or has expired.
+    async fn get(&self, id: u64) -> anyhow::Result<Option<Session>>;
+
+    /// Saves the session `id`, which expires at `expires`.
+    async fn put(&self, id: u64, session: &Session, expires: SystemTime) -> anyhow::Result<()>;
+
+    /// Removes the session `id`, returning it unless it did not exist or had expired.
+    async fn remove(&self, id: u64) -> anyhow::Result<Option<Session>>;
+
+    /// Returns the sessions of the user with `uid` which have not expired.
+    async fn of_user(&self, uid: u64) -> anyhow::Result<Vec<(u64, Session)>>;
+}
+
+/// Store keeping sessions in memory, which are lost on restart.
+#[derive(Default)]
+struct Memory(RwLock<HashMap<u64, (Session, SystemTime)>>);
+
+#[async_trait]
+impl Store for Memory {
+    async fn get(&self, id: u64) -> anyhow::Result<Option<Session>> {
+        let sessions = self.0.read().await;
+        Ok(sessions
+            .get(&id)
+            .filter(|(_, expires)| *expires > SystemTime::now())
+            .map(|(session, _)| session.clone()))
+    }
+
+    async fn put(&self, id: u64, session: &Session, expires: SystemTime) -> anyhow::Result<()> {
+        let now = SystemTime::now();
+        let mut sessions = self.0.write().await;
+        sessions.retain(|_, (_, expires)| *expires > now);
+        _ = sessions.insert(id, (session.clone(), expires));
+        Ok(())
+    }
+
+    async fn remove(&self, id: u64) -> anyhow::Result<Option<Session>> {
+        let removed = self.0.write().await.remove(&id);
+        Ok(removed
+            .filter(|(_, expires)| *expires > SystemTime::now())
+            .map(|(session, _)| session))
+    }
+
+    async fn of_user(&self, uid: u64) -> anyhow::Result<Vec<(u64, Session)>> {
+        let now = SystemTime::now();
+        Ok(self
+            .0
+            .read()
+            .await
+            .iter()
+            .filter(|(_, (session, expires))| session.uid == uid && *expires > now)
+            .map(|(id, (session, _))| (*id, session.clone()))
+            .collect())
+    }
+}
+
+/// Opens the session store on startup.
+pub(super) async fn init(store: SessionStore) -> anyhow::Result<()> {
+    let store: Option<Box<dyn Store>> = match store {
+        SessionStore::Cookie => {
+            info!("keeping sessions in their cookies only");
+            None
+        }
+        SessionStore::Memory => Some(Box::new(Memory::default())),
+        #[cfg(feature = "redis")]
+        SessionStore::Redis(url) => Some(Box::new(self::redis::Redis::connect(&url).await?)),
+        #[cfg(not(feature = "redis"))]
+        SessionStore::Redis(_) => {
+            bail!("cannot keep sessions in Redis without the `redis` feature")
+        }
+        #[cfg(feature = "sqlite")]
+        SessionStore::Sqlite(path) => Some(Box::new(self::sqlite::Sqlite::open(path).await?)),
+        #[cfg(not(feature = "sqlite"))]
+        SessionStore::Sqlite(_) => {
+            bail!("cannot keep sessions in SQLite without the `sqlite` feature")
+        }
+    };
+    STORE
+        .set(store)
+        .map_err(|_| anyhow::anyhow!("session store already initialized"))
+}
+
+/// Returns the session store, unless sessions are only kept in their cookies.
+fn store() -> Option<&'static dyn Store> {
+    STORE
+        .get_or_init(|| Some(Box::new(Memory::default())))
+        .as_deref()
+}
+
+fn secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Formats the ID of a session, which does not fit into the numbers of JavaScript.
+fn format_id(id: u64) -> String {
+    format!("{id:016x}")
+}
+
+/// Starts a session of the user with `uid`, who logged in with `agent` and was issued `token`
+/// by `provider`, returning its ID unless sessions are only kept in their cookies. Sessions
+/// which are not renewed expire after the session TTL.
+pub(super) async fn start(
+    config: &Config,
+    uid: u64,
+    provider: Option<&Provider>,
+    agent: Option<String>,
+    token: Option<&TokenResponse>,
+) -> anyhow::Result<Option<u64>> {
+    let store = match store() {
+        Some(store) => store,
+        None => return Ok(None),
+    };
+    let id = rand::thread_rng().next_u64();
+    let now = SystemTime::now();
+    let session = Session {
+        uid,
+        provider: provider.map(|provider| provider.name.clone()),
+        refresh: token.and_then(|token| token.refresh_token().cloned()),
+        id_token: token
+            .and_then(|token| token.extra_fields().id_token())
+            .map(ToString::to_string),
+        agent,
+        created: now,
+        issued: now,
+    };
+    store.put(id, &session, now + config.ttl).await?;
+    Ok(Some(id))
+}
+
+/// Returns the session `id`, logging errors of the store.
+async fn get(id: u64) -> Option<Session> {
+    store()?.get(id).await.unwrap_or_else(|e| {
+        error!(error = ?e, "failed to look up session");
+        None
+    })
+}
+
+/// Returns whether the session `id` of the user with `uid` is active.
+pub(super) async fn is_active(id: u64, uid: u64) -> bool {
+    matches!(get(id).await, Some(session) if session.uid == uid)
+}
+
+/// Takes the refresh token of the session `id` of `user`, unless another request of the
+/// session took it or the session was renewed since `user` was issued. The provider which
+/// issued the token is returned along with it.
+pub(super) async fn take_refresh(
+    id: u64,
+    user: &User,
+    ttl: Duration,
+) -> Option<(String, RefreshToken)> {
+    let _update = UPDATE.lock().await;
+    let mut session = get(id)
+        .await
+        .filter(|session| session.uid == user.uid() && session.issued <= user.issued())?;
+    let provider = session.provider.clone()?;
+    let token = session.refresh.take()?;
+    if let Err(e) = store()?.put(id, &session, session.issued + ttl).await {
+        error!(error = ?e, "failed to save session");
+        return None;
+    }
+    Some((provider, token))
+}
+
+/// Records that the session `id` was renewed with `token`, returning whether it is active.
+pub(super) async fn renewed(id: u64, token: RefreshToken, ttl: Duration) -> bool {
+    let _update = UPDATE.lock().await;
+    let (store, mut session) = match (store(), get(id).await) {
+        (Some(store), Some(session)) => (store, session),
+        _ => return false,
+    };
+    session.refresh = Some(token);
+    session.issued = SystemTime::now();
+    match store.put(id, &session, session.issued + ttl).await {
+        Ok(()) => true,
+        Err(e) => {
+            error!(error = ?e, "failed to save session");
+            false
+        }
+    }
+}
+
+/// Ends the session `id`, returning it if it was active.
+pub(super) async fn end(id: u64) -> Option<Session> {
+    store()?.remove(id).await.unwrap_or_else(|e| {
+        error!(error = ?e, "failed to end session");
+        None
+    })
+}
+
+/// Ends the sessions of the user with `uid` matching `filter`, returning them.
+async fn end_matching(uid: u64, filter: impl Fn(u64) -> bool) -> anyhow::Result<Vec<Session>> {
+    let mut ended = vec![];
+    let store = match store() {
+        Some(store) => store,
+        None => return Ok(ended),
+    };
+    for (id, _) in store.of_user(uid).await? {
+        if filter(id) {
+            ended.extend(store.remove(id).await?);
+        }
+    }
+    Ok(ended)
+}
+
+/// Ends all sessions of the user with `uid`, returning how many were active.
+pub(super) async fn end_user(uid: u64) -> usize {
+    end_matching(uid, |_| true)
+        .await
+        .map(|ended| ended.len())
+        .unwrap_or_else(|e| {
+            error!(error = ?e, uid, "failed to end sessions");
+            0
+        })
+}
+
+/// Revokes the refresh token of `session` at the provider which issued it.
+pub(super) async fn revoke(config: &Config, session: Session) {
+    let provider = session
+        .provider
+        .as_deref()
+        .and_then(|name| config.provider(name));
+    if let (Some(provider), Some(token)) = (provider, session.refresh) {
+        provider.revoke(token).await;
+    }
+}
+
+/// Lists the active sessions of the current user, which are not known if sessions are only
+/// kept in their cookies.
+pub(super) async fn list(user: User) -> Result<impl IntoResponse, StatusCode> {
+    let store = store().ok_or(StatusCode::NOT_FOUND)?;
+    let mut sessions = store.of_user(user.uid()).await.map_err(|e| {
+        error!(error = ?e, "failed to list sessions");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    sessions.sort_by_key(|(_, session)| session.created);
+    let sessions: Vec<_> = sessions
+        .into_iter()
+        .map(|(id, session)| {
+            json!({
+                "id": format_id(id),
+                "current": user.session() == Some(id),
+                "provider": session.provider,
+                "agent": session.agent,
+                "created": secs(session.created),
+                "renewed": secs(session.issued),
+            })
+        })
+        .collect();
+    Ok(Json(sessions))
+}
+
+/// Ends a session of the current user by its ID.
+pub(super) async fn delete(
+    Path(id): Path<String>,
+    user: User,
+    Extension(config): Extension<Arc<Config>>,
+) -> StatusCode {
+    let id = match u64::from_str_radix(&id, 16) {
+        Ok(id) => id,
+        Err(_) => return StatusCode::NOT_FOUND,
+    };
+    match end_matching(user.uid(), |other| other == id).await {
+        Ok(mut ended) => match ended.pop() {
+            Some(session) => {
+                info!(%user, session = %format_id(id), "ended session");
+                revoke(&config, session).await;
+                StatusCode::NO_CONTENT
+            }
+            None => StatusCode::NOT_FOUND,
+        },
+        Err(e) => {
+            error!(error = ?e, "failed to end session");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+/// Ends all sessions of the current user but the one of the request.
+pub(super) async fn delete_others(
+    user: User,
+    Extension(config): Extension<Arc<Config>>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let ended = end_matching(user.uid(), |id| user.session() != Some(id))
+        .await
+        .map_err(|e| {
+            error!(error = ?e, "failed to end sessions");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    info!(%user, count = ended.len(), "ended other sessions");
+    let count = ended.len();
+    for session in ended {
+        revoke(&config, session).await;
+    }
+    Ok(Json(json!({ "ended": count })))
+}
@@ -0,0 +1,104 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Sessions kept in Redis, which expire along with their keys.
+//!
+//! Each session is kept as JSON under `benefice:session:<id>`, and the IDs of the sessions of
+//! a user in the set `benefice:user-sessions:<uid>`, from which expired sessions are removed
+//! when the sessions of the user are listed.
+
+use super::{Session, Store};
+
+use std::time::SystemTime;
+
+use ::redis::aio::MultiplexedConnection;
+use ::redis::AsyncCommands;
+use anyhow::Context as _;
+use axum::async_trait;
+use tracing::info;
+
+pub(super) struct Redis {
+    conn: MultiplexedConnection,
+}
+
+fn key(id: u64) -> String {
+    format!("benefice:session:{id:016x}")
+}
+
+fn user_key(uid: u64) -> String {
+    format!("benefice:user-sessions:{uid}")
+}
+
+impl Redis {
+    /// Connects to the Redis server at `url`.
+    pub(super) async fn connect(url: &str) -> anyhow::Result<Self> {
+        let client = ::redis::Client::open(url).context("invalid Redis URL")?;
+        let conn = client
+            .get_multiplexed_tokio_connection()
+            .await
+            .context("failed to connect to Redis")?;
+        info!("keeping sessions in Redis");
+        Ok(Self { conn })
+    }
+}
+
+#[async_trait]
+impl Store for Redis {
+    async fn get(&self, id: u64) -> anyhow::Result<Option<Session>> {
+        let mut conn = self.conn.clone();
+        let data: Option<String> = conn.get(key(id)).await?;
+        data.map(|data| serde_json::from_str(&data))
+            .transpose()
+            .context("malformed session")
+    }
+
+    async fn put(&self, id: u64, session: &Session, expires: SystemTime) -> anyhow::Result<()> {
+        let ttl = expires
+            .duration_since(SystemTime::now())
+            .unwrap_or_default()
+            .as_millis()
+            .max(1) as u64;
+        let data = serde_json::to_string(session).context("failed to encode session")?;
+        let mut conn = self.conn.clone();
+        ::redis::pipe()
+            .atomic()
+            .cmd("SET")
+            .arg(key(id))
+            .arg(data)
+            .arg("PX")
+            .arg(ttl)
+            .ignore()
+            .cmd("SADD")
+            .arg(user_key(session.uid))
+            .arg(id)
+            .ignore()
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn remove(&self, id: u64) -> anyhow::Result<Option<Session>> {
+        let session = self.get(id).await?;
+        let mut conn = self.conn.clone();
+        conn.del::<_, ()>(key(id)).await?;
+        if let Some(session) = &session {
+            conn.srem::<_, _, ()>(user_key(session.uid), id).await?;
+        }
+        Ok(session)
+    }
+
+    async fn of_user(&self, uid: u64) -> anyhow::Result<Vec<(u64, Session)>> {
+        let mut conn = self.conn.clone();
+        let ids: Vec<u64> = conn.smembers(user_key(uid)).await?;
+        let mut sessions = vec![];
+        for id in ids {
+            match self.get(id).await? {
+                Some(session) => sessions.push((id, session)),
+                None => {
+                    conn.srem::<_, _, ()>(user_key(uid), id).await?;
+                }
+            }
+        }
+        Ok(sessions)
+    }
+}
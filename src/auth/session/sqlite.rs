@@ -0,0 +1,136 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Sessions kept in an SQLite database, whose expired sessions are deleted whenever a session
+//! is saved.
+
+use super::{Session, Store};
+
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context as _;
+use axum::async_trait;
+use rusqlite::{params, Connection, OptionalExtension};
+use tokio::task::spawn_blocking;
+use tracing::info;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS sessions (
+    id INTEGER PRIMARY KEY,
+    uid INTEGER NOT NULL,
+    data TEXT NOT NULL,
+    expires INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS sessions_uid ON sessions (uid);
+";
+
+pub(super) struct Sqlite(Arc<Mutex<Connection>>);
+
+/// Returns `time` as seconds since the epoch, as stored in the database.
+fn secs(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+fn decode(data: &str) -> anyhow::Result<Session> {
+    serde_json::from_str(data).context("malformed session")
+}
+
+impl Sqlite {
+    /// Opens the database at `path`, creating it if it does not exist.
+    pub(super) async fn open(path: PathBuf) -> anyhow::Result<Self> {
+        let conn = spawn_blocking(move || -> anyhow::Result<_> {
+            let conn = Connection::open(&path)
+                .with_context(|| format!("failed to open session database `{}`", path.display()))?;
+            conn.execute_batch(SCHEMA)
+                .context("failed to create session table")?;
+            info!(path = %path.display(), "keeping sessions in SQLite");
+            Ok(conn)
+        })
+        .await??;
+        Ok(Self(Arc::new(Mutex::new(conn))))
+    }
+
+    /// Runs `f` with the connection to the database, off the runtime.
+    async fn run<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+    ) -> anyhow::Result<T> {
+        let conn = self.0.clone();
+        spawn_blocking(move || f(&conn.lock().unwrap()))
+            .await?
+            .context("session database failed")
+    }
+}
+
+// IDs are stored as their bits, which exceed the signed integers of SQLite.
+#[async_trait]
+impl Store for Sqlite {
+    async fn get(&self, id: u64) -> anyhow::Result<Option<Session>> {
+        let now = secs(SystemTime::now());
+        let data = self
+            .run(move |conn| {
+                conn.query_row(
+                    "SELECT data FROM sessions WHERE id = ?1 AND expires > ?2",
+                    params![id as i64, now],
+                    |row| row.get::<_, String>(0),
+                )
+                .optional()
+            })
+            .await?;
+        data.as_deref().map(decode).transpose()
+    }
+
+    async fn put(&self, id: u64, session: &Session, expires: SystemTime) -> anyhow::Result<()> {
+        let uid = session.uid as i64;
+        let data = serde_json::to_string(session).context("failed to encode session")?;
+        let now = secs(SystemTime::now());
+        let expires = secs(expires);
+        self.run(move |conn| {
+            _ = conn.execute("DELETE FROM sessions WHERE expires <= ?1", params![now])?;
+            _ = conn.execute(
+                "INSERT OR REPLACE INTO sessions (id, uid, data, expires) VALUES (?1, ?2, ?3, ?4)",
+                params![id as i64, uid, data, expires],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn remove(&self, id: u64) -> anyhow::Result<Option<Session>> {
+        let now = secs(SystemTime::now());
+        let data = self
+            .run(move |conn| {
+                conn.query_row(
+                    "DELETE FROM sessions WHERE id = ?1 RETURNING data, expires",
+                    params![id as i64],
+                    |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?)),
+                )
+                .optional()
+            })
+            .await?;
+        data.filter(|(_, expires)| *expires > now)
+            .map(|(data, _)| decode(&data))
+            .transpose()
+    }
+
+    async fn of_user(&self, uid: u64) -> anyhow::Result<Vec<(u64, Session)>> {
+        let now = secs(SystemTime::now());
+        let rows = self
+            .run(move |conn| {
+                let mut stmt =
+                    conn.prepare("SELECT id, data FROM sessions WHERE uid = ?1 AND expires > ?2")?;
+                let rows = stmt.query_map(params![uid as i64, now], |row| {
+                    Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+                })?;
+                rows.collect::<rusqlite::Result<Vec<_>>>()
+            })
+            .await?;
+        rows.into_iter()
+            .map(|(id, data)| Ok((id as u64, decode(&data)?)))
+            .collect()
+    }
+}
@@ -0,0 +1,171 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Read-only links to the output of a job, which let people without an account watch it live,
+//! e.g. during a talk.
+//!
+//! The owner of a job mints share links embedding the job and the expiry of the link, encrypted
+//! with the session key like guest links. Viewers can only read the output of the job, they can
+//! neither send it input nor control it.
+
+use super::{Config, User};
+use crate::logs::Stream;
+use crate::templates::{HtmlTemplate, ShareTemplate};
+use crate::{audit, settings};
+
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::extract::{Extension, Path, Query};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{debug, error, info};
+
+/// Longest time a share link may be valid for.
+const MAX_VALIDITY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Contents of a share link.
+#[derive(Debug, Deserialize, Serialize)]
+struct Grant {
+    job: String,
+    expires: SystemTime,
+}
+
+fn default_hours() -> u64 {
+    2
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct MintRequest {
+    /// Number of hours the link is valid for.
+    #[serde(default = "default_hours")]
+    hours: u64,
+}
+
+impl Default for MintRequest {
+    fn default() -> Self {
+        Self {
+            hours: default_hours(),
+        }
+    }
+}
+
+/// Mints a share link to the output of the job with `id`, which must be the job the user is
+/// running.
+pub(super) async fn mint(
+    user: User,
+    Path(id): Path<String>,
+    Extension(config): Extension<Arc<Config>>,
+    request: Option<Json<MintRequest>>,
+) -> Result<impl IntoResponse, Response> {
+    let MintRequest { hours } = request.map(|Json(request)| request).unwrap_or_default();
+    let validity = Duration::from_secs(hours.saturating_mul(60 * 60));
+    if validity.is_zero() || validity > MAX_VALIDITY {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!(
+                "Share links must be valid for between 1 and {} hours",
+                MAX_VALIDITY.as_secs() / 60 / 60
+            ),
+        )
+            .into_response());
+    }
+
+    match crate::JOBS.read().await.get(&user) {
+        Some(job) if job.read().await.id == id => {}
+        _ => return Err(StatusCode::NOT_FOUND.into_response()),
+    }
+
+    let grant = Grant {
+        job: id,
+        expires: SystemTime::now() + validity,
+    };
+    let token = config.key.seal(&serde_json::to_vec(&grant).unwrap());
+    let link = config
+        .server
+        .join(&format!("/share/{token}"))
+        .map_err(|e| {
+            error!(error = ?e, "failed to build share link");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        })?;
+    info!(%user, job_id = grant.job, hours, "minted share link");
+    audit::Event::new(user.uid(), audit::Action::Share)
+        .job(&grant.job)
+        .record()
+        .await;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({
+            "link": link.as_str(),
+            "expires": grant
+                .expires
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        })),
+    ))
+}
+
+/// Returns the ID of the job a share link grants access to, if the link is valid.
+fn open(config: &Config, token: &str) -> Result<String, Response> {
+    let grant = config
+        .key
+        .open(token)
+        .and_then(|plaintext| serde_json::from_slice::<Grant>(&plaintext).ok());
+    match grant {
+        Some(grant) if grant.expires > SystemTime::now() => Ok(grant.job),
+        grant => {
+            debug!(expired = grant.is_some(), "rejecting share link");
+            Err((
+                StatusCode::FORBIDDEN,
+                "This share link is invalid or has expired",
+            )
+                .into_response())
+        }
+    }
+}
+
+/// Shows the output of the job a share link grants access to.
+pub(super) async fn view(
+    Path(token): Path<String>,
+    Extension(config): Extension<Arc<Config>>,
+) -> Result<impl IntoResponse, Response> {
+    let id = open(&config, &token)?;
+    Ok(HtmlTemplate(ShareTemplate {
+        demo_fqdn: settings::other().demo_fqdn,
+        user: false,
+        id,
+        token,
+    }))
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub(super) struct OutputQuery {
+    offset: Option<u64>,
+}
+
+/// Reads a chunk of a stream of the job a share link grants access to, without marking it as
+/// read for its owner.
+pub(super) async fn output(
+    Path((token, stream)): Path<(String, Stream)>,
+    Extension(config): Extension<Arc<Config>>,
+    Query(OutputQuery { offset }): Query<OutputQuery>,
+) -> Result<impl IntoResponse, Response> {
+    let id = open(&config, &token)?;
+    let jobs = crate::JOBS.read().await;
+    for job in jobs.values() {
+        let job = job.read().await;
+        if job.id == id {
+            let output = job.output.clone();
+            return output
+                .map(|output| output.peek(stream, offset))
+                .ok_or_else(|| StatusCode::NOT_FOUND.into_response());
+        }
+    }
+    Err(StatusCode::NOT_FOUND.into_response())
+}
@@ -0,0 +1,432 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Personal access tokens, which authenticate API clients on behalf of a user.
+//!
+//! Tokens are limited to scopes and expire. They may also be bound to a single job, in which
+//! case they are only accepted by the API routes of that job, e.g. for dashboards which display
+//! its output. Only digests of their secrets are kept, in a file within the runtime directory
+//! so that tokens survive restarts.
+//!
+//! Tokens only keep the ID of their user. The roles of the user are looked up whenever a token
+//! is used, so that a token does not keep roles its user has lost since it was minted.
+
+use super::{Config, User};
+use crate::audit;
+use crate::denylist::digest;
+use crate::history;
+use crate::redirect;
+use crate::templates::{HtmlTemplate, TokensTemplate};
+
+use std::collections::{BTreeSet, HashMap};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context as _;
+use axum::extract::{Extension, FromRequest, Path as AxumPath, RequestParts};
+use axum::headers::authorization::Bearer;
+use axum::headers::Authorization;
+use axum::http::{Method, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::{async_trait, Json, TypedHeader};
+use base64::write::EncoderStringWriter;
+use base64::URL_SAFE_NO_PAD;
+use once_cell::sync::{Lazy, OnceCell};
+use rand::RngCore;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::json;
+use tempfile::NamedTempFile;
+use tokio::sync::RwLock;
+use tracing::{error, info, Span};
+use uuid::Uuid;
+
+/// Name of the token file within the runtime directory.
+const TOKENS_FILE: &str = "benefice-tokens.json";
+
+/// Validity of tokens created without an explicit one.
+pub(super) const DEFAULT_VALIDITY_DAYS: u64 = 90;
+
+/// Longest validity of a token.
+const MAX_VALIDITY_DAYS: u64 = 365;
+
+/// Length of the prefix of the secret shown to identify a token.
+const PREFIX_LEN: usize = 8;
+
+/// Path to the token file, set by `load`.
+static PATH: OnceCell<PathBuf> = OnceCell::new();
+
+/// Personal access tokens keyed by the digest of their secret.
+static TOKENS: Lazy<RwLock<HashMap<String, Token>>> = Lazy::new(Default::default);
+
+/// What a token may be used for.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Scope {
+    /// Submitting and controlling jobs.
+    Submit,
+    /// Reading the status and output of jobs.
+    Read,
+    /// Administration, only granted to administrators.
+    Admin,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct Token {
+    id: Uuid,
+    /// ID of the user the token authenticates.
+    #[serde(alias = "user", deserialize_with = "uid_of")]
+    uid: u64,
+    /// Label chosen by the user.
+    #[serde(default)]
+    name: String,
+    /// Beginning of the secret, to recognize the token.
+    prefix: String,
+    scopes: BTreeSet<Scope>,
+    /// Job the token is bound to, if any.
+    #[serde(default)]
+    job: Option<String>,
+    created: SystemTime,
+    expires: SystemTime,
+}
+
+impl Token {
+    fn is_expired(&self) -> bool {
+        self.expires < SystemTime::now()
+    }
+}
+
+/// Deserializes the ID of the user of a token, which tokens minted before they only kept the
+/// ID of their user kept along with the rest of the user.
+fn uid_of<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Owner {
+        Uid(u64),
+        User { uid: u64 },
+    }
+    match Owner::deserialize(deserializer)? {
+        Owner::Uid(uid) | Owner::User { uid } => Ok(uid),
+    }
+}
+
+fn secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn write(path: &Path, tokens: &HashMap<String, Token>) -> anyhow::Result<()> {
+    let dir = path
+        .parent()
+        .context("token file has no parent directory")?;
+    let mut file = NamedTempFile::new_in(dir).context("failed to create token file")?;
+    serde_json::to_writer(&mut file, tokens).context("failed to encode tokens")?;
+    file.flush().context("failed to write tokens")?;
+    _ = file.persist(path).context("failed to persist token file")?;
+    Ok(())
+}
+
+async fn update<T>(f: impl FnOnce(&mut HashMap<String, Token>) -> T) -> T {
+    let mut tokens = TOKENS.write().await;
+    let result = f(&mut tokens);
+    tokens.retain(|_, token| !token.is_expired());
+    if let Some(path) = PATH.get() {
+        if let Err(e) = write(path, &tokens) {
+            error!(error = ?e, "failed to save tokens");
+        }
+    }
+    result
+}
+
+/// Loads the tokens saved in `runtime_dir` on startup.
+pub(crate) fn load(runtime_dir: impl AsRef<Path>) -> anyhow::Result<()> {
+    let path = runtime_dir.as_ref().join(TOKENS_FILE);
+    let tokens: HashMap<String, Token> = match std::fs::read(&path) {
+        Ok(data) => serde_json::from_slice(&data)
+            .with_context(|| format!("failed to decode tokens in `{}`", path.display()))?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+        Err(e) => return Err(e).context("failed to read token file"),
+    };
+    info!(count = tokens.len(), "loaded API tokens");
+    *TOKENS.try_write().context("tokens already in use")? = tokens;
+    PATH.set(path)
+        .map_err(|_| anyhow::anyhow!("tokens already loaded"))
+}
+
+/// Authenticates a request by the token passed as `Authorization: Bearer <token>`, if any,
+/// which must grant `scope`. Tokens bound to a job are only accepted for routes of that job.
+pub(super) async fn authenticate<B: Send>(
+    req: &mut RequestParts<B>,
+    scope: Scope,
+) -> Result<Option<User>, StatusCode> {
+    let bearer = match TypedHeader::<Authorization<Bearer>>::from_request(req).await {
+        Ok(TypedHeader(Authorization(bearer))) => bearer,
+        Err(_) => return Ok(None),
+    };
+
+    let (uid, job) = {
+        let tokens = TOKENS.read().await;
+        let token = tokens
+            .get(&digest(bearer.token()))
+            .filter(|token| !token.is_expired())
+            .ok_or(StatusCode::UNAUTHORIZED)?;
+        if !token.scopes.contains(&scope) {
+            return Err(StatusCode::FORBIDDEN);
+        }
+        (token.uid, token.job.clone())
+    };
+    if let Some(job) = job {
+        let params = AxumPath::<HashMap<String, String>>::from_request(req)
+            .await
+            .map(|AxumPath(params)| params)
+            .unwrap_or_default();
+        if params.get("id") != Some(&job) {
+            return Err(StatusCode::FORBIDDEN);
+        }
+    }
+    let config = req.extensions().get::<Arc<Config>>().cloned().unwrap();
+    let user = User::new(uid, config.current_roles(uid).await, None);
+    _ = Span::current().record("user_id", uid);
+    Ok(Some(user))
+}
+
+/// Mints a token for `user`, returning its identifier and its secret.
+/// The token authenticates the user with the roles they have when it is used.
+pub(crate) async fn mint(
+    user: User,
+    name: &str,
+    scopes: BTreeSet<Scope>,
+    job: Option<String>,
+    validity: Duration,
+) -> (Uuid, String) {
+    let mut secret = [0; 32];
+    rand::thread_rng().fill_bytes(&mut secret);
+
+    let mut b64 = EncoderStringWriter::new(URL_SAFE_NO_PAD);
+    b64.write_all(&secret).unwrap();
+    let secret = b64.into_inner();
+
+    let created = SystemTime::now();
+    let token = Token {
+        id: Uuid::new_v4(),
+        uid: user.uid(),
+        name: name.trim().chars().take(64).collect(),
+        prefix: secret[..PREFIX_LEN].into(),
+        scopes,
+        job,
+        created,
+        expires: created + validity,
+    };
+    let id = token.id;
+    info!(%user, token_id = %id, scopes = ?token.scopes, job = ?token.job, "minted API token");
+    update(|tokens| _ = tokens.insert(digest(&secret), token)).await;
+    audit::Event::new(user.uid(), audit::Action::TokenCreate)
+        .token(id)
+        .record()
+        .await;
+    (id, secret)
+}
+
+/// A user authenticated by a personal access token passed as
+/// `Authorization: Bearer <token>`. Reading requires the `read` scope,
+/// anything else the `submit` scope.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct ApiUser(pub(crate) User);
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for ApiUser {
+    type Rejection = StatusCode;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let scope = match *req.method() {
+            Method::GET | Method::HEAD => Scope::Read,
+            _ => Scope::Submit,
+        };
+        authenticate(req, scope)
+            .await?
+            .map(Self)
+            .ok_or(StatusCode::UNAUTHORIZED)
+    }
+}
+
+/// Lists the tokens of the current user. The secrets are never returned, only their prefix.
+pub(super) async fn list(user: User) -> impl IntoResponse {
+    let mut tokens: Vec<_> = TOKENS
+        .read()
+        .await
+        .values()
+        .filter(|token| token.uid == user.uid() && !token.is_expired())
+        .cloned()
+        .collect();
+    tokens.sort_by_key(|token| token.created);
+    let tokens: Vec<_> = tokens
+        .into_iter()
+        .map(|token| {
+            json!({
+                "id": token.id,
+                "name": token.name,
+                "prefix": token.prefix,
+                "scopes": token.scopes,
+                "job": token.job,
+                "created": secs(token.created),
+                "expires": secs(token.expires),
+            })
+        })
+        .collect();
+    Json(tokens)
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub(super) struct CreateRequest {
+    name: String,
+    /// Scopes of the token, `submit` and `read` if none are given.
+    scopes: Option<BTreeSet<Scope>>,
+    /// Number of days the token is valid for.
+    days: Option<u64>,
+    /// Job of the user to bind the token to.
+    job: Option<String>,
+}
+
+/// Mints a new token for the current user. This is the only time the secret is revealed.
+pub(super) async fn create(
+    user: User,
+    Extension(config): Extension<Arc<Config>>,
+    request: Option<Json<CreateRequest>>,
+) -> Result<impl IntoResponse, Response> {
+    let request = request.map(|Json(request)| request).unwrap_or_default();
+    let scopes = request
+        .scopes
+        .unwrap_or_else(|| [Scope::Submit, Scope::Read].into());
+    if scopes.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "A token needs at least one scope").into_response());
+    }
+    // The tier of a guest is only known to their session, which tokens would outlive.
+    if user.expires().is_some() {
+        return Err((StatusCode::FORBIDDEN, "Guests cannot create API tokens").into_response());
+    }
+    if scopes.contains(&Scope::Admin) && !config.is_admin(&user) {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Only administrators may create tokens with the admin scope",
+        )
+            .into_response());
+    }
+    if let Some(job) = &request.job {
+        if scopes.contains(&Scope::Admin) {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "Tokens bound to a job cannot have the admin scope",
+            )
+                .into_response());
+        }
+        if !history::recent(&user)
+            .await
+            .iter()
+            .any(|run| &run.id == job)
+        {
+            return Err((StatusCode::NOT_FOUND, "No such job").into_response());
+        }
+    }
+    let days = request.days.unwrap_or(DEFAULT_VALIDITY_DAYS);
+    if !(1..=MAX_VALIDITY_DAYS).contains(&days) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("Tokens are valid for 1 to {MAX_VALIDITY_DAYS} days"),
+        )
+            .into_response());
+    }
+
+    let validity = Duration::from_secs(days * 24 * 60 * 60);
+    let (id, secret) = mint(
+        user,
+        &request.name,
+        scopes.clone(),
+        request.job.clone(),
+        validity,
+    )
+    .await;
+    let response = json!({
+        "id": id,
+        "prefix": &secret[..PREFIX_LEN],
+        "token": secret,
+        "scopes": scopes,
+        "job": request.job,
+        "expires": secs(SystemTime::now() + validity),
+    });
+
+    Ok((StatusCode::CREATED, Json(response)))
+}
+
+/// Revokes a token of the current user by its identifier.
+pub(super) async fn revoke(AxumPath(id): AxumPath<Uuid>, user: User) -> StatusCode {
+    let revoked = update(|tokens| {
+        let count = tokens.len();
+        tokens.retain(|_, token| token.uid != user.uid() || token.id != id);
+        tokens.len() < count
+    })
+    .await;
+    if revoked {
+        info!(%user, token_id = %id, "revoked API token");
+        audit::Event::new(user.uid(), audit::Action::TokenRevoke)
+            .token(id)
+            .record()
+            .await;
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+/// Revokes all tokens of the user with `uid`, returning their identifiers.
+pub(crate) async fn revoke_all(uid: u64) -> Vec<Uuid> {
+    let revoked: Vec<_> = update(|tokens| {
+        let revoked = tokens
+            .values()
+            .filter(|token| token.uid == uid)
+            .map(|token| token.id)
+            .collect();
+        tokens.retain(|_, token| token.uid != uid);
+        revoked
+    })
+    .await;
+    for id in &revoked {
+        info!(uid, token_id = %id, "revoked API token");
+        audit::Event::new(uid, audit::Action::TokenRevoke)
+            .token(id)
+            .record()
+            .await;
+    }
+    revoked
+}
+
+/// Tokens as kept in a backup of the state of this instance.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(transparent)]
+pub(crate) struct Backup(HashMap<String, Token>);
+
+/// Returns all tokens for a backup of the state of this instance.
+pub(crate) async fn export() -> Backup {
+    Backup(TOKENS.read().await.clone())
+}
+
+/// Replaces all tokens with the ones restored from a backup, returning how many of them
+/// have not expired.
+pub(crate) async fn import(Backup(restored): Backup) -> usize {
+    update(|tokens| *tokens = restored).await;
+    TOKENS.read().await.len()
+}
+
+/// Renders the page to manage the tokens of the current user.
+pub(crate) async fn page(user: Option<User>, demo_fqdn: String) -> Response {
+    match user {
+        Some(_) => HtmlTemplate(TokensTemplate {
+            demo_fqdn,
+            user: true,
+        })
+        .into_response(),
+        None => redirect::to("/login").into_response(),
+    }
+}
@@ -1,34 +1,54 @@
 // SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
 // SPDX-License-Identifier: AGPL-3.0-only
 
+use std::collections::HashMap;
 use std::fmt::Display;
 use std::hash::Hash;
-use std::io::{Cursor, Read, Write};
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
-use aes_gcm::aead::Aead;
-use aes_gcm::{Aes128Gcm, NewAead, Nonce};
 use axum::extract::{FromRequest, RequestParts};
-use axum::headers::{Cookie, HeaderName};
-use axum::http::HeaderValue;
+use axum::headers::{Cookie, HeaderMapExt, HeaderName};
 use axum::http::{header::SET_COOKIE, StatusCode};
+use axum::http::{HeaderMap, HeaderValue};
 use axum::{async_trait, TypedHeader};
-use base64::read::DecoderReader;
-use base64::write::EncoderStringWriter;
-use base64::URL_SAFE_NO_PAD;
-use rand::RngCore;
+use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+use tracing::Span;
 
-use super::Config;
+use super::{session, Config, Role, Roles};
 
 const COOKIE_NAME: &str = "SESSION";
 
+/// Times at which the sessions of users were revoked, keyed by their user ID.
+static REVOKED: Lazy<RwLock<HashMap<u64, SystemTime>>> = Lazy::new(Default::default);
+
+/// Revokes all sessions of the user with `uid` created until now.
+/// Revocations are forgotten once all sessions they apply to have expired after `ttl`.
+pub(super) async fn revoke(uid: u64, ttl: Duration) {
+    let now = SystemTime::now();
+    let mut revoked = REVOKED.write().await;
+    revoked.retain(|_, at| *at + ttl > now);
+    _ = revoked.insert(uid, now);
+}
+
 #[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 pub(crate) struct User {
     time: SystemTime,
     uid: u64,
+    /// Roles of the user as of when they logged in.
+    #[serde(default)]
+    roles: Roles,
+    /// Whether the user has the starred role, which sessions and tokens created before roles
+    /// only have this way.
     has_starred_enarx: bool,
+    /// When the session of a guest expires, regardless of the session TTL.
+    #[serde(default)]
+    expires: Option<SystemTime>,
+    /// Session this cookie belongs to, which is only accepted while the session is active.
+    #[serde(default)]
+    session: Option<u64>,
 }
 
 impl Eq for User {}
@@ -52,41 +72,73 @@ impl Display for User {
 }
 
 impl User {
+    /// Returns the user with `uid` and `roles`, who logs in now.
+    pub(super) fn new(uid: u64, roles: Roles, session: Option<u64>) -> Self {
+        User {
+            time: SystemTime::now(),
+            uid,
+            roles,
+            has_starred_enarx: roles.contains(Role::Starred),
+            expires: None,
+            session,
+        }
+    }
+
     pub(super) fn create(
         config: &Config,
         uid: u64,
-        has_starred_enarx: bool,
+        roles: Roles,
+        session: Option<u64>,
+    ) -> (HeaderName, HeaderValue) {
+        Self::new(uid, roles, session).cookie(config)
+    }
+
+    /// Creates a session for a guest, which ends at `expires` at the latest.
+    pub(super) fn create_guest(
+        config: &Config,
+        uid: u64,
+        roles: Roles,
+        expires: SystemTime,
     ) -> (HeaderName, HeaderValue) {
-        let time = SystemTime::now();
         let user = User {
-            time,
+            time: SystemTime::now(),
             uid,
-            has_starred_enarx,
+            roles,
+            has_starred_enarx: roles.contains(Role::Starred),
+            expires: Some(expires),
+            session: None,
         };
+        user.cookie(config)
+    }
 
-        // Encode the structure.
-        let plaintext = serde_json::to_vec(&user).unwrap();
-
-        // Generate the nonce.
-        let mut rng = rand::thread_rng();
-        let mut nonce = Nonce::default();
-        rng.fill_bytes(&mut nonce);
-
-        // Do the encryption.
-        let aes = Aes128Gcm::new(&config.key);
-        let ciphertext = aes.encrypt(&nonce, &*plaintext).unwrap();
+    /// Renews the session, which keeps the roles the user had when they logged in.
+    pub(super) fn renew(&self, config: &Config) -> (HeaderName, HeaderValue) {
+        let user = User {
+            time: SystemTime::now(),
+            ..*self
+        };
+        user.cookie(config)
+    }
 
-        // Encode the results.
-        let mut b64 = EncoderStringWriter::new(URL_SAFE_NO_PAD);
-        b64.write_all(&nonce).unwrap();
-        b64.write_all(&ciphertext).unwrap();
+    fn cookie(&self, config: &Config) -> (HeaderName, HeaderValue) {
+        // Encode and encrypt the structure.
+        let plaintext = serde_json::to_vec(self).unwrap();
+        let value = config.key.seal(&plaintext);
+
+        let max_age = match self.expires {
+            Some(expires) => expires
+                .duration_since(self.time)
+                .unwrap_or_default()
+                .min(config.ttl),
+            None => config.ttl,
+        };
 
         // Create the cookie.
         let s = format!(
             "{}={}; SameSite=Lax; Path=/; Max-Age={}",
             COOKIE_NAME,
-            b64.into_inner(),
-            config.ttl.as_secs(),
+            value,
+            max_age.as_secs(),
         );
 
         (SET_COOKIE, HeaderValue::from_str(&s).unwrap())
@@ -96,6 +148,34 @@ impl User {
         let s = format!("{}=; SameSite=Lax; Path=/; Max-Age=0", COOKIE_NAME);
         (SET_COOKIE, HeaderValue::from_str(&s).unwrap())
     }
+
+    /// Decrypts the session cookie `value`, regardless of whether the session is still valid.
+    fn open(config: &Config, value: &str) -> Option<Self> {
+        let plaintext = config.key.open(value)?;
+        serde_json::from_slice(&plaintext).ok()
+    }
+
+    /// Returns the session of a request with `headers`, regardless of whether it is still
+    /// valid.
+    pub(super) fn from_headers(config: &Config, headers: &HeaderMap) -> Option<Self> {
+        let cookies = headers.typed_get::<Cookie>()?;
+        Self::open(config, cookies.get(COOKIE_NAME)?)
+    }
+
+    /// Returns whether the session was revoked.
+    pub(super) async fn is_revoked(&self) -> bool {
+        matches!(REVOKED.read().await.get(&self.uid), Some(at) if self.time <= *at)
+    }
+
+    /// When the session was created or last renewed.
+    pub(super) fn issued(&self) -> SystemTime {
+        self.time
+    }
+
+    /// Session this cookie belongs to, unless it was issued before sessions were kept.
+    pub(super) fn session(&self) -> Option<u64> {
+        self.session
+    }
 }
 
 #[async_trait]
@@ -112,41 +192,48 @@ impl<B: Send> FromRequest<B> for User {
             .map_err(|_| StatusCode::BAD_REQUEST)?;
         let value = cookies.get(COOKIE_NAME).ok_or(StatusCode::BAD_REQUEST)?;
 
-        // Decode the input.
-        let mut cur = Cursor::new(value.as_bytes());
-        let mut b64 = DecoderReader::new(&mut cur, URL_SAFE_NO_PAD);
-
-        // Read the nonce.
-        let mut nonce = Nonce::default();
-        b64.read_exact(&mut nonce)
-            .map_err(|_| StatusCode::BAD_REQUEST)?;
-
-        // Read the ciphertext.
-        let mut ciphertext = Vec::new();
-        let _ = b64
-            .read_to_end(&mut ciphertext)
-            .map_err(|_| StatusCode::BAD_REQUEST)?;
-
-        // Decrypt the ciphertext.
-        let aes = Aes128Gcm::new(&config.key);
-        let plaintext = aes
-            .decrypt(&nonce, &*ciphertext)
-            .map_err(|_| StatusCode::BAD_REQUEST)?;
-
-        // Decode the object.
-        let user: User = serde_json::from_slice(&plaintext).map_err(|_| StatusCode::BAD_REQUEST)?;
+        // Decrypt and decode the object.
+        let user = Self::open(&config, value).ok_or(StatusCode::BAD_REQUEST)?;
 
         // Check for freshness.
-        if user.time + config.ttl < SystemTime::now() {
+        let now = SystemTime::now();
+        if user.time + config.ttl < now || matches!(user.expires, Some(at) if at < now) {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+
+        // Check for revocation.
+        if user.is_revoked().await {
             return Err(StatusCode::BAD_REQUEST);
         }
+        if let Some(id) = user.session {
+            if !session::is_active(id, user.uid).await {
+                return Err(StatusCode::BAD_REQUEST);
+            }
+        }
 
+        _ = Span::current().record("user_id", user.uid);
         Ok(user)
     }
 }
 
 impl User {
-    pub(crate) fn has_starred_enarx(&self) -> bool {
-        self.has_starred_enarx
+    /// Roles of the user, which always include the default role.
+    pub(crate) fn roles(&self) -> Roles {
+        let mut roles = self.roles;
+        roles.insert(Role::Default);
+        if self.has_starred_enarx {
+            roles.insert(Role::Starred);
+        }
+        roles
+    }
+
+    /// GitHub user ID.
+    pub(crate) fn uid(&self) -> u64 {
+        self.uid
+    }
+
+    /// When the account expires, if the user is a guest.
+    pub(crate) fn expires(&self) -> Option<SystemTime> {
+        self.expires
     }
 }
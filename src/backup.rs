@@ -0,0 +1,85 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Backup and restore of the state of this instance, so that operators can migrate a
+//! long-lived instance to a new host without losing its history.
+//!
+//! The archive holds the API tokens, the denylist, the features disabled at runtime and the
+//! run history. Running jobs are not part of it, as they cannot move between hosts, and neither
+//! are the audit log and the job logs, which are plain files that can be copied instead.
+
+use crate::auth::{token, Admin, User};
+use crate::denylist;
+use crate::features::{self, Feature};
+use crate::fingerprint::Fingerprint;
+use crate::history::{self, Run};
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use axum::http::header::CONTENT_DISPOSITION;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{error, info};
+
+/// Version of the format of the archive, bumped on incompatible changes.
+const VERSION: u32 = 1;
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct Archive {
+    version: u32,
+    tokens: token::Backup,
+    banned: BTreeMap<String, Option<Fingerprint>>,
+    disabled_features: BTreeSet<Feature>,
+    history: Vec<(User, Vec<Run>)>,
+}
+
+/// Downloads an archive of the state of this instance.
+pub(crate) async fn export(Admin(admin): Admin) -> impl IntoResponse {
+    let archive = Archive {
+        version: VERSION,
+        tokens: token::export().await,
+        banned: denylist::export().await,
+        disabled_features: features::export().await,
+        history: history::export().await,
+    };
+    info!(%admin, "exporting state");
+    (
+        [(
+            CONTENT_DISPOSITION,
+            "attachment; filename=\"benefice-state.json\"",
+        )],
+        Json(archive),
+    )
+}
+
+/// Replaces the state of this instance with an archive exported by `export`.
+pub(crate) async fn import(
+    Admin(admin): Admin,
+    Json(archive): Json<Archive>,
+) -> Result<impl IntoResponse, Response> {
+    if archive.version != VERSION {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("Unsupported state archive version {}", archive.version),
+        )
+            .into_response());
+    }
+    info!(%admin, "importing state");
+
+    let banned = denylist::import(archive.banned).await.map_err(|e| {
+        error!(error = ?e, "failed to import banned digests");
+        (StatusCode::BAD_REQUEST, format!("{e:#}")).into_response()
+    })?;
+    let tokens = token::import(archive.tokens).await;
+    features::import(archive.disabled_features).await;
+    let runs = history::import(archive.history).await;
+    info!(%admin, tokens, banned, runs, "imported state");
+    Ok(Json(json!({
+        "tokens": tokens,
+        "banned": banned,
+        "runs": runs,
+    })))
+}
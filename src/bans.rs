@@ -0,0 +1,286 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Operator-defined list of users and networks banned from submitting workloads.
+//!
+//! Users are identified by their OIDC subject, e.g. `github|1234`, prefixed with the name of
+//! their provider unless it is the primary one, e.g. `keycloak:f81d4fae`. Networks are given
+//! in CIDR notation, e.g. `192.0.2.0/24`, or by a single address. Users with the `banned`
+//! role are banned as well. Bans are enforced by the `Unbanned` extractor of the routes which
+//! start jobs.
+
+use crate::audit;
+use crate::auth::{Admin, ApiUser, Role, Subjects, User};
+use crate::net::Net;
+
+use std::collections::BTreeSet;
+use std::fmt::{self, Display};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+
+use anyhow::Context as _;
+use axum::async_trait;
+use axum::extract::{ConnectInfo, FromRequest, RequestParts};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use once_cell::sync::{Lazy, OnceCell};
+use serde::Deserialize;
+use tokio::sync::RwLock;
+use tracing::{error, info, warn};
+
+/// Banned users and networks.
+static BANNED: Lazy<RwLock<BTreeSet<Target>>> = Lazy::new(Default::default);
+
+/// File the bans are saved to.
+static FILE: OnceCell<PathBuf> = OnceCell::new();
+
+/// Resolves the subjects of banned users to their IDs.
+static SUBJECTS: OnceCell<Subjects> = OnceCell::new();
+
+/// A user or network which is banned.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Target {
+    /// OIDC subject of a user, e.g. `github|1234`, and the ID of the user.
+    Subject { subject: String, uid: u64 },
+    /// A network in CIDR notation, or a single address.
+    Net(Net),
+}
+
+impl Target {
+    /// Parses a subject or network, resolving subjects to users with `subjects`.
+    fn parse(s: &str, subjects: &Subjects) -> anyhow::Result<Self> {
+        let s = s.trim();
+        if let Some(uid) = subjects.uid(s) {
+            return Ok(Self::Subject {
+                subject: s.into(),
+                uid,
+            });
+        }
+
+        s.parse().map(Self::Net).with_context(|| {
+            format!("`{s}` is neither the OIDC subject of a user of a provider nor a network")
+        })
+    }
+
+    fn matches_uid(&self, uid: u64) -> bool {
+        matches!(self, Self::Subject { uid: banned, .. } if *banned == uid)
+    }
+
+    fn matches_addr(&self, ip: IpAddr) -> bool {
+        matches!(self, Self::Net(net) if net.contains(ip))
+    }
+}
+
+impl Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Subject { subject, .. } => subject.fmt(f),
+            Self::Net(net) => net.fmt(f),
+        }
+    }
+}
+
+/// Initializes the bans, whose subjects are resolved with `subjects`, loading them from the
+/// file at `path` if given.
+pub(crate) fn init(subjects: Subjects, path: Option<&Path>) -> anyhow::Result<()> {
+    if let Some(path) = path {
+        load(path, &subjects)?;
+    }
+    SUBJECTS
+        .set(subjects)
+        .map_err(|_| anyhow::anyhow!("bans already initialized"))
+}
+
+/// Loads the bans from the file at `path`, to which bans made via the admin API are saved.
+/// The file contains one subject or network per line, and may contain comments starting
+/// with `#`.
+fn load(path: &Path, subjects: &Subjects) -> anyhow::Result<()> {
+    let banned = match std::fs::read_to_string(path) {
+        Ok(data) => data
+            .lines()
+            .filter(|line| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+            .map(|line| Target::parse(line, subjects))
+            .collect::<anyhow::Result<_>>()
+            .with_context(|| format!("failed to parse `{}`", path.display()))?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeSet::new(),
+        Err(e) => return Err(e).with_context(|| format!("failed to read `{}`", path.display())),
+    };
+
+    info!(count = banned.len(), "loaded bans");
+    *BANNED.try_write().context("bans are in use")? = banned;
+    FILE.set(path.into())
+        .map_err(|_| anyhow::anyhow!("bans already loaded"))
+}
+
+fn save(banned: &BTreeSet<Target>) {
+    if let Some(path) = FILE.get() {
+        let data: String = banned.iter().map(|target| format!("{target}\n")).collect();
+        if let Err(e) = std::fs::write(path, data) {
+            error!(error = ?e, path = %path.display(), "failed to save bans");
+        }
+    }
+}
+
+/// Proof that neither the user nor the address of a request are banned. Requests without
+/// a user are only checked by their address, and left to the handler to reject.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Unbanned;
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for Unbanned {
+    type Rejection = Response;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let addr = req
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+        let user = match ApiUser::from_request(req).await {
+            Ok(ApiUser(user)) => Some(user),
+            Err(_) => User::from_request(req).await.ok(),
+        };
+
+        let banned = BANNED.read().await;
+        let hit = banned.iter().find(|target| {
+            user.map_or(false, |user| target.matches_uid(user.uid()))
+                || addr.map_or(false, |addr| target.matches_addr(addr))
+        });
+        let hit = match hit {
+            Some(hit) => hit.to_string(),
+            None if user.map_or(false, |user| user.roles().contains(Role::Banned)) => {
+                format!("role {}", Role::Banned)
+            }
+            None => return Ok(Self),
+        };
+
+        warn!(
+            user = ?user.map(|user| user.uid()),
+            ?addr,
+            ban = hit,
+            "rejected banned submission"
+        );
+        if let Some(user) = user {
+            audit::Event::new(user.uid(), audit::Action::BanHit)
+                .detail(hit)
+                .record()
+                .await;
+        }
+        Err((
+            StatusCode::FORBIDDEN,
+            "You have been banned from submitting workloads to this instance",
+        )
+            .into_response())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct Ban {
+    /// OIDC subject of a user, or a network in CIDR notation.
+    target: String,
+}
+
+pub(crate) async fn list(_: Admin) -> impl IntoResponse {
+    let banned: Vec<_> = BANNED
+        .read()
+        .await
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+    Json(banned)
+}
+
+pub(crate) async fn add(
+    Admin(admin): Admin,
+    Json(Ban { target }): Json<Ban>,
+) -> Result<StatusCode, Response> {
+    let subjects = SUBJECTS.get_or_init(Subjects::default);
+    let target = Target::parse(&target, subjects)
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("{e:#}")).into_response())?;
+
+    let mut banned = BANNED.write().await;
+    info!(%admin, ban = %target, "banning");
+    _ = banned.insert(target);
+    save(&banned);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(crate) async fn remove(Admin(admin): Admin, Json(Ban { target }): Json<Ban>) -> StatusCode {
+    let subjects = SUBJECTS.get_or_init(Subjects::default);
+    let target = match Target::parse(&target, subjects) {
+        Ok(target) => target,
+        Err(_) => return StatusCode::BAD_REQUEST,
+    };
+
+    let mut banned = BANNED.write().await;
+    if banned.remove(&target) {
+        info!(%admin, ban = %target, "lifting ban");
+        save(&banned);
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::auth::Providers;
+
+    fn subjects() -> Subjects {
+        let providers: Providers = r#"
+            [[providers]]
+            name = "keycloak"
+            issuer = "https://keycloak.example.com/realms/corp"
+            client = "benefice"
+
+            [[providers]]
+            name = "okta"
+            issuer = "https://example.okta.com"
+            client = "benefice"
+        "#
+        .parse()
+        .unwrap();
+        Subjects::new(&providers.providers)
+    }
+
+    #[test]
+    fn users_of_other_providers_are_banned() {
+        let subjects = subjects();
+        let ban = Target::parse("keycloak:f81d4fae", &subjects).unwrap();
+        assert_eq!(ban.to_string(), "keycloak:f81d4fae");
+        assert!(ban.matches_uid(subjects.uid("keycloak:f81d4fae").unwrap()));
+
+        // The same subject of another provider is another user.
+        assert!(!ban.matches_uid(subjects.uid("okta:f81d4fae").unwrap()));
+        assert!(!ban.matches_uid(subjects.uid("keycloak:0f3c9e2a").unwrap()));
+    }
+
+    #[test]
+    fn users_of_the_primary_provider_are_banned() {
+        let subjects = subjects();
+        let ban = Target::parse("github|1234", &subjects).unwrap();
+        assert!(ban.matches_uid(1234));
+        assert!(!ban.matches_uid(4321));
+        assert!(!Target::parse("keycloak:1234", &subjects)
+            .unwrap()
+            .matches_uid(1234));
+    }
+
+    #[test]
+    fn unknown_subjects_are_rejected() {
+        let subjects = subjects();
+        for s in [
+            "github|",
+            "gitlab|1234",
+            "auth0:f81d4fae",
+            "keycloak:",
+            "example.com",
+        ] {
+            assert!(Target::parse(s, &subjects).is_err(), "{s}");
+        }
+        let ban = Target::parse("2001:db8::/32", &subjects).unwrap();
+        assert!(ban.matches_addr("2001:db8::1".parse().unwrap()));
+    }
+}
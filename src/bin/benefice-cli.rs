@@ -0,0 +1,270 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+#![forbid(unsafe_code)]
+#![deny(
+    clippy::all,
+    absolute_paths_not_starting_with_crate,
+    deprecated_in_future,
+    missing_copy_implementations,
+    missing_debug_implementations,
+    noop_method_call,
+    rust_2018_compatibility,
+    rust_2018_idioms,
+    rust_2021_compatibility,
+    single_use_lifetimes,
+    trivial_bounds,
+    trivial_casts,
+    trivial_numeric_casts,
+    unreachable_code,
+    unreachable_patterns,
+    unreachable_pub,
+    unstable_features,
+    unused,
+    unused_import_braces,
+    unused_lifetimes,
+    unused_results,
+    variant_size_differences
+)]
+
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+use anyhow::{anyhow, Context as _};
+use benefice_client::{Client, Job, Metadata, Stream, Url};
+use clap::{Args as ClapArgs, Parser, Subcommand};
+
+/// Label of the tokens minted by `login`, unless another one is given.
+const TOKEN_NAME: &str = "benefice-cli";
+
+/// Command-line client for a benefice instance.
+///
+/// Requests are authenticated with a personal access token, which is either
+/// obtained and stored by `login`, or minted by a logged in user at `/me/tokens`.
+#[derive(Parser, Debug)]
+#[command(author, version, about)]
+struct Args {
+    /// Root URL of the benefice instance.
+    /// For example: https://benefice.example.com
+    #[arg(long)]
+    url: Url,
+
+    /// Personal access token, instead of the one stored by `login`.
+    #[arg(long)]
+    token: Option<String>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+/// Name and description of a job.
+#[derive(ClapArgs, Debug)]
+struct Meta {
+    /// Name of the job, to tell it apart from other jobs.
+    #[arg(long)]
+    name: Option<String>,
+
+    /// Description of the job.
+    #[arg(long)]
+    description: Option<String>,
+}
+
+impl From<Meta> for Metadata {
+    fn from(Meta { name, description }: Meta) -> Self {
+        Self { name, description }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Log in at the OpenID Connect provider of the instance and store the token for it.
+    Login {
+        /// Label of the token, to tell it apart at `/me/tokens`.
+        #[arg(long, default_value = TOKEN_NAME)]
+        name: String,
+    },
+
+    /// Upload and run a workload, streaming its output and exiting with its exit code.
+    Run {
+        /// WebAssembly module to run.
+        wasm: PathBuf,
+
+        /// Enarx configuration of the workload.
+        conf: PathBuf,
+
+        /// WebAssembly module uploaded recently, to only upload the changes against it.
+        /// The module is uploaded in full if the instance no longer keeps it.
+        #[arg(long)]
+        base: Option<PathBuf>,
+
+        #[command(flatten)]
+        meta: Meta,
+    },
+
+    /// Deploy a workload from Drawbridge, streaming its output and exiting with its exit code.
+    Deploy {
+        /// Drawbridge slug of the workload, for example `user/my-repo:0.1.0`.
+        slug: String,
+
+        #[command(flatten)]
+        meta: Meta,
+    },
+
+    /// Kill the running workload.
+    Kill,
+
+    /// List the recent runs.
+    History,
+}
+
+async fn run(
+    client: &Client,
+    wasm: PathBuf,
+    conf: PathBuf,
+    base: Option<PathBuf>,
+    meta: Meta,
+) -> anyhow::Result<i32> {
+    let read = |path: &PathBuf| {
+        std::fs::read(path).with_context(|| format!("failed to read `{}`", path.display()))
+    };
+    let wasm = read(&wasm)?;
+    let conf = std::fs::read_to_string(&conf)
+        .with_context(|| format!("failed to read `{}`", conf.display()))?;
+
+    let job = match base {
+        Some(base) => {
+            let base = read(&base)?;
+            client.submit_delta(&base, wasm, conf, meta.into()).await?
+        }
+        None => client.submit(wasm, conf, meta.into()).await?,
+    };
+    watch(client, job).await
+}
+
+/// Streams the output of `job` until it exits, returning its exit code.
+async fn watch(client: &Client, job: Job) -> anyhow::Result<i32> {
+    let id = job.id;
+    eprintln!("> Started job {id}");
+    for (host, (_, url)) in &job.ports {
+        eprintln!("> Port {host} -> {url}");
+    }
+
+    let code = client
+        .watch(&id, |stream, data| {
+            match stream {
+                Stream::Stdout => std::io::stdout().write_all(data)?,
+                Stream::Stderr => std::io::stderr().write_all(data)?,
+            }
+            Ok(())
+        })
+        .await?;
+    Ok(code.unwrap_or_else(|| {
+        eprintln!("> Job {id} was killed or timed out");
+        1
+    }))
+}
+
+/// Prints the recent runs, newest first.
+async fn history(client: &Client) -> anyhow::Result<()> {
+    for run in client.history().await? {
+        let outcome = match &run.outcome {
+            Some(outcome) => outcome.reason.as_str(),
+            None => "running",
+        };
+        let workload = run.slug.as_deref().unwrap_or("uploaded workload");
+        match &run.name {
+            Some(name) => println!("{}\t{outcome}\t{name} ({workload})", run.id),
+            None => println!("{}\t{outcome}\t{workload}", run.id),
+        }
+    }
+    Ok(())
+}
+
+/// Returns the file the tokens stored by `login` are kept in, by instance URL.
+fn tokens_path() -> anyhow::Result<PathBuf> {
+    let dir = match std::env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME").context("HOME is not set")?).join(".config"),
+    };
+    Ok(dir.join("benefice").join("tokens.json"))
+}
+
+fn load_tokens(path: &Path) -> anyhow::Result<BTreeMap<String, String>> {
+    match std::fs::read(path) {
+        Ok(data) => serde_json::from_slice(&data)
+            .with_context(|| format!("failed to decode `{}`", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Default::default()),
+        Err(e) => Err(e).with_context(|| format!("failed to read `{}`", path.display())),
+    }
+}
+
+/// Logs in to the instance at `url` with the device authorization flow, and stores the token.
+async fn login(url: Url, name: &str) -> anyhow::Result<()> {
+    let path = tokens_path()?;
+    let mut tokens = load_tokens(&path)?;
+
+    let client = Client::login(url.clone(), name, |code| {
+        match &code.verification_uri_complete {
+            Some(uri) => eprintln!("> Open {uri} to log in"),
+            None => eprintln!(
+                "> Open {} and enter the code {} to log in",
+                code.verification_uri, code.user_code
+            ),
+        }
+        eprintln!("> The code expires in {} seconds", code.expires_in);
+    })
+    .await?;
+    _ = tokens.insert(url.to_string(), client.token().into());
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create `{}`", dir.display()))?;
+    }
+    // The tokens are credentials, so only the user may read them.
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&path)
+        .with_context(|| format!("failed to write `{}`", path.display()))?;
+    serde_json::to_writer_pretty(&mut file, &tokens)
+        .with_context(|| format!("failed to write `{}`", path.display()))?;
+    eprintln!("> Logged in to {url}");
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    if let Command::Login { name } = &args.command {
+        return login(args.url, name).await;
+    }
+    let token = match args.token {
+        Some(token) => token,
+        None => load_tokens(&tokens_path()?)?
+            .remove(args.url.as_str())
+            .ok_or_else(|| anyhow!("not logged in to {}, run `login` or pass --token", args.url))?,
+    };
+    let client = Client::new(args.url, token);
+
+    match args.command {
+        Command::Login { .. } => unreachable!("handled above"),
+        Command::Run {
+            wasm,
+            conf,
+            base,
+            meta,
+        } => exit(run(&client, wasm, conf, base, meta).await?),
+        Command::Deploy { slug, meta } => {
+            let job = client.deploy(&slug, meta.into()).await?;
+            exit(watch(&client, job).await?)
+        }
+        Command::Kill => client.kill().await,
+        Command::History => history(&client).await,
+    }
+}
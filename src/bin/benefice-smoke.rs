@@ -0,0 +1,275 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+#![forbid(unsafe_code)]
+#![deny(
+    clippy::all,
+    absolute_paths_not_starting_with_crate,
+    deprecated_in_future,
+    missing_copy_implementations,
+    missing_debug_implementations,
+    noop_method_call,
+    rust_2018_compatibility,
+    rust_2018_idioms,
+    rust_2021_compatibility,
+    single_use_lifetimes,
+    trivial_bounds,
+    trivial_casts,
+    trivial_numeric_casts,
+    unreachable_code,
+    unreachable_patterns,
+    unreachable_pub,
+    unstable_features,
+    unused,
+    unused_import_braces,
+    unused_lifetimes,
+    unused_results,
+    variant_size_differences
+)]
+
+use std::future::Future;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, ensure, Context as _};
+use benefice_client::{Client, Job, Metadata, State, Status, Stream, Url};
+use clap::Parser;
+use reqwest::header::{COOKIE, SET_COOKIE};
+use reqwest::redirect::Policy;
+use serde_json::json;
+use tokio::time::sleep;
+
+/// Workload run by default, which the development profile bundles as an example.
+const ECHO_WASM: &[u8] = include_bytes!("../../dev/examples/echo/main.wasm");
+const ECHO_CONF: &str = include_str!("../../dev/examples/echo/Enarx.toml");
+
+/// Name of the session cookie set by the instance.
+const SESSION_COOKIE: &str = "SESSION";
+
+/// Interval between polls of the status and the output of a job.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Longest time a job may take to produce output or to be removed.
+const STEP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Time allowed beyond the timeout of a job for it to be killed.
+const TIMEOUT_GRACE: Duration = Duration::from_secs(30);
+
+/// End-to-end smoke test of a running benefice instance.
+///
+/// Drives the instance through logging in, submitting a workload, streaming its output,
+/// killing it and letting another job time out, all via the public API. Exits with a non-zero
+/// code as soon as a step fails, so that it can gate staging deployments and releases.
+#[derive(Parser, Debug)]
+#[command(author, version, about)]
+struct Args {
+    /// Root URL of the benefice instance.
+    /// For example: http://localhost:3000
+    #[arg(long)]
+    url: Url,
+
+    /// Personal access token. If none is given, the test logs in as the local administrator,
+    /// which requires the instance to run with `--dev-profile`.
+    #[arg(long)]
+    token: Option<String>,
+
+    /// WebAssembly module to run instead of the bundled echo example.
+    /// Requires `--conf`.
+    #[arg(long, requires = "conf")]
+    wasm: Option<PathBuf>,
+
+    /// Enarx configuration of the workload given with `--wasm`.
+    #[arg(long, requires = "wasm")]
+    conf: Option<PathBuf>,
+
+    /// Skip waiting for a job to time out, which takes as long as the timeout of jobs.
+    #[arg(long)]
+    skip_timeout: bool,
+}
+
+/// Runs the step `name`, reporting how long it took.
+async fn step<T>(name: &str, f: impl Future<Output = anyhow::Result<T>>) -> anyhow::Result<T> {
+    let start = Instant::now();
+    let result = f.await.with_context(|| format!("step `{name}` failed"))?;
+    eprintln!("ok {name} ({:.1}s)", start.elapsed().as_secs_f64());
+    Ok(result)
+}
+
+/// Logs in with the authentication of the development profile, and mints a token for the
+/// test.
+async fn login(url: &Url) -> anyhow::Result<String> {
+    let http = reqwest::Client::builder()
+        .redirect(Policy::none())
+        .build()
+        .context("failed to build HTTP client")?;
+
+    let resp = http
+        .get(url.join("/login")?)
+        .send()
+        .await
+        .context("failed to log in")?;
+    let session = resp
+        .headers()
+        .get_all(SET_COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok()?.split(';').next())
+        .find(|cookie| cookie.starts_with(&format!("{SESSION_COOKIE}=")))
+        .map(str::to_string)
+        .context("no session was created, is the instance running with `--dev-profile`?")?;
+
+    let resp = http
+        .post(url.join("/tokens")?)
+        .header(COOKIE, session)
+        .json(&json!({
+            "name": "benefice-smoke",
+            "scopes": ["submit", "read"],
+            "days": 1,
+        }))
+        .send()
+        .await
+        .context("failed to create token")?;
+    let status = resp.status();
+    ensure!(
+        status.is_success(),
+        "failed to create token: {status}: {}",
+        resp.text().await?
+    );
+    let token: serde_json::Value = resp.json().await.context("failed to decode token")?;
+    token["token"]
+        .as_str()
+        .map(str::to_string)
+        .context("no token was returned")
+}
+
+/// Polls the status of the job with `id` until `done` returns true for it, or `timeout`
+/// elapses. A job which is unknown has no status.
+async fn wait_for(
+    client: &Client,
+    id: &str,
+    timeout: Duration,
+    done: impl Fn(Option<&Status>) -> bool,
+) -> anyhow::Result<Option<Status>> {
+    let start = Instant::now();
+    loop {
+        let status = client.status(id).await?;
+        if done(status.as_ref()) {
+            return Ok(status);
+        }
+        if start.elapsed() >= timeout {
+            bail!(
+                "job {id} is still in state {:?}",
+                status.map(|status| status.state)
+            );
+        }
+        sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Reads the output of `job` until some is produced.
+async fn stream(client: &Client, job: &Job) -> anyhow::Result<()> {
+    let start = Instant::now();
+    while start.elapsed() < STEP_TIMEOUT {
+        for stream in [Stream::Stdout, Stream::Stderr] {
+            let chunk = client
+                .read(&job.id, stream, Some(0))
+                .await?
+                .with_context(|| format!("job {} was removed", job.id))?;
+            if !chunk.data.is_empty() {
+                return Ok(());
+            }
+        }
+        sleep(POLL_INTERVAL).await;
+    }
+    bail!("job {} produced no output", job.id)
+}
+
+/// Kills `job`, checking that it ended and that its run was recorded as killed.
+async fn delete(client: &Client, job: &Job) -> anyhow::Result<()> {
+    client.kill().await?;
+    _ = wait_for(client, &job.id, STEP_TIMEOUT, |status| {
+        status.map_or(true, |status| status.state == State::Ended)
+    })
+    .await?;
+
+    let runs = client.history().await?;
+    let run = runs
+        .iter()
+        .find(|run| run.id == job.id)
+        .with_context(|| format!("job {} is missing from the run history", job.id))?;
+    let reason = run.outcome.as_ref().map(|outcome| outcome.reason.as_str());
+    ensure!(
+        reason == Some("killed"),
+        "job {} ended with {reason:?} rather than being killed",
+        job.id
+    );
+    Ok(())
+}
+
+/// Submits a workload and waits for its job to time out.
+async fn timeout(client: &Client, wasm: Vec<u8>, conf: String) -> anyhow::Result<()> {
+    let job = client.submit(wasm, conf, meta("timeout")).await?;
+    let remaining = client
+        .status(&job.id)
+        .await?
+        .and_then(|status| status.remaining)
+        .with_context(|| format!("job {} has no timeout", job.id))?;
+    eprintln!("   job {} times out in {remaining}s", job.id);
+
+    let status = wait_for(
+        client,
+        &job.id,
+        Duration::from_secs(remaining) + TIMEOUT_GRACE,
+        |status| status.map_or(true, |status| status.state == State::Ended),
+    )
+    .await?;
+    let reason = status
+        .as_ref()
+        .and_then(|status| status.outcome.as_ref())
+        .map(|outcome| outcome.reason.as_str());
+    ensure!(
+        reason == Some("timeout"),
+        "job {} ended with {reason:?} rather than timing out",
+        job.id
+    );
+    Ok(())
+}
+
+fn meta(step: &str) -> Metadata {
+    Metadata {
+        name: Some(format!("benefice-smoke {step}")),
+        description: Some("End-to-end smoke test".into()),
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let (wasm, conf) = match (&args.wasm, &args.conf) {
+        (Some(wasm), Some(conf)) => (
+            std::fs::read(wasm).with_context(|| format!("failed to read `{}`", wasm.display()))?,
+            std::fs::read_to_string(conf)
+                .with_context(|| format!("failed to read `{}`", conf.display()))?,
+        ),
+        _ => (ECHO_WASM.to_vec(), ECHO_CONF.to_string()),
+    };
+
+    let token = match args.token {
+        Some(token) => token,
+        None => step("login", login(&args.url)).await?,
+    };
+    let client = Client::new(args.url, token);
+
+    let job = step(
+        "submit",
+        client.submit(wasm.clone(), conf.clone(), meta("submit")),
+    )
+    .await?;
+    step("stream", stream(&client, &job)).await?;
+    step("delete", delete(&client, &job)).await?;
+    if args.skip_timeout {
+        eprintln!("skipped timeout");
+    } else {
+        step("timeout", timeout(&client, wasm, conf)).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,103 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Short-lived cache of JSON responses which status pages poll aggressively, so that polling
+//! does not hit the job registry on every request.
+//!
+//! Responses carry an ETag, so that clients which already have the current response are
+//! answered with `304 Not Modified` instead.
+
+use crate::clock::{self, Instant};
+use crate::denylist::digest;
+
+use std::future::Future;
+use std::time::Duration;
+
+use axum::http::header::{CACHE_CONTROL, CONTENT_TYPE, ETAG, IF_NONE_MATCH};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use tokio::sync::Mutex;
+use tracing::error;
+
+#[derive(Debug)]
+struct Entry {
+    created: Instant,
+    body: Vec<u8>,
+    etag: String,
+}
+
+/// Cache of a single response.
+#[derive(Debug)]
+pub(crate) struct Cache {
+    ttl: Duration,
+    entry: Mutex<Option<Entry>>,
+}
+
+impl Cache {
+    pub(crate) fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entry: Mutex::new(None),
+        }
+    }
+
+    /// Serves the cached response, computing it with `compute` if it has expired. Concurrent
+    /// requests wait for the response being computed instead of computing it again.
+    pub(crate) async fn serve<F>(
+        &self,
+        headers: &HeaderMap,
+        compute: impl FnOnce() -> F,
+    ) -> Response
+    where
+        F: Future<Output = serde_json::Value>,
+    {
+        let mut entry = self.entry.lock().await;
+        let entry = match &mut *entry {
+            Some(entry) if entry.created.elapsed() < self.ttl => entry,
+            entry => {
+                let body = match serde_json::to_vec(&compute().await) {
+                    Ok(body) => body,
+                    Err(e) => {
+                        error!(error = ?e, "failed to encode cached response");
+                        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                    }
+                };
+                let etag = format!("\"{}\"", &digest(&body)[..16]);
+                entry.insert(Entry {
+                    created: clock::now(),
+                    body,
+                    etag,
+                })
+            }
+        };
+
+        let cache_control = format!("public, max-age={}", self.ttl.as_secs());
+        if matches(headers, &entry.etag) {
+            return (
+                StatusCode::NOT_MODIFIED,
+                [(ETAG, entry.etag.clone()), (CACHE_CONTROL, cache_control)],
+            )
+                .into_response();
+        }
+        (
+            [
+                (CONTENT_TYPE, "application/json".to_string()),
+                (ETAG, entry.etag.clone()),
+                (CACHE_CONTROL, cache_control),
+            ],
+            entry.body.clone(),
+        )
+            .into_response()
+    }
+}
+
+/// Returns whether the `If-None-Match` header of a request matches `etag`.
+fn matches(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .any(|tag| tag == etag || tag == "*")
+}
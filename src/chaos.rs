@@ -0,0 +1,76 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Fault injection for exercising failure handling in integration tests and staging.
+//!
+//! Only compiled in with the `chaos` feature, never enable it in production builds.
+
+use crate::auth::Admin;
+
+use std::time::Duration;
+
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use once_cell::sync::Lazy;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+/// Currently injected faults.
+static FAULTS: Lazy<RwLock<Faults>> = Lazy::new(Default::default);
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct Faults {
+    /// Probability with which spawning a job fails.
+    fail_spawn: f64,
+    /// Delay added to every read of job output, in milliseconds.
+    slow_reads: u64,
+    /// Probability with which a chunk of job output is dropped.
+    drop_output: f64,
+}
+
+fn roll(probability: f64) -> bool {
+    probability > 0.0 && rand::thread_rng().gen_bool(probability.min(1.0))
+}
+
+/// Returns whether spawning a job should fail.
+pub(crate) async fn fail_spawn() -> bool {
+    let fail = roll(FAULTS.read().await.fail_spawn);
+    if fail {
+        warn!("injecting job spawn failure");
+    }
+    fail
+}
+
+/// Delays reads of job output.
+pub(crate) async fn slow_read() {
+    let delay = FAULTS.read().await.slow_reads;
+    if delay > 0 {
+        tokio::time::sleep(Duration::from_millis(delay)).await;
+    }
+}
+
+/// Returns whether a chunk of job output should be dropped.
+pub(crate) async fn drop_output() -> bool {
+    let drop = roll(FAULTS.read().await.drop_output);
+    if drop {
+        warn!("injecting dropped job output");
+    }
+    drop
+}
+
+pub(crate) async fn get(_: Admin) -> impl IntoResponse {
+    Json(FAULTS.read().await.clone())
+}
+
+pub(crate) async fn set(Admin(admin): Admin, Json(faults): Json<Faults>) -> StatusCode {
+    if !(0.0..=1.0).contains(&faults.fail_spawn) || !(0.0..=1.0).contains(&faults.drop_output) {
+        return StatusCode::UNPROCESSABLE_ENTITY;
+    }
+    info!(%admin, ?faults, "injecting faults");
+    *FAULTS.write().await = faults;
+    StatusCode::NO_CONTENT
+}
@@ -0,0 +1,130 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Dry run of the configuration with `--check-config`, e.g. in CI of deployment configs.
+//!
+//! The options and `@config.toml` files are parsed as on startup, then the external
+//! dependencies of this instance are checked, without starting it.
+
+use crate::{auth, Other};
+
+use std::ffi::OsStr;
+
+use anyhow::{bail, Context as _};
+use tokio::process::Command;
+
+/// Runs `command` with `args`, returning the first line of its output.
+async fn version(command: &OsStr, args: &[&str]) -> anyhow::Result<String> {
+    let out = Command::new(command)
+        .args(args)
+        .output()
+        .await
+        .with_context(|| format!("failed to execute `{}`", command.to_string_lossy()))?;
+    if !out.status.success() {
+        bail!(
+            "`{}` failed with {}: {}",
+            command.to_string_lossy(),
+            out.status,
+            String::from_utf8_lossy(&out.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&out.stdout)
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .into())
+}
+
+/// Checks that the port range can serve the workloads of all jobs.
+fn ports(other: &Other) -> anyhow::Result<String> {
+    let range = &other.port_range;
+    if range.is_empty() {
+        bail!("the port range {}-{} is empty", range.start, range.end);
+    }
+    if range.contains(&other.addr.port()) {
+        bail!(
+            "the port range includes the listen port {}",
+            other.addr.port()
+        );
+    }
+    if let Some(listen_max) = other.listen_max {
+        if usize::from(listen_max) > range.len() {
+            bail!("the port range is smaller than the listen ports of a single workload");
+        }
+        let needed = other.jobs_max * usize::from(listen_max);
+        if needed > range.len() {
+            return Ok(format!(
+                "{}-{}, only {} of the {needed} ports {} jobs may listen on",
+                range.start,
+                range.end,
+                range.len(),
+                other.jobs_max
+            ));
+        }
+    }
+    Ok(format!(
+        "{}-{}, {} ports",
+        range.start,
+        range.end,
+        range.len()
+    ))
+}
+
+/// Checks the external dependencies of this instance, printing a report.
+/// Returns an error if any of them is unusable.
+pub(crate) async fn run(oidc: &auth::Oidc, other: &Other) -> anyhow::Result<()> {
+    let checks = [
+        (
+            "config",
+            Ok("all options and config files are valid".into()),
+        ),
+        (
+            "oidc",
+            if oidc.dev {
+                Ok("disabled by the development profile".into())
+            } else {
+                oidc.discover().await.map(|()| match oidc.providers.len() {
+                    0 => format!("{} is reachable", oidc.issuer),
+                    n => format!("{} and {n} other providers are reachable", oidc.issuer),
+                })
+            },
+        ),
+        ("oci", version(&other.oci_command, &["--version"]).await),
+        (
+            "enarx",
+            version(
+                &other.oci_command,
+                &[
+                    "run",
+                    "--rm",
+                    other.oci_image.as_str(),
+                    "enarx",
+                    "--version",
+                ],
+            )
+            .await,
+        ),
+        ("ports", ports(other)),
+        (
+            "runtime-dir",
+            tempfile::tempfile_in(&other.runtime_dir)
+                .map(|_| format!("{} is writable", other.runtime_dir.display()))
+                .context("the runtime directory is not writable"),
+        ),
+    ];
+
+    let mut failed = 0;
+    for (name, result) in checks {
+        match result {
+            Ok(report) => println!("ok      {name}: {report}"),
+            Err(e) => {
+                println!("FAILED  {name}: {e:#}");
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        bail!("{failed} configuration check(s) failed");
+    }
+    Ok(())
+}
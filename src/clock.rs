@@ -0,0 +1,221 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Clock on which the TTLs and deadlines of jobs are based.
+//!
+//! The clock is monotonic, so that adjustments of the wall clock do not shorten or extend jobs.
+//! It does not advance while the host is suspended either, so that a laptop running local demos
+//! does not kill its jobs right after resuming. It is the clock of the Tokio runtime, which can
+//! be paused and advanced in tests with `tokio::time::pause` and `tokio::time::advance`.
+//!
+//! The wall clock is only used for timestamps which are persisted or shown to users.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub(crate) use tokio::time::Instant;
+
+/// Returns the current instant.
+pub(crate) fn now() -> Instant {
+    Instant::now()
+}
+
+/// Returns the wall-clock time of `instant`, in seconds since the Unix epoch,
+/// e.g. to persist a deadline.
+pub(crate) fn unix_secs(instant: Instant) -> u64 {
+    let now = now();
+    let time = if instant >= now {
+        SystemTime::now() + (instant - now)
+    } else {
+        SystemTime::now() - (now - instant)
+    };
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Timeout clock of a job, which is stopped while the job is paused, for a limited time.
+#[derive(Debug)]
+pub(crate) struct Timeout {
+    /// When the job was started.
+    started: Instant,
+    /// When the job is killed after its timeout, excluding the current pause.
+    deadline: Instant,
+    /// When the job was paused, if it is paused.
+    paused: Option<Instant>,
+    /// Total duration of the previous pauses of the job.
+    paused_for: Duration,
+    /// Longest time the clock is stopped for by a single pause.
+    max_pause: Duration,
+}
+
+impl Timeout {
+    /// Starts the clock of a job which times out after `ttl`, and whose pauses stop the clock
+    /// for up to `max_pause` each.
+    pub(crate) fn start(ttl: Duration, max_pause: Duration) -> Self {
+        let now = now();
+        Self {
+            started: now,
+            deadline: now + ttl,
+            paused: None,
+            paused_for: Duration::ZERO,
+            max_pause,
+        }
+    }
+
+    /// Returns how long the current pause has stopped the clock for.
+    fn current_pause(&self) -> Duration {
+        self.paused
+            .map_or(Duration::ZERO, |at| at.elapsed().min(self.max_pause))
+    }
+
+    /// Returns whether the clock is stopped.
+    pub(crate) fn is_paused(&self) -> bool {
+        self.paused.is_some()
+    }
+
+    /// Returns how long the job has been running for, excluding pauses.
+    pub(crate) fn runtime(&self) -> Duration {
+        self.started
+            .elapsed()
+            .saturating_sub(self.paused_for + self.current_pause())
+    }
+
+    /// Returns when the job times out.
+    pub(crate) fn deadline(&self) -> Instant {
+        self.deadline + self.current_pause()
+    }
+
+    /// Extends the timeout by `by`, up to a total runtime of `max_runtime`.
+    /// Returns the new deadline, or `None` if the maximum has already been reached.
+    pub(crate) fn extend(&mut self, by: Duration, max_runtime: Duration) -> Option<Instant> {
+        let current = self.deadline();
+        let max = now() + max_runtime.saturating_sub(self.runtime());
+        let deadline = (current + by).min(max);
+        if deadline <= current {
+            return None;
+        }
+        self.deadline += deadline - current;
+        Some(deadline)
+    }
+
+    /// Stops the clock, unless it is already stopped.
+    pub(crate) fn pause(&mut self) {
+        if self.paused.is_none() {
+            self.paused = Some(now());
+        }
+    }
+
+    /// Starts the clock again, returning how long it was stopped for.
+    pub(crate) fn resume(&mut self) -> Duration {
+        let pause = self.current_pause();
+        self.paused = None;
+        self.paused_for += pause;
+        self.deadline += pause;
+        pause
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::time::{advance, sleep_until};
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    #[tokio::test(start_paused = true)]
+    async fn deadline_follows_the_clock() {
+        let started = now();
+        let timeout = Timeout::start(10 * MINUTE, 30 * MINUTE);
+        assert_eq!(timeout.deadline(), started + 10 * MINUTE);
+
+        advance(4 * MINUTE).await;
+        assert_eq!(timeout.runtime(), 4 * MINUTE);
+        assert_eq!(timeout.deadline(), started + 10 * MINUTE);
+
+        sleep_until(timeout.deadline()).await;
+        assert_eq!(timeout.runtime(), 10 * MINUTE);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pause_stops_the_clock() {
+        let started = now();
+        let mut timeout = Timeout::start(10 * MINUTE, 30 * MINUTE);
+        advance(MINUTE).await;
+
+        timeout.pause();
+        assert!(timeout.is_paused());
+        advance(5 * MINUTE).await;
+        assert_eq!(timeout.runtime(), MINUTE);
+        assert_eq!(timeout.deadline(), started + 15 * MINUTE);
+
+        // Pausing again does not restart the pause.
+        timeout.pause();
+        assert_eq!(timeout.resume(), 5 * MINUTE);
+        assert!(!timeout.is_paused());
+        advance(MINUTE).await;
+        assert_eq!(timeout.runtime(), 2 * MINUTE);
+        assert_eq!(timeout.deadline(), started + 15 * MINUTE);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pause_is_limited() {
+        let started = now();
+        let mut timeout = Timeout::start(10 * MINUTE, 30 * MINUTE);
+        timeout.pause();
+        advance(35 * MINUTE).await;
+        // The clock runs again once the pause exceeds its limit.
+        assert_eq!(timeout.deadline(), started + 40 * MINUTE);
+        assert_eq!(timeout.runtime(), 5 * MINUTE);
+
+        assert_eq!(timeout.resume(), 30 * MINUTE);
+        assert_eq!(timeout.deadline(), started + 40 * MINUTE);
+        sleep_until(timeout.deadline()).await;
+        assert_eq!(timeout.runtime(), 10 * MINUTE);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn extend_is_limited_by_the_max_runtime() {
+        let started = now();
+        let mut timeout = Timeout::start(10 * MINUTE, 30 * MINUTE);
+        advance(5 * MINUTE).await;
+
+        assert_eq!(
+            timeout.extend(5 * MINUTE, 30 * MINUTE),
+            Some(started + 15 * MINUTE)
+        );
+        assert_eq!(
+            timeout.extend(60 * MINUTE, 30 * MINUTE),
+            Some(started + 30 * MINUTE)
+        );
+        assert_eq!(timeout.extend(MINUTE, 30 * MINUTE), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn extend_while_paused_excludes_the_pause() {
+        let started = now();
+        let mut timeout = Timeout::start(10 * MINUTE, 30 * MINUTE);
+        timeout.pause();
+        advance(10 * MINUTE).await;
+
+        // The job has not run at all, so it may still run for the whole maximum.
+        assert_eq!(
+            timeout.extend(60 * MINUTE, 20 * MINUTE),
+            Some(started + 30 * MINUTE)
+        );
+        assert_eq!(timeout.resume(), 10 * MINUTE);
+        assert_eq!(timeout.deadline(), started + 30 * MINUTE);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unix_secs_follows_the_wall_clock() {
+        let wall = |offset: Duration| {
+            (SystemTime::now() + offset)
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+        };
+        let secs = unix_secs(now() + 10 * MINUTE);
+        assert!((wall(10 * MINUTE) - 1..=wall(10 * MINUTE) + 1).contains(&secs));
+    }
+}
@@ -0,0 +1,366 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Comparison of an uploaded workload across the Enarx backends of this instance.
+//!
+//! A comparison runs the workload once on each backend, without input or ports, and reports
+//! the startup time, duration and output of each run side by side. Runs of a comparison are
+//! jobs of their own, which count against the capacity of the instance, but do not replace
+//! the job of the user.
+
+use crate::auth::{IsAdmin, User};
+use crate::features::{self, Feature};
+use crate::job::{Job, Transport};
+use crate::redact::Redactor;
+use crate::templates::{CompareTemplate, HtmlTemplate};
+use crate::{config, denylist, policy, wasm, Limits, Other, Workload, JOBS};
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use axum::extract::Multipart;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use serde_json::json;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::sync::RwLock;
+use tokio::time::timeout;
+use tracing::{error, info};
+use uuid::Uuid;
+
+/// Maximum time a run of a comparison may take.
+const RUN_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Maximum output of each stream of a run which is reported.
+const MAX_OUTPUT: usize = 64 * 1024;
+
+/// Number of runs of comparisons in progress across all users.
+static RUNNING: AtomicUsize = AtomicUsize::new(0);
+
+/// Latest comparison of each user.
+static COMPARISONS: Lazy<RwLock<HashMap<User, Comparison>>> = Lazy::new(Default::default);
+
+/// Returns the number of runs of comparisons in progress, which occupy job slots.
+pub(crate) fn running() -> usize {
+    RUNNING.load(Ordering::Relaxed)
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct Comparison {
+    id: String,
+    runs: Vec<Run>,
+}
+
+/// A run of a workload on a backend.
+#[derive(Clone, Debug, Serialize)]
+struct Run {
+    backend: String,
+    running: bool,
+    /// Seconds until the workload produced its first output.
+    startup: Option<f64>,
+    /// Seconds until the workload exited.
+    duration: Option<f64>,
+    code: Option<i32>,
+    timed_out: bool,
+    /// Why the workload could not be run, if it could not.
+    error: Option<String>,
+    stdout: String,
+    stderr: String,
+}
+
+impl Run {
+    fn new(backend: &str) -> Self {
+        Self {
+            backend: backend.into(),
+            running: true,
+            startup: None,
+            duration: None,
+            code: None,
+            timed_out: false,
+            error: None,
+            stdout: String::new(),
+            stderr: String::new(),
+        }
+    }
+}
+
+/// Output of a stream of a run.
+#[derive(Debug, Default)]
+struct Output {
+    data: Vec<u8>,
+    /// Time since the start of the run until the first output.
+    first: Option<Duration>,
+}
+
+impl Output {
+    fn into_string(self) -> String {
+        let truncated = self.data.len() > MAX_OUTPUT;
+        let mut output =
+            String::from_utf8_lossy(&self.data[..self.data.len().min(MAX_OUTPUT)]).into_owned();
+        if truncated {
+            output.push_str("\n[output truncated]");
+        }
+        output
+    }
+}
+
+/// Reads a stream of a run until it is closed, redacting it.
+async fn capture(rdr: Option<impl AsyncRead + Unpin>, started: Instant) -> Output {
+    let mut output = Output::default();
+    let mut rdr = match rdr {
+        Some(rdr) => rdr,
+        None => return output,
+    };
+    let mut redactor = Redactor::default();
+    let mut buf = [0; 4096];
+    loop {
+        let (chunk, closed) = match rdr.read(&mut buf).await {
+            Ok(0) | Err(_) => (redactor.redact(Vec::new()), true),
+            Ok(n) => (redactor.redact(buf[..n].to_vec()), false),
+        };
+        if output.first.is_none() && !chunk.is_empty() {
+            output.first = Some(started.elapsed());
+        }
+        if output.data.len() <= MAX_OUTPUT {
+            output.data.extend(chunk);
+        }
+        if closed {
+            return output;
+        }
+    }
+}
+
+/// Runs a spawned `job` to completion, recording its outcome in the comparison with `id`.
+async fn run(owner: User, id: String, index: usize, mut job: Job) {
+    let started = Instant::now();
+    job.exec.stdin = None;
+    let (stdout, stderr) = job.take_pipes();
+
+    let result = timeout(RUN_TIMEOUT, async {
+        let (stdout, stderr) = tokio::join!(capture(stdout, started), capture(stderr, started));
+        (stdout, stderr, job.exec.wait().await)
+    })
+    .await;
+    let duration = started.elapsed();
+
+    let mut comparisons = COMPARISONS.write().await;
+    if let Some(run) = comparisons
+        .get_mut(&owner)
+        .filter(|comparison| comparison.id == id)
+        .and_then(|comparison| comparison.runs.get_mut(index))
+    {
+        run.running = false;
+        match result {
+            Ok((stdout, stderr, status)) => {
+                run.startup = stdout
+                    .first
+                    .into_iter()
+                    .chain(stderr.first)
+                    .min()
+                    .map(|startup| startup.as_secs_f64());
+                run.duration = Some(duration.as_secs_f64());
+                run.code = status.ok().and_then(|status| status.code());
+                run.stdout = stdout.into_string();
+                run.stderr = stderr.into_string();
+            }
+            Err(_) => run.timed_out = true,
+        }
+    }
+    drop(comparisons);
+
+    job.discard().await;
+    _ = RUNNING.fetch_sub(1, Ordering::Relaxed);
+}
+
+/// Starts a comparison of an uploaded workload across all backends.
+/// Comparisons are reserved to administrators and users of the starred tier.
+pub(crate) async fn start(
+    user: User,
+    IsAdmin(admin): IsAdmin,
+    mut multipart: Multipart,
+    limits: Limits,
+    other: Other,
+) -> Result<Json<serde_json::Value>, Response> {
+    features::require(Feature::Uploads).await?;
+    if other.backends.is_empty() {
+        return Err((
+            StatusCode::NOT_FOUND,
+            "No backends are configured for comparisons on this instance",
+        )
+            .into_response());
+    }
+    if !admin && !limits.is_starred(user.roles()) {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Comparisons are available to users of the starred tier",
+        )
+            .into_response());
+    }
+    let busy = COMPARISONS
+        .read()
+        .await
+        .get(&user)
+        .map_or(false, |comparison| {
+            comparison.runs.iter().any(|run| run.running)
+        });
+    if busy {
+        return Err((
+            StatusCode::CONFLICT,
+            "Your previous comparison is still running",
+        )
+            .into_response());
+    }
+
+    let max_wasm_size = limits.decide(&policy::Context::new(user)).size;
+    // The spooled upload is accounted for as long as its file exists.
+    let mut upload = other.disk_budget.reservation();
+    let mut wasm = None;
+    let mut wasm_sig = None;
+    let mut conf = None;
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|_| StatusCode::BAD_REQUEST.into_response())?
+    {
+        match field.name() {
+            Some("wasm") if wasm.is_none() => {
+                wasm = crate::parse_file_field(field, max_wasm_size, &other.spool, &mut upload)
+                    .await?
+                    .into()
+            }
+            Some("wasm_sig") if wasm_sig.is_none() => {
+                wasm_sig = crate::parse_string_field(field).await?.into()
+            }
+            Some("toml") if conf.is_none() => {
+                let data = crate::parse_bytes_field(field, crate::MAX_CONF_SIZE).await?;
+                conf = crate::text_file(data)?.into()
+            }
+            _ => return Err(StatusCode::BAD_REQUEST.into_response()),
+        }
+    }
+    let wasm = wasm.ok_or_else(|| StatusCode::BAD_REQUEST.into_response())?;
+    let conf = conf.ok_or_else(|| StatusCode::BAD_REQUEST.into_response())?;
+
+    _ = wasm::validate(wasm.path(), max_wasm_size).await?;
+    let wasm = tokio::fs::read(wasm.path()).await.map_err(|e| {
+        error!(error = ?e, "failed to read uploaded wasm");
+        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    })?;
+    if !other.signing.signers.is_empty() {
+        _ = other.signing.verify(&wasm, wasm_sig.as_deref())?;
+    }
+    _ = denylist::check(user, &wasm).await?;
+
+    let parsed = config::parse(&conf)?;
+    other.egress.check(&parsed).await?;
+    if !crate::listen_ports::<Vec<_>>(&parsed).is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Workloads listening on ports cannot be compared",
+        )
+            .into_response());
+    }
+
+    let (jobs, _) = crate::eta::next_slot(JOBS.read().await.values(), other.jobs_max).await;
+    if jobs + running() + other.backends.len() > other.jobs_max {
+        return Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Not enough slots are free to run a comparison right now, try again later",
+        )
+            .into_response());
+    }
+
+    let id = Uuid::new_v4().to_string();
+    let mut jobs = vec![];
+    for backend in &other.backends {
+        let mut disk = other.disk_budget.reservation();
+        disk.grow((wasm.len() + conf.len()) as u64)?;
+        let workload = Workload::Upload {
+            wasm: other.spool.write(&wasm).await?,
+            conf: other.spool.write(conf.as_bytes()).await?,
+            disk,
+        };
+        let job = Job::spawn(
+            Uuid::new_v4().to_string(),
+            user,
+            workload,
+            &other.ss_command,
+            &other.oci_command,
+            &other.oci_image,
+            other.port_range.clone(),
+            None::<(Transport, u16)>,
+            |_, _| String::new(),
+            &other.devices,
+            &other.paths,
+            other.privileged,
+            other.host_network,
+            Some(backend.as_str()),
+            false,
+            other.memory_limit,
+            other.cpu_limit,
+            other.cgroup_slice.as_deref(),
+            other.spawn_retries,
+            RUN_TIMEOUT,
+            async {},
+        )
+        .await;
+        jobs.push(job);
+    }
+
+    info!(comparison_id = id, %user, backends = ?other.backends, "comparison started");
+    let mut runs = vec![];
+    let mut spawned = vec![];
+    for (index, (backend, job)) in other.backends.iter().zip(jobs).enumerate() {
+        let mut run = Run::new(backend);
+        match job {
+            Ok(job) => {
+                _ = RUNNING.fetch_add(1, Ordering::Relaxed);
+                spawned.push((index, job));
+            }
+            Err(_) => {
+                run.running = false;
+                run.error = Some(format!("the workload could not be started on {backend}"));
+            }
+        }
+        runs.push(run);
+    }
+    _ = COMPARISONS.write().await.insert(
+        user,
+        Comparison {
+            id: id.clone(),
+            runs,
+        },
+    );
+    for (index, job) in spawned {
+        _ = tokio::spawn(run(user, id.clone(), index, job));
+    }
+
+    Ok(Json(json!({
+        "id": id,
+        "backends": other.backends,
+    })))
+}
+
+/// Returns the latest comparison of the user.
+pub(crate) async fn status(user: User) -> Result<impl IntoResponse, StatusCode> {
+    COMPARISONS
+        .read()
+        .await
+        .get(&user)
+        .cloned()
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Renders the page to compare a workload across backends.
+pub(crate) async fn page(user: Option<User>, other: Other) -> impl IntoResponse {
+    HtmlTemplate(CompareTemplate {
+        demo_fqdn: other.demo_fqdn,
+        user: user.is_some(),
+        backends: other.backends,
+    })
+}
@@ -0,0 +1,268 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! `@config.toml` files, which are expanded into the command-line arguments they stand for.
+//!
+//! Top-level keys are the names of command-line options. Arrays are passed as repeated
+//! options, and tables which are not one of the sections below, such as `[gc-policy]`
+//! or `[[examples.examples]]`, are passed as TOML to the option of the same name.
+//!
+//! Related options may also be grouped in sections, for example:
+//!
+//! ```toml
+//! url = "https://benefice.example.com"
+//! admins = [1234, 5678]
+//!
+//! [limits]
+//! size-default = 10
+//! timeout-default = 300
+//!
+//! [oidc]
+//! client = "benefice"
+//! secret = "/run/secrets/oidc"
+//!
+//! [ports]
+//! min = 8000
+//! max = 9000
+//! ```
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fmt::Display;
+use std::path::PathBuf;
+
+use anyhow::{bail, Context as _};
+use serde::Deserialize;
+use toml::Value;
+
+/// Contents of a configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct ConfFile {
+    #[serde(default)]
+    limits: Limits,
+    #[serde(default)]
+    oidc: Oidc,
+    #[serde(default)]
+    ports: Ports,
+    #[serde(default)]
+    spool: Spool,
+    #[serde(default)]
+    s3: S3,
+    #[serde(default)]
+    egress: Egress,
+    /// Options outside of sections.
+    #[serde(flatten)]
+    options: BTreeMap<String, Value>,
+}
+
+/// The `[limits]` section.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct Limits {
+    jobs: Option<usize>,
+    size_default: Option<usize>,
+    size_starred: Option<usize>,
+    timeout_default: Option<u64>,
+    timeout_starred: Option<u64>,
+    timeout_max_default: Option<u64>,
+    timeout_max_starred: Option<u64>,
+    submissions_default: Option<u32>,
+    submissions_starred: Option<u32>,
+    daily_runs: Option<u32>,
+    daily_minutes: Option<u32>,
+    starred_roles: Vec<String>,
+}
+
+/// The `[oidc]` section.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct Oidc {
+    issuer: Option<String>,
+    client: Option<String>,
+    secret: Option<PathBuf>,
+    session_key: Option<PathBuf>,
+    cookie_secret: Option<PathBuf>,
+    session_store: Option<String>,
+    session_ttl: Option<u64>,
+    kill_on_logout: Option<bool>,
+    admins: Vec<u64>,
+    roles_claim: Option<String>,
+    role: Vec<String>,
+}
+
+/// The `[ports]` section.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct Ports {
+    min: Option<u16>,
+    max: Option<u16>,
+    listen_max: Option<u16>,
+}
+
+/// The `[spool]` section.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct Spool {
+    threshold: Option<usize>,
+    dir: Option<PathBuf>,
+}
+
+/// The `[s3]` section.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct S3 {
+    url: Option<String>,
+    region: Option<String>,
+    access_key_id: Option<String>,
+    secret_access_key: Option<PathBuf>,
+}
+
+/// The `[egress]` section.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct Egress {
+    allow: Vec<String>,
+    deny: Vec<String>,
+}
+
+/// Command-line arguments being built.
+#[derive(Debug, Default)]
+struct Args(Vec<OsString>);
+
+impl Args {
+    fn push(&mut self, name: &str, value: impl Into<OsString>) {
+        self.0.push(format!("--{name}").into());
+        self.0.push(value.into());
+    }
+
+    fn opt(&mut self, name: &str, value: Option<impl Display>) {
+        if let Some(value) = value {
+            self.push(name, value.to_string());
+        }
+    }
+
+    fn path(&mut self, name: &str, value: Option<PathBuf>) {
+        if let Some(value) = value {
+            self.push(name, value);
+        }
+    }
+
+    fn flag(&mut self, name: &str, value: Option<bool>) {
+        if value == Some(true) {
+            self.0.push(format!("--{name}").into());
+        }
+    }
+
+    fn many(&mut self, name: &str, values: Vec<impl Display>) {
+        for value in values {
+            self.push(name, value.to_string());
+        }
+    }
+
+    /// Adds an option outside of sections, whose type is only known to the parser.
+    fn value(&mut self, name: &str, value: Value) -> anyhow::Result<()> {
+        match value {
+            Value::Boolean(value) => self.flag(name, Some(value)),
+            Value::String(value) => self.push(name, value),
+            Value::Integer(value) => self.push(name, value.to_string()),
+            Value::Float(value) => self.push(name, value.to_string()),
+            Value::Datetime(value) => self.push(name, value.to_string()),
+            Value::Table(_) => self.push(
+                name,
+                toml::to_string(&value).with_context(|| format!("invalid `{name}`"))?,
+            ),
+            Value::Array(values) => {
+                for value in values {
+                    if value.is_array() {
+                        bail!("`{name}` may not contain nested arrays");
+                    }
+                    self.value(name, value)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ConfFile {
+    fn into_args(self, args: &mut Args) -> anyhow::Result<()> {
+        let Self {
+            limits,
+            oidc,
+            ports,
+            spool,
+            s3,
+            egress,
+            options,
+        } = self;
+
+        for (name, value) in options {
+            args.value(&name.replace('_', "-"), value)?;
+        }
+
+        args.opt("jobs", limits.jobs);
+        args.opt("size-limit-default", limits.size_default);
+        args.opt("size-limit-starred", limits.size_starred);
+        args.opt("timeout-default", limits.timeout_default);
+        args.opt("timeout-starred", limits.timeout_starred);
+        args.opt("timeout-max-default", limits.timeout_max_default);
+        args.opt("timeout-max-starred", limits.timeout_max_starred);
+        args.opt("submissions-default", limits.submissions_default);
+        args.opt("submissions-starred", limits.submissions_starred);
+        args.opt("daily-runs", limits.daily_runs);
+        args.opt("daily-minutes", limits.daily_minutes);
+        args.many("starred-roles", limits.starred_roles);
+
+        args.opt("oidc-issuer", oidc.issuer);
+        args.opt("oidc-client", oidc.client);
+        args.path("oidc-secret", oidc.secret);
+        args.path("session-key", oidc.session_key);
+        args.path("cookie-secret", oidc.cookie_secret);
+        args.opt("session-store", oidc.session_store);
+        args.opt("session-ttl", oidc.session_ttl);
+        args.flag("kill-on-logout", oidc.kill_on_logout);
+        args.many("admins", oidc.admins);
+        args.opt("oidc-roles-claim", oidc.roles_claim);
+        args.many("oidc-role", oidc.role);
+
+        args.opt("port-min", ports.min);
+        args.opt("port-max", ports.max);
+        args.opt("listen-max", ports.listen_max);
+
+        args.opt("spool-threshold", spool.threshold);
+        args.path("spool-dir", spool.dir);
+
+        args.opt("s3-url", s3.url);
+        args.opt("s3-region", s3.region);
+        args.opt("s3-access-key-id", s3.access_key_id);
+        args.path("s3-secret-access-key", s3.secret_access_key);
+
+        args.many("egress-allow", egress.allow);
+        args.many("egress-deny", egress.deny);
+
+        Ok(())
+    }
+}
+
+/// Returns the command-line arguments of this process, with each `@config.toml` argument
+/// replaced by the options in the file.
+pub(crate) fn args() -> anyhow::Result<Vec<OsString>> {
+    let mut args = Args::default();
+    for arg in std::env::args_os() {
+        let path = match arg.to_str().and_then(|arg| arg.strip_prefix('@')) {
+            Some(path) => path,
+            None => {
+                args.0.push(arg);
+                continue;
+            }
+        };
+        let conf = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file `{path}`"))?;
+        toml::from_str::<ConfFile>(&conf)
+            .with_context(|| format!("failed to parse config file `{path}`"))?
+            .into_args(&mut args)
+            .with_context(|| format!("invalid config file `{path}`"))?;
+    }
+    Ok(args.0)
+}
@@ -0,0 +1,338 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Validation of Enarx.toml, reporting problems along with the fields they occur in.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::net::IpAddr;
+
+use axum::extract::ContentLengthLimit;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use enarx_config::Config;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+/// Key of the directory of the container the workload writes its artifacts to, which can be
+/// downloaded once the job has exited. It is specific to this server and not passed to Enarx.
+pub(crate) const ARTIFACTS: &str = "artifacts";
+
+/// Directory of the container Enarx.toml and the workload are mounted in.
+const APP_DIR: &str = "/app";
+
+/// Complete schema of Enarx.toml.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Schema {
+    steward: Option<String>,
+    #[serde(default)]
+    args: Vec<String>,
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+    #[serde(default)]
+    files: Vec<FileSchema>,
+    artifacts: Option<String>,
+}
+
+/// Entry of `files`, with the union of the fields of all kinds.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileSchema {
+    kind: Kind,
+    name: Option<String>,
+    addr: Option<String>,
+    host: Option<String>,
+    port: Option<u16>,
+    prot: Option<Prot>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Kind {
+    Null,
+    Stdin,
+    Stdout,
+    Stderr,
+    Listen,
+    Connect,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Prot {
+    Tcp,
+    Tls,
+}
+
+/// A problem with an Enarx.toml.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct Diagnostic {
+    /// 1-based line the problem occurs on, if known.
+    pub(crate) line: Option<usize>,
+    /// 1-based column the problem occurs at, if known.
+    pub(crate) column: Option<usize>,
+    /// Path of the field the problem occurs in, e.g. `files[1].port`.
+    pub(crate) field: Option<String>,
+    pub(crate) message: String,
+}
+
+impl Diagnostic {
+    fn field(field: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            line: None,
+            column: None,
+            field: Some(field.into()),
+            message: message.into(),
+        }
+    }
+}
+
+impl From<toml::de::Error> for Diagnostic {
+    fn from(e: toml::de::Error) -> Self {
+        let position = e.line_col();
+        let message = e.to_string();
+        // The position is reported separately.
+        let message = match message.rsplit_once(" at line ") {
+            Some((message, _)) if position.is_some() => message.into(),
+            _ => message,
+        };
+        Self {
+            line: position.map(|(line, _)| line + 1),
+            column: position.map(|(_, column)| column + 1),
+            field: None,
+            message,
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.line, self.column) {
+            (Some(line), Some(column)) => write!(f, "line {line}, column {column}: ")?,
+            (Some(line), None) => write!(f, "line {line}: ")?,
+            _ => {}
+        }
+        if let Some(field) = &self.field {
+            write!(f, "`{field}`: ")?;
+        }
+        f.write_str(&self.message)
+    }
+}
+
+/// Checks the constraints the schema cannot express.
+fn check(schema: &Schema) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+
+    if let Some(steward) = &schema.steward {
+        if reqwest::Url::parse(steward).is_err() {
+            diagnostics.push(Diagnostic::field("steward", "must be a valid URL"));
+        }
+    }
+    if let Some(artifacts) = &schema.artifacts {
+        let dir = artifacts.trim_end_matches('/');
+        if !artifacts.starts_with('/') || artifacts.contains('\0') {
+            diagnostics.push(Diagnostic::field(ARTIFACTS, "must be an absolute path"));
+        } else if dir.is_empty() || dir == APP_DIR || dir.starts_with(&format!("{APP_DIR}/")) {
+            diagnostics.push(Diagnostic::field(
+                ARTIFACTS,
+                format!("must not be `/` or within `{APP_DIR}`"),
+            ));
+        }
+    }
+    for (i, arg) in schema.args.iter().enumerate() {
+        if arg.contains('\0') {
+            diagnostics.push(Diagnostic::field(
+                format!("args[{i}]"),
+                "must not contain NUL characters",
+            ));
+        }
+    }
+    for (name, value) in &schema.env {
+        if name.is_empty() || name.contains('=') || name.contains('\0') {
+            diagnostics.push(Diagnostic::field(
+                format!("env.{name}"),
+                "environment variable names must not be empty or contain `=`",
+            ));
+        } else if value.contains('\0') {
+            diagnostics.push(Diagnostic::field(
+                format!("env.{name}"),
+                "must not contain NUL characters",
+            ));
+        }
+    }
+
+    let mut names = HashSet::new();
+    let mut stdio = HashSet::new();
+    for (i, file) in schema.files.iter().enumerate() {
+        let mut problem = |field: &str, message: &str| {
+            diagnostics.push(Diagnostic::field(format!("files[{i}]{field}"), message))
+        };
+
+        if let Some(name) = &file.name {
+            if name.is_empty() {
+                problem(".name", "must not be empty");
+            } else if !names.insert(name.as_str()) {
+                problem(".name", "is already used by another file");
+            }
+        }
+
+        match file.kind {
+            Kind::Null | Kind::Stdin | Kind::Stdout | Kind::Stderr => {
+                if matches!(file.kind, Kind::Stdin | Kind::Stdout | Kind::Stderr)
+                    && !stdio.insert(file.kind)
+                {
+                    problem(".kind", "may only be used by a single file");
+                }
+                for (field, set) in [
+                    (".addr", file.addr.is_some()),
+                    (".host", file.host.is_some()),
+                    (".port", file.port.is_some()),
+                    (".prot", file.prot.is_some()),
+                ] {
+                    if set {
+                        problem(field, "is only allowed for `listen` and `connect` files");
+                    }
+                }
+            }
+            Kind::Listen => {
+                if file.name.is_none() {
+                    problem(".name", "is required for `listen` files");
+                }
+                if file.host.is_some() {
+                    problem(".host", "is only allowed for `connect` files, use `addr`");
+                }
+                if let Some(addr) = &file.addr {
+                    if addr.parse::<IpAddr>().is_err() {
+                        problem(".addr", "must be an IP address");
+                    }
+                }
+                match file.port {
+                    None => problem(".port", "is required for `listen` files"),
+                    Some(0) => problem(".port", "must not be 0"),
+                    Some(_) => {}
+                }
+            }
+            Kind::Connect => {
+                if file.addr.is_some() {
+                    problem(".addr", "is only allowed for `listen` files, use `host`");
+                }
+                match &file.host {
+                    None => problem(".host", "is required for `connect` files"),
+                    Some(host) if host.is_empty() => problem(".host", "must not be empty"),
+                    Some(_) => {}
+                }
+                match file.port {
+                    None => problem(".port", "is required for `connect` files"),
+                    Some(0) => problem(".port", "must not be 0"),
+                    Some(_) => {}
+                }
+            }
+        }
+    }
+    diagnostics
+}
+
+/// Returns the 1-based line and column of the field at `path` in `conf`, if it can be found.
+/// Only fields in the top-level table, `[env]` and `[[files]]` tables are recognized.
+fn locate(conf: &str, path: &str) -> Option<(usize, usize)> {
+    let (table, index, key) = if let Some(rest) = path.strip_prefix("files[") {
+        let (index, key) = rest.split_once(']')?;
+        (
+            "[[files]]",
+            index.parse().ok()?,
+            key.trim_start_matches('.'),
+        )
+    } else if let Some(key) = path.strip_prefix("env.") {
+        ("[env]", 0, key)
+    } else {
+        ("", 0, path.split('[').next().unwrap_or(path))
+    };
+
+    let mut seen = 0;
+    let mut inside = table.is_empty();
+    let mut header = None;
+    for (n, line) in conf.lines().enumerate() {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with('[') {
+            inside = trimmed.trim_end() == table && seen == index;
+            if trimmed.trim_end() == table {
+                if inside {
+                    header = Some((n + 1, line.len() - trimmed.len() + 1));
+                }
+                seen += 1;
+            }
+            continue;
+        }
+        if !inside || key.is_empty() {
+            continue;
+        }
+        let quoted = format!("\"{key}\"");
+        let rest = trimmed
+            .strip_prefix(key)
+            .or_else(|| trimmed.strip_prefix(quoted.as_str()));
+        if matches!(rest, Some(rest) if rest.trim_start().starts_with('=')) {
+            return Some((n + 1, line.len() - trimmed.len() + 1));
+        }
+    }
+    header
+}
+
+/// Validates the Enarx.toml in `conf`, returning all problems found.
+pub(crate) fn validate(conf: &str) -> Result<Config, Vec<Diagnostic>> {
+    let schema: Schema = toml::from_str(conf).map_err(|e| vec![e.into()])?;
+    let mut diagnostics = check(&schema);
+    if !diagnostics.is_empty() {
+        for diagnostic in &mut diagnostics {
+            let position = diagnostic.field.as_deref().and_then(|f| locate(conf, f));
+            if let Some((line, column)) = position {
+                diagnostic.line = Some(line);
+                diagnostic.column = Some(column);
+            }
+        }
+        return Err(diagnostics);
+    }
+    toml::from_str(&for_enarx(conf).map_err(|e| vec![e.into()])?).map_err(|e| vec![e.into()])
+}
+
+/// Returns the directory the workload of `conf` writes its artifacts to, if it declares one.
+pub(crate) fn artifacts(conf: &str) -> Option<String> {
+    let conf: toml::Value = toml::from_str(conf).ok()?;
+    conf.get(ARTIFACTS)?.as_str().map(Into::into)
+}
+
+/// Returns `conf` without the fields which are specific to this server, to be passed to Enarx.
+pub(crate) fn for_enarx(conf: &str) -> Result<String, toml::de::Error> {
+    let mut value: toml::Value = toml::from_str(conf)?;
+    match value
+        .as_table_mut()
+        .and_then(|table| table.remove(ARTIFACTS))
+    {
+        Some(_) => Ok(toml::to_string(&value).expect("parsed TOML is serializable")),
+        None => Ok(conf.into()),
+    }
+}
+
+/// Lints the Enarx.toml in the request body, e.g. for the editor on the upload page.
+pub(crate) async fn lint(
+    ContentLengthLimit(conf): ContentLengthLimit<String, { crate::MAX_CONF_SIZE as u64 }>,
+) -> impl IntoResponse {
+    let diagnostics = validate(&conf).err().unwrap_or_default();
+    Json(json!({
+        "valid": diagnostics.is_empty(),
+        "diagnostics": diagnostics,
+    }))
+}
+
+/// Parses the Enarx.toml in `conf`, rejecting it with a description of all problems found.
+pub(crate) fn parse(conf: &str) -> Result<Config, Response> {
+    validate(conf).map_err(|diagnostics| {
+        let problems: Vec<_> = diagnostics.iter().map(ToString::to_string).collect();
+        (
+            StatusCode::BAD_REQUEST,
+            format!("Your Enarx.toml is invalid:\n{}", problems.join("\n")),
+        )
+            .into_response()
+    })
+}
@@ -0,0 +1,244 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Daily quota of each user, limiting the jobs they run and the minutes their jobs run for
+//! within any 24 hours, beyond the single job they may run at a time.
+//!
+//! The usage of each user is saved in the runtime directory, so that restarting this instance
+//! does not reset it.
+
+use crate::auth::User;
+use crate::security::{self, Quota};
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::Context as _;
+use axum::http::header::RETRY_AFTER;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use once_cell::sync::{Lazy, OnceCell};
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+use tokio::sync::RwLock;
+use tracing::{error, info};
+
+/// Period the quota applies to.
+const PERIOD: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Name of the file in the runtime directory the usage is saved to.
+const USAGE_FILE: &str = "daily-usage.json";
+
+/// Runs of each user within the period, by their user ID.
+static USAGE: Lazy<RwLock<HashMap<u64, Vec<Run>>>> = Lazy::new(Default::default);
+
+static PATH: OnceCell<PathBuf> = OnceCell::new();
+
+/// Daily quota of a user.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Limit {
+    /// Number of jobs a user may run, if limited.
+    pub(crate) runs: Option<u32>,
+    /// Total minutes the jobs of a user may run for, if limited.
+    pub(crate) minutes: Option<u32>,
+}
+
+impl Limit {
+    pub(crate) fn is_limited(&self) -> bool {
+        self.runs.is_some() || self.minutes.is_some()
+    }
+}
+
+/// What is left of the daily quota of a user.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Allowance {
+    /// Jobs the user may still run, if limited.
+    pub(crate) runs: Option<u32>,
+    /// Minutes the jobs of the user may still run for, if limited.
+    pub(crate) minutes: Option<u32>,
+    /// Time until the oldest run of the user no longer counts against the quota.
+    pub(crate) renews: Duration,
+}
+
+impl Allowance {
+    fn is_exhausted(&self) -> bool {
+        self.runs == Some(0) || self.minutes == Some(0)
+    }
+
+    /// Returns the hours until the quota renews, rounded up.
+    fn renews_in_hours(&self) -> u64 {
+        (self.renews.as_secs() + 3599) / 3600
+    }
+
+    /// Summarizes the allowance for pages, e.g. `3 jobs / 45 minutes left`.
+    pub(crate) fn summary(&self) -> String {
+        let left: Vec<_> = [
+            self.runs.map(|runs| format!("{runs} jobs")),
+            self.minutes.map(|minutes| format!("{minutes} minutes")),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        let summary = format!("{} left", left.join(" / "));
+        if self.is_exhausted() {
+            format!("{summary}, more in {} hour(s)", self.renews_in_hours())
+        } else {
+            summary
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct Run {
+    id: String,
+    started: SystemTime,
+    /// How long the job ran for, `None` while it is running.
+    runtime: Option<Duration>,
+}
+
+impl Run {
+    fn runtime(&self) -> Duration {
+        self.runtime
+            .unwrap_or_else(|| self.started.elapsed().unwrap_or_default())
+    }
+
+    fn is_current(&self, now: SystemTime) -> bool {
+        self.started + PERIOD > now
+    }
+}
+
+fn write(path: &Path, usage: &HashMap<u64, Vec<Run>>) -> anyhow::Result<()> {
+    let dir = path
+        .parent()
+        .context("usage file has no parent directory")?;
+    let mut file = NamedTempFile::new_in(dir).context("failed to create usage file")?;
+    serde_json::to_writer(&mut file, usage).context("failed to encode usage")?;
+    file.flush().context("failed to write usage")?;
+    _ = file.persist(path).context("failed to persist usage file")?;
+    Ok(())
+}
+
+async fn update<T>(f: impl FnOnce(&mut HashMap<u64, Vec<Run>>) -> T) -> T {
+    let mut usage = USAGE.write().await;
+    let result = f(&mut usage);
+    let now = SystemTime::now();
+    for runs in usage.values_mut() {
+        runs.retain(|run| run.is_current(now));
+    }
+    usage.retain(|_, runs| !runs.is_empty());
+    if let Some(path) = PATH.get() {
+        if let Err(e) = write(path, &usage) {
+            error!(error = ?e, "failed to save daily usage");
+        }
+    }
+    result
+}
+
+/// Loads the usage saved in `runtime_dir` on startup. Jobs which were running are cleaned up
+/// on startup, so they count as having ended now.
+pub(crate) fn load(runtime_dir: impl AsRef<Path>) -> anyhow::Result<()> {
+    let path = runtime_dir.as_ref().join(USAGE_FILE);
+    let mut usage: HashMap<u64, Vec<Run>> = match std::fs::read(&path) {
+        Ok(data) => serde_json::from_slice(&data)
+            .with_context(|| format!("failed to decode usage in `{}`", path.display()))?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+        Err(e) => return Err(e).context("failed to read usage file"),
+    };
+    for run in usage.values_mut().flatten() {
+        run.runtime = Some(run.runtime());
+    }
+    info!(users = usage.len(), "loaded daily usage");
+    *USAGE.try_write().context("daily usage already in use")? = usage;
+    PATH.set(path)
+        .map_err(|_| anyhow::anyhow!("daily usage already loaded"))
+}
+
+/// Returns what is left of the daily quota `limit` of `user`.
+pub(crate) async fn allowance(user: User, limit: Limit) -> Allowance {
+    let now = SystemTime::now();
+    let usage = USAGE.read().await;
+    let runs: Vec<_> = usage
+        .get(&user.uid())
+        .map(|runs| runs.iter().filter(|run| run.is_current(now)).collect())
+        .unwrap_or_default();
+
+    let used = runs.iter().map(|run| run.runtime()).sum::<Duration>();
+    let renews = runs
+        .iter()
+        .map(|run| run.started + PERIOD)
+        .min()
+        .and_then(|renews| renews.duration_since(now).ok())
+        .unwrap_or_default();
+    Allowance {
+        runs: limit
+            .runs
+            .map(|max| max.saturating_sub(runs.len().try_into().unwrap_or(u32::MAX))),
+        minutes: limit.minutes.map(|max| {
+            let left = (u64::from(max) * 60).saturating_sub(used.as_secs());
+            // Less than a minute left is not enough to run a job.
+            (left / 60).try_into().unwrap_or(u32::MAX)
+        }),
+        renews,
+    }
+}
+
+/// Checks whether `user` may start a job within their daily quota `limit`, telling them when
+/// to try again otherwise.
+pub(crate) async fn check(user: User, limit: Limit) -> Result<(), Response> {
+    if !limit.is_limited() {
+        return Ok(());
+    }
+    let allowance = allowance(user, limit).await;
+    if !allowance.is_exhausted() {
+        return Ok(());
+    }
+
+    info!(%user, ?limit, renews = allowance.renews.as_secs(), "daily quota exhausted");
+    security::quota_exceeded(user, Quota::Daily).await;
+    let used = match (allowance.runs, limit.runs) {
+        (Some(0), Some(runs)) => format!("{runs} jobs"),
+        _ => format!("{} minutes of runtime", limit.minutes.unwrap_or_default()),
+    };
+    let hours = allowance.renews_in_hours();
+    Err((
+        StatusCode::TOO_MANY_REQUESTS,
+        [(RETRY_AFTER, allowance.renews.as_secs().max(1).to_string())],
+        format!(
+            "You have used your daily quota of {used}, \
+            try again in {hours} hour(s)"
+        ),
+    )
+        .into_response())
+}
+
+/// Records that `user` started the job with `id`.
+pub(crate) async fn record(user: User, id: &str) {
+    update(|usage| {
+        usage.entry(user.uid()).or_default().push(Run {
+            id: id.into(),
+            started: SystemTime::now(),
+            runtime: None,
+        })
+    })
+    .await
+}
+
+/// Records that the job with `id` of `user` ended after running for `runtime`.
+pub(crate) async fn finish(user: User, id: &str, runtime: Duration) {
+    update(|usage| {
+        if let Some(run) = usage
+            .get_mut(&user.uid())
+            .and_then(|runs| runs.iter_mut().find(|run| run.id == id))
+        {
+            run.runtime = Some(runtime);
+        }
+    })
+    .await
+}
+
+/// Forgets the runs which no longer count against the quota.
+pub(crate) async fn gc() {
+    update(|_| {}).await
+}
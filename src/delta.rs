@@ -0,0 +1,197 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Delta uploads of wasm modules against a module the user uploaded previously.
+//!
+//! A delta is a sequence of instructions, which are applied in order to build the new module:
+//!
+//! - `0x00 <offset> <length>` copies `length` bytes at `offset` of the base module,
+//! - `0x01 <length> <bytes>` inserts the `length` bytes following the instruction,
+//!
+//! where all integers are unsigned LEB128. The base module is identified by its SHA-256 digest.
+//!
+//! The modules kept as bases are stored in the `bases` directory of the runtime directory, which
+//! is emptied on startup.
+
+use crate::auth::User;
+use crate::fingerprint::leb128;
+
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context as _;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use once_cell::sync::{Lazy, OnceCell};
+use tokio::fs;
+use tokio::sync::Mutex;
+use tracing::{debug, error};
+
+/// Instruction copying a range of the base module.
+const COPY: u8 = 0x00;
+
+/// Instruction inserting literal bytes.
+const INSERT: u8 = 0x01;
+
+/// Number of modules kept per user as bases for deltas.
+const BASES_PER_USER: usize = 2;
+
+/// Combined size in bytes of all modules kept as bases for deltas.
+const BASES_SIZE: usize = 256 * 1024 * 1024; // 256 MiB
+
+/// Directory of the modules kept as bases, set by `init`.
+static DIR: OnceCell<PathBuf> = OnceCell::new();
+
+#[derive(Debug)]
+struct Base {
+    owner: u64,
+    sha256: String,
+    size: usize,
+}
+
+impl Base {
+    fn path(&self) -> Option<PathBuf> {
+        Some(
+            DIR.get()?
+                .join(format!("{}-{}.wasm", self.owner, self.sha256)),
+        )
+    }
+}
+
+/// Recently uploaded modules, oldest first.
+static BASES: Lazy<Mutex<VecDeque<Base>>> = Lazy::new(Default::default);
+
+/// Creates the empty directory of the bases in `runtime_dir` on startup.
+pub(crate) async fn init(runtime_dir: &Path) -> anyhow::Result<()> {
+    let dir = runtime_dir.join("bases");
+    match fs::remove_dir_all(&dir).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            return Err(e).with_context(|| format!("failed to remove `{}`", dir.display()))
+        }
+        _ => {}
+    }
+    fs::create_dir_all(&dir)
+        .await
+        .with_context(|| format!("failed to create `{}`", dir.display()))?;
+    DIR.set(dir)
+        .map_err(|_| anyhow::anyhow!("delta bases already initialized"))
+}
+
+/// Removes the file of a `base` which is no longer kept.
+async fn forget(base: Base) {
+    if let Some(path) = base.path() {
+        if let Err(e) = fs::remove_file(&path).await {
+            error!(error = ?e, path = %path.display(), "failed to remove delta base");
+        }
+    }
+}
+
+/// Keeps `wasm` with `sha256` uploaded by `user`, so that it can be used as the base of a delta.
+pub(crate) async fn remember(user: User, sha256: String, wasm: &[u8]) {
+    let base = Base {
+        owner: user.uid(),
+        sha256,
+        size: wasm.len(),
+    };
+    let path = match base.path() {
+        Some(path) => path,
+        None => return,
+    };
+
+    let mut bases = BASES.lock().await;
+    bases.retain(|other| other.owner != base.owner || other.sha256 != base.sha256);
+    if let Err(e) = fs::write(&path, wasm).await {
+        error!(error = ?e, path = %path.display(), "failed to write delta base");
+        return;
+    }
+    bases.push_back(base);
+
+    let mut expired = vec![];
+    let mut kept = 0;
+    for i in (0..bases.len()).rev() {
+        if bases[i].owner == user.uid() {
+            kept += 1;
+            if kept > BASES_PER_USER {
+                expired.extend(bases.remove(i));
+            }
+        }
+    }
+    while bases.iter().map(|base| base.size).sum::<usize>() > BASES_SIZE {
+        expired.extend(bases.pop_front());
+    }
+    for base in expired {
+        forget(base).await;
+    }
+}
+
+/// Builds a module by applying `delta` to the module with `sha256` previously uploaded by `user`.
+pub(crate) async fn apply(
+    user: User,
+    sha256: &str,
+    delta: &[u8],
+    max_size: usize,
+) -> Result<Vec<u8>, Response> {
+    let sha256 = sha256.trim().to_ascii_lowercase();
+    let unknown = || {
+        (
+            StatusCode::CONFLICT,
+            "The base of the delta is not known, upload the full workload instead",
+        )
+            .into_response()
+    };
+    let path = BASES
+        .lock()
+        .await
+        .iter()
+        .find(|base| base.owner == user.uid() && base.sha256 == sha256)
+        .and_then(Base::path)
+        .ok_or_else(unknown)?;
+    let base = match fs::read(&path).await {
+        Ok(base) => base,
+        // The base was replaced by a newer upload in the meantime.
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Err(unknown()),
+        Err(e) => {
+            error!(error = ?e, path = %path.display(), "failed to read delta base");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+        }
+    };
+
+    let malformed = || (StatusCode::BAD_REQUEST, "The delta is malformed").into_response();
+    let mut out = Vec::with_capacity(base.len());
+    let mut pos = 0;
+    while let Some(op) = delta.get(pos) {
+        pos += 1;
+        let chunk = match *op {
+            COPY => {
+                let offset = leb128(delta, &mut pos).ok_or_else(malformed)?;
+                let len = leb128(delta, &mut pos).ok_or_else(malformed)?;
+                offset
+                    .checked_add(len)
+                    .and_then(|end| base.get(offset..end))
+                    .ok_or_else(malformed)?
+            }
+            INSERT => {
+                let len = leb128(delta, &mut pos).ok_or_else(malformed)?;
+                let chunk = pos
+                    .checked_add(len)
+                    .and_then(|end| delta.get(pos..end))
+                    .ok_or_else(malformed)?;
+                pos += len;
+                chunk
+            }
+            _ => return Err(malformed()),
+        };
+        if out.len() + chunk.len() > max_size {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE.into_response());
+        }
+        out.extend_from_slice(chunk);
+    }
+    debug!(
+        %user,
+        base = sha256,
+        delta = delta.len(),
+        size = out.len(),
+        "applied wasm delta"
+    );
+    Ok(out)
+}
@@ -0,0 +1,304 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Operator-defined list of banned workloads, identified by the SHA-256 digest of their wasm.
+//!
+//! Fingerprints of banned workloads are kept when available, so that trivially
+//! modified copies of them can be flagged for review.
+
+use crate::audit;
+use crate::auth::{Admin, User};
+use crate::fingerprint::Fingerprint;
+use crate::gc::{self, Rule};
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context as _;
+use axum::extract::Path as AxumPath;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use once_cell::sync::{Lazy, OnceCell};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tokio::sync::{Mutex, RwLock};
+use tracing::{error, info, warn};
+
+/// Workloads at least this similar to a banned one are flagged for review.
+const SIMILARITY_THRESHOLD: f64 = 0.8;
+
+/// Number of recently submitted fingerprints remembered, so that they are
+/// available when a workload gets banned.
+const SEEN_MAX: usize = 1024;
+
+/// Banned digests as lowercase hex strings, with their fingerprints if known.
+static BANNED: Lazy<RwLock<BTreeMap<String, Option<Fingerprint>>>> = Lazy::new(Default::default);
+
+/// Fingerprints of recently submitted workloads keyed by their digest.
+static SEEN: Lazy<Mutex<HashMap<String, Fingerprint>>> = Lazy::new(Default::default);
+
+/// Submissions similar to a banned workload, newest first.
+static FLAGGED: Lazy<RwLock<VecDeque<Flag>>> = Lazy::new(Default::default);
+
+/// File the banned digests are persisted to, if any.
+static FILE: OnceCell<PathBuf> = OnceCell::new();
+
+#[derive(Clone, Debug, Serialize)]
+struct Flag {
+    sha256: String,
+    similar_to: String,
+    similarity: f64,
+    user: String,
+    /// Time of the submission in seconds since the Unix epoch.
+    time: u64,
+}
+
+/// Returns the lowercase hex SHA-256 digest of `data`.
+pub(crate) fn digest(data: impl AsRef<[u8]>) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+fn parse_digest(digest: &str) -> Option<String> {
+    let digest = digest.trim().to_ascii_lowercase();
+    (digest.len() == 64 && digest.bytes().all(|b| b.is_ascii_hexdigit())).then_some(digest)
+}
+
+/// Path of the file the fingerprints of banned workloads are persisted to.
+fn fingerprints_path(path: &Path) -> PathBuf {
+    path.with_extension("fingerprints.json")
+}
+
+/// Loads the banned digests from `path`, one per line. Empty lines and lines starting with `#`
+/// are ignored. Digests banned at runtime are written back to the same file.
+pub(crate) fn load(path: impl AsRef<Path>) -> anyhow::Result<()> {
+    let path = path.as_ref();
+    let mut banned: BTreeMap<_, _> = match std::fs::read_to_string(path) {
+        Ok(data) => data
+            .lines()
+            .filter(|line| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+            .map(|line| {
+                parse_digest(line)
+                    .map(|digest| (digest, None))
+                    .with_context(|| format!("invalid SHA-256 digest `{line}`"))
+            })
+            .collect::<anyhow::Result<_>>()?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+        Err(e) => return Err(e).with_context(|| format!("failed to read `{}`", path.display())),
+    };
+
+    let prints_path = fingerprints_path(path);
+    match std::fs::read(&prints_path) {
+        Ok(data) => {
+            let prints: BTreeMap<String, Fingerprint> = serde_json::from_slice(&data)
+                .with_context(|| format!("failed to parse `{}`", prints_path.display()))?;
+            for (digest, print) in prints {
+                if let Some(entry) = banned.get_mut(&digest) {
+                    *entry = Some(print);
+                }
+            }
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => {
+            return Err(e).with_context(|| format!("failed to read `{}`", prints_path.display()))
+        }
+    }
+
+    *BANNED.try_write().context("banned digests are in use")? = banned;
+    FILE.set(path.into())
+        .map_err(|_| anyhow::anyhow!("banned digests already loaded"))
+}
+
+fn save(banned: &BTreeMap<String, Option<Fingerprint>>) {
+    if let Some(path) = FILE.get() {
+        let data: String = banned.keys().map(|digest| format!("{digest}\n")).collect();
+        if let Err(e) = std::fs::write(path, data) {
+            error!(error = ?e, path = %path.display(), "failed to save banned digests");
+        }
+
+        let prints: BTreeMap<_, _> = banned
+            .iter()
+            .filter_map(|(digest, print)| Some((digest, print.as_ref()?)))
+            .collect();
+        let prints_path = fingerprints_path(path);
+        if let Err(e) = serde_json::to_vec(&prints)
+            .map_err(std::io::Error::from)
+            .and_then(|data| std::fs::write(&prints_path, data))
+        {
+            error!(error = ?e, path = %prints_path.display(), "failed to save banned fingerprints");
+        }
+    }
+}
+
+/// Checks an uploaded workload against the denylist, returning its digest if it is allowed.
+/// Workloads similar to a banned one are allowed, but flagged for review.
+pub(crate) async fn check(user: User, wasm: &[u8]) -> Result<String, Response> {
+    let digest = digest(wasm);
+    let banned = BANNED.read().await;
+    if banned.contains_key(&digest) {
+        warn!(%user, sha256 = digest, "rejected banned workload");
+        audit::Event::new(user.uid(), audit::Action::DenylistHit)
+            .sha256(Some(digest))
+            .detail("banned")
+            .record()
+            .await;
+        return Err((
+            StatusCode::FORBIDDEN,
+            "This workload has been banned by the operators of this instance",
+        )
+            .into_response());
+    }
+
+    let print = match Fingerprint::new(wasm) {
+        Some(print) => print,
+        None => return Ok(digest),
+    };
+
+    let similar = banned
+        .iter()
+        .filter_map(|(banned, other)| Some((banned, other.as_ref()?.similarity(&print))))
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .filter(|(_, similarity)| *similarity >= SIMILARITY_THRESHOLD);
+    if let Some((similar_to, similarity)) = similar {
+        warn!(
+            %user,
+            sha256 = digest,
+            similar_to,
+            similarity,
+            "workload is similar to a banned one, flagging it for review"
+        );
+        audit::Event::new(user.uid(), audit::Action::DenylistHit)
+            .sha256(Some(digest.clone()))
+            .detail(format!("similar to {similar_to}"))
+            .record()
+            .await;
+        let mut flagged = FLAGGED.write().await;
+        flagged.push_front(Flag {
+            sha256: digest.clone(),
+            similar_to: similar_to.clone(),
+            similarity,
+            user: user.to_string(),
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        });
+        apply(&gc::policy().flagged, &mut flagged);
+    }
+
+    let mut seen = SEEN.lock().await;
+    if seen.len() >= SEEN_MAX {
+        if let Some(evicted) = seen.keys().next().cloned() {
+            _ = seen.remove(&evicted);
+        }
+    }
+    _ = seen.insert(digest.clone(), print);
+
+    Ok(digest)
+}
+
+fn apply(rule: &Rule, flagged: &mut VecDeque<Flag>) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let retained = rule.retain(
+        flagged.iter(),
+        |flag| now.saturating_sub(Duration::from_secs(flag.time)),
+        |_| 0,
+    );
+    flagged.truncate(retained);
+}
+
+/// Forgets the flagged submissions which are no longer retained by `rule`.
+pub(crate) async fn gc(rule: &Rule) {
+    apply(rule, &mut *FLAGGED.write().await);
+}
+
+pub(crate) async fn list(_: Admin) -> impl IntoResponse {
+    let banned: Vec<_> = BANNED
+        .read()
+        .await
+        .iter()
+        .map(|(digest, print)| {
+            json!({
+                "sha256": digest,
+                "fingerprinted": print.is_some(),
+            })
+        })
+        .collect();
+    Json(banned)
+}
+
+/// Returns the banned digests with their fingerprints for a backup of the state of this
+/// instance.
+pub(crate) async fn export() -> BTreeMap<String, Option<Fingerprint>> {
+    BANNED.read().await.clone()
+}
+
+/// Replaces the banned digests with the ones restored from a backup, returning how many
+/// there are.
+pub(crate) async fn import(
+    restored: BTreeMap<String, Option<Fingerprint>>,
+) -> anyhow::Result<usize> {
+    let restored: BTreeMap<_, _> = restored
+        .into_iter()
+        .map(|(digest, print)| {
+            parse_digest(&digest)
+                .map(|digest| (digest, print))
+                .with_context(|| format!("invalid SHA-256 digest `{digest}`"))
+        })
+        .collect::<anyhow::Result<_>>()?;
+    let mut banned = BANNED.write().await;
+    *banned = restored;
+    save(&banned);
+    Ok(banned.len())
+}
+
+pub(crate) async fn flagged(_: Admin) -> impl IntoResponse {
+    Json(FLAGGED.read().await.clone())
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct Ban {
+    pub(crate) sha256: String,
+}
+
+/// Bans the workload with the SHA-256 digest `sha256` on behalf of `admin`,
+/// returning the normalized digest.
+pub(crate) async fn ban(admin: User, sha256: &str) -> Result<String, StatusCode> {
+    let digest = parse_digest(sha256).ok_or(StatusCode::BAD_REQUEST)?;
+
+    // Use the fingerprint of the workload if it was submitted recently.
+    let print = SEEN.lock().await.get(&digest).cloned();
+
+    let mut banned = BANNED.write().await;
+    info!(
+        %admin,
+        sha256 = digest,
+        fingerprinted = print.is_some(),
+        "banning workload"
+    );
+    _ = banned.insert(digest.clone(), print);
+    save(&banned);
+    Ok(digest)
+}
+
+pub(crate) async fn add(Admin(admin): Admin, Json(Ban { sha256 }): Json<Ban>) -> StatusCode {
+    match ban(admin, &sha256).await {
+        Ok(_) => StatusCode::NO_CONTENT,
+        Err(status) => status,
+    }
+}
+
+pub(crate) async fn remove(Admin(admin): Admin, AxumPath(digest): AxumPath<String>) -> StatusCode {
+    let mut banned = BANNED.write().await;
+    if banned.remove(&digest.to_ascii_lowercase()).is_some() {
+        info!(%admin, sha256 = digest, "unbanning workload");
+        save(&banned);
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
@@ -0,0 +1,134 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Development profile enabled with `--dev-profile`, so that contributors can exercise the
+//! whole user experience locally within seconds, without Enarx, a container engine or an
+//! OpenID Connect provider.
+//!
+//! The profile shrinks the limits and timeouts, runs jobs with a mock container engine which
+//! echoes their standard input, logs users in as a local administrator and seeds the gallery
+//! with bundled examples.
+
+use crate::auth::{self, Role, Roles};
+use crate::{Args, DemoHost};
+
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context as _};
+
+/// User ID of the local administrator, which GitHub never assigns.
+pub(crate) const UID: u64 = 0;
+
+/// Returns the roles of the local administrator.
+pub(crate) fn roles() -> Roles {
+    [Role::Admin, Role::Starred, Role::Default]
+        .into_iter()
+        .collect()
+}
+
+/// Maximum number of jobs.
+const JOBS_MAX: usize = 4;
+
+/// Maximum default and starred file sizes (in MiB).
+const SIZE_LIMITS: (usize, usize) = (1, 2);
+
+/// Maximum default and starred job timeouts (in seconds).
+const TIMEOUTS: (u64, u64) = (60, 2 * 60);
+
+/// Maximum default and starred total runtimes jobs may be extended to (in seconds).
+const TIMEOUTS_MAX: (u64, u64) = (3 * 60, 5 * 60);
+
+/// Mock container engine.
+const ENGINE: &str = include_str!("../dev/engine.sh");
+
+/// Bundled examples, as their name, `main.wasm`, `Enarx.toml` and description.
+const EXAMPLES: [(&str, &[u8], &str, &str); 2] = [
+    (
+        "echo",
+        include_bytes!("../dev/examples/echo/main.wasm"),
+        include_str!("../dev/examples/echo/Enarx.toml"),
+        include_str!("../dev/examples/echo/description.txt"),
+    ),
+    (
+        "hello",
+        include_bytes!("../dev/examples/hello/main.wasm"),
+        include_str!("../dev/examples/hello/Enarx.toml"),
+        include_str!("../dev/examples/hello/description.txt"),
+    ),
+];
+
+/// Directory in the runtime directory the engine and the examples are written to.
+fn dir(runtime_dir: &Path) -> PathBuf {
+    runtime_dir.join("benefice-dev")
+}
+
+/// Applies the profile to the options. The limits and timeouts are shrunk to the ones of the
+/// profile, while the options required in production default to serving on localhost.
+///
+/// Anyone who can reach the server logs in as an administrator, so it listens on the loopback
+/// address instead of all addresses, and refuses to listen on any other address.
+pub(crate) fn apply(args: &mut Args) -> anyhow::Result<()> {
+    let dir = dir(&args.runtime_dir);
+
+    let ip = match args.addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ip => ip,
+    };
+    if !ip.is_loopback() {
+        bail!("the development profile only listens on loopback addresses, not on {ip}");
+    }
+    args.addr.set_ip(ip);
+
+    let port = args.addr.port();
+    _ = args.url.get_or_insert_with(|| {
+        auth::Url::parse(&format!("http://localhost:{port}")).expect("valid URL")
+    });
+    _ = args
+        .demo_fqdn
+        .get_or_insert_with(|| DemoHost(auth::Host::Domain("localhost".into())));
+
+    args.jobs = args.jobs.min(JOBS_MAX);
+    args.size_limit_default = args.size_limit_default.min(SIZE_LIMITS.0);
+    args.size_limit_starred = args.size_limit_starred.min(SIZE_LIMITS.1);
+    args.timeout_default = args.timeout_default.min(TIMEOUTS.0);
+    args.timeout_starred = args.timeout_starred.min(TIMEOUTS.1);
+    args.timeout_max_default = args.timeout_max_default.min(TIMEOUTS_MAX.0);
+    args.timeout_max_starred = args.timeout_max_starred.min(TIMEOUTS_MAX.1);
+
+    args.oci_command = dir.join("engine").into();
+    _ = args
+        .examples_dir
+        .get_or_insert_with(|| dir.join("examples"));
+    if !args.admins.contains(&UID) {
+        args.admins.push(UID);
+    }
+    Ok(())
+}
+
+/// Writes the mock container engine and the bundled examples to `runtime_dir` on startup.
+pub(crate) fn init(runtime_dir: &Path) -> anyhow::Result<()> {
+    let dir = dir(runtime_dir);
+    let write = |path: PathBuf, contents: &[u8]| {
+        fs::write(&path, contents).with_context(|| format!("failed to write `{}`", path.display()))
+    };
+
+    fs::create_dir_all(&dir).with_context(|| format!("failed to create `{}`", dir.display()))?;
+    let engine = dir.join("engine");
+    write(engine.clone(), ENGINE.as_bytes())?;
+    fs::set_permissions(&engine, fs::Permissions::from_mode(0o755))
+        .with_context(|| format!("failed to make `{}` executable", engine.display()))?;
+
+    for (name, wasm, conf, description) in EXAMPLES {
+        let example = dir.join("examples").join(name);
+        fs::create_dir_all(&example)
+            .with_context(|| format!("failed to create `{}`", example.display()))?;
+        write(example.join("main.wasm"), wasm)?;
+        write(example.join("Enarx.toml"), conf.as_bytes())?;
+        write(example.join("description.txt"), description.as_bytes())?;
+    }
+    Ok(())
+}
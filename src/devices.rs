@@ -0,0 +1,93 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Checks of the device nodes required to run workloads.
+
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use once_cell::sync::Lazy;
+use tokio::sync::RwLock;
+use tokio::time::sleep;
+use tracing::{error, info};
+
+/// Interval between checks of the devices while no jobs are started.
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Reason why the devices are unavailable, as of the last check.
+static UNAVAILABLE: Lazy<RwLock<Option<String>>> = Lazy::new(Default::default);
+
+/// Returns the device nodes required by the backend selected via `ENARX_BACKEND`.
+fn backend_devices() -> &'static [&'static str] {
+    match std::env::var("ENARX_BACKEND").as_deref() {
+        Ok("sgx") => &["/dev/sgx_enclave"],
+        Ok("sev") => &["/dev/sev"],
+        Ok("kvm") => &["/dev/kvm"],
+        _ => &[],
+    }
+}
+
+/// Returns the reason why `path` cannot be used, if any.
+async fn probe(path: &Path) -> Option<String> {
+    match tokio::fs::metadata(path).await {
+        Ok(meta) if meta.file_type().is_char_device() => None,
+        Ok(_) => Some(format!("{} is not a device", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            Some(format!("this host lost its {} device", path.display()))
+        }
+        Err(e) => Some(format!("{} is not accessible: {e}", path.display())),
+    }
+}
+
+/// Checks the `devices` exposed to jobs along with the ones required by the backend,
+/// recording the outcome for the health check.
+async fn probe_all(devices: &[PathBuf]) -> Option<String> {
+    let backend = backend_devices().iter().map(Path::new);
+    for path in devices.iter().map(PathBuf::as_path).chain(backend) {
+        if let Some(reason) = probe(path).await {
+            let mut unavailable = UNAVAILABLE.write().await;
+            if unavailable.as_ref() != Some(&reason) {
+                error!(reason, "devices are unavailable");
+            }
+            *unavailable = Some(reason.clone());
+            return Some(reason);
+        }
+    }
+
+    if UNAVAILABLE.write().await.take().is_some() {
+        info!("devices are available again");
+    }
+    None
+}
+
+/// Rejects starting a job if the devices it requires are unavailable.
+pub(crate) async fn check(devices: &[PathBuf]) -> Result<(), Response> {
+    match probe_all(devices).await {
+        None => Ok(()),
+        Some(reason) => Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!(
+                "Workloads cannot be run right now, because {reason}. \
+                Please contact the operators of this instance."
+            ),
+        )
+            .into_response()),
+    }
+}
+
+/// Returns the reason why the devices are unavailable, as of the last check.
+pub(crate) async fn unavailable() -> Option<String> {
+    UNAVAILABLE.read().await.clone()
+}
+
+/// Periodically checks the devices, so that the health check reflects their state
+/// even while no jobs are started.
+pub(crate) async fn monitor(devices: Vec<PathBuf>) {
+    loop {
+        _ = probe_all(&devices).await;
+        sleep(CHECK_INTERVAL).await;
+    }
+}
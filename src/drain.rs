@@ -0,0 +1,89 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Draining of this instance, during which `/readyz` reports it as not ready, so that load
+//! balancers stop sending new users to it while the requests in progress complete.
+//!
+//! Administrators can drain the instance at any time. It is also drained for a configurable
+//! window after SIGTERM, before the listener is closed.
+
+use crate::auth::Admin;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::sleep;
+use tracing::{error, info};
+
+/// Whether an administrator has drained this instance.
+static DRAINING: AtomicBool = AtomicBool::new(false);
+
+/// Whether this instance is shutting down.
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Reports whether this instance is ready to accept new users.
+pub(crate) async fn readyz() -> impl IntoResponse {
+    let draining = DRAINING.load(Ordering::Relaxed);
+    let shutting_down = SHUTTING_DOWN.load(Ordering::Relaxed);
+    let ready = !draining && !shutting_down;
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        status,
+        Json(json!({
+            "ready": ready,
+            "draining": draining,
+            "shutting_down": shutting_down,
+        })),
+    )
+}
+
+/// Resolves once SIGTERM was received and `delay` has passed since, during which this
+/// instance reports as not ready.
+pub(crate) async fn shutdown(delay: Duration) {
+    let mut terminations = match signal(SignalKind::terminate()) {
+        Ok(terminations) => terminations,
+        Err(e) => {
+            error!(error = ?e, "failed to listen for SIGTERM, shutting down without draining");
+            return std::future::pending().await;
+        }
+    };
+    _ = terminations.recv().await;
+    SHUTTING_DOWN.store(true, Ordering::Relaxed);
+    info!(
+        delay = delay.as_secs(),
+        "received SIGTERM, draining before shutting down"
+    );
+    sleep(delay).await;
+    info!("shutting down");
+}
+
+pub(crate) async fn get(_: Admin) -> impl IntoResponse {
+    Json(json!({
+        "draining": DRAINING.load(Ordering::Relaxed),
+        "shutting_down": SHUTTING_DOWN.load(Ordering::Relaxed),
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct Toggle {
+    draining: bool,
+}
+
+pub(crate) async fn set(
+    Admin(admin): Admin,
+    Json(Toggle { draining }): Json<Toggle>,
+) -> StatusCode {
+    info!(%admin, draining, "toggling drain mode");
+    DRAINING.store(draining, Ordering::Relaxed);
+    StatusCode::NO_CONTENT
+}
@@ -0,0 +1,214 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Validation of the outbound connections workloads declare in their Enarx.toml.
+
+use crate::net::{canonical, Net};
+
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use anyhow::bail;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use enarx_config::{Config, File};
+use tokio::net::lookup_host;
+use tracing::{debug, warn};
+
+/// A host or network outbound connections are matched against.
+#[derive(Clone, Debug)]
+pub(crate) enum Rule {
+    /// A network in CIDR notation, or a single address.
+    Net(Net),
+    /// A domain name, matching the domain itself and all of its subdomains.
+    Domain(String),
+}
+
+impl FromStr for Rule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse() {
+            Ok(net) => Ok(Self::Net(net)),
+            Err(e) if s.contains('/') => Err(e),
+            Err(_) if s.is_empty() => bail!("invalid network `{s}`"),
+            Err(_) => Ok(Self::Domain(
+                s.trim_start_matches("*.")
+                    .trim_matches('.')
+                    .to_ascii_lowercase(),
+            )),
+        }
+    }
+}
+
+impl Rule {
+    fn matches_addr(&self, ip: IpAddr) -> bool {
+        match self {
+            Self::Net(net) => net.contains(ip),
+            Self::Domain(..) => false,
+        }
+    }
+
+    fn matches_host(&self, host: &str) -> bool {
+        match self {
+            Self::Domain(domain) => {
+                host == domain
+                    || host
+                        .strip_suffix(domain.as_str())
+                        .map_or(false, |sub| sub.ends_with('.'))
+            }
+            Self::Net(..) => false,
+        }
+    }
+}
+
+/// Returns whether `ip` belongs to a loopback, private, link-local or otherwise
+/// non-public network.
+fn is_internal(ip: IpAddr) -> bool {
+    match canonical(ip) {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || a == 0
+                // Shared address space, RFC 6598.
+                || (a == 100 && (64..128).contains(&b))
+        }
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            ip.is_loopback()
+                || ip.is_unspecified()
+                // Unique local addresses.
+                || first & 0xfe00 == 0xfc00
+                // Link-local unicast.
+                || first & 0xffc0 == 0xfe80
+        }
+    }
+}
+
+/// Resolves `host`, rejecting it unless all of its addresses are public, and returns the
+/// address to connect to on `port`. Connecting to that address rather than resolving the host
+/// again keeps it from being rebound to an internal address after it was checked.
+pub(crate) async fn resolve_public(host: &str, port: u16) -> Result<SocketAddr, Response> {
+    let reject = |reason: &str| {
+        warn!(host, port, reason, "rejecting fetch");
+        Err((
+            StatusCode::BAD_REQUEST,
+            format!("The server may not fetch files from {host}: {reason}"),
+        )
+            .into_response())
+    };
+
+    let addrs: Vec<SocketAddr> = match lookup_host((host, port)).await {
+        Ok(addrs) => addrs.collect(),
+        Err(e) => {
+            debug!(host, error = ?e, "failed to resolve fetch host");
+            return reject("the host could not be resolved");
+        }
+    };
+    match addrs.first() {
+        None => reject("the host could not be resolved"),
+        Some(_) if addrs.iter().any(|addr| is_internal(addr.ip())) => {
+            reject("the host is on an internal network")
+        }
+        Some(addr) => Ok(*addr),
+    }
+}
+
+/// Rules outbound connections of workloads are checked against.
+/// Connections to internal networks are always rejected, unless they are explicitly allowed.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Policy {
+    /// If not empty, only connections to these hosts and networks are allowed.
+    pub(crate) allow: Vec<Rule>,
+    /// Connections to these hosts and networks are rejected.
+    pub(crate) deny: Vec<Rule>,
+}
+
+impl Policy {
+    /// Rejects `conf` if it connects to a host which is not allowed.
+    pub(crate) async fn check(&self, conf: &Config) -> Result<(), Response> {
+        for file in &conf.files {
+            if let File::Connect { host, port, .. } = file {
+                self.check_host(host, *port).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Rejects connections to `host` on `port` if the host is not allowed.
+    pub(crate) async fn check_host(&self, host: &str, port: u16) -> Result<(), Response> {
+        let reject = |reason: &str| {
+            warn!(host, port, reason, "rejecting outbound connection");
+            Err((
+                StatusCode::BAD_REQUEST,
+                format!("Your workload may not connect to {host}:{port}: {reason}"),
+            )
+                .into_response())
+        };
+
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        let addrs: Vec<IpAddr> = match host.parse() {
+            Ok(addr) => vec![addr],
+            Err(_) => match lookup_host((host.as_str(), port)).await {
+                Ok(addrs) => addrs.map(|addr| addr.ip()).collect(),
+                Err(e) => {
+                    debug!(host, error = ?e, "failed to resolve connect host");
+                    return reject("the host could not be resolved");
+                }
+            },
+        };
+        if addrs.is_empty() {
+            return reject("the host could not be resolved");
+        }
+
+        if self.deny.iter().any(|rule| {
+            rule.matches_host(&host) || addrs.iter().any(|addr| rule.matches_addr(*addr))
+        }) {
+            return reject("the host is not allowed");
+        }
+
+        let allowed = self.allow.iter().any(|rule| rule.matches_host(&host))
+            || addrs
+                .iter()
+                .all(|addr| self.allow.iter().any(|rule| rule.matches_addr(*addr)));
+        if allowed {
+            Ok(())
+        } else if addrs.iter().copied().any(is_internal) {
+            reject("the host is on an internal network")
+        } else if !self.allow.is_empty() {
+            reject("the host is not allowed")
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn internal_hosts_are_not_fetched_from() {
+        for host in [
+            "127.0.0.1",
+            "10.0.0.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "::1",
+            "fe80::1",
+        ] {
+            assert!(resolve_public(host, 80).await.is_err(), "{host}");
+        }
+        assert!(resolve_public("::ffff:192.168.0.1", 80).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn public_hosts_are_pinned() {
+        let addr = resolve_public("192.0.2.1", 443).await.unwrap();
+        assert_eq!(addr, "192.0.2.1:443".parse().unwrap());
+    }
+}
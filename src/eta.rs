@@ -0,0 +1,112 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Estimates of when a slot for a new job frees up on a full instance.
+
+use crate::cache::Cache;
+use crate::clock;
+use crate::job::Job;
+
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::time::Duration;
+
+use axum::http::HeaderMap;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::Response;
+use futures_util::stream::{self, Stream};
+use once_cell::sync::Lazy;
+use serde_json::json;
+use tokio::sync::{Mutex, RwLock};
+use tokio::time::sleep;
+
+/// Number of recently ended jobs the average duration is computed from.
+const HISTORY_LEN: usize = 100;
+
+/// Interval at which the capacity is sent to clients waiting for a slot.
+const EVENT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Time for which the capacity is cached, as status pages poll it.
+const CACHE_TTL: Duration = Duration::from_secs(2);
+
+/// Cached capacity.
+static CACHE: Lazy<Cache> = Lazy::new(|| Cache::new(CACHE_TTL));
+
+/// Durations of recently ended jobs, oldest first.
+static DURATIONS: Lazy<Mutex<VecDeque<Duration>>> = Lazy::new(Default::default);
+
+/// Records the `duration` of a job which has ended.
+pub(crate) async fn record(duration: Duration) {
+    let mut durations = DURATIONS.lock().await;
+    durations.push_back(duration);
+    if durations.len() > HISTORY_LEN {
+        _ = durations.pop_front();
+    }
+}
+
+async fn average() -> Option<Duration> {
+    let durations = DURATIONS.lock().await;
+    let count = u32::try_from(durations.len()).ok().filter(|n| *n > 0)?;
+    Some(durations.iter().sum::<Duration>() / count)
+}
+
+/// Returns the number of running `jobs` and how long until one of the `jobs_max` slots
+/// is expected to be free, based on the remaining time to live of the running jobs
+/// and how long jobs usually run for.
+pub(crate) async fn next_slot(
+    jobs: impl Iterator<Item = &RwLock<Job>>,
+    jobs_max: usize,
+) -> (usize, Duration) {
+    let average = average().await;
+    let now = clock::now();
+    let mut ends = vec![];
+    for job in jobs {
+        let mut job = job.write().await;
+        if !matches!(job.exec.try_wait(), Ok(None)) {
+            continue;
+        }
+        let remaining = job.deadline().saturating_duration_since(now);
+        let expected = average.map_or(remaining, |average| {
+            average.saturating_sub(job.runtime()).min(remaining)
+        });
+        ends.push(expected);
+    }
+
+    let running = ends.len();
+    if running < jobs_max {
+        return (running, Duration::ZERO);
+    }
+    ends.sort_unstable();
+    // The job expected to end `running - jobs_max` places after the first frees the next slot.
+    (running, ends[running - jobs_max])
+}
+
+async fn capacity(jobs_max: usize) -> serde_json::Value {
+    let (running, eta) = next_slot(crate::JOBS.read().await.values(), jobs_max).await;
+    json!({
+        "running": running,
+        "max": jobs_max,
+        "eta": eta.as_secs(),
+        "queued": crate::queue::len(),
+    })
+}
+
+/// Returns the number of running jobs and the estimated time in seconds until a slot is free,
+/// cached briefly, since status pages poll it.
+pub(crate) async fn status(jobs_max: usize, headers: HeaderMap) -> Response {
+    CACHE.serve(&headers, || capacity(jobs_max)).await
+}
+
+/// Streams the capacity of this instance to clients waiting for a slot.
+pub(crate) async fn events(jobs_max: usize) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let events = stream::unfold(true, move |first| async move {
+        if !first {
+            sleep(EVENT_INTERVAL).await;
+        }
+        let event = Event::default()
+            .json_data(capacity(jobs_max).await)
+            .unwrap_or_default();
+        Some((Ok(event), false))
+    });
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
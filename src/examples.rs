@@ -1,7 +1,10 @@
 use std::fmt;
+use std::fs;
 use std::ops::Deref;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
+use anyhow::Context as _;
 use serde::Deserialize;
 
 #[derive(Debug, Deserialize, Clone)]
@@ -113,3 +116,56 @@ Copyright (c) .NET Foundation and Contributors"#.to_string()
         Examples { examples }
     }
 }
+
+/// A prebuilt example workload stored on the local filesystem.
+#[derive(Debug, Clone)]
+pub(crate) struct LocalExample {
+    pub(crate) name: String,
+    pub(crate) description: String,
+    pub(crate) wasm: PathBuf,
+    pub(crate) conf: PathBuf,
+}
+
+impl LocalExample {
+    /// Loads all examples in `dir`. Each example is a subdirectory containing
+    /// `main.wasm`, `Enarx.toml` and, optionally, a `description.txt`.
+    pub(crate) fn load_dir(dir: impl AsRef<Path>) -> anyhow::Result<Vec<Self>> {
+        let dir = dir.as_ref();
+        let mut examples = vec![];
+        for entry in fs::read_dir(dir)
+            .with_context(|| format!("failed to read examples directory `{}`", dir.display()))?
+        {
+            let path = entry
+                .with_context(|| format!("failed to read entry of `{}`", dir.display()))?
+                .path();
+            if !path.is_dir() {
+                continue;
+            }
+
+            let name = match path.file_name().and_then(|name| name.to_str()) {
+                Some(name) => name.to_string(),
+                None => continue,
+            };
+            let wasm = path.join("main.wasm");
+            let conf = path.join("Enarx.toml");
+            if !wasm.is_file() || !conf.is_file() {
+                anyhow::bail!(
+                    "example `{}` must contain `main.wasm` and `Enarx.toml`",
+                    path.display()
+                );
+            }
+            let description = fs::read_to_string(path.join("description.txt"))
+                .map(|desc| desc.trim().to_string())
+                .unwrap_or_default();
+
+            examples.push(LocalExample {
+                name,
+                description,
+                wasm,
+                conf,
+            });
+        }
+        examples.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(examples)
+    }
+}
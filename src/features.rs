@@ -0,0 +1,115 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Subsystems which can be switched off at runtime, e.g. in response to an incident.
+
+use crate::auth::Admin;
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use axum::body::Body;
+use axum::extract::Path as AxumPath;
+use axum::http::{Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use clap::ValueEnum;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+use tracing::info;
+
+/// Currently disabled features.
+static DISABLED: Lazy<RwLock<BTreeSet<Feature>>> = Lazy::new(Default::default);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Feature {
+    /// Running uploaded workloads.
+    Uploads,
+    /// Running examples from the gallery.
+    Gallery,
+    /// The `/api/v1` endpoints used with personal access tokens.
+    Api,
+    /// Routing of job subdomains to workload ports.
+    Proxy,
+}
+
+impl Feature {
+    const ALL: [Self; 4] = [Self::Uploads, Self::Gallery, Self::Api, Self::Proxy];
+}
+
+/// Disables `features` on startup.
+pub(crate) fn init(features: impl IntoIterator<Item = Feature>) -> anyhow::Result<()> {
+    let mut disabled = DISABLED
+        .try_write()
+        .map_err(|_| anyhow::anyhow!("features are in use"))?;
+    disabled.extend(features);
+    Ok(())
+}
+
+pub(crate) async fn is_enabled(feature: Feature) -> bool {
+    !DISABLED.read().await.contains(&feature)
+}
+
+/// Rejects the request if `feature` is disabled.
+pub(crate) async fn require(feature: Feature) -> Result<(), Response> {
+    if is_enabled(feature).await {
+        Ok(())
+    } else {
+        Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            "This feature is temporarily disabled by the operators of this instance",
+        )
+            .into_response())
+    }
+}
+
+/// Rejects requests to the `/api` endpoints while the API is disabled.
+pub(crate) async fn gate_api(req: Request<Body>, next: Next<Body>) -> Response {
+    if req.uri().path().starts_with("/api/") {
+        if let Err(resp) = require(Feature::Api).await {
+            return resp;
+        }
+    }
+    next.run(req).await
+}
+
+/// Returns the disabled features for a backup of the state of this instance.
+pub(crate) async fn export() -> BTreeSet<Feature> {
+    DISABLED.read().await.clone()
+}
+
+/// Replaces the disabled features with the ones restored from a backup.
+pub(crate) async fn import(restored: BTreeSet<Feature>) {
+    *DISABLED.write().await = restored;
+}
+
+pub(crate) async fn list(_: Admin) -> impl IntoResponse {
+    let disabled = DISABLED.read().await;
+    let features: BTreeMap<_, _> = Feature::ALL
+        .into_iter()
+        .map(|feature| (feature, !disabled.contains(&feature)))
+        .collect();
+    Json(features)
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct Toggle {
+    enabled: bool,
+}
+
+pub(crate) async fn set(
+    Admin(admin): Admin,
+    AxumPath(feature): AxumPath<Feature>,
+    Json(Toggle { enabled }): Json<Toggle>,
+) -> StatusCode {
+    let mut disabled = DISABLED.write().await;
+    info!(%admin, ?feature, enabled, "toggling feature");
+    if enabled {
+        _ = disabled.remove(&feature);
+    } else {
+        _ = disabled.insert(feature);
+    }
+    StatusCode::NO_CONTENT
+}
@@ -0,0 +1,84 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Fuzzy fingerprints of wasm modules.
+//!
+//! A fingerprint is the set of hashes of the function bodies in the code section,
+//! so it is unaffected by padding, custom sections or changes to data segments.
+
+use std::collections::BTreeSet;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// ID of the wasm code section.
+const CODE_SECTION: u8 = 10;
+
+/// Modules with fewer functions are too small to be compared meaningfully.
+const MIN_FUNCTIONS: usize = 8;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Fingerprint(BTreeSet<u64>);
+
+/// Reads an unsigned LEB128 encoded integer at `pos`, advancing it.
+pub(crate) fn leb128(data: &[u8], pos: &mut usize) -> Option<usize> {
+    let mut result = 0usize;
+    for shift in (0..35).step_by(7) {
+        let byte = *data.get(*pos)?;
+        *pos += 1;
+        result |= usize::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(result);
+        }
+    }
+    None
+}
+
+fn hash(body: &[u8]) -> u64 {
+    let digest = Sha256::digest(body);
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    u64::from_le_bytes(bytes)
+}
+
+impl Fingerprint {
+    /// Computes the fingerprint of a wasm module, returning `None` if the module
+    /// is malformed or too small to be fingerprinted.
+    pub(crate) fn new(wasm: &[u8]) -> Option<Self> {
+        if wasm.get(..4)? != b"\0asm" {
+            return None;
+        }
+
+        let mut hashes = BTreeSet::new();
+        let mut pos = 8;
+        while pos < wasm.len() {
+            let id = wasm[pos];
+            pos += 1;
+            let size = leb128(wasm, &mut pos)?;
+            let section = wasm.get(pos..pos.checked_add(size)?)?;
+            pos += size;
+
+            if id == CODE_SECTION {
+                let mut p = 0;
+                for _ in 0..leb128(section, &mut p)? {
+                    let size = leb128(section, &mut p)?;
+                    _ = hashes.insert(hash(section.get(p..p.checked_add(size)?)?));
+                    p += size;
+                }
+            }
+        }
+
+        (hashes.len() >= MIN_FUNCTIONS).then_some(Self(hashes))
+    }
+
+    /// Jaccard similarity of two fingerprints, between 0 and 1.
+    pub(crate) fn similarity(&self, other: &Self) -> f64 {
+        let shared = self.0.intersection(&other.0).count();
+        let total = self.0.union(&other.0).count();
+        if total == 0 {
+            0.0
+        } else {
+            shared as f64 / total as f64
+        }
+    }
+}
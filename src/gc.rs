@@ -0,0 +1,141 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Retention policy of the data kept by benefice, applied periodically by a single task.
+
+use crate::auth::github;
+use crate::{daily, denylist, history, logs, ratelimit, security, storage};
+
+use std::str::FromStr;
+use std::time::Duration;
+
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+use tokio::time::sleep;
+
+/// Interval between garbage collection runs.
+const GC_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Configured retention policy.
+static POLICY: OnceCell<Policy> = OnceCell::new();
+
+/// Retention rule of a category of data. Items are removed oldest first
+/// as soon as any of the limits is exceeded.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct Rule {
+    /// Maximum age of an item in seconds.
+    max_age: Option<u64>,
+    /// Maximum number of items.
+    max_count: Option<usize>,
+    /// Maximum total size of the items in bytes.
+    max_size: Option<u64>,
+}
+
+impl Rule {
+    /// Returns the number of `items`, ordered newest first, which are retained.
+    pub(crate) fn retain<T>(
+        &self,
+        items: impl IntoIterator<Item = T>,
+        age: impl Fn(&T) -> Duration,
+        size: impl Fn(&T) -> u64,
+    ) -> usize {
+        let mut total = 0;
+        items
+            .into_iter()
+            .take(self.max_count.unwrap_or(usize::MAX))
+            .take_while(|item| {
+                total += size(item);
+                !matches!(self.max_age, Some(max) if age(item).as_secs() > max)
+                    && !matches!(self.max_size, Some(max) if total > max)
+            })
+            .count()
+    }
+}
+
+/// Retention policy, consisting of a rule per category of data.
+///
+/// For example:
+/// ```toml
+/// [workloads]
+/// max-age = 86400
+/// max-size = 1073741824
+///
+/// [history]
+/// max-count = 10
+/// ```
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct Policy {
+    /// Retained files of uploaded workloads.
+    pub(crate) workloads: Rule,
+    /// Recent runs of each user.
+    pub(crate) history: Rule,
+    /// Submissions flagged for review.
+    pub(crate) flagged: Rule,
+    /// Logs of the output of jobs.
+    pub(crate) logs: Rule,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            workloads: Rule {
+                max_age: Some(24 * 60 * 60),
+                ..Default::default()
+            },
+            history: Rule {
+                max_count: Some(10),
+                ..Default::default()
+            },
+            flagged: Rule {
+                max_count: Some(1024),
+                ..Default::default()
+            },
+            logs: Rule {
+                max_age: Some(24 * 60 * 60),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+impl FromStr for Policy {
+    type Err = toml::de::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        toml::from_str(s)
+    }
+}
+
+/// Sets the retention policy of this instance.
+pub(crate) fn init(policy: Policy) -> anyhow::Result<()> {
+    POLICY
+        .set(policy)
+        .map_err(|_| anyhow::anyhow!("retention policy already initialized"))
+}
+
+/// Returns the retention policy of this instance.
+pub(crate) fn policy() -> &'static Policy {
+    POLICY.get_or_init(Default::default)
+}
+
+/// Periodically applies the retention policy to all categories of data.
+pub(crate) async fn run() {
+    let policy = policy();
+    loop {
+        sleep(GC_INTERVAL).await;
+
+        if let Some(storage) = storage::get() {
+            storage.gc("workloads/", &policy.workloads).await;
+            storage.gc("logs/", &policy.logs).await;
+        }
+        history::gc(&policy.history).await;
+        denylist::gc(&policy.flagged).await;
+        logs::gc(&policy.logs).await;
+        security::gc().await;
+        ratelimit::gc();
+        daily::gc().await;
+        github::gc().await;
+    }
+}
@@ -0,0 +1,183 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use crate::auth::User;
+use crate::gc::{self, Rule};
+use crate::job::{Metadata, Outcome};
+use crate::lifecycle::Annotation;
+use crate::wasi::Report;
+
+use std::collections::{HashMap, VecDeque};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::response::IntoResponse;
+use axum::Json;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::RwLock;
+
+/// Recent runs of each user, newest first.
+static HISTORY: Lazy<RwLock<HashMap<User, VecDeque<Run>>>> = Lazy::new(Default::default);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Run {
+    pub(crate) id: String,
+    /// Drawbridge slug of the workload, `None` for uploaded workloads.
+    pub(crate) slug: Option<String>,
+    #[serde(default)]
+    pub(crate) meta: Metadata,
+    pub(crate) started: SystemTime,
+    /// Whether the files of the uploaded workload are retained for download.
+    pub(crate) retained: bool,
+    /// How the job ended, `None` while it is running.
+    pub(crate) outcome: Option<Outcome>,
+    /// WASI capabilities exercised by the workload, if reported.
+    pub(crate) wasi: Option<Report>,
+    /// Stages of the Keep of the job, once it has ended.
+    #[serde(default)]
+    pub(crate) annotations: Vec<Annotation>,
+}
+
+impl Run {
+    /// Human readable time elapsed since the run was started.
+    pub(crate) fn age(&self) -> String {
+        let secs = self.started.elapsed().unwrap_or_default().as_secs();
+        match secs {
+            0..=59 => format!("{secs} seconds ago"),
+            60..=3599 => format!("{} minutes ago", secs / 60),
+            3600..=86399 => format!("{} hours ago", secs / 3600),
+            _ => format!("{} days ago", secs / 86400),
+        }
+    }
+}
+
+fn apply(rule: &Rule, runs: &mut VecDeque<Run>) {
+    let retained = rule.retain(
+        runs.iter(),
+        |run| run.started.elapsed().unwrap_or_default(),
+        |_| 0,
+    );
+    runs.truncate(retained);
+}
+
+/// Records a new run of `user`, forgetting the oldest ones as required by the retention policy.
+pub(crate) async fn record(user: User, run: Run) {
+    let mut history = HISTORY.write().await;
+    let runs = history.entry(user).or_default();
+    runs.push_front(run);
+    apply(&gc::policy().history, runs);
+}
+
+/// Records how the run with `id` of `user` ended, and the WASI capabilities it exercised.
+pub(crate) async fn finish(
+    user: User,
+    id: &str,
+    outcome: Outcome,
+    wasi: Option<Report>,
+    annotations: Vec<Annotation>,
+) {
+    if let Some(run) = HISTORY
+        .write()
+        .await
+        .get_mut(&user)
+        .and_then(|runs| runs.iter_mut().find(|run| run.id == id))
+    {
+        run.outcome = Some(outcome);
+        run.wasi = wasi;
+        run.annotations = annotations;
+    }
+}
+
+/// Returns the run with `id` of `user`.
+pub(crate) async fn get(user: &User, id: &str) -> Option<Run> {
+    HISTORY
+        .read()
+        .await
+        .get(user)?
+        .iter()
+        .find(|run| run.id == id)
+        .cloned()
+}
+
+/// Forgets the runs of all users which are no longer retained by `rule`.
+pub(crate) async fn gc(rule: &Rule) {
+    let mut history = HISTORY.write().await;
+    for runs in history.values_mut() {
+        apply(rule, runs);
+    }
+    history.retain(|_, runs| !runs.is_empty());
+}
+
+/// Forgets all runs of the user with `uid`, returning how many were forgotten.
+pub(crate) async fn purge(uid: u64) -> usize {
+    let mut history = HISTORY.write().await;
+    let mut purged = 0;
+    history.retain(|user, runs| {
+        if user.uid() == uid {
+            purged += runs.len();
+        }
+        user.uid() != uid
+    });
+    purged
+}
+
+/// Returns the runs of all users for a backup of the state of this instance.
+pub(crate) async fn export() -> Vec<(User, Vec<Run>)> {
+    HISTORY
+        .read()
+        .await
+        .iter()
+        .map(|(user, runs)| (*user, runs.iter().cloned().collect()))
+        .collect()
+}
+
+/// Replaces the runs of all users with the ones restored from a backup, forgetting the ones
+/// which are no longer retained, and returns how many runs were restored.
+pub(crate) async fn import(restored: Vec<(User, Vec<Run>)>) -> usize {
+    let rule = &gc::policy().history;
+    let mut history = HISTORY.write().await;
+    history.clear();
+    for (user, runs) in restored {
+        let mut runs = VecDeque::from(runs);
+        apply(rule, &mut runs);
+        if !runs.is_empty() {
+            _ = history.insert(user, runs);
+        }
+    }
+    history.values().map(VecDeque::len).sum()
+}
+
+/// Returns the recent runs of `user`, newest first.
+pub(crate) async fn recent(user: &User) -> Vec<Run> {
+    HISTORY
+        .read()
+        .await
+        .get(user)
+        .map(|runs| runs.iter().cloned().collect())
+        .unwrap_or_default()
+}
+
+/// Returns the recent runs of the user, newest first.
+pub(crate) async fn api(user: User) -> impl IntoResponse {
+    let runs: Vec<_> = recent(&user)
+        .await
+        .into_iter()
+        .map(|run| {
+            json!({
+                "id": run.id,
+                "name": run.meta.name,
+                "description": run.meta.description,
+                "slug": run.slug,
+                "started": run
+                    .started
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+                "retained": run.retained,
+                "outcome": run.outcome,
+            })
+        })
+        .collect();
+    Json(runs)
+}
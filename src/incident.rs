@@ -0,0 +1,87 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Bulk administrative actions, so that administrators can respond to incidents quickly,
+//! e.g. abuse of a public instance during an event.
+//!
+//! Each action is audited as an administrative action, along with the jobs it kills and the
+//! tokens it revokes.
+
+use crate::auth::{token, Admin, User};
+use crate::denylist::{self, Ban};
+use crate::job::{Job, Reason};
+use crate::{audit, history, JOBS};
+
+use axum::extract::Path as AxumPath;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use serde_json::json;
+use tracing::info;
+
+/// Kills the jobs for which `matches` returns true on behalf of `admin`,
+/// returning their IDs.
+async fn kill(admin: User, matches: impl Fn(&User, &Job) -> bool) -> Vec<String> {
+    let mut jobs = JOBS.write().await;
+    let mut owners = vec![];
+    for (user, job) in jobs.iter() {
+        if matches(user, &*job.read().await) {
+            owners.push(*user);
+        }
+    }
+
+    let mut killed = vec![];
+    for user in owners {
+        if let Some(job) = jobs.remove(&user) {
+            let job = job.into_inner();
+            info!(%admin, %user, job_id = job.id, "killing job");
+            let event = audit::Event::new(user.uid(), audit::Action::Kill).job(&job.id);
+            killed.push(job.id.clone());
+            job.kill(Reason::Killed).await;
+            event.record().await;
+        }
+    }
+    killed
+}
+
+/// Kills all jobs of the user with `uid`.
+pub(crate) async fn kill_jobs(
+    Admin(admin): Admin,
+    AxumPath(uid): AxumPath<u64>,
+) -> impl IntoResponse {
+    let killed = kill(admin, |user, _| user.uid() == uid).await;
+    Json(json!({ "killed": killed }))
+}
+
+/// Forgets the run history of the user with `uid`.
+pub(crate) async fn purge_history(
+    Admin(admin): Admin,
+    AxumPath(uid): AxumPath<u64>,
+) -> impl IntoResponse {
+    let purged = history::purge(uid).await;
+    info!(%admin, uid, purged, "purged run history");
+    Json(json!({ "purged": purged }))
+}
+
+/// Revokes all API tokens of the user with `uid`.
+pub(crate) async fn revoke_tokens(
+    Admin(admin): Admin,
+    AxumPath(uid): AxumPath<u64>,
+) -> impl IntoResponse {
+    let revoked = token::revoke_all(uid).await;
+    info!(%admin, uid, revoked = revoked.len(), "revoked API tokens");
+    Json(json!({ "revoked": revoked }))
+}
+
+/// Bans a workload by its SHA-256 digest and kills all jobs running it.
+pub(crate) async fn ban_and_kill(
+    Admin(admin): Admin,
+    Json(Ban { sha256 }): Json<Ban>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let digest = denylist::ban(admin, &sha256).await?;
+    let killed = kill(admin, |_, job| job.sha256.as_ref() == Some(&digest)).await;
+    Ok(Json(json!({
+        "sha256": digest,
+        "killed": killed,
+    })))
+}
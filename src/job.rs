@@ -2,84 +2,542 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 
 use super::Workload;
+use crate::auth::User;
+use crate::clock::{self, Instant};
+use crate::config;
+use crate::lifecycle::Annotation;
+use crate::output::{Cap, Streams};
+use crate::wasi::{self, Report};
 
 use std::collections::{HashMap, HashSet};
 use std::env;
 use std::ffi::{OsStr, OsString};
+use std::fmt;
 use std::future::Future;
+use std::io;
 use std::ops::Range;
 use std::path::Path;
 use std::process::Stdio;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::{anyhow, Context};
 use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response};
 use futures_util::future::{AbortHandle, Abortable};
 use rand::RngCore;
-use tokio::process::{Child, Command};
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::process::{Child, ChildStdout, Command};
+use tokio::time::{sleep, timeout};
 use tracing::{debug, error, info, warn};
 
+/// Exit code of a process killed by `SIGXCPU`.
+const SIGXCPU_EXIT_CODE: i32 = 128 + 24;
+
+/// Longest time the timeout clock of a paused job is stopped for. Once it is exceeded,
+/// the clock runs again, so that paused jobs cannot hold on to their resources forever.
+const MAX_PAUSE: Duration = Duration::from_secs(30 * 60);
+
+/// Total size in bytes of the files of all jobs.
+static DISK_USAGE: AtomicU64 = AtomicU64::new(0);
+
+/// Limits of the output of jobs, protecting browsers and disks from workloads flooding them.
+#[derive(Copy, Clone, Debug, Default)]
+pub(crate) struct OutputLimits {
+    /// Total number of bytes a job may output, after which it is killed.
+    pub(crate) bytes: Option<u64>,
+    /// Number of lines a job may output per second, after which it is paused.
+    pub(crate) lines_per_sec: Option<u64>,
+}
+
+/// Output limit exceeded by a job.
+#[derive(Copy, Clone, Debug)]
+pub(crate) enum OutputExceeded {
+    Bytes(u64),
+    Rate(u64),
+}
+
+/// Disk space budgets in bytes for the files of jobs.
+#[derive(Copy, Clone, Debug, Default)]
+pub(crate) struct DiskBudget {
+    /// Budget of a single job.
+    pub(crate) job: Option<u64>,
+    /// Budget of all jobs combined.
+    pub(crate) total: Option<u64>,
+}
+
+impl DiskBudget {
+    /// Returns whether the combined budget is used up, so that no new jobs can be accepted.
+    pub(crate) fn is_exhausted(&self) -> bool {
+        matches!(self.total, Some(total) if DISK_USAGE.load(Ordering::Relaxed) >= total)
+    }
+
+    /// Starts accounting for the files of a new workload, before any of them is written.
+    pub(crate) fn reservation(&self) -> Reservation {
+        Reservation {
+            budget: *self,
+            size: 0,
+        }
+    }
+}
+
+/// Disk space accounted for the files of a workload, which is released when it is dropped
+/// along with the files.
+#[derive(Debug)]
+pub(crate) struct Reservation {
+    budget: DiskBudget,
+    size: u64,
+}
+
+impl Reservation {
+    /// Accounts for `size` more bytes of the files, if that fits within the budgets.
+    /// This is called before the bytes are written, so that the budgets are never exceeded.
+    pub(crate) fn grow(&mut self, size: u64) -> Result<(), Response> {
+        let new_size = self.size.saturating_add(size);
+        if matches!(self.budget.job, Some(job) if new_size > job) {
+            return Err((
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "The files of this workload exceed the per-workload disk budget",
+            )
+                .into_response());
+        }
+        _ = DISK_USAGE
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                let new = used.checked_add(size)?;
+                match self.budget.total {
+                    Some(total) if new > total => None,
+                    _ => Some(new),
+                }
+            })
+            .map_err(|used| {
+                warn!(used, size, "disk budget exhausted");
+                (
+                    StatusCode::INSUFFICIENT_STORAGE,
+                    "Not enough disk space is available right now, try again later",
+                )
+                    .into_response()
+            })?;
+        self.size = new_size;
+        Ok(())
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        _ = DISK_USAGE.fetch_sub(self.size, Ordering::Relaxed);
+    }
+}
+
+/// Maximum time to wait for a job to accept input, before reporting that it accepted none.
+const STDIN_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Delay before retrying to spawn a job, which is doubled after every attempt.
+const SPAWN_BACKOFF: Duration = Duration::from_millis(100);
+
+/// How long a newly spawned job is watched for failing to start inside its container, as long
+/// as it may still be retried.
+const STARTUP_WINDOW: Duration = Duration::from_millis(500);
+
+/// Interval at which a newly spawned job is checked for having exited.
+const STARTUP_POLL: Duration = Duration::from_millis(50);
+
+const OUT_OF_RESOURCES: &str = "the host is temporarily out of resources, try again later";
+
+const DEVICE_BUSY: &str = "the host's trusted execution device is busy, try again later";
+
+/// Returns the reason for a failure to spawn a job to show to the user,
+/// along with whether the failure is transient and worth retrying.
+fn spawn_failure(e: &io::Error) -> (&'static str, bool) {
+    match e.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted => (OUT_OF_RESOURCES, true),
+        io::ErrorKind::OutOfMemory => ("the host is out of memory, try again later", true),
+        io::ErrorKind::ResourceBusy => (DEVICE_BUSY, true),
+        io::ErrorKind::NotFound => ("the container engine is not installed on this host", false),
+        io::ErrorKind::PermissionDenied => {
+            ("the container engine cannot be run on this host", false)
+        }
+        _ => ("an unexpected error occurred", false),
+    }
+}
+
+/// Returns the reason to show to the user for a job which exited with `code` right after it
+/// was spawned, if its standard error `stderr` shows that Enarx failed to start it for a
+/// transient reason, such as the SGX or SEV device of the host being busy, rather than the
+/// workload failing.
+fn container_failure(code: Option<i32>, stderr: &[u8]) -> Option<&'static str> {
+    if code == Some(0) {
+        return None;
+    }
+    let stderr = String::from_utf8_lossy(stderr);
+    let mentions = |messages: &[&str]| messages.iter().any(|m| stderr.contains(m));
+    if mentions(&["Device or resource busy", "EBUSY"]) {
+        Some(DEVICE_BUSY)
+    } else if mentions(&["Resource temporarily unavailable", "EAGAIN"]) {
+        Some(OUT_OF_RESOURCES)
+    } else {
+        None
+    }
+}
+
+/// Waits for the newly spawned job `exec` to exit unsuccessfully within the startup window,
+/// returning its exit code and what it wrote to its standard error if it did.
+async fn exited_early(exec: &mut Child) -> Option<(Option<i32>, Vec<u8>)> {
+    // `Child::wait` would close the standard input of the job, so it is polled instead.
+    let deadline = clock::now() + STARTUP_WINDOW;
+    let status = loop {
+        match exec.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) if clock::now() < deadline => sleep(STARTUP_POLL).await,
+            _ => return None,
+        }
+    };
+    if status.success() {
+        return None;
+    }
+    let mut stderr = Vec::new();
+    if let Some(pipe) = exec.stderr.as_mut() {
+        if let Err(e) = timeout(STARTUP_WINDOW, pipe.read_to_end(&mut stderr)).await {
+            warn!(error = ?e, "timed out reading the output of a job which failed to start");
+        }
+    }
+    Some((status.code(), stderr))
+}
+
+/// Removes the container of the job with `id`, which is not removed on exit.
+async fn remove_container(oci_command: &OsStr, id: &str) {
+    match Command::new(oci_command)
+        .args(["rm", "--force", id])
+        .output()
+        .await
+    {
+        Ok(out) if out.status.success() => {}
+        Ok(out) => error!(
+            job_id = id,
+            stderr = %String::from_utf8_lossy(&out.stderr),
+            "failed to remove job container"
+        ),
+        Err(e) => error!(error = ?e, job_id = id, "failed to remove job container"),
+    }
+}
+
+/// Grace period between the soft CPU time limit, which sends `SIGXCPU`,
+/// and the hard one, which sends `SIGKILL`.
+const CPU_LIMIT_GRACE: u64 = 5;
+
+/// Why a job ended.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Reason {
+    /// The workload exited on its own.
+    Finished,
+    /// The job was killed after running out of time.
+    Timeout,
+    /// The job was killed, e.g. by its owner or because it was replaced.
+    Killed,
+    /// The workload exceeded its memory limit.
+    OutOfMemory,
+    /// The workload exceeded its CPU time limit.
+    CpuLimit,
+    /// The workload exceeded its output limit.
+    OutputLimit,
+}
+
+/// Longest name of a job in characters.
+const NAME_MAX: usize = 64;
+
+/// Longest description of a job in characters.
+const DESCRIPTION_MAX: usize = 1024;
+
+/// Name and description given to a job by its owner, which are easier to tell apart than IDs,
+/// e.g. during a presentation.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct Metadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) description: Option<String>,
+}
+
+impl Metadata {
+    /// Creates the metadata of a job from the `name` and `description` given by its owner,
+    /// trimmed to their maximum length. Names are kept on a single line.
+    pub(crate) fn new(name: Option<String>, description: Option<String>) -> Self {
+        let name = name.map(|name| {
+            name.chars()
+                .filter(|c| !c.is_control())
+                .take(NAME_MAX)
+                .collect::<String>()
+        });
+        let description = description.map(|text| text.chars().take(DESCRIPTION_MAX).collect());
+        let trim = |text: Option<String>| {
+            text.map(|text| text.trim().to_string())
+                .filter(|text| !text.is_empty())
+        };
+        Self {
+            name: trim(name),
+            description: trim(description),
+        }
+    }
+}
+
+/// How a job ended.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Outcome {
+    pub(crate) reason: Reason,
+    /// Exit code of the workload, if it exited on its own.
+    pub(crate) code: Option<i32>,
+    /// Signal which terminated the workload, if any.
+    pub(crate) signal: Option<i32>,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.reason, self.code, self.signal) {
+            (Reason::Finished, _, Some(signal)) => write!(f, "terminated by signal {signal}"),
+            (Reason::Finished, Some(code), None) => write!(f, "exited with code {code}"),
+            (Reason::Finished, None, None) => f.write_str("exited"),
+            (Reason::Timeout, ..) => f.write_str("was killed after reaching its timeout"),
+            (Reason::Killed, ..) => f.write_str("was killed"),
+            (Reason::OutOfMemory, ..) => f.write_str("was killed after exceeding its memory limit"),
+            (Reason::CpuLimit, ..) => f.write_str("was killed after exceeding its CPU time limit"),
+            (Reason::OutputLimit, ..) => f.write_str("was killed after exceeding its output limit"),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct Job {
     destructor: AbortHandle,
-    workload: Workload,
+    owner: User,
+    /// Workload of the job, until its files are removed.
+    workload: Option<Workload>,
+    /// Enarx.toml passed to Enarx, if it differs from the one of the workload.
+    enarx_conf: Option<NamedTempFile>,
+    oci_command: OsString,
+    /// Memory limit in MiB.
+    memory_limit: Option<u64>,
+    /// CPU time limit in seconds.
+    cpu_limit: Option<u64>,
+    /// Whether the termination notice was already returned.
+    notified: bool,
+    /// Clock of the timeout of the job.
+    timeout: clock::Timeout,
 
     pub(crate) id: String,
     pub(crate) exec: Child,
+    /// Standard error read from the pipe of the job while checking that it started, which
+    /// precedes the rest of its standard error.
+    early_stderr: Vec<u8>,
+    /// Output of the job, once it is captured.
+    pub(crate) output: Option<Arc<Streams>>,
+    /// Whether the trace of WASI calls is filtered out of the standard error and reported.
+    wasi_report: bool,
     // Host port -> (Container port, Url)
     pub(crate) mapped_ports: HashMap<u16, (u16, String)>,
+    /// Directory of the container the workload writes its artifacts to, if it declares one.
+    pub(crate) artifacts: Option<String>,
+    /// SHA-256 digest of the wasm of an uploaded workload.
+    pub(crate) sha256: Option<String>,
+    pub(crate) meta: Metadata,
+}
+
+/// Transport protocol of a port.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum Transport {
+    Tcp,
+    Udp,
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp => f.write_str("tcp"),
+            Self::Udp => f.write_str("udp"),
+        }
+    }
+}
+
+impl FromStr for Transport {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tcp" => Ok(Self::Tcp),
+            "udp" => Ok(Self::Udp),
+            _ => Err(anyhow!("unknown transport `{s}`")),
+        }
+    }
 }
 
 #[cfg(target_os = "linux")]
-async fn used_ports<T: FromIterator<u16>>(ss: impl AsRef<OsStr>) -> anyhow::Result<T> {
+pub(crate) async fn used_ports<T: FromIterator<(Transport, u16)>>(
+    ss: impl AsRef<OsStr>,
+) -> anyhow::Result<T> {
     use std::io::{BufRead, BufReader};
 
     let out = Command::new(ss)
-        .arg("-ltnH")
+        .arg("-ltunH")
         .output()
         .await
         .context("failed to run `ss`")?;
     BufReader::new(out.stdout.as_slice())
         .lines()
         .map(|s| {
-            s.context("failed to read line")?
-                .split_whitespace()
+            let s = s.context("failed to read line")?;
+            let mut columns = s.split_whitespace();
+            let transport = columns
+                .next()
+                .ok_or_else(|| anyhow!("transport column missing"))?
+                .parse()?;
+            let port = columns
                 .nth(3)
                 .ok_or_else(|| anyhow!("address column missing"))?
                 .split(':')
                 .last()
                 .ok_or_else(|| anyhow!("failed to parse socket address"))?
                 .parse()
-                .context("failed to parse port")
+                .context("failed to parse port")?;
+            Ok((transport, port))
         })
         .collect()
 }
 
+/// Rewrites the ports of the listen files in the Enarx.toml at `path` to the host ports
+/// they are mapped to in `ports`, which maps host ports to the ports in the config.
+async fn rewrite_listen_ports(
+    path: &Path,
+    ports: &HashMap<u16, (Transport, u16)>,
+) -> anyhow::Result<()> {
+    let conf = tokio::fs::read_to_string(path)
+        .await
+        .context("failed to read Enarx.toml")?;
+    let mut conf: toml::Value = toml::from_str(&conf).context("failed to parse Enarx.toml")?;
+
+    let hosts: HashMap<_, _> = ports
+        .iter()
+        .map(|(host, (_, cont))| (*cont, *host))
+        .collect();
+    let files = conf
+        .get_mut("files")
+        .and_then(toml::Value::as_array_mut)
+        .into_iter()
+        .flatten();
+    for file in files {
+        if file.get("kind").and_then(toml::Value::as_str) != Some("listen") {
+            continue;
+        }
+        if let Some(port) = file.get_mut("port") {
+            if let Some(host) = port
+                .as_integer()
+                .and_then(|port| u16::try_from(port).ok())
+                .and_then(|port| hosts.get(&port))
+            {
+                *port = toml::Value::Integer((*host).into());
+            }
+        }
+    }
+
+    let conf = toml::to_string(&conf).context("failed to encode Enarx.toml")?;
+    tokio::fs::write(path, conf)
+        .await
+        .context("failed to write Enarx.toml")
+}
+
+/// Returns the directory the workload writes its artifacts to, if the Enarx.toml at `path`
+/// declares one, along with a copy of the Enarx.toml to pass to Enarx instead, without it.
+async fn split_artifacts(path: &Path) -> anyhow::Result<(Option<String>, Option<NamedTempFile>)> {
+    let conf = tokio::fs::read_to_string(path)
+        .await
+        .context("failed to read Enarx.toml")?;
+    let artifacts = match config::artifacts(&conf) {
+        Some(artifacts) => artifacts,
+        None => return Ok((None, None)),
+    };
+
+    let conf = config::for_enarx(&conf).context("failed to parse Enarx.toml")?;
+    let dir = path
+        .parent()
+        .context("Enarx.toml has no parent directory")?;
+    let copy = NamedTempFile::new_in(dir).context("failed to create Enarx.toml")?;
+    tokio::fs::write(copy.path(), conf)
+        .await
+        .context("failed to write Enarx.toml")?;
+    Ok((Some(artifacts), Some(copy)))
+}
+
 impl Job {
     /// Spawns a new job via selected OCI engine, it is not safe for concurrent use.
     #[allow(clippy::too_many_arguments)]
     pub(crate) async fn spawn(
         id: String,
+        owner: User,
         workload: Workload,
         ss_command: impl AsRef<OsStr>,
         oci_command: impl AsRef<OsStr>,
         oci_image: impl AsRef<str>,
         port_range: Range<u16>,
-        ports: impl IntoIterator<Item = (u16, String)>,
+        ports: impl IntoIterator<Item = (Transport, u16)>,
+        public_url: impl Fn(u16, u16) -> String,
         devices: impl IntoIterator<Item = impl AsRef<Path>>,
         paths: impl IntoIterator<Item = impl AsRef<Path>>,
         privileged: bool,
+        host_network: bool,
+        backend: Option<&str>,
+        wasi_report: bool,
+        memory_limit: Option<u64>,
+        cpu_limit: Option<u64>,
+        cgroup_slice: Option<&str>,
+        spawn_retries: u32,
+        ttl: Duration,
         destructor: impl Future<Output = ()> + Send + 'static,
     ) -> Result<Self, Response> {
-        info!(job_id = id, ?workload, "spawning a job");
+        info!(job_id = id, %owner, ?workload, "spawning a job");
         let mut cmd = Command::new(&oci_command);
+
+        // Tag the engine client process, so that host tools like `ps` can attribute it.
+        let mut arg0 = oci_command.as_ref().to_os_string();
+        arg0.push(format!(" [benefice job {id} owner {owner}]"));
+
+        // The container is not removed automatically on exit, so that its state
+        // can be inspected. It is removed when the job is killed instead.
         let cmd = cmd
-            .stdin(Stdio::null())
+            .arg0(arg0)
+            .stdin(Stdio::piped())
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
             .kill_on_drop(true)
-            .args(["run", "--rm", "--name", id.as_str()])
-            .arg("--log-driver=none");
+            .args(["run", "--interactive", "--name", id.as_str()])
+            .arg("--log-driver=none")
+            .args(["--label", &format!("benefice.job={id}")])
+            .args(["--label", &format!("benefice.owner={owner}")])
+            .args(["-e", &format!("BENEFICE_JOB={id}")])
+            .args(["-e", &format!("BENEFICE_OWNER={owner}")]);
+
+        let cmd = if let Some(limit) = cpu_limit {
+            let hard = limit + CPU_LIMIT_GRACE;
+            cmd.arg(format!("--ulimit=cpu={limit}:{hard}"))
+        } else {
+            cmd
+        };
+
+        let cmd = if let Some(slice) = cgroup_slice {
+            let parent = slice.strip_suffix(".slice").unwrap_or(slice);
+            cmd.arg(format!("--cgroup-parent={parent}-{owner}.slice"))
+        } else {
+            cmd
+        };
+
+        let cmd = if let Some(limit) = memory_limit {
+            // Disallow swap, so that the limit is enforced promptly.
+            cmd.arg(format!("--memory={limit}m"))
+                .arg(format!("--memory-swap={limit}m"))
+        } else {
+            cmd
+        };
 
         let cmd = if privileged {
             cmd.arg("--privileged")
@@ -96,53 +554,116 @@ impl Job {
             cmd.args(["-v", &format!("{path}:{path}")])
         });
 
-        let cmd = env::var_os("ENARX_BACKEND")
-            .into_iter()
-            .fold(cmd, |cmd, backend| {
-                let mut var = OsString::from("ENARX_BACKEND=");
-                var.push(backend);
-                cmd.arg("-e").arg(var)
-            });
+        let backend = backend
+            .map(OsString::from)
+            .or_else(|| env::var_os("ENARX_BACKEND"));
+        let cmd = backend.into_iter().fold(cmd, |cmd, backend| {
+            let mut var = OsString::from("ENARX_BACKEND=");
+            var.push(backend);
+            cmd.arg("-e").arg(var)
+        });
+
+        let cmd = if wasi_report {
+            cmd.args(["-e", &format!("RUST_LOG={}", wasi::LOG_FILTER)])
+        } else {
+            cmd
+        };
 
         let ports: Vec<_> = ports.into_iter().collect();
-        let port_count = ports.len();
 
-        let mapped_ports = if port_count > 0 {
+        // Host port -> (Transport, Container port)
+        let mapped: HashMap<u16, (Transport, u16)> = if !ports.is_empty() {
             let used: HashSet<_> = used_ports(ss_command).await.map_err(|e| {
                 error!(error = ?e, "failed to lookup used ports");
                 StatusCode::INTERNAL_SERVER_ERROR.into_response()
             })?;
-            let start = port_range.start
-                + (rand::thread_rng().next_u32() as usize % port_range.len()) as u16;
-            let mapped: HashMap<_, _> = (start..port_range.end)
-                .chain(port_range.start..start)
-                .into_iter()
-                .filter(|p| !used.contains(p))
-                .zip(ports)
-                .collect();
-            if mapped.len() < port_count {
-                warn!("insufficient amount of open ports");
-                return Err((
-                    StatusCode::SERVICE_UNAVAILABLE,
-                    "Insufficient amount of open ports on the system, try again later",
-                )
-                    .into_response());
+            // Ports leased to other jobs may not be bound yet, or anymore.
+            let leased: HashSet<u16> = crate::state::leased_ports().await;
+            let used = |transport: Transport, port: u16| {
+                used.contains(&(transport, port)) || leased.contains(&port)
+            };
+            if host_network && matches!(workload, Workload::Drawbridge { .. }) {
+                // The config of Drawbridge workloads cannot be rewritten,
+                // so they have to listen on the ports they ask for.
+                if let Some((transport, port)) = ports
+                    .iter()
+                    .find(|(transport, port)| used(*transport, *port))
+                {
+                    return Err((
+                        StatusCode::CONFLICT,
+                        format!("Port {port}/{transport} is already in use, try again later"),
+                    )
+                        .into_response());
+                }
+                ports
+                    .iter()
+                    .map(|(transport, port)| (*port, (*transport, *port)))
+                    .collect()
+            } else {
+                let start = port_range.start
+                    + (rand::thread_rng().next_u32() as usize % port_range.len()) as u16;
+                let mut candidates = (start..port_range.end).chain(port_range.start..start);
+                let mut mapped = HashMap::new();
+                for (transport, cont) in ports {
+                    match candidates.find(|p| !used(transport, *p)) {
+                        Some(host) => _ = mapped.insert(host, (transport, cont)),
+                        None => {
+                            warn!("insufficient amount of open ports");
+                            return Err((
+                                StatusCode::SERVICE_UNAVAILABLE,
+                                "Insufficient amount of open ports on the system, try again later",
+                            )
+                                .into_response());
+                        }
+                    }
+                }
+                mapped
             }
-            mapped
         } else {
             Default::default()
         };
-        let cmd = mapped_ports.iter().fold(cmd, |cmd, (host, (cont, _))| {
-            cmd.arg("-p").arg(format!("{host}:{cont}"))
-        });
+
+        let cmd = if host_network {
+            if let Workload::Upload { conf, .. } = &workload {
+                rewrite_listen_ports(conf.path(), &mapped)
+                    .await
+                    .map_err(|e| {
+                        error!(error = ?e, "failed to rewrite listen ports");
+                        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+                    })?;
+            }
+            cmd.arg("--network=host")
+        } else {
+            mapped.iter().fold(cmd, |cmd, (host, (transport, cont))| {
+                cmd.arg("-p").arg(format!("{host}:{cont}/{transport}"))
+            })
+        };
+        let mapped_ports = mapped
+            .into_iter()
+            .map(|(host, (_, cont))| (host, (cont, public_url(host, cont))))
+            .collect();
+
+        let (artifacts, enarx_conf) = match &workload {
+            Workload::Drawbridge { .. } => (None, None),
+            Workload::Upload { conf, .. } => split_artifacts(conf.path()).await.map_err(|e| {
+                error!(error = ?e, "failed to prepare Enarx.toml");
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            })?,
+        };
 
         let cmd = match &workload {
             Workload::Drawbridge { slug } => {
                 cmd.args([oci_image.as_ref(), "enarx", "deploy", slug.as_str()])
             }
-            Workload::Upload { wasm, conf } => cmd.args([
+            Workload::Upload { wasm, conf, .. } => cmd.args([
                 "-v",
-                &format!("{}:/app/Enarx.toml", conf.path().display()),
+                &format!(
+                    "{}:/app/Enarx.toml",
+                    enarx_conf
+                        .as_ref()
+                        .map_or(conf.path(), NamedTempFile::path)
+                        .display()
+                ),
                 "-v",
                 &format!("{}:/app/main.wasm", wasm.path().display()),
                 oci_image.as_ref(),
@@ -154,36 +675,424 @@ impl Job {
             ]),
         };
         debug!(?cmd, "spawning a job run command");
-        let exec = cmd.spawn().map_err(|e| {
-            error!(error = ?e, "failed to start job");
-            StatusCode::INTERNAL_SERVER_ERROR.into_response()
-        })?;
+        let mut backoff = SPAWN_BACKOFF;
+        let mut attempt = 0;
+        let mut early_stderr = Vec::new();
+        let exec = loop {
+            #[cfg(feature = "chaos")]
+            let result = if crate::chaos::fail_spawn().await {
+                Err(io::ErrorKind::WouldBlock.into())
+            } else {
+                cmd.spawn()
+            };
+            #[cfg(not(feature = "chaos"))]
+            let result = cmd.spawn();
+
+            // Enarx failing to start the workload inside the container, e.g. because the
+            // trusted execution device is busy, only shows in the exit of the job.
+            let (reason, transient, e) = match result {
+                Ok(mut exec) if attempt < spawn_retries => match exited_early(&mut exec).await {
+                    None => break exec,
+                    Some((code, stderr)) => match container_failure(code, &stderr) {
+                        Some(reason) => {
+                            remove_container(oci_command.as_ref(), &id).await;
+                            let stderr = String::from_utf8_lossy(&stderr);
+                            let e = format!("exited with {code:?}: {}", stderr.trim());
+                            (reason, true, e)
+                        }
+                        None => {
+                            early_stderr = stderr;
+                            break exec;
+                        }
+                    },
+                },
+                Ok(exec) => break exec,
+                Err(e) => {
+                    let (reason, transient) = spawn_failure(&e);
+                    (reason, transient, e.to_string())
+                }
+            };
+            if transient && attempt < spawn_retries {
+                attempt += 1;
+                warn!(error = %e, attempt, ?backoff, "failed to start job, retrying");
+                sleep(backoff).await;
+                backoff *= 2;
+                continue;
+            }
+
+            error!(error = %e, attempt, "failed to start job");
+            let status = if transient {
+                StatusCode::SERVICE_UNAVAILABLE
+            } else {
+                StatusCode::INTERNAL_SERVER_ERROR
+            };
+            return Err((
+                status,
+                format!("Your workload could not be started: {reason}"),
+            )
+                .into_response());
+        };
 
         let (destructor_tx, destructor_rx) = AbortHandle::new_pair();
         _ = tokio::spawn(Abortable::new(destructor, destructor_rx));
         Ok(Self {
             id,
+            owner,
             exec,
+            early_stderr,
+            output: None,
+            timeout: clock::Timeout::start(ttl, MAX_PAUSE),
+            wasi_report,
             mapped_ports,
-            workload,
+            workload: Some(workload),
+            enarx_conf,
+            artifacts,
+            sha256: None,
+            meta: Metadata::default(),
+            oci_command: oci_command.as_ref().into(),
+            memory_limit,
+            cpu_limit,
+            notified: false,
             destructor: destructor_tx,
         })
     }
 
-    pub(crate) async fn kill(mut self) {
+    /// Returns whether the container was killed for running out of memory and its exit code.
+    async fn inspect(&self) -> Option<(bool, i32)> {
+        let out = match Command::new(&self.oci_command)
+            .args([
+                "inspect",
+                "--format",
+                "{{.State.OOMKilled}} {{.State.ExitCode}}",
+                self.id.as_str(),
+            ])
+            .output()
+            .await
+        {
+            Ok(out) => String::from_utf8_lossy(&out.stdout).into_owned(),
+            Err(e) => {
+                error!(error = ?e, job_id = self.id, "failed to inspect job");
+                return None;
+            }
+        };
+        let (oom_killed, code) = out.trim().split_once(' ')?;
+        Some((oom_killed == "true", code.parse().ok()?))
+    }
+
+    /// Determines how the job ended, if it has exited on its own.
+    pub(crate) async fn exit_outcome(&mut self) -> Option<Outcome> {
+        let status = self.exec.try_wait().ok()??;
+        let (oom_killed, code) = match self.inspect().await {
+            Some(state) => state,
+            None => (false, status.code()?),
+        };
+        let reason = if oom_killed && self.memory_limit.is_some() {
+            Reason::OutOfMemory
+        } else if code == SIGXCPU_EXIT_CODE && self.cpu_limit.is_some() {
+            Reason::CpuLimit
+        } else {
+            Reason::Finished
+        };
+        // Container engines report termination by a signal as 128 + signal number.
+        let signal = (code > 128).then_some(code - 128);
+        Some(Outcome {
+            reason,
+            code: Some(code),
+            signal,
+        })
+    }
+
+    /// Returns a notice for the user if the job has exited after exceeding its memory
+    /// or CPU time limit. The notice is only returned once.
+    pub(crate) async fn termination_notice(&mut self) -> Option<String> {
+        if self.notified || (self.memory_limit.is_none() && self.cpu_limit.is_none()) {
+            return None;
+        }
+        let outcome = self.exit_outcome().await?;
+        self.notified = true;
+
+        match (outcome.reason, self.memory_limit, self.cpu_limit) {
+            (Reason::OutOfMemory, Some(limit), _) => {
+                info!(job_id = self.id, "job exceeded its memory limit");
+                Some(format!(
+                    "\nYour workload exceeded the {limit} MiB memory limit and was killed\n"
+                ))
+            }
+            (Reason::CpuLimit, _, Some(limit)) => {
+                info!(job_id = self.id, "job exceeded its CPU time limit");
+                Some(format!(
+                    "\nYour workload exceeded the {limit} second CPU time limit and was killed\n"
+                ))
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns whether the job is paused.
+    pub(crate) fn is_paused(&self) -> bool {
+        self.timeout.is_paused()
+    }
+
+    /// Returns how long the job has been running for, excluding pauses.
+    pub(crate) fn runtime(&self) -> Duration {
+        self.timeout.runtime()
+    }
+
+    /// Returns when the job is killed after its timeout.
+    pub(crate) fn deadline(&self) -> Instant {
+        self.timeout.deadline()
+    }
+
+    /// Extends the timeout of the job by `by`, up to a total runtime of `max_runtime`.
+    /// Returns the new deadline, or `None` if the maximum has already been reached.
+    pub(crate) fn extend(&mut self, by: Duration, max_runtime: Duration) -> Option<Instant> {
+        self.timeout.extend(by, max_runtime)
+    }
+
+    async fn oci(&self, command: &str) -> Result<(), Response> {
+        match Command::new(&self.oci_command)
+            .args([command, self.id.as_str()])
+            .output()
+            .await
+        {
+            Ok(out) if out.status.success() => Ok(()),
+            Ok(out) => {
+                error!(
+                    job_id = self.id,
+                    command,
+                    stderr = %String::from_utf8_lossy(&out.stderr),
+                    "failed to run container command"
+                );
+                Err(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+            }
+            Err(e) => {
+                error!(error = ?e, job_id = self.id, command, "failed to run container command");
+                Err(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+            }
+        }
+    }
+
+    /// Stops all processes of the job and the clock of its timeout, for up to [`MAX_PAUSE`].
+    pub(crate) async fn pause(&mut self) -> Result<(), Response> {
+        if !matches!(self.exec.try_wait(), Ok(None)) {
+            return Err((StatusCode::CONFLICT, "The job has already ended").into_response());
+        }
+        if self.is_paused() {
+            return Err((StatusCode::CONFLICT, "The job is already paused").into_response());
+        }
+        self.oci("pause").await?;
+        self.timeout.pause();
+        // The ports stay leased for as long as the job may be paused.
+        crate::state::extend(&self.id, self.timeout.deadline() + MAX_PAUSE).await;
+        info!(job_id = self.id, "paused job");
+        Ok(())
+    }
+
+    /// Continues a paused job, starting the clock of its timeout again.
+    pub(crate) async fn resume(&mut self) -> Result<(), Response> {
+        if !self.is_paused() {
+            return Err((StatusCode::CONFLICT, "The job is not paused").into_response());
+        }
+        self.oci("unpause").await?;
+        let pause = self.timeout.resume();
+        crate::state::extend(&self.id, self.timeout.deadline()).await;
+        info!(
+            job_id = self.id,
+            paused_for = pause.as_secs(),
+            "resumed job"
+        );
+        Ok(())
+    }
+
+    /// Writes as much of `data` to the standard input of the job as it accepts within a short
+    /// time, returning the number of bytes written, so that callers can apply backpressure.
+    /// Writing no data closes the standard input.
+    pub(crate) async fn write_stdin(&mut self, data: &[u8]) -> Result<usize, StatusCode> {
+        if data.is_empty() {
+            self.exec.stdin = None;
+            return Ok(0);
+        }
+
+        let stdin = self.exec.stdin.as_mut().ok_or(StatusCode::GONE)?;
+        match timeout(STDIN_TIMEOUT, stdin.write(data)).await {
+            Ok(Ok(size)) => Ok(size),
+            Ok(Err(e)) if e.kind() == io::ErrorKind::BrokenPipe => {
+                debug!(job_id = self.id, "job closed its standard input");
+                self.exec.stdin = None;
+                Err(StatusCode::GONE)
+            }
+            Ok(Err(e)) => {
+                error!(error = ?e, job_id = self.id, "failed to write to job");
+                Err(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+            Err(..) => Ok(0),
+        }
+    }
+
+    /// Archives the artifacts the workload wrote, once the job has exited.
+    pub(crate) async fn archive_artifacts(&mut self, limit: u64) -> Result<Vec<u8>, Response> {
+        let dir = self.artifacts.as_deref().ok_or_else(|| {
+            (
+                StatusCode::NOT_FOUND,
+                "The workload does not declare an artifacts directory",
+            )
+                .into_response()
+        })?;
+        if matches!(self.exec.try_wait(), Ok(None)) {
+            return Err((StatusCode::CONFLICT, "The job is still running").into_response());
+        }
+        crate::artifacts::archive(&self.oci_command, &self.id, dir, limit).await
+    }
+
+    /// Captures the output of the job, which is logged with up to `limit` bytes per stream,
+    /// capped by `cap`, if any, and read from `output` instead of its pipes.
+    pub(crate) fn capture_output(&mut self, limit: u64, cap: Option<Cap>) {
+        if let (Some(stdout), Some(stderr)) = self.take_pipes() {
+            let (id, owner, trace) = (&self.id, self.owner, self.wasi_report);
+            let streams = Streams::capture(id, owner, stdout, stderr, trace, limit, cap);
+            self.output = Some(Arc::new(streams));
+        }
+    }
+
+    /// Takes the output pipes of the job, whose standard error is read from the start.
+    pub(crate) fn take_pipes(
+        &mut self,
+    ) -> (
+        Option<ChildStdout>,
+        Option<impl AsyncRead + Unpin + Send + 'static>,
+    ) {
+        let early = io::Cursor::new(std::mem::take(&mut self.early_stderr));
+        let stderr = self.exec.stderr.take().map(|stderr| early.chain(stderr));
+        (self.exec.stdout.take(), stderr)
+    }
+
+    /// Returns the WASI calls of the job recorded so far, if they are reported.
+    pub(crate) fn wasi_report(&self) -> Option<Report> {
+        self.output.as_ref().and_then(|output| output.report())
+    }
+
+    /// Returns the stages of the Keep of the job recognized so far.
+    pub(crate) fn annotations(&self) -> Vec<Annotation> {
+        self.output
+            .as_ref()
+            .map_or_else(Vec::new, |output| output.annotations())
+    }
+
+    /// Kills the job for `reason`, recording how it ended in the history of its owner.
+    /// The reason is ignored if the job has already exited on its own.
+    ///
+    /// The job is torn down even if the returned future is dropped midway, e.g. because the
+    /// client of the request killing the job disconnected.
+    pub(crate) async fn kill(mut self, reason: Reason) {
+        detach(async move {
+            let outcome = self.exit_outcome().await.unwrap_or(Outcome {
+                reason,
+                code: None,
+                signal: None,
+            });
+            let wasi = self.wasi_report();
+            let annotations = self.annotations();
+            crate::history::finish(self.owner, &self.id, outcome, wasi, annotations).await;
+            crate::webhooks::notify(&self.id, outcome).await;
+            crate::daily::finish(self.owner, &self.id, self.runtime()).await;
+            crate::eta::record(self.runtime()).await;
+            self.remove().await;
+        })
+        .await;
+    }
+
+    /// Kills the job without recording how it ended, e.g. a job comparing backends.
+    /// Its workload is not kept for a restart. Like `kill`, it is cancellation-safe.
+    pub(crate) async fn discard(mut self) {
+        detach(async move {
+            self.workload = None;
+            self.remove().await;
+        })
+        .await;
+    }
+
+    /// Removes the container and the files of the job.
+    async fn remove(&mut self) {
         self.destructor.abort();
+        if let Some(output) = &self.output {
+            output.detach();
+        }
         if let Err(e) = self.exec.kill().await {
             error!(error = ?e, job_id = self.id, "failed to kill job");
         }
-        if let Workload::Upload { wasm, conf } = self.workload {
-            debug!("closing `main.wasm`");
-            if let Err(e) = wasm.close() {
-                error!(error = ?e, job_id = self.id, "failed to close `main.wasm`");
-            };
-            debug!("closing `Enarx.toml`");
-            if let Err(e) = conf.close() {
-                error!(error = ?e, job_id = self.id, "failed to close `Enarx.toml`");
-            };
+        remove_container(&self.oci_command, &self.id).await;
+        crate::state::remove(&self.id).await;
+        self.remove_files();
+    }
+
+    /// Hands the files of the job, which are no longer needed once it has exited,
+    /// over to be kept for a restart. Their disk budget is released once they are deleted.
+    pub(crate) fn remove_files(&mut self) {
+        if let Some(workload) = self.workload.take() {
+            let keep = crate::restart::keep(self.owner, self.id.clone(), workload);
+            _ = tokio::spawn(keep);
         }
+        self.enarx_conf = None;
+    }
+}
+
+/// Runs `teardown` to completion on its own task, so that it is not cancelled when the future
+/// awaiting it is dropped, which would leave the container of the job behind.
+async fn detach(teardown: impl Future<Output = ()> + Send + 'static) {
+    if let Err(e) = tokio::spawn(teardown).await {
+        error!(error = ?e, "failed to tear down job");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BUSY: &[u8] =
+        b"Error: failed to open /dev/sgx_enclave: Device or resource busy (os error 16)\n";
+
+    #[test]
+    fn container_failure_is_classified() {
+        assert_eq!(container_failure(Some(1), BUSY), Some(DEVICE_BUSY));
+        assert_eq!(
+            container_failure(Some(1), b"Error: mmap failed: EAGAIN\n"),
+            Some(OUT_OF_RESOURCES)
+        );
+        assert_eq!(container_failure(Some(1), b"Error: invalid wasm\n"), None);
+        assert_eq!(container_failure(None, b""), None);
+        assert_eq!(container_failure(Some(0), BUSY), None);
+    }
+
+    fn spawn(script: &str) -> Child {
+        Command::new("sh")
+            .args(["-c", script])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn busy_device_in_container_is_transient() {
+        let mut exec = spawn("echo 'Error: Device or resource busy (os error 16)' >&2; exit 1");
+        let (code, stderr) = exited_early(&mut exec).await.expect("job exited early");
+        assert_eq!(code, Some(1));
+        assert_eq!(container_failure(code, &stderr), Some(DEVICE_BUSY));
+    }
+
+    #[tokio::test]
+    async fn successful_exit_is_not_a_failed_start() {
+        let mut exec = spawn("echo 'Device or resource busy' >&2");
+        assert!(exited_early(&mut exec).await.is_none());
+        assert!(exec.stderr.is_some());
+    }
+
+    #[tokio::test]
+    async fn running_job_keeps_its_input() {
+        let mut exec = spawn("sleep 5");
+        assert!(exited_early(&mut exec).await.is_none());
+        assert!(exec.stdin.is_some());
     }
 }
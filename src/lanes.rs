@@ -0,0 +1,58 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Separate lane for large uploads, so that they cannot hold up typical small ones.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::timeout;
+use tracing::{debug, warn};
+
+/// Maximum time a large upload waits for a free slot in its lane.
+const LANE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Debug)]
+pub(crate) struct UploadLanes {
+    /// Size in bytes above which uploads are considered large.
+    threshold: u64,
+    /// Slots of the lane for large uploads.
+    large: Arc<Semaphore>,
+}
+
+impl UploadLanes {
+    pub(crate) fn new(threshold: u64, concurrency: usize) -> Self {
+        Self {
+            threshold,
+            large: Arc::new(Semaphore::new(concurrency)),
+        }
+    }
+
+    /// Waits for a slot in the lane of an upload of `len` bytes, which is held until
+    /// the returned permit is dropped. Uploads of unknown length are considered large.
+    /// Small uploads pass through without waiting.
+    pub(crate) async fn enter(
+        &self,
+        len: Option<u64>,
+    ) -> Result<Option<OwnedSemaphorePermit>, Response> {
+        if matches!(len, Some(len) if len <= self.threshold) {
+            return Ok(None);
+        }
+
+        debug!(?len, "queueing large upload");
+        match timeout(LANE_TIMEOUT, self.large.clone().acquire_owned()).await {
+            Ok(Ok(permit)) => Ok(Some(permit)),
+            Ok(Err(_)) | Err(_) => {
+                warn!(?len, "too many large uploads in progress");
+                Err((
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "Too many large workloads are being uploaded right now, try again later",
+                )
+                    .into_response())
+            }
+        }
+    }
+}
@@ -0,0 +1,134 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Annotations of the lifecycle of the Keep of a job, e.g. once it has been attested, so that
+//! pages can show the stages the Keep went through rather than only its raw output.
+//!
+//! The stages are recognized by the messages Enarx logs on the standard error of the job, as
+//! text or as JSON lines. The lines are passed on unchanged.
+
+use crate::clock::{self, Instant};
+
+use once_cell::sync::Lazy;
+use regex::bytes::Regex;
+use serde::{Deserialize, Serialize};
+
+/// Longest incomplete line held back until it is complete.
+const MAX_PENDING: usize = 4096;
+
+/// Maximum number of annotations of a job, so that a workload cannot flood them.
+const MAX_ANNOTATIONS: usize = 32;
+
+/// Stage of the lifecycle of a Keep.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Stage {
+    /// The Keep has been attested.
+    Attested,
+    /// The workload listens for connections, e.g. on a TLS port.
+    Listening,
+}
+
+/// Patterns of the messages marking the stages, capturing their details if any.
+static MARKERS: Lazy<Vec<(Stage, Regex)>> = Lazy::new(|| {
+    [
+        (
+            Stage::Attested,
+            r"(?i)\battestation\b[^\n]*\b(?:complete|completed|succeeded|successful)\b",
+        ),
+        (
+            Stage::Listening,
+            r"(?i)\blistening on\s+(?:[a-z]+://)?([\w.:\[\]-]+)",
+        ),
+        (Stage::Listening, r"(?i)\btls listener\b[^\n]*\bready\b"),
+    ]
+    .into_iter()
+    .map(|(stage, pattern)| {
+        (
+            stage,
+            Regex::new(pattern).expect("invalid lifecycle marker"),
+        )
+    })
+    .collect()
+});
+
+/// Annotation of a job with a stage of the lifecycle of its Keep.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub(crate) struct Annotation {
+    pub(crate) stage: Stage,
+    /// Milliseconds since the output of the job is captured.
+    pub(crate) at: u64,
+    /// Details of the stage, e.g. the address the workload listens on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) detail: Option<String>,
+}
+
+/// Annotates a job with the stages recognized in its standard error.
+#[derive(Debug)]
+pub(crate) struct Annotator {
+    started: Instant,
+    /// Output of an incomplete line, which is held back until the line is complete.
+    pending: Vec<u8>,
+    annotations: Vec<Annotation>,
+}
+
+impl Default for Annotator {
+    fn default() -> Self {
+        Self {
+            started: clock::now(),
+            pending: Vec::new(),
+            annotations: Vec::new(),
+        }
+    }
+}
+
+impl Annotator {
+    /// Scans the complete lines of a `chunk` read from the standard error of the job for the
+    /// stages of its Keep. An empty chunk scans the held back line as well.
+    pub(crate) fn scan(&mut self, chunk: &[u8]) {
+        let flush = chunk.is_empty();
+        self.pending.extend_from_slice(chunk);
+        let end = if flush || self.pending.len() > MAX_PENDING {
+            self.pending.len()
+        } else {
+            match self.pending.iter().rposition(|b| *b == b'\n') {
+                Some(pos) => pos + 1,
+                None => return,
+            }
+        };
+
+        let lines: Vec<u8> = self.pending.drain(..end).collect();
+        for line in lines.split(|b| *b == b'\n') {
+            self.annotate(line);
+        }
+    }
+
+    fn annotate(&mut self, line: &[u8]) {
+        let (stage, captures) = match MARKERS
+            .iter()
+            .find_map(|(stage, marker)| Some((*stage, marker.captures(line)?)))
+        {
+            Some(found) => found,
+            None => return,
+        };
+        let detail = captures
+            .get(1)
+            .map(|detail| String::from_utf8_lossy(detail.as_bytes()).into_owned());
+        let known = self
+            .annotations
+            .iter()
+            .any(|annotation| annotation.stage == stage && annotation.detail == detail);
+        if !known && self.annotations.len() < MAX_ANNOTATIONS {
+            self.annotations.push(Annotation {
+                stage,
+                at: self.started.elapsed().as_millis() as u64,
+                detail,
+            });
+        }
+    }
+
+    /// Returns the annotations recorded so far, in order of the stages.
+    pub(crate) fn annotations(&self) -> Vec<Annotation> {
+        self.annotations.clone()
+    }
+}
@@ -0,0 +1,245 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Explanation of the limits which apply to a user, and why.
+
+use crate::auth::{Role, User};
+use crate::output::CapAction;
+use crate::policy::{self, Context};
+use crate::templates::{HtmlTemplate, LimitsTemplate};
+use crate::{Limits, Other};
+
+use std::time::Duration;
+
+use axum::response::IntoResponse;
+use axum::Json;
+use chrono::{DateTime, Utc};
+use humansize::{file_size_opts as options, FileSize};
+use serde::Serialize;
+use serde_json::json;
+
+/// A limit applying to a user.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct Limit {
+    pub(crate) name: &'static str,
+    pub(crate) value: String,
+    /// Why the limit applies.
+    pub(crate) reason: String,
+}
+
+impl Limit {
+    fn new(name: &'static str, value: impl Into<String>, reason: impl Into<String>) -> Self {
+        Self {
+            name,
+            value: value.into(),
+            reason: reason.into(),
+        }
+    }
+}
+
+fn human(bytes: u64) -> String {
+    bytes
+        .file_size(options::CONVENTIONAL)
+        .unwrap_or_else(|_| format!("{bytes} B"))
+}
+
+fn minutes(duration: Duration) -> String {
+    match duration.as_secs() {
+        secs if secs % 60 == 0 => format!("{} minutes", secs / 60),
+        secs => format!("{secs} seconds"),
+    }
+}
+
+/// Returns the tier of `user` and the limits which apply to them.
+fn explain(user: Option<&User>, limits: &Limits, other: &Other) -> (String, Vec<Limit>) {
+    let star = user.map_or(false, |user| limits.is_starred(user.roles()));
+    let repos = other
+        .star_repos
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(" or ");
+    let extra = user.and_then(|user| limits.tier(user.roles()));
+    let (tier, why): (String, String) = match (user, extra) {
+        (None, _) => ("anonymous".into(), "you are not logged in".into()),
+        (Some(_), Some(tier)) => (
+            tier.name.clone(),
+            format!("your roles grant the {} tier", tier.name),
+        ),
+        (Some(user), None) if user.expires().is_some() && star => (
+            "guest (starred)".into(),
+            "your guest link grants the starred tier".into(),
+        ),
+        (Some(user), None) if user.expires().is_some() => (
+            "guest".into(),
+            "your guest link grants the default tier".into(),
+        ),
+        (Some(user), None) if star && user.roles().contains(Role::Starred) => (
+            "starred".into(),
+            format!("you have starred {repos} on GitHub"),
+        ),
+        (Some(user), None) if star && user.roles().contains(Role::Member) => (
+            "starred".into(),
+            "you are a member of a GitHub organization of this instance".into(),
+        ),
+        (Some(_), None) if star => ("starred".into(), "your roles grant the starred tier".into()),
+        (Some(_), None) => (
+            "default".into(),
+            format!("you have not starred {repos} on GitHub, which grants higher limits"),
+        ),
+    };
+
+    let ctx = user.copied().map_or_else(Context::anonymous, Context::new);
+    let decision = limits.decide(&ctx);
+    // A policy of the operators may grant other limits than the tier of the user.
+    let decided = |reason: String| {
+        if policy::get().is_tiered() {
+            reason
+        } else {
+            "set by the limit policy of this instance".into()
+        }
+    };
+
+    let mut explained = vec![];
+    if user.is_none() {
+        explained.push(Limit::new("workloads", "none", "log in to run workloads"));
+    }
+    if let Some(user) = user {
+        explained.push(Limit::new(
+            "roles",
+            user.roles().to_string(),
+            "granted by your identity provider when you logged in",
+        ));
+    }
+    if let Some(expires) = user.and_then(User::expires) {
+        let expires = DateTime::<Utc>::from(expires).format("%Y-%m-%d %H:%M UTC");
+        explained.push(Limit::new(
+            "account",
+            format!("expires at {expires}"),
+            "guest accounts are time-boxed by the administrator who created the guest link",
+        ));
+    }
+    explained.push(Limit::new(
+        "workload size",
+        decision.size_human(),
+        decided(format!("the size limit of the {tier} tier, because {why}")),
+    ));
+    explained.push(Limit::new(
+        "runtime",
+        minutes(decision.time_to_live),
+        decided(format!("the timeout of the {tier} tier, because {why}")),
+    ));
+    explained.push(Limit::new(
+        "extended runtime",
+        minutes(decision.max_time_to_live),
+        decided(format!(
+            "the maximum runtime jobs of the {tier} tier can be extended to"
+        )),
+    ));
+    if let Some(submissions) = decision.submissions {
+        explained.push(Limit::new(
+            "submissions",
+            format!("{submissions} per hour"),
+            decided(format!(
+                "the submission rate of the {tier} tier, because {why}"
+            )),
+        ));
+    }
+    if let Some(runs) = limits.daily.runs {
+        explained.push(Limit::new(
+            "daily jobs",
+            runs.to_string(),
+            "the number of jobs you may run within 24 hours",
+        ));
+    }
+    if let Some(daily_minutes) = limits.daily.minutes {
+        explained.push(Limit::new(
+            "daily runtime",
+            format!("{daily_minutes} minutes"),
+            "the total time your jobs may run for within 24 hours",
+        ));
+    }
+    explained.push(Limit::new(
+        "concurrent jobs",
+        "1",
+        "starting a new job kills the job you are running",
+    ));
+    explained.push(Limit::new(
+        "instance capacity",
+        format!("{} jobs", other.jobs_max),
+        "the number of jobs this instance runs at a time across all users",
+    ));
+    if let Some(max_wait) = other.queue_max_wait {
+        explained.push(Limit::new(
+            "queue wait",
+            minutes(max_wait),
+            "while the instance is at capacity, submissions wait this long for a slot",
+        ));
+    }
+    if let Some(memory) = other.memory_limit {
+        explained.push(Limit::new(
+            "memory",
+            format!("{memory} MiB"),
+            "jobs exceeding their memory limit are killed",
+        ));
+    }
+    if let Some(cpu) = other.cpu_limit {
+        explained.push(Limit::new(
+            "CPU time",
+            format!("{cpu} seconds"),
+            "jobs exceeding their CPU time limit are killed",
+        ));
+    }
+    if let Some(cap) = other.output_cap {
+        explained.push(Limit::new(
+            "output",
+            human(cap.bytes),
+            match cap.action {
+                CapAction::Truncate => "output of a job beyond it is discarded",
+                CapAction::Kill => "jobs exceeding it are killed",
+            },
+        ));
+    }
+    if let Some(listen_max) = other.listen_max {
+        explained.push(Limit::new(
+            "listen ports",
+            listen_max.to_string(),
+            "the number of ports a workload may listen on",
+        ));
+    }
+    if let Some(disk) = other.disk_budget.job {
+        explained.push(Limit::new(
+            "disk space",
+            human(disk),
+            "the combined size of the files of a job",
+        ));
+    }
+    if other.signing.required {
+        explained.push(Limit::new(
+            "signatures",
+            "required",
+            "this instance only runs uploaded workloads signed by a trusted signer",
+        ));
+    }
+    (tier, explained)
+}
+
+/// Renders the limits which apply to the current user.
+pub(crate) async fn page(user: Option<User>, limits: Limits, other: Other) -> impl IntoResponse {
+    let (tier, limits) = explain(user.as_ref(), &limits, &other);
+    HtmlTemplate(LimitsTemplate {
+        demo_fqdn: other.demo_fqdn,
+        user: user.is_some(),
+        tier,
+        limits,
+    })
+}
+
+/// Returns the limits which apply to the current user.
+pub(crate) async fn api(user: User, limits: Limits, other: Other) -> impl IntoResponse {
+    let (tier, limits) = explain(Some(&user), &limits, &other);
+    Json(json!({
+        "tier": tier,
+        "limits": limits,
+    }))
+}
@@ -0,0 +1,297 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Complete logs of the output of jobs, kept in the `jobs` directory of the runtime directory,
+//! so that output which is not polled by a client is not lost.
+//!
+//! The output of a job is appended to the log of its stream as it is drained from the pipe,
+//! after it is redacted. The logs are capped in size, and can be downloaded while the job runs
+//! and after it exited, until they are removed by the retention policy.
+//!
+//! If a storage is configured, the logs are moved into it once the job exited, so that they
+//! can be downloaded from any instance sharing the storage.
+
+use crate::auth::User;
+use crate::gc::Rule;
+use crate::{history, storage};
+
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use axum::extract::{Path as AxumPath, Query};
+use axum::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+use axum::http::{HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use humansize::{file_size_opts as options, FileSize};
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, error, info};
+use uuid::Uuid;
+
+/// Directory of the logs, set by `init`.
+static DIR: OnceCell<PathBuf> = OnceCell::new();
+
+/// Output stream of a job.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Stream {
+    #[default]
+    Out,
+    Err,
+}
+
+impl Stream {
+    fn file_name(self) -> &'static str {
+        match self {
+            Self::Out => "stdout.log",
+            Self::Err => "stderr.log",
+        }
+    }
+}
+
+/// Returns the key of the log of `stream` of the job with `id` in the storage.
+fn key(id: &str, stream: Stream) -> String {
+    format!("logs/{id}/{}", stream.file_name())
+}
+
+/// Creates the directory of the logs in `runtime_dir` on startup.
+pub(crate) async fn init(runtime_dir: &Path) -> anyhow::Result<()> {
+    let dir = runtime_dir.join("jobs");
+    fs::create_dir_all(&dir)
+        .await
+        .map_err(|e| anyhow::anyhow!("failed to create `{}`: {e}", dir.display()))?;
+    DIR.set(dir)
+        .map_err(|_| anyhow::anyhow!("job logs already initialized"))
+}
+
+/// Returns the log of `stream` of the job with `id`.
+fn path(id: &str, stream: Stream) -> Option<PathBuf> {
+    Some(DIR.get()?.join(id).join(stream.file_name()))
+}
+
+async fn open(path: &Path) -> std::io::Result<File> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).await?;
+    }
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+}
+
+/// Log of an output stream of a job.
+#[derive(Debug)]
+pub(crate) struct Log {
+    id: String,
+    stream: Stream,
+    /// File of the log, until it is truncated.
+    file: Option<File>,
+    /// Number of bytes written to the log.
+    written: u64,
+    limit: u64,
+}
+
+impl Log {
+    /// Creates the log of `stream` of the job with `id`, which keeps up to `limit` bytes.
+    pub(crate) async fn create(id: &str, stream: Stream, limit: u64) -> Self {
+        let file = match path(id, stream) {
+            Some(path) => match open(&path).await {
+                Ok(file) => Some(file),
+                Err(e) => {
+                    error!(error = ?e, job_id = id, "failed to create job log");
+                    None
+                }
+            },
+            None => None,
+        };
+        Self {
+            id: id.into(),
+            stream,
+            file,
+            written: 0,
+            limit,
+        }
+    }
+
+    /// Appends a `chunk` of output to the log.
+    pub(crate) async fn append(&mut self, chunk: &[u8]) {
+        let mut file = match self.file.take() {
+            Some(file) => file,
+            None => return,
+        };
+
+        let room = self.limit.saturating_sub(self.written);
+        let truncated = chunk.len() as u64 > room;
+        let mut chunk = chunk.to_vec();
+        if truncated {
+            let limit = self
+                .limit
+                .file_size(options::CONVENTIONAL)
+                .unwrap_or_else(|_| format!("{} B", self.limit));
+            chunk.truncate(room as usize);
+            chunk.extend_from_slice(format!("\n[The log was truncated at {limit}]\n").as_bytes());
+        }
+        self.written += chunk.len() as u64;
+        let written = match file.write_all(&chunk).await {
+            // The file is closed once truncated, so it is flushed right away.
+            Ok(()) if truncated => file.flush().await,
+            written => written,
+        };
+        match written {
+            Ok(()) if truncated => info!(job_id = self.id, "truncated job log"),
+            Ok(()) => self.file = Some(file),
+            Err(e) => error!(error = ?e, job_id = self.id, "failed to write job log"),
+        }
+    }
+
+    /// Moves the log into the storage, if one is configured, once its stream was drained.
+    /// The log is kept on local disk if it cannot be stored.
+    pub(crate) async fn finish(mut self) {
+        let (storage, path) = match (storage::get(), path(&self.id, self.stream)) {
+            (Some(storage), Some(path)) => (storage, path),
+            _ => return,
+        };
+        if let Some(mut file) = self.file.take() {
+            if let Err(e) = file.flush().await {
+                error!(error = ?e, job_id = self.id, "failed to write job log");
+            }
+        }
+        let log = match fs::read(&path).await {
+            Ok(log) => log,
+            Err(e) => {
+                error!(error = ?e, job_id = self.id, "failed to read job log");
+                return;
+            }
+        };
+        if let Err(e) = storage.put(&key(&self.id, self.stream), log).await {
+            error!(error = ?e, job_id = self.id, "failed to store job log");
+            return;
+        }
+        if let Err(e) = fs::remove_file(&path).await {
+            error!(error = ?e, job_id = self.id, "failed to remove stored job log");
+        }
+        // Remove the directory of the logs as well, once both of them are stored.
+        if let Some(dir) = path.parent() {
+            _ = fs::remove_dir(dir).await;
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub(crate) struct LogsQuery {
+    stream: Stream,
+}
+
+/// Downloads the log of an output stream of a job of the user, while it runs or after it
+/// exited. Logs which were moved into the storage are downloaded from there.
+pub(crate) async fn download(
+    AxumPath(id): AxumPath<String>,
+    user: User,
+    Query(LogsQuery { stream }): Query<LogsQuery>,
+) -> Result<Response, Response> {
+    // The ID is joined to the path of the log, so it must not be an arbitrary path.
+    if Uuid::parse_str(&id).is_err() || !history::recent(&user).await.iter().any(|run| run.id == id)
+    {
+        return Err(StatusCode::NOT_FOUND.into_response());
+    }
+    let path = path(&id, stream).ok_or_else(|| StatusCode::NOT_FOUND.into_response())?;
+    let disposition = format!("attachment; filename=\"{id}-{}\"", stream.file_name());
+    let log = match fs::read(&path).await {
+        Ok(log) => log,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let removed = || (StatusCode::NOT_FOUND, "The log of this job was removed");
+            let storage = storage::get().ok_or_else(|| removed().into_response())?;
+            let mut resp = storage
+                .download(&key(&id, stream))
+                .await
+                .map_err(|resp| match resp.status() {
+                    StatusCode::NOT_FOUND => removed().into_response(),
+                    _ => resp,
+                })?;
+            if resp.status().is_success() {
+                let headers = resp.headers_mut();
+                _ = headers.insert(
+                    CONTENT_TYPE,
+                    HeaderValue::from_static("text/plain; charset=utf-8"),
+                );
+                if let Ok(disposition) = HeaderValue::from_str(&disposition) {
+                    _ = headers.insert(CONTENT_DISPOSITION, disposition);
+                }
+            }
+            return Ok(resp);
+        }
+        Err(e) => {
+            error!(error = ?e, job_id = id, "failed to read job log");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+        }
+    };
+    Ok((
+        [
+            (CONTENT_TYPE, "text/plain; charset=utf-8".to_string()),
+            (CONTENT_DISPOSITION, disposition),
+        ],
+        log,
+    )
+        .into_response())
+}
+
+/// Logs of a job.
+struct Logs {
+    dir: PathBuf,
+    /// When the logs were last written to.
+    modified: SystemTime,
+    size: u64,
+}
+
+/// Returns the logs of all jobs, newest first.
+async fn list(dir: &Path) -> std::io::Result<Vec<Logs>> {
+    let mut all = vec![];
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let mut logs = Logs {
+            dir: entry.path(),
+            modified: SystemTime::UNIX_EPOCH,
+            size: 0,
+        };
+        let mut files = fs::read_dir(&logs.dir).await?;
+        while let Some(file) = files.next_entry().await? {
+            let meta = file.metadata().await?;
+            logs.modified = logs.modified.max(meta.modified()?);
+            logs.size += meta.len();
+        }
+        all.push(logs);
+    }
+    all.sort_by(|a, b| b.modified.cmp(&a.modified));
+    Ok(all)
+}
+
+/// Removes the local logs exceeding the retention `rule`. The logs moved into the storage are
+/// removed by its own garbage collection.
+pub(crate) async fn gc(rule: &Rule) {
+    let dir = match DIR.get() {
+        Some(dir) => dir,
+        None => return,
+    };
+    let all = match list(dir).await {
+        Ok(all) => all,
+        Err(e) => {
+            error!(error = ?e, "failed to list job logs");
+            return;
+        }
+    };
+    let retained = rule.retain(
+        all.iter(),
+        |logs| logs.modified.elapsed().unwrap_or_default(),
+        |logs| logs.size,
+    );
+    for logs in &all[retained..] {
+        match fs::remove_dir_all(&logs.dir).await {
+            Ok(()) => debug!(dir = %logs.dir.display(), "removed expired job logs"),
+            Err(e) => error!(error = ?e, dir = %logs.dir.display(), "failed to remove job logs"),
+        }
+    }
+}
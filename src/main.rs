@@ -28,46 +28,111 @@
     variant_size_differences
 )]
 
+mod artifacts;
+mod audit;
 mod auth;
+mod backup;
+mod bans;
+mod cache;
+#[cfg(feature = "chaos")]
+mod chaos;
+mod check;
+mod clock;
+mod compare;
+mod conffile;
+mod config;
+mod daily;
+mod delta;
+mod denylist;
+mod dev;
+mod devices;
+mod drain;
+mod egress;
+mod eta;
 mod examples;
+mod features;
+mod fingerprint;
+mod gc;
+mod history;
+mod incident;
 mod job;
+mod lanes;
+mod lifecycle;
+mod limits;
+mod logs;
+mod net;
+mod output;
+mod policy;
+mod proxy;
+mod queue;
+mod ratelimit;
+mod redact;
+mod redirect;
+mod restart;
 mod secret;
+mod security;
+mod settings;
+mod signing;
+mod spool;
+mod state;
+mod storage;
+mod supervisor;
 mod templates;
-
-use self::auth::{Key, User};
+mod uploads;
+mod wasi;
+mod wasm;
+mod webhooks;
+
+use self::auth::{ApiUser, Key, Role, Roles, User};
+use self::bans::Unbanned;
+use self::clock::Instant;
 use self::examples::Examples;
-use self::job::Job;
-use self::templates::{HtmlTemplate, IdxTemplate, Page};
-
-use std::collections::HashMap;
+use self::features::Feature;
+use self::job::{
+    DiskBudget, Job, Metadata, OutputExceeded, OutputLimits, Reason, Reservation, Transport,
+};
+use self::lanes::UploadLanes;
+use self::logs::Stream;
+use self::output::{Cap, CapAction, Chunk};
+use self::proxy::Proxy;
+use self::security::Quota;
+use self::spool::Spool;
+use self::storage::Storage;
+use self::templates::{HtmlTemplate, IdxTemplate, JobTemplate, Page};
+use self::uploads::UploadId;
+
+use std::collections::{HashMap, HashSet};
 use std::env::temp_dir;
-use std::ffi::{OsStr, OsString};
-use std::io::Write;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::ffi::OsString;
+use std::fmt::{self, Display};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::ops::Range;
-use std::path::{Path, PathBuf};
-use std::time::Duration;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
 
-use anyhow::Context as _;
+use anyhow::{bail, Context as _};
+use axum::body::Bytes;
 use axum::extract::multipart::Field;
-use axum::extract::{Multipart, Path as AxumPath};
+use axum::extract::ws::{Message, WebSocketUpgrade};
+use axum::extract::{ContentLengthLimit, MatchedPath, Multipart, Path as AxumPath, Query};
+use axum::headers::ContentLength;
+use axum::http::header::RETRY_AFTER;
 use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response};
-use axum::routing::{get, post};
-use axum::{Json, Router, Server};
+use axum::routing::{delete, get, post, put};
+use axum::{middleware, Json, Router, Server, TypedHeader};
 use axum_extra::extract::CookieJar;
-use clap::Parser;
-use confargs::{args, prefix_char_filter, Toml};
+use clap::{Parser, ValueEnum};
 use enarx_config::{Config, File, Protocol};
-use futures_util::{stream, StreamExt};
 use humansize::{file_size_opts as options, FileSize};
-use once_cell::sync::{Lazy, OnceCell};
+use once_cell::sync::Lazy;
+use serde::Deserialize;
 use serde_json::json;
 use tempfile::NamedTempFile;
 use tokio::fs::read_to_string;
-use tokio::io::{AsyncRead, AsyncReadExt};
 use tokio::sync::RwLock;
-use tokio::time::{sleep, timeout};
+use tokio::time::{sleep, sleep_until};
 use tower_http::{
     trace::{
         DefaultOnBodyChunk, DefaultOnEos, DefaultOnFailure, DefaultOnRequest, DefaultOnResponse,
@@ -75,53 +140,75 @@ use tower_http::{
     },
     LatencyUnit,
 };
-use tracing::{error, info, Level};
+use tracing::{error, info, warn, Level};
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 use uuid::Uuid;
 
-// TODO: raise this when this is fixed: https://github.com/profianinc/benefice/issues/75
-const READ_TIMEOUT: Duration = Duration::from_millis(500);
+/// Interval between polls of the output of a job until it outputs anything.
+const FIRST_OUTPUT_POLL: Duration = Duration::from_millis(250);
 
 /// Maximum size of Enarx.toml in bytes
 const MAX_CONF_SIZE: usize = 256 * 1024; // 256 KiB
 
+/// Factor by which uploaded wasm modules may exceed the size limit to still be analyzed.
+const OVERSIZE_FACTOR: usize = 2;
+
+/// Maximum time allowed for fetching a workload file from a URL.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Interval between checks for jobs which have exited on their own.
+const REAP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long an exited job is kept, so that its output and exit status can still be retrieved.
+const REAP_GRACE: Duration = Duration::from_secs(5 * 60);
+
+/// Name of the retained wasm file of uploaded workloads.
+const WORKLOAD_WASM: &str = "main.wasm";
+
+/// Name of the retained config file of uploaded workloads.
+const WORKLOAD_CONF: &str = "Enarx.toml";
+
 /// Active jobs
 static JOBS: Lazy<RwLock<HashMap<User, RwLock<Job>>>> = Lazy::new(Default::default);
 
-/// Examples
-static EXAMPLES: OnceCell<Examples> = OnceCell::new();
-
 /// Demo workload executor.
 ///
 /// Any command-line options listed here may be specified by one or
 /// more configuration files, which can be used by passing the
 /// name of the file on the command-line with the syntax `@config.toml`.
-/// The configuration file must contain valid TOML table mapping argument
-/// names to their values.
+/// The configuration file must contain a valid TOML table mapping argument
+/// names to their values, optionally grouped in sections such as `[limits]`,
+/// `[oidc]` and `[ports]`. Options which may be given multiple times take arrays.
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
 struct Args {
     /// Address to bind to.
+    /// Use `[::]:3000` to listen on both IPv4 and IPv6.
     #[arg(long, default_value_t = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 3000))]
     addr: SocketAddr,
 
     /// Externally accessible root URL.
     /// For example: https://benefice.example.com
-    #[arg(long)]
-    url: auth::Url,
+    #[arg(long, required_unless_present = "dev_profile")]
+    url: Option<auth::Url>,
 
-    /// Externally accessible domain name for serving demos.
+    /// Externally accessible domain name or IP address for serving demos.
     /// This should not be the same as the benefice server URL for security reasons.
     /// For example: demo.example.com
-    #[arg(long)]
-    demo_fqdn: String,
+    #[arg(long, required_unless_present = "dev_profile")]
+    demo_fqdn: Option<DemoHost>,
 
     /// Maximum jobs.
     /// Defaults to 16x the number of cores on the system.
     #[arg(long, default_value_t = num_cpus::get() * 16)]
     jobs: usize,
 
+    /// Longest time (in seconds) a submission waits in a queue for a slot while all slots
+    /// are taken, instead of being rejected right away. 0 disables the queue.
+    #[arg(long, default_value_t = 0)]
+    queue_max_wait: u64,
+
     /// Default file size limit (in MiB).
     #[arg(long, default_value_t = 10)]
     size_limit_default: usize,
@@ -138,6 +225,52 @@ struct Args {
     #[arg(long, default_value_t = 15 * 60)]
     timeout_starred: u64,
 
+    /// Maximum total runtime a default job may be extended to (in seconds).
+    #[arg(long, default_value_t = 15 * 60)]
+    timeout_max_default: u64,
+
+    /// Maximum total runtime a starred job may be extended to (in seconds).
+    #[arg(long, default_value_t = 60 * 60)]
+    timeout_max_starred: u64,
+
+    /// Number of jobs a default user may submit per hour. 0 means no limit.
+    #[arg(long, default_value_t = 0)]
+    submissions_default: u32,
+
+    /// Number of jobs a starred user may submit per hour. 0 means no limit.
+    #[arg(long, default_value_t = 0)]
+    submissions_starred: u32,
+
+    /// Number of jobs a user may run within 24 hours. 0 means no limit.
+    #[arg(long, default_value_t = 0)]
+    daily_runs: u32,
+
+    /// Total minutes the jobs of a user may run for within 24 hours. 0 means no limit.
+    #[arg(long, default_value_t = 0)]
+    daily_minutes: u32,
+
+    /// Roles of the users who get the limits of the starred tier, separated by commas.
+    /// Users with none of these roles get the default limits.
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_values_t = [Role::Starred, Role::Member]
+    )]
+    starred_roles: Vec<Role>,
+
+    /// Tiers of limits besides the default and starred ones, ranked above the starred tier
+    /// from lowest to highest. This will be parsed as TOML, with an array of `tiers` tables of
+    /// a `name`, the `roles` granting the tier, and the `size` (in MiB), `timeout`,
+    /// `timeout-max` (in seconds) and `submissions` (per hour) limits of the tier.
+    #[arg(long)]
+    tiers: Option<policy::ExtraTiers>,
+
+    /// GitHub repository whose stargazers get the `starred` role when they log in, as
+    /// `<owner>/<name>`. May be given multiple times.
+    #[arg(long, default_value = auth::github::ENARX)]
+    star_repo: Vec<auth::github::Repo>,
+
     /// The lowest listen port to be allocated via the selected OCI container engine.
     #[arg(long, default_value_t = 1024)]
     port_min: u16,
@@ -169,8 +302,8 @@ struct Args {
     oidc_issuer: auth::Url,
 
     /// OpenID Connect client ID.
-    #[arg(long)]
-    oidc_client: String,
+    #[arg(long, required_unless_present = "dev_profile")]
+    oidc_client: Option<String>,
 
     /// Path to a file containing OpenID Connect secret.
     #[arg(long)]
@@ -180,7 +313,38 @@ struct Args {
     #[arg(long)]
     session_key: Option<secret::SecretFile<Key>>,
 
+    /// Path to a file containing a secret from which the key encrypting the session cookie is
+    /// derived. Sessions are then only kept in their cookies, which survive restarts without a
+    /// session store, but cannot be listed, ended one by one or renewed with refresh tokens.
+    #[arg(long, conflicts_with_all = ["session_key", "session_store"])]
+    cookie_secret: Option<secret::SecretFile<String>>,
+
+    /// Claim of the OpenID Connect ID token listing the groups of a user.
+    #[arg(long, default_value = "groups")]
+    oidc_roles_claim: String,
+
+    /// Role granted to the members of a group of the OpenID Connect provider, as
+    /// `<role>=<group>`, e.g. `admin=benefice-admins`. May be given multiple times.
+    /// The roles are `admin`, `starred`, `member`, `default` and `banned`.
+    #[arg(long)]
+    oidc_role: Vec<auth::GroupRole>,
+
+    /// OpenID Connect providers users can log in with besides the one given with
+    /// `--oidc-issuer`, whose users are GitHub accounts. This will be parsed as TOML, with an
+    /// array of `providers` tables of a `name` used in URLs, a `title` shown on the login page,
+    /// the `issuer` URL, the `client` ID and the path to a file containing the client `secret`.
+    /// Users of these providers are told apart by their issuer.
+    #[arg(long)]
+    oidc_providers: Option<auth::Providers>,
+
+    /// Where sessions are kept: `memory`, `redis://<host>[:<port>][/<db>]` with the `redis`
+    /// feature, or `sqlite:<path>` with the `sqlite` feature. Sessions kept in Redis or SQLite
+    /// survive restarts if `--session-key` is given.
+    #[arg(long, default_value = "memory")]
+    session_store: auth::SessionStore,
+
     /// Session cookie time to live (in minutes).
+    /// Sessions of providers issuing refresh tokens are renewed once half of it has passed.
     #[arg(long, default_value_t = 24 * 60)]
     session_ttl: u64,
 
@@ -197,38 +361,339 @@ struct Args {
     #[arg(long)]
     paths: Vec<PathBuf>,
 
+    /// Memory limit of each workload (in MiB, 0 to disable).
+    #[arg(long, default_value_t = 0)]
+    memory_limit: u64,
+
+    /// CPU time limit of each workload (in seconds, 0 to disable).
+    /// Unlike the timeout, this only accounts for time the workload spends running.
+    #[arg(long, default_value_t = 0)]
+    cpu_limit: u64,
+
+    /// Total output of each workload read by its owner (in MiB, 0 to disable).
+    /// Workloads exceeding it are killed once the output beyond it is read.
+    /// Conflicts with `--output-max-bytes`, which caps the output as it is captured instead.
+    #[arg(long, default_value_t = 0)]
+    output_limit: u64,
+
+    /// Lines of output each workload may print per second (0 to disable).
+    /// Workloads exceeding it are paused until they are resumed by their owner.
+    #[arg(long, default_value_t = 0)]
+    output_rate_limit: u64,
+
+    /// Disk space budget for the files of each workload (in MiB, 0 to disable).
+    #[arg(long, default_value_t = 0)]
+    disk_budget_job: u64,
+
+    /// Disk space budget for the files of all workloads combined (in MiB, 0 to disable).
+    /// New workloads are refused while it is used up.
+    #[arg(long, default_value_t = 0)]
+    disk_budget_total: u64,
+
+    /// Size of uploads above which they are handled in a separate lane (in MiB),
+    /// so that large uploads cannot hold up small ones.
+    #[arg(long, default_value_t = 5)]
+    large_upload_threshold: u64,
+
+    /// Maximum number of large uploads handled concurrently.
+    #[arg(long, default_value_t = 2)]
+    large_upload_concurrency: usize,
+
+    /// Size of uploaded files up to which they are kept in memory (in KiB), 0 to disable.
+    /// Larger files are spilled to the runtime directory.
+    #[arg(long, default_value_t = 1024)]
+    spool_threshold: usize,
+
+    /// Memory-backed directory, where uploaded files kept in memory are stored.
+    #[arg(long, default_value = "/dev/shm")]
+    spool_dir: PathBuf,
+
+    /// Size limit of the artifacts of each workload (in MiB), which workloads write to the
+    /// directory declared as `artifacts` in their Enarx.toml to be downloaded after they exited.
+    #[arg(long, default_value_t = 16)]
+    artifacts_limit: u64,
+
+    /// Size limit of the log of each output stream of each job (in MiB), which keeps the
+    /// complete output of the job to be downloaded during and after the run.
+    #[arg(long, default_value_t = 16)]
+    log_limit: u64,
+
+    /// Cap of the output of each job across both streams (in bytes, 0 to disable), enforced as
+    /// the output is captured, whether it is read or not.
+    /// Conflicts with `--output-limit`: as output is read only after it is captured, the cap
+    /// would either make the limit unreachable or apply a second, smaller limit of its own.
+    #[arg(long, default_value_t = 0)]
+    output_max_bytes: u64,
+
+    /// What happens once a job exceeds the output cap.
+    #[arg(long, value_enum, default_value = "truncate")]
+    output_max_action: CapAction,
+
+    /// Number of times to retry starting a workload after a transient failure,
+    /// e.g. because the host is temporarily out of resources.
+    #[arg(long, default_value_t = 3)]
+    spawn_retries: u32,
+
+    /// Systemd slice to place workloads in, e.g. `benefice.slice`.
+    /// The workloads of each user are placed in a child slice named after their user ID.
+    #[arg(long)]
+    cgroup_slice: Option<String>,
+
+    /// Directory to retain the files of uploaded workloads in, so that users can download them.
+    #[arg(long)]
+    storage_dir: Option<PathBuf>,
+
+    /// Path-style URL of an S3 bucket to retain the files of uploaded workloads in,
+    /// instead of a local directory. Downloads are served using presigned URLs.
+    /// For example: https://s3.us-east-1.amazonaws.com/benefice
+    #[arg(
+        long,
+        conflicts_with = "storage_dir",
+        requires_all = ["s3_access_key_id", "s3_secret_access_key"]
+    )]
+    s3_url: Option<reqwest::Url>,
+
+    /// Region of the S3 bucket.
+    #[arg(long, default_value = "us-east-1")]
+    s3_region: String,
+
+    /// Access key ID used to access the S3 bucket.
+    #[arg(long)]
+    s3_access_key_id: Option<String>,
+
+    /// Path to a file containing the secret access key used to access the S3 bucket.
+    #[arg(long)]
+    s3_secret_access_key: Option<secret::SecretFile<String>>,
+
+    /// Retention policy of retained workloads, run history, flagged submissions and job logs.
+    /// This will be parsed as TOML, with a table of `max-age` (in seconds), `max-count`
+    /// and `max-size` (in bytes) limits per category.
+    #[arg(long)]
+    gc_policy: Option<gc::Policy>,
+
+    /// Domain whose subdomains `<job ID>-<port>.<domain>` are routed to the HTTP ports of jobs,
+    /// as an alternative to exposing the ports on the demo host directly.
+    /// The domain must have a wildcard DNS record pointing to this server.
+    /// For example: demo.example.com
+    #[arg(long)]
+    proxy_domain: Option<String>,
+
+    /// Whether to also kill the job of a user who is logged out via OpenID Connect
+    /// back-channel logout.
+    /// Back-channel logout requests are accepted at `/backchannel-logout`.
+    #[arg(long)]
+    kill_on_logout: bool,
+
+    /// Features to disable on startup. Administrators can toggle them at runtime.
+    #[arg(long, value_enum)]
+    disabled_features: Vec<Feature>,
+
+    /// GitHub user IDs of the administrators of this instance.
+    #[arg(long)]
+    admins: Vec<u64>,
+
+    /// GitHub organization, or team of one as `<org>/<team>`, whose members get the
+    /// `member` role when they log in. May be given multiple times.
+    /// For example: enarx/maintainers
+    #[arg(long)]
+    github_org: Vec<auth::github::Org>,
+
+    /// Path to a file containing a GitHub token of a member of the organizations, used to
+    /// see private membership and membership of teams.
+    #[arg(long)]
+    github_token: Option<secret::SecretFile<String>>,
+
+    /// How long stars and organization membership looked up on GitHub are cached for
+    /// (in seconds). Lookups older than that are refreshed in the background.
+    #[arg(long, default_value_t = 60 * 60)]
+    github_cache_ttl: u64,
+
+    /// File containing SHA-256 digests of banned wasm workloads, one per line.
+    /// Digests banned via the admin API are saved to this file.
+    #[arg(long)]
+    banned_hashes: Option<PathBuf>,
+
+    /// File containing OIDC subjects of users, e.g. `github|1234` or `keycloak:f81d4fae` for
+    /// users of other providers, and networks in CIDR notation banned from submitting
+    /// workloads, one per line.
+    /// Bans made via the admin API are saved to this file.
+    #[arg(long)]
+    ban_file: Option<PathBuf>,
+
+    /// Rhai script deciding the limits which apply to each request, instead of the tiers
+    /// alone. Requires the `scripting` feature.
+    #[arg(long)]
+    limit_policy: Option<PathBuf>,
+
+    /// Hosts and networks workloads may connect to, as domain names, which also match
+    /// their subdomains, IP addresses or networks in CIDR notation.
+    /// If specified, all other outbound connections in Enarx.toml are rejected.
+    /// Connections to internal networks are rejected unless allowed here.
+    #[arg(long)]
+    egress_allow: Vec<egress::Rule>,
+
+    /// Hosts and networks workloads may not connect to, in the same format as `--egress-allow`.
+    #[arg(long)]
+    egress_deny: Vec<egress::Rule>,
+
+    /// Regular expression whose matches are masked in the output of jobs,
+    /// e.g. to hide IP addresses or tokens printed by workloads. May be given multiple times.
+    #[arg(long)]
+    redact: Vec<redact::Rule>,
+
+    /// Signers whose cosign signatures of uploaded workloads are trusted,
+    /// as `<name>=<path to the PEM encoded public key>`.
+    /// Signatures are uploaded in the `wasm_sig` field alongside the workload.
+    #[arg(long)]
+    trusted_signers: Vec<signing::Signer>,
+
+    /// Whether to reject uploaded workloads which are not signed by a trusted signer.
+    #[arg(long, requires = "trusted_signers")]
+    require_signatures: bool,
+
     /// Whether to run the container in privileged mode.
     #[arg(long)]
     privileged: bool,
 
+    /// Whether to run the container in the host network namespace instead of mapping its ports.
+    /// The listen ports of uploaded workloads are rewritten to free ports from the port range,
+    /// while Drawbridge workloads are rejected if their ports are in use.
+    #[arg(long)]
+    host_network: bool,
+
+    /// Whether to report the WASI capabilities exercised by workloads on the job page,
+    /// as traced by Enarx on the standard error of the job.
+    #[arg(long)]
+    wasi_report: bool,
+
+    /// Enarx backends uploaded workloads can be compared across, e.g. `sgx` and `kvm`.
+    /// Comparisons are available to administrators and users who have starred Enarx.
+    #[arg(long)]
+    backends: Vec<String>,
+
+    /// Path to a template of the body of job callbacks, replacing the default JSON body.
+    /// The `{{uuid}}`, `{{status}}`, `{{outcome}}` and `{{link}}` placeholders are replaced
+    /// by the ID of the job, why it ended, a description of how it ended and a link to its page.
+    #[arg(long)]
+    webhook_template: Option<PathBuf>,
+
+    /// URL security-relevant events are posted to as JSON, e.g. hits of the denylist, users
+    /// repeatedly exceeding their quotas, administrative actions and denied attempts to
+    /// administer this instance. The events are recorded in the audit log as well.
+    #[arg(long)]
+    security_webhook: Option<reqwest::Url>,
+
+    /// Time to keep serving requests after SIGTERM before shutting down (in seconds),
+    /// during which `/readyz` reports this instance as not ready, so that load balancers
+    /// stop sending new users to it.
+    #[arg(long, default_value_t = 0)]
+    shutdown_delay: u64,
+
+    /// Path of the append-only audit log, to which logins, submitted jobs, how they ended
+    /// and API token changes are appended as JSON lines.
+    #[arg(long)]
+    audit_log: Option<PathBuf>,
+
+    /// Format of the logs. The `json` format includes the route, job ID, user ID
+    /// and response status of requests as fields.
+    /// Setting the `RUST_LOG_JSON` environment variable also selects the `json` format.
+    #[arg(long, value_enum, default_value = "text")]
+    log_format: LogFormat,
+
+    /// Checks the configuration and the external dependencies of this instance, such as
+    /// the OpenID Connect issuer, the OCI container engine and the Enarx image,
+    /// and exits with a report.
+    #[arg(long)]
+    check_config: bool,
+
+    /// Runs this instance for local development, with small limits and short timeouts,
+    /// a mock container engine which echoes the standard input of jobs instead of running
+    /// Enarx, a login without OpenID Connect as a local administrator and bundled examples.
+    /// `--url` and `--demo-fqdn` default to serving on localhost, and the server only listens
+    /// on loopback addresses, as anyone who can reach it logs in as an administrator.
+    /// Never use it in production.
+    #[arg(long)]
+    dev_profile: bool,
+
     /// Examples to be displayed on the examples page. If none are provided some built-in examples will be provided.
     /// This will be parsed as TOML.
     #[arg(long)]
     examples: Option<Examples>,
+
+    /// Directory of prebuilt examples to be displayed on the examples page.
+    /// Each example is a subdirectory containing `main.wasm`, `Enarx.toml`
+    /// and, optionally, a `description.txt`.
+    #[arg(long)]
+    examples_dir: Option<PathBuf>,
 }
 
 impl Args {
-    fn split(self) -> (Limits, auth::Oidc, Other) {
+    fn split(mut self) -> anyhow::Result<(Limits, auth::Oidc, Other)> {
+        if self.dev_profile {
+            dev::apply(&mut self)?;
+        }
+        if self.output_limit > 0 && self.output_max_bytes > 0 {
+            bail!("--output-limit and --output-max-bytes cannot be used together");
+        }
+        // These are required unless the development profile provides them.
+        let url = self.url.context("--url is required")?;
+        let demo_fqdn = self.demo_fqdn.context("--demo-fqdn is required")?;
+
         let limits = Limits {
             size_limit_default: self.size_limit_default,
             size_limit_starred: self.size_limit_starred,
             timeout_default: Duration::from_secs(self.timeout_default),
             timeout_starred: Duration::from_secs(self.timeout_starred),
+            timeout_max_default: Duration::from_secs(self.timeout_max_default),
+            timeout_max_starred: Duration::from_secs(self.timeout_max_starred),
+            submissions_default: (self.submissions_default > 0).then_some(self.submissions_default),
+            submissions_starred: (self.submissions_starred > 0).then_some(self.submissions_starred),
+            daily: daily::Limit {
+                runs: (self.daily_runs > 0).then_some(self.daily_runs),
+                minutes: (self.daily_minutes > 0).then_some(self.daily_minutes),
+            },
+            starred_roles: self.starred_roles.into_iter().collect(),
+            tiers: self.tiers.unwrap_or_default().tiers,
         };
 
+        let proxy = self
+            .proxy_domain
+            .map(|domain| Proxy::new(domain, url.scheme().into()));
+
+        let cookie_secret = self.cookie_secret.map(String::from);
         let oidc = auth::Oidc {
-            server: self.url,
+            server: url.clone(),
             issuer: self.oidc_issuer,
-            client: self.oidc_client,
+            client: self.oidc_client.unwrap_or_default(),
             secret: self.oidc_secret.map(|sf| sf.into()),
             session_ttl: Duration::from_secs(self.session_ttl * 60),
-            session_key: self.session_key.map(|k| k.into()).unwrap_or_default(),
+            session_key: match &cookie_secret {
+                Some(secret) => Key::derive(secret),
+                None => self.session_key.map(|k| k.into()).unwrap_or_default(),
+            },
+            session_store: match cookie_secret {
+                Some(_) => auth::SessionStore::Cookie,
+                None => self.session_store,
+            },
+            admins: self.admins.into_iter().collect(),
+            roles_claim: self.oidc_roles_claim,
+            role_groups: self.oidc_role,
+            providers: self.oidc_providers.unwrap_or_default().providers,
+            star_repos: self.star_repo.clone(),
+            github_orgs: self.github_org,
+            github_token: self.github_token.map(|sf| sf.into()),
+            github_cache_ttl: Duration::from_secs(self.github_cache_ttl),
+            kill_on_logout: self.kill_on_logout,
+            dev: self.dev_profile,
         };
 
         let other = Other {
-            demo_fqdn: self.demo_fqdn,
+            demo_fqdn: demo_fqdn.to_string(),
             addr: self.addr,
+            url,
             jobs_max: self.jobs,
+            queue_max_wait: (self.queue_max_wait > 0)
+                .then_some(Duration::from_secs(self.queue_max_wait)),
             port_range: self.port_min..self.port_max,
             listen_max: if self.listen_max == 0 {
                 None
@@ -238,18 +703,126 @@ impl Args {
             ss_command: self.ss_command,
             oci_command: self.oci_command,
             oci_image: self.oci_image,
-            runtime_dir: self.runtime_dir,
+            runtime_dir: self.runtime_dir.clone(),
             devices: self.devices,
             paths: self.paths,
             privileged: self.privileged,
+            signing: signing::Policy {
+                signers: self.trusted_signers,
+                required: self.require_signatures,
+            },
+            egress: egress::Policy {
+                allow: self.egress_allow,
+                deny: self.egress_deny,
+            },
+            host_network: self.host_network,
+            wasi_report: self.wasi_report,
+            backends: self.backends,
+            webhook_template: self.webhook_template,
+            security_webhook: self.security_webhook,
+            shutdown_delay: Duration::from_secs(self.shutdown_delay),
+            check_config: self.check_config,
+            dev_profile: self.dev_profile,
+            log_format: self.log_format,
+            audit_log: self.audit_log,
+            redact: self.redact,
+            memory_limit: if self.memory_limit == 0 {
+                None
+            } else {
+                Some(self.memory_limit)
+            },
+            cpu_limit: if self.cpu_limit == 0 {
+                None
+            } else {
+                Some(self.cpu_limit)
+            },
+            cgroup_slice: self.cgroup_slice,
+            output_limits: OutputLimits {
+                bytes: (self.output_limit > 0).then_some(self.output_limit * 1024 * 1024),
+                lines_per_sec: (self.output_rate_limit > 0).then_some(self.output_rate_limit),
+            },
+            disk_budget: DiskBudget {
+                job: (self.disk_budget_job > 0).then_some(self.disk_budget_job * 1024 * 1024),
+                total: (self.disk_budget_total > 0).then_some(self.disk_budget_total * 1024 * 1024),
+            },
+            upload_lanes: UploadLanes::new(
+                self.large_upload_threshold * 1024 * 1024,
+                self.large_upload_concurrency,
+            ),
+            spool: Spool::new(
+                (self.spool_threshold > 0).then_some(self.spool_threshold * 1024),
+                self.spool_dir,
+                self.runtime_dir,
+            ),
+            artifacts_limit: self.artifacts_limit * 1024 * 1024,
+            log_limit: self.log_limit * 1024 * 1024,
+            output_cap: (self.output_max_bytes > 0).then_some(Cap {
+                bytes: self.output_max_bytes,
+                action: self.output_max_action,
+            }),
+            spawn_retries: self.spawn_retries,
+            storage: match (self.storage_dir, self.s3_url) {
+                (_, Some(url)) => Some(Storage::S3(storage::S3 {
+                    url,
+                    region: self.s3_region,
+                    access_key_id: self.s3_access_key_id.unwrap_or_default(),
+                    secret_access_key: self
+                        .s3_secret_access_key
+                        .map(|sf| sf.into())
+                        .unwrap_or_default(),
+                })),
+                (Some(dir), None) => Some(Storage::Local(dir)),
+                (None, None) => None,
+            },
+            proxy,
+            gc_policy: self.gc_policy.unwrap_or_default(),
+            disabled_features: self.disabled_features,
             examples: self.examples,
+            examples_dir: self.examples_dir,
+            banned_hashes: self.banned_hashes,
+            ban_file: self.ban_file,
+            limit_policy: self.limit_policy,
+            star_repos: self.star_repo,
         };
 
-        (limits, oidc, other)
+        Ok((limits, oidc, other))
+    }
+}
+
+/// Format of the logs.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum LogFormat {
+    /// Human readable lines.
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+/// Host serving demos, formatted for use in URLs.
+#[derive(Clone, Debug)]
+struct DemoHost(auth::Host);
+
+impl FromStr for DemoHost {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Accept IPv6 literals both with and without brackets.
+        if let Ok(addr) = s.parse::<Ipv6Addr>() {
+            return Ok(Self(auth::Host::Ipv6(addr)));
+        }
+        auth::Host::parse(s)
+            .map(Self)
+            .with_context(|| format!("invalid host `{s}`"))
+    }
+}
+
+impl Display for DemoHost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
     }
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Clone, Debug)]
 struct Limits {
     /// Size in megabytes
     size_limit_default: usize,
@@ -257,9 +830,36 @@ struct Limits {
     size_limit_starred: usize,
     timeout_default: Duration,
     timeout_starred: Duration,
+    /// Maximum total runtime a job may be extended to
+    timeout_max_default: Duration,
+    /// Maximum total runtime a job of a starred user may be extended to
+    timeout_max_starred: Duration,
+    /// Number of jobs a user may submit per hour, if limited
+    submissions_default: Option<u32>,
+    /// Number of jobs a starred user may submit per hour, if limited
+    submissions_starred: Option<u32>,
+    /// Daily quota of each user
+    daily: daily::Limit,
+    /// Roles of the users who get the limits of the starred tier
+    starred_roles: Roles,
+    /// Tiers defined by the operators, from lowest to highest
+    tiers: Vec<policy::Tier>,
 }
 
 impl Limits {
+    /// Returns whether a user with `roles` gets the limits of the starred tier.
+    fn is_starred(&self, roles: Roles) -> bool {
+        roles.intersects(self.starred_roles)
+    }
+
+    /// Returns the highest tier defined by the operators a user with `roles` gets, if any.
+    fn tier(&self, roles: Roles) -> Option<&policy::Tier> {
+        self.tiers
+            .iter()
+            .rev()
+            .find(|tier| roles.intersects(tier.roles))
+    }
+
     fn time_to_live(&self, star: bool) -> Duration {
         if star {
             self.timeout_starred
@@ -268,6 +868,17 @@ impl Limits {
         }
     }
 
+    /// Get the maximum total runtime a job may be extended to, which is never less
+    /// than its initial time to live.
+    fn max_time_to_live(&self, star: bool) -> Duration {
+        let max = if star {
+            self.timeout_max_starred
+        } else {
+            self.timeout_max_default
+        };
+        max.max(self.time_to_live(star))
+    }
+
     /// Get the maximum allowed wasm size in bytes.
     fn size(&self, star: bool) -> usize {
         let size_megabytes = if star {
@@ -278,13 +889,18 @@ impl Limits {
         size_megabytes * 1024 * 1024
     }
 
-    fn size_human(&self, star: bool) -> String {
-        self.size(star)
-            .file_size(options::CONVENTIONAL)
-            .unwrap_or_else(|e| {
-                error!(error = ?e, "Failed to get human readable size string");
-                "?".to_string()
-            })
+    /// Get the number of jobs a user may submit per hour, if limited.
+    fn submissions(&self, star: bool) -> Option<u32> {
+        if star {
+            self.submissions_starred
+        } else {
+            self.submissions_default
+        }
+    }
+
+    /// Decides the limits which apply in `ctx` with the limit policy of this instance.
+    fn decide(&self, ctx: &policy::Context) -> policy::Decision {
+        policy::get().decide(self, ctx)
     }
 }
 
@@ -292,7 +908,10 @@ impl Limits {
 struct Other {
     demo_fqdn: String,
     addr: SocketAddr,
+    url: auth::Url,
     jobs_max: usize,
+    /// Longest time a submission waits for a slot, if submissions are queued.
+    queue_max_wait: Option<Duration>,
     port_range: Range<u16>,
     listen_max: Option<u16>,
     ss_command: OsString,
@@ -302,159 +921,857 @@ struct Other {
     devices: Vec<PathBuf>,
     paths: Vec<PathBuf>,
     privileged: bool,
+    signing: signing::Policy,
+    egress: egress::Policy,
+    host_network: bool,
+    wasi_report: bool,
+    backends: Vec<String>,
+    webhook_template: Option<PathBuf>,
+    security_webhook: Option<reqwest::Url>,
+    /// Time to keep serving requests after SIGTERM.
+    shutdown_delay: Duration,
+    /// Whether to only check the configuration.
+    check_config: bool,
+    /// Whether the development profile is enabled.
+    dev_profile: bool,
+    log_format: LogFormat,
+    audit_log: Option<PathBuf>,
+    redact: Vec<redact::Rule>,
+    memory_limit: Option<u64>,
+    cpu_limit: Option<u64>,
+    cgroup_slice: Option<String>,
+    output_limits: OutputLimits,
+    disk_budget: DiskBudget,
+    upload_lanes: UploadLanes,
+    spool: Spool,
+    /// Size limit of the artifacts of each workload in bytes.
+    artifacts_limit: u64,
+    /// Size limit of the log of each output stream of each job in bytes.
+    log_limit: u64,
+    output_cap: Option<Cap>,
+    spawn_retries: u32,
+    storage: Option<Storage>,
+    proxy: Option<Proxy>,
+    gc_policy: gc::Policy,
+    disabled_features: Vec<Feature>,
     examples: Option<Examples>,
+    examples_dir: Option<PathBuf>,
+    banned_hashes: Option<PathBuf>,
+    ban_file: Option<PathBuf>,
+    limit_policy: Option<PathBuf>,
+    star_repos: Vec<auth::github::Repo>,
 }
 
-async fn read_chunk(mut rdr: impl AsyncRead + Unpin) -> Result<Vec<u8>, StatusCode> {
-    let mut buf = [0; 4096];
-    match timeout(READ_TIMEOUT, rdr.read(&mut buf)).await {
-        Ok(Err(e)) => {
-            error!(error = ?e, "failed to read chunk");
-            Err(StatusCode::INTERNAL_SERVER_ERROR)
-        }
-        Ok(Ok(size)) => Ok(buf[..size].to_vec()),
-        Err(..) => Ok(Vec::new()),
-    }
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct OutputQuery {
+    /// Offset in the stream to read from, instead of after the output read last.
+    offset: Option<u64>,
+    /// Whether to read both streams merged in order of arrival, with each line tagged with
+    /// its stream.
+    merged: bool,
 }
 
-async fn read_stdout(AxumPath(id): AxumPath<String>, user: User) -> Result<Vec<u8>, StatusCode> {
-    if let Some(job) = JOBS.read().await.get(&user) {
-        let mut lock = job.write().await;
-
-        if lock.id != id {
-            // The client is requesting a job that doesn't exist.
-            return Err(StatusCode::NOT_FOUND);
+/// Reads a chunk of `stream` of the job with `id` of `user`, or of the merged output if `None`,
+/// from `offset`, or after the output read last, enforcing the output `limits` of the job on
+/// the output not read before.
+async fn read_output(
+    id: &str,
+    user: User,
+    stream: Option<Stream>,
+    offset: Option<u64>,
+    limits: OutputLimits,
+) -> Result<Chunk, StatusCode> {
+    #[cfg(feature = "chaos")]
+    chaos::slow_read().await;
+
+    // The output is read without locking the job, which may be queried or controlled
+    // in the meantime.
+    let output = match JOBS.read().await.get(&user) {
+        Some(job) => {
+            let lock = job.read().await;
+            if lock.id != id {
+                // The client is requesting a job that doesn't exist.
+                return Err(StatusCode::NOT_FOUND);
+            }
+            lock.output.clone().ok_or_else(|| {
+                error!(%user, job_id = id, "job output is not captured");
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?
         }
+        None => return Err(StatusCode::NOT_FOUND),
+    };
+    let (mut chunk, exceeded) = match stream {
+        Some(stream) => output.read(stream, offset, limits),
+        None => output.read_merged(offset, limits),
+    };
 
-        if let Some(stdout) = lock.exec.stdout.as_mut() {
-            read_chunk(stdout).await
-        } else {
-            error!(%user, job_id = id, "job is missing STDOUT");
-            Err(StatusCode::INTERNAL_SERVER_ERROR)
+    // Once the job has exited, explain why it was killed, if it exceeded a limit.
+    let drained = match stream {
+        Some(Stream::Out) => false,
+        Some(Stream::Err) => output.is_drained(Stream::Err),
+        None => output.is_drained(Stream::Out) && output.is_drained(Stream::Err),
+    };
+    if chunk.data.is_empty() && drained {
+        if let Some(job) = JOBS.read().await.get(&user) {
+            let mut lock = job.write().await;
+            if lock.id == id {
+                if let Some(notice) = lock.termination_notice().await {
+                    chunk.data.extend_from_slice(notice.as_bytes());
+                    return Ok(chunk);
+                }
+            }
         }
-    } else {
-        Err(StatusCode::NOT_FOUND)
     }
+    #[cfg(feature = "chaos")]
+    if chaos::drop_output().await {
+        chunk.data.clear();
+    }
+    chunk.data = enforce_output_limits(user, id, exceeded, chunk.data).await;
+    Ok(chunk)
 }
 
-async fn read_stderr(AxumPath(id): AxumPath<String>, user: User) -> Result<Vec<u8>, StatusCode> {
-    if let Some(job) = JOBS.read().await.get(&user) {
-        let mut lock = job.write().await;
+/// Reads a chunk of the standard output of a job, or of the merged output if `merged` is set.
+/// The offset of the chunk in the stream and the offset to read the next chunk from are
+/// returned in the `X-Output-Offset` and `X-Output-Next` headers.
+async fn read_stdout(
+    AxumPath(id): AxumPath<String>,
+    user: User,
+    Query(OutputQuery { offset, merged }): Query<OutputQuery>,
+    limits: OutputLimits,
+) -> Result<Chunk, StatusCode> {
+    let stream = (!merged).then_some(Stream::Out);
+    read_output(&id, user, stream, offset, limits).await
+}
+
+/// Reads a chunk of the standard error of a job, like `read_stdout`.
+async fn read_stderr(
+    AxumPath(id): AxumPath<String>,
+    user: User,
+    Query(OutputQuery { offset, merged }): Query<OutputQuery>,
+    limits: OutputLimits,
+) -> Result<Chunk, StatusCode> {
+    let stream = (!merged).then_some(Stream::Err);
+    read_output(&id, user, stream, offset, limits).await
+}
 
-        if lock.id != id {
-            // The client is requesting a job that doesn't exist.
-            return Err(StatusCode::NOT_FOUND);
+/// Pauses or kills the job with `id` of `user` if it has `exceeded` an output limit,
+/// appending a notice explaining why to the `chunk` of output read last.
+async fn enforce_output_limits(
+    user: User,
+    id: &str,
+    exceeded: Option<OutputExceeded>,
+    mut chunk: Vec<u8>,
+) -> Vec<u8> {
+    match exceeded {
+        None => {}
+        Some(OutputExceeded::Rate(max)) => {
+            if let Some(job) = JOBS.read().await.get(&user) {
+                let mut job = job.write().await;
+                if job.id == id && !job.is_paused() && job.pause().await.is_ok() {
+                    warn!(job_id = id, %user, max, "paused job exceeding its output rate");
+                    security::quota_exceeded(user, Quota::OutputRate).await;
+                    let notice = format!(
+                        "\nYour workload printed more than {max} lines per second \
+                        and was paused, resume it to continue\n"
+                    );
+                    chunk.extend_from_slice(notice.as_bytes());
+                }
+            }
+        }
+        Some(OutputExceeded::Bytes(max)) => {
+            warn!(job_id = id, %user, max, "killing job exceeding its output limit");
+            kill_job(user, id, Reason::OutputLimit).await;
+            security::quota_exceeded(user, Quota::Output).await;
+            let max = max
+                .file_size(options::CONVENTIONAL)
+                .unwrap_or_else(|_| format!("{max} B"));
+            chunk.extend_from_slice(
+                format!("\nYour workload exceeded the output limit of {max} and was killed\n")
+                    .as_bytes(),
+            );
         }
+    }
+    chunk
+}
 
-        if let Some(stdout) = lock.exec.stderr.as_mut() {
-            read_chunk(stdout).await
-        } else {
-            error!(%user, job_id = id, "job is missing STDERR");
-            Err(StatusCode::INTERNAL_SERVER_ERROR)
+/// Kills the job with `id` of `user` for `reason`, unless it has been replaced already.
+/// The registry of jobs is not locked while the job is torn down.
+async fn kill_job(user: User, id: &str, reason: Reason) {
+    let job = {
+        let mut jobs = JOBS.write().await;
+        match jobs.get(&user) {
+            Some(job) if job.read().await.id == id => jobs.remove(&user),
+            _ => None,
         }
-    } else {
-        Err(StatusCode::NOT_FOUND)
+    };
+    if let Some(job) = job {
+        job.into_inner().kill(reason).await;
     }
 }
 
-#[derive(Debug, Clone, Default)]
-struct SpanMaker;
+/// Maximum size in bytes of a single write to the standard input of a job.
+const STDIN_CHUNK_MAX: u64 = 64 * 1024;
 
-impl<B> tower_http::trace::MakeSpan<B> for SpanMaker {
-    fn make_span(&mut self, request: &axum::http::request::Request<B>) -> tracing::span::Span {
-        let reqid = uuid::Uuid::new_v4();
-        tracing::span!(
-            Level::INFO,
-            "request",
-            method = %request.method(),
-            uri = %request.uri(),
-            version = ?request.version(),
-            headers = ?request.headers(),
-            request_id = %reqid,
-        )
+async fn write_job_stdin(user: User, id: &str, data: &[u8]) -> Result<usize, StatusCode> {
+    let jobs = JOBS.read().await;
+    let mut lock = match jobs.get(&user) {
+        Some(job) => job.write().await,
+        None => return Err(StatusCode::NOT_FOUND),
+    };
+
+    if lock.id != id {
+        // The client is requesting a job that doesn't exist.
+        return Err(StatusCode::NOT_FOUND);
     }
+    lock.write_stdin(data).await
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let (limits, oidc, other) = args::<Toml>(prefix_char_filter::<'@'>)
-        .context("Failed to parse config")
-        .map(Args::parse_from)?
-        .split();
+/// Writes the request body to the standard input of a job, responding with the number
+/// of bytes written. The rest has to be sent again once the job has consumed its input.
+async fn write_stdin(
+    AxumPath(id): AxumPath<String>,
+    user: User,
+    ContentLengthLimit(data): ContentLengthLimit<Bytes, STDIN_CHUNK_MAX>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let written = write_job_stdin(user, &id, &data).await?;
+    Ok(Json(json!({ "written": written })))
+}
 
-    let tracing_registry = tracing_subscriber::registry().with(tracing_subscriber::EnvFilter::new(
-        std::env::var("RUST_LOG").unwrap_or_else(|_| {
-            "benefice=info,example_tracing_aka_logging=debug,tower_http=debug".into()
-        }),
-    ));
-    if std::env::var("RUST_LOG_JSON").is_ok() {
-        tracing_registry
-            .with(tracing_subscriber::fmt::layer().json())
-            .init();
-    } else {
-        tracing_registry
-            .with(tracing_subscriber::fmt::layer())
-            .init();
+/// Reads the first output of the job with `id` of `user`, on either stream,
+/// unless the job ends first.
+async fn first_output(id: String, user: User) -> Option<(&'static str, Vec<u8>)> {
+    let limits = settings::other().output_limits;
+    loop {
+        let stdout = read_output(&id, user, Some(Stream::Out), None, limits)
+            .await
+            .ok()?;
+        if !stdout.data.is_empty() {
+            return Some(("stdout", stdout.data));
+        }
+        let stderr = read_output(&id, user, Some(Stream::Err), None, limits)
+            .await
+            .ok()?;
+        if !stderr.data.is_empty() {
+            return Some(("stderr", stderr.data));
+        }
+        sleep(FIRST_OUTPUT_POLL).await;
     }
+}
 
-    // Initialize the examples. If none are provided the default examples will be used.
-    EXAMPLES
-        .set(other.examples.unwrap_or_default())
-        .expect("initialize examples");
+/// Streams the messages of a WebSocket to the standard input of a job.
+async fn stdin_ws(AxumPath(id): AxumPath<String>, user: User, ws: WebSocketUpgrade) -> Response {
+    match JOBS.read().await.get(&user) {
+        Some(job) if job.read().await.id == id => {}
+        _ => return StatusCode::NOT_FOUND.into_response(),
+    }
 
-    let app = Router::new()
-        .route("/out/:id", post(read_stdout))
-        .route("/err/:id", post(read_stderr))
-        .route(
-            "/drawbridge",
-            get({
-                let demo_fqdn = other.demo_fqdn.clone();
-                move |user| root_get(user, limits, Page::Drawbridge, demo_fqdn)
-            }),
-        )
-        .route(
-            "/upload",
-            get({
-                let demo_fqdn = other.demo_fqdn.clone();
-                move |user| root_get(user, limits, Page::Upload, demo_fqdn)
-            }),
-        )
-        .route(
-            "/",
-            get({
-                let demo_fqdn = other.demo_fqdn.clone();
-                move |user| root_get(user, limits, Page::Examples, demo_fqdn)
-            })
-            .post({
-                let demo_fqdn = other.demo_fqdn.clone();
-                move |user, mp| {
-                    root_post(
-                        user,
-                        mp,
-                        limits,
-                        other.port_range,
-                        other.listen_max,
-                        other.jobs_max,
-                        other.ss_command,
-                        other.oci_command,
-                        other.oci_image,
-                        other.runtime_dir,
-                        other.devices,
-                        other.paths,
-                        other.privileged,
-                        demo_fqdn,
-                    )
+    ws.on_upgrade(move |mut socket| async move {
+        while let Some(Ok(msg)) = socket.recv().await {
+            let data = match msg {
+                Message::Binary(data) => data,
+                Message::Text(text) => text.into_bytes(),
+                Message::Close(..) => break,
+                Message::Ping(..) | Message::Pong(..) => continue,
+            };
+            // The next message is only received once the job has consumed this one.
+            let mut rest = data.as_slice();
+            while !rest.is_empty() {
+                match write_job_stdin(user, &id, rest).await {
+                    Ok(written) => rest = &rest[written..],
+                    Err(_) => return,
                 }
-            })
-            .delete(root_delete),
-        );
+            }
+        }
+    })
+}
 
-    let app = oidc.routes(app).await?;
-    let app = app.layer(
-        TraceLayer::new_for_http()
-            .make_span_with(SpanMaker::default())
-            .on_request(DefaultOnRequest::new().level(Level::INFO))
-            .on_response(
+/// Returns the state of a job, how long it has been running and how long it may still run,
+/// its ports and how much output has been read from it.
+async fn job_status(
+    AxumPath(id): AxumPath<String>,
+    user: User,
+) -> Result<impl IntoResponse, StatusCode> {
+    let jobs = JOBS.read().await;
+    let mut lock = match jobs.get(&user) {
+        Some(job) => job.write().await,
+        None => return finished_job_status(&user, &id).await,
+    };
+
+    if lock.id != id {
+        drop(lock);
+        return finished_job_status(&user, &id).await;
+    }
+
+    let status = lock.exec.try_wait().map_err(|e| {
+        error!(error = ?e, %user, job_id = id, "failed to query job status");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let outcome = match status {
+        Some(_) => lock.exit_outcome().await,
+        None => None,
+    };
+    let output = lock.output.as_ref().map_or((0, 0), |output| output.bytes());
+    Ok(Json(json!({
+        "id": lock.id,
+        "name": lock.meta.name,
+        "description": lock.meta.description,
+        "state": match status {
+            None if lock.is_paused() => "paused",
+            None => "running",
+            Some(_) => "exited",
+        },
+        "running": status.is_none(),
+        "paused": lock.is_paused(),
+        "code": status.and_then(|status| status.code()),
+        "outcome": outcome,
+        "elapsed": lock.runtime().as_secs(),
+        "remaining": lock.deadline().saturating_duration_since(clock::now()).as_secs(),
+        "ports": lock.mapped_ports,
+        "output": {
+            "stdout": output.0,
+            "stderr": output.1,
+        },
+        "wasi": status.and_then(|_| lock.wasi_report()),
+        "annotations": lock.annotations(),
+        "artifacts": status.is_some() && lock.artifacts.is_some(),
+    })))
+}
+
+/// Default extension of the timeout of a job.
+const EXTENSION_DEFAULT: u64 = 5 * 60;
+
+#[derive(Debug, Deserialize)]
+struct ExtendRequest {
+    /// Number of seconds to extend the timeout by.
+    seconds: u64,
+}
+
+/// Extends the timeout of a running job, up to the maximum runtime of the tier of the user.
+async fn extend_job(
+    AxumPath(id): AxumPath<String>,
+    user: User,
+    request: Option<Json<ExtendRequest>>,
+    limits: Limits,
+) -> Result<Json<serde_json::Value>, Response> {
+    let seconds = request.map_or(EXTENSION_DEFAULT, |Json(request)| request.seconds);
+
+    let jobs = JOBS.read().await;
+    let mut lock = match jobs.get(&user) {
+        Some(job) => job.write().await,
+        None => return Err(StatusCode::NOT_FOUND.into_response()),
+    };
+    if lock.id != id {
+        return Err(StatusCode::NOT_FOUND.into_response());
+    }
+    if !matches!(lock.exec.try_wait(), Ok(None)) {
+        return Err((StatusCode::CONFLICT, "The job has already ended").into_response());
+    }
+
+    let max = limits.decide(&policy::Context::new(user)).max_time_to_live;
+    let deadline = lock
+        .extend(Duration::from_secs(seconds), max)
+        .ok_or_else(|| {
+            (
+                StatusCode::CONFLICT,
+                "The job has already been extended to the maximum runtime",
+            )
+                .into_response()
+        })?;
+
+    let remaining = deadline.saturating_duration_since(clock::now());
+    state::extend(&id, deadline).await;
+    info!(job_id = id, %user, remaining = remaining.as_secs(), "extended job timeout");
+
+    Ok(Json(json!({
+        "id": id,
+        "remaining": remaining.as_secs(),
+    })))
+}
+
+/// Pauses a running job, stopping the clock of its timeout while it is paused.
+async fn pause_job(
+    AxumPath(id): AxumPath<String>,
+    user: User,
+) -> Result<Json<serde_json::Value>, Response> {
+    let jobs = JOBS.read().await;
+    let mut lock = match jobs.get(&user) {
+        Some(job) => job.write().await,
+        None => return Err(StatusCode::NOT_FOUND.into_response()),
+    };
+    if lock.id != id {
+        return Err(StatusCode::NOT_FOUND.into_response());
+    }
+    lock.pause().await?;
+    Ok(Json(json!({
+        "id": id,
+        "paused": true,
+    })))
+}
+
+/// Resumes a paused job, responding with the remaining time until its timeout.
+async fn resume_job(
+    AxumPath(id): AxumPath<String>,
+    user: User,
+) -> Result<Json<serde_json::Value>, Response> {
+    let jobs = JOBS.read().await;
+    let mut lock = match jobs.get(&user) {
+        Some(job) => job.write().await,
+        None => return Err(StatusCode::NOT_FOUND.into_response()),
+    };
+    if lock.id != id {
+        return Err(StatusCode::NOT_FOUND.into_response());
+    }
+    lock.resume().await?;
+    Ok(Json(json!({
+        "id": id,
+        "paused": false,
+        "remaining": lock.deadline().saturating_duration_since(clock::now()).as_secs(),
+    })))
+}
+
+/// Runs the workload of an exited job again, without uploading it again.
+async fn restart_job(
+    AxumPath(id): AxumPath<String>,
+    user: User,
+    limits: Limits,
+    other: Other,
+) -> Result<Json<serde_json::Value>, Response> {
+    if let Some(job) = JOBS.read().await.get(&user) {
+        let mut job = job.write().await;
+        if job.id == id && matches!(job.exec.try_wait(), Ok(None)) {
+            return Err((StatusCode::CONFLICT, "The job is still running").into_response());
+        }
+    }
+
+    let workload = restart::take(user, &id).await.ok_or_else(|| {
+        (
+            StatusCode::GONE,
+            "The workload of the job is no longer available, deploy it again",
+        )
+            .into_response()
+    })?;
+    info!(job_id = id, %user, "restarting job");
+    let meta = history::get(&user, &id)
+        .await
+        .map(|run| run.meta)
+        .unwrap_or_default();
+    start_job(user, workload, meta, None, None, limits, other).await
+}
+
+/// Returns the status of a job of `user` which has ended, as recorded in their history.
+async fn finished_job_status(user: &User, id: &str) -> Result<Json<serde_json::Value>, StatusCode> {
+    let run = history::get(user, id).await.ok_or(StatusCode::NOT_FOUND)?;
+    let outcome = run.outcome.ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(json!({
+        "id": id,
+        "name": run.meta.name,
+        "description": run.meta.description,
+        "state": "ended",
+        "running": false,
+        "code": outcome.code,
+        "outcome": outcome,
+        "wasi": run.wasi,
+        "annotations": run.annotations,
+    })))
+}
+
+/// Periodically removes jobs which have exited on their own, freeing their slots and files.
+async fn reap() {
+    let mut exited: HashMap<String, Instant> = HashMap::new();
+    loop {
+        sleep(REAP_INTERVAL).await;
+
+        let mut present = HashSet::new();
+        let mut expired = vec![];
+        for (user, job) in JOBS.read().await.iter() {
+            let mut job = job.write().await;
+            if !matches!(job.exec.try_wait(), Ok(Some(_))) {
+                continue;
+            }
+            if !exited.contains_key(&job.id) {
+                job.remove_files();
+                state::release_ports(&job.id).await;
+            }
+            let since = *exited.entry(job.id.clone()).or_insert_with(clock::now);
+            if since.elapsed() >= REAP_GRACE {
+                expired.push((*user, job.id.clone()));
+            }
+            _ = present.insert(job.id.clone());
+        }
+        exited.retain(|id, _| present.contains(id));
+
+        let mut reaped = vec![];
+        let mut jobs = JOBS.write().await;
+        for (user, id) in expired {
+            match jobs.get(&user) {
+                Some(job) if job.read().await.id == id => {
+                    info!(job_id = id, %user, "reaping exited job");
+                    reaped.extend(jobs.remove(&user));
+                }
+                _ => {}
+            }
+        }
+        drop(jobs);
+        for job in reaped {
+            job.into_inner().kill(Reason::Finished).await;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct SpanMaker;
+
+impl SpanMaker {
+    /// Returns the ID of the job a request to `route` with `path` is about, if any.
+    fn job_id<'a>(route: &str, path: &'a str) -> Option<&'a str> {
+        // Other resources are identified by `:id` too.
+        if ["/tokens/", "/uploads/", "/api/v1/uploads/"]
+            .iter()
+            .any(|prefix| route.starts_with(prefix))
+        {
+            return None;
+        }
+        route
+            .split('/')
+            .zip(path.split('/'))
+            .find_map(|(pattern, segment)| (pattern == ":id").then_some(segment))
+    }
+}
+
+impl<B> tower_http::trace::MakeSpan<B> for SpanMaker {
+    fn make_span(&mut self, request: &axum::http::request::Request<B>) -> tracing::span::Span {
+        let reqid = uuid::Uuid::new_v4();
+        let route = request
+            .extensions()
+            .get::<MatchedPath>()
+            .map(MatchedPath::as_str);
+        let job_id = route.and_then(|route| Self::job_id(route, request.uri().path()));
+        // The user ID is recorded once the user is authenticated.
+        tracing::span!(
+            Level::INFO,
+            "request",
+            method = %request.method(),
+            uri = %request.uri(),
+            route,
+            job_id,
+            user_id = tracing::field::Empty,
+            version = ?request.version(),
+            headers = ?request.headers(),
+            request_id = %reqid,
+        )
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let (limits, oidc, other) = conffile::args()
+        .context("Failed to parse config")
+        .map(Args::parse_from)?
+        .split()?;
+
+    let tracing_registry = tracing_subscriber::registry().with(tracing_subscriber::EnvFilter::new(
+        std::env::var("RUST_LOG").unwrap_or_else(|_| {
+            "benefice=info,example_tracing_aka_logging=debug,tower_http=debug".into()
+        }),
+    ));
+    if other.log_format == LogFormat::Json || std::env::var("RUST_LOG_JSON").is_ok() {
+        tracing_registry
+            .with(tracing_subscriber::fmt::layer().json().flatten_event(true))
+            .init();
+    } else {
+        tracing_registry
+            .with(tracing_subscriber::fmt::layer())
+            .init();
+    }
+
+    if other.dev_profile {
+        warn!("running with the development profile, never use it in production");
+        dev::init(&other.runtime_dir).context("failed to set up the development profile")?;
+    }
+
+    if other.check_config {
+        return check::run(&oidc, &other).await;
+    }
+
+    // Clean up any jobs left behind if the previous instance crashed.
+    state::recover(&other.runtime_dir, &other.oci_command)
+        .await
+        .context("failed to recover job state")?;
+
+    auth::token::load(&other.runtime_dir).context("failed to load API tokens")?;
+    daily::load(&other.runtime_dir).context("failed to load daily usage")?;
+
+    if let Some(path) = &other.audit_log {
+        audit::init(path).await?;
+    }
+
+    // The examples are initialized along with the settings which can be reloaded.
+    settings::init(limits, other.clone())?;
+
+    if let Some(path) = &other.banned_hashes {
+        denylist::load(path)?;
+    }
+
+    bans::init(oidc.subjects(), other.ban_file.as_deref())?;
+
+    if let Some(storage) = other.storage.clone() {
+        storage::init(storage)?;
+    }
+    gc::init(other.gc_policy.clone())?;
+    policy::init(other.limit_policy.as_deref())?;
+    features::init(other.disabled_features.iter().copied())?;
+    redact::init(other.redact.clone())?;
+    redirect::init(other.url.clone())?;
+    logs::init(&other.runtime_dir).await?;
+    delta::init(&other.runtime_dir).await?;
+    if let Some(path) = &other.webhook_template {
+        webhooks::init(path)?;
+    }
+    if let Some(url) = other.security_webhook.clone() {
+        security::init(url)?;
+    }
+
+    supervisor::spawn("reaper", reap);
+    supervisor::spawn("gc", gc::run);
+    supervisor::spawn("restart", restart::expire);
+    supervisor::spawn("settings", settings::watch);
+    supervisor::spawn("devices", {
+        let devices = other.devices.clone();
+        move || devices::monitor(devices.clone())
+    });
+
+    // Handlers use the current settings, which may have been reloaded since startup.
+    let app = Router::new()
+        .route("/healthz", get(supervisor::healthz))
+        .route("/readyz", get(drain::readyz))
+        .route(
+            "/admin/banned-hashes",
+            get(denylist::list).post(denylist::add),
+        )
+        .route("/admin/banned-hashes/:sha256", delete(denylist::remove))
+        .route("/admin/flagged", get(denylist::flagged))
+        .route(
+            "/admin/bans",
+            get(bans::list).post(bans::add).delete(bans::remove),
+        )
+        .route("/admin/ban", post(incident::ban_and_kill))
+        .route("/admin/users/:uid/jobs", delete(incident::kill_jobs))
+        .route("/admin/users/:uid/history", delete(incident::purge_history))
+        .route("/admin/users/:uid/tokens", delete(incident::revoke_tokens))
+        .route("/admin/ports", get(state::ports))
+        .route("/admin/features", get(features::list))
+        .route("/admin/features/:feature", put(features::set))
+        .route("/admin/reload", post(settings::reload_admin))
+        .route("/admin/drain", get(drain::get).put(drain::set))
+        .route("/admin/audit", get(audit::export))
+        .route("/admin/state", get(backup::export).put(backup::import))
+        .route("/validate", post(config::lint))
+        .route("/status/:id", get(job_status))
+        .route(
+            "/limits",
+            get(|user| limits::page(user, settings::limits(), settings::other())),
+        )
+        .route(
+            "/api/v1/limits",
+            get(|ApiUser(user)| limits::api(user, settings::limits(), settings::other())),
+        )
+        .route(
+            "/jobs/:id/extend",
+            post(|id, user, request| extend_job(id, user, request, settings::limits())),
+        )
+        .route(
+            "/jobs/:id/artifacts",
+            get(|id, user| artifacts::download(id, user, settings::other().artifacts_limit)),
+        )
+        .route("/jobs/:id/logs", get(logs::download))
+        .route("/jobs/:id/pause", post(pause_job))
+        .route(
+            "/jobs/:id/restart",
+            post(|_: Unbanned, id, user| {
+                restart_job(id, user, settings::limits(), settings::other())
+            }),
+        )
+        .route("/jobs/:id/resume", post(resume_job))
+        .route(
+            "/compare",
+            get(|user| compare::page(user, settings::other())).post(
+                |_: Unbanned, user, admin, mp| {
+                    compare::start(user, admin, mp, settings::limits(), settings::other())
+                },
+            ),
+        )
+        .route("/compare/status", get(compare::status))
+        .route(
+            "/api/v1/compare",
+            get(|ApiUser(user)| compare::status(user)).post(
+                |_: Unbanned, ApiUser(user), admin, mp| {
+                    compare::start(user, admin, mp, settings::limits(), settings::other())
+                },
+            ),
+        )
+        .route(
+            "/capacity",
+            get(|headers| eta::status(settings::other().jobs_max, headers)),
+        )
+        .route(
+            "/capacity/events",
+            get(|| eta::events(settings::other().jobs_max)),
+        )
+        .route("/queue", get(queue::status))
+        .route(
+            "/out/:id",
+            post(|id, user, query| read_stdout(id, user, query, settings::other().output_limits)),
+        )
+        .route(
+            "/err/:id",
+            post(|id, user, query| read_stderr(id, user, query, settings::other().output_limits)),
+        )
+        .route("/in/:id", post(write_stdin))
+        .route("/in/:id/ws", get(stdin_ws))
+        .route(
+            "/api/v1/jobs",
+            post(|_: Unbanned, ApiUser(user), len, upload_id, mp| {
+                let (limits, other) = (settings::limits(), settings::other());
+                root_post(Some(user), len, upload_id, mp, limits, other)
+            })
+            .get(|ApiUser(user)| history::api(user))
+            .delete(|ApiUser(user)| root_delete(user)),
+        )
+        .route(
+            "/api/v1/uploads/:id",
+            delete(|id, ApiUser(user)| uploads::abort(id, user)),
+        )
+        .route(
+            "/api/v1/deploy",
+            post(|_: Unbanned, ApiUser(user), Json(deploy)| {
+                deploy_post(user, deploy, settings::limits(), settings::other())
+            }),
+        )
+        .route(
+            "/api/v1/jobs/:id",
+            get(|id, ApiUser(user)| job_status(id, user)),
+        )
+        .route(
+            "/api/v1/jobs/:id/extend",
+            post(|id, ApiUser(user), request| extend_job(id, user, request, settings::limits())),
+        )
+        .route(
+            "/api/v1/jobs/:id/restart",
+            post(|_: Unbanned, id, ApiUser(user)| {
+                restart_job(id, user, settings::limits(), settings::other())
+            }),
+        )
+        .route(
+            "/api/v1/jobs/:id/pause",
+            post(|id, ApiUser(user)| pause_job(id, user)),
+        )
+        .route(
+            "/api/v1/jobs/:id/resume",
+            post(|id, ApiUser(user)| resume_job(id, user)),
+        )
+        .route(
+            "/api/v1/jobs/:id/stdout",
+            get(|id, ApiUser(user), query| {
+                read_stdout(id, user, query, settings::other().output_limits)
+            }),
+        )
+        .route(
+            "/api/v1/jobs/:id/stderr",
+            get(|id, ApiUser(user), query| {
+                read_stderr(id, user, query, settings::other().output_limits)
+            }),
+        )
+        .route(
+            "/api/v1/jobs/:id/stdin",
+            post(|id, ApiUser(user), data| write_stdin(id, user, data)),
+        )
+        .route(
+            "/api/v1/jobs/:id/files/:name",
+            get(|path, ApiUser(user)| job_file(path, user)),
+        )
+        .route(
+            "/api/v1/jobs/:id/logs",
+            get(|id, ApiUser(user), query| logs::download(id, user, query)),
+        )
+        .route(
+            "/api/v1/jobs/:id/artifacts",
+            get(|id, ApiUser(user)| {
+                artifacts::download(id, user, settings::other().artifacts_limit)
+            }),
+        )
+        .route("/job/:id/files/:name", get(job_file))
+        .route(
+            "/me/tokens",
+            get({
+                let demo_fqdn = other.demo_fqdn.clone();
+                move |user| auth::token::page(user, demo_fqdn)
+            }),
+        )
+        .route(
+            "/job/:id",
+            get({
+                let demo_fqdn = other.demo_fqdn.clone();
+                move |id, user, jar| job_get(id, user, jar, demo_fqdn)
+            }),
+        )
+        .route(
+            "/examples/:name/run",
+            post(|_: Unbanned, name, user| {
+                gallery_run(name, user, settings::limits(), settings::other())
+            }),
+        )
+        .route(
+            "/drawbridge",
+            get({
+                let demo_fqdn = other.demo_fqdn.clone();
+                move |user| root_get(user, settings::limits(), Page::Drawbridge, demo_fqdn)
+            }),
+        )
+        .route(
+            "/upload",
+            get({
+                let demo_fqdn = other.demo_fqdn.clone();
+                move |user| root_get(user, settings::limits(), Page::Upload, demo_fqdn)
+            }),
+        )
+        .route(
+            "/",
+            get({
+                let demo_fqdn = other.demo_fqdn.clone();
+                move |user| root_get(user, settings::limits(), Page::Examples, demo_fqdn)
+            })
+            .post(|_: Unbanned, user, len, upload_id, mp| {
+                root_post(
+                    user,
+                    len,
+                    upload_id,
+                    mp,
+                    settings::limits(),
+                    settings::other(),
+                )
+            })
+            .delete(root_delete),
+        )
+        .route("/uploads/:id", delete(uploads::abort))
+        .route("/uploads/:id/events", get(uploads::watch));
+
+    #[cfg(feature = "chaos")]
+    let app = app.route("/admin/chaos", get(chaos::get).put(chaos::set));
+
+    let app = oidc.routes(app).await?;
+    let app = app.layer(middleware::from_fn(features::gate_api));
+    let app = match other.proxy.clone() {
+        Some(proxy) => app.layer(middleware::from_fn(move |req, next| {
+            proxy.clone().route(req, next)
+        })),
+        None => app,
+    };
+    let app = app.layer(
+        TraceLayer::new_for_http()
+            .make_span_with(SpanMaker::default())
+            .on_request(DefaultOnRequest::new().level(Level::INFO))
+            .on_response(
                 DefaultOnResponse::new()
                     .level(Level::INFO)
                     .latency_unit(LatencyUnit::Micros),
@@ -472,39 +1789,117 @@ async fn main() -> anyhow::Result<()> {
             ),
     );
 
-    Server::bind(&other.addr)
-        .serve(app.into_make_service())
-        .await?;
+    // Jobs left behind are cleaned up on the next start, as after a crash.
+    tokio::select! {
+        result = Server::bind(&other.addr)
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>()) => result?,
+        () = drain::shutdown(other.shutdown_delay) => {}
+    }
     Ok(())
 }
 
+/// Returns the ID of the job `user` is currently running, if any.
+async fn running_job(user: &User) -> Option<String> {
+    let jobs = JOBS.read().await;
+    let mut job = jobs.get(user)?.write().await;
+    matches!(job.exec.try_wait(), Ok(None)).then(|| job.id.clone())
+}
+
 async fn root_get(
     user: Option<User>,
     limits: Limits,
     page: Page,
     demo_fqdn: String,
 ) -> impl IntoResponse {
-    let (user, star) = match user {
-        None => (false, false),
-        Some(user) => (true, user.has_starred_enarx()),
+    let (star, running, history) = match user {
+        None => (false, None, vec![]),
+        Some(user) => (
+            limits.is_starred(user.roles()),
+            running_job(&user).await,
+            history::recent(&user).await,
+        ),
     };
+    let allowance = match user {
+        Some(user) if limits.daily.is_limited() => Some(daily::allowance(user, limits.daily).await),
+        _ => None,
+    };
+    let ctx = user.map_or_else(policy::Context::anonymous, policy::Context::new);
+    let decision = limits.decide(&ctx);
 
+    let settings = settings::load();
     let tmpl = IdxTemplate {
         demo_fqdn,
         page,
         toml: enarx_config::CONFIG_TEMPLATE,
-        // SAFETY: This should always be initialized in main by this point.
-        examples: EXAMPLES.get().unwrap(),
-        user,
+        examples: &settings.examples,
+        gallery: &settings.gallery,
+        user: user.is_some(),
         star,
-        _size: limits.size(star),
-        size_human: limits.size_human(star),
-        ttl: limits.time_to_live(star).as_secs(),
+        star_repos: &settings.other.star_repos,
+        running,
+        history,
+        _size: decision.size,
+        size_human: decision.size_human(),
+        ttl: decision.time_to_live.as_secs(),
+        allowance,
     };
 
     HtmlTemplate(tmpl).into_response()
 }
 
+async fn job_get(
+    AxumPath(id): AxumPath<String>,
+    user: Option<User>,
+    jar: CookieJar,
+    demo_fqdn: String,
+) -> Result<Response, StatusCode> {
+    let user = match user {
+        Some(user) => user,
+        None => {
+            // Come back to this job once the user has logged in.
+            let jar = redirect::remember(jar, format!("/job/{id}"));
+            return Ok((jar, redirect::to("/login")).into_response());
+        }
+    };
+
+    let jobs = JOBS.read().await;
+    let job = match jobs.get(&user) {
+        Some(job) => Some(job.read().await),
+        None => None,
+    };
+    let job = match job {
+        Some(job) if job.id == id => job,
+        _ => {
+            // Show how the job ended, if it did.
+            let outcome = history::get(&user, &id)
+                .await
+                .and_then(|run| run.outcome)
+                .ok_or(StatusCode::NOT_FOUND)?;
+            return Ok(HtmlTemplate(JobTemplate {
+                demo_fqdn,
+                user: true,
+                id,
+                ports: "{}".into(),
+                outcome: Some(outcome.to_string()),
+            })
+            .into_response());
+        }
+    };
+
+    let ports = serde_json::to_string(&job.mapped_ports).map_err(|e| {
+        error!(error = ?e, %user, job_id = id, "failed to encode job ports");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(HtmlTemplate(JobTemplate {
+        demo_fqdn,
+        user: true,
+        id,
+        ports,
+        outcome: None,
+    })
+    .into_response())
+}
+
 #[inline]
 async fn parse_string_field(field: Field<'_>) -> Result<String, Response> {
     if field.content_type().is_some() {
@@ -516,17 +1911,16 @@ async fn parse_string_field(field: Field<'_>) -> Result<String, Response> {
         .map_err(|_| StatusCode::BAD_REQUEST.into_response())
 }
 
+/// Streams a field of up to `max_size` bytes to a spooled file, accounting for it in `disk`.
 #[inline]
 async fn parse_file_field(
     mut field: Field<'_>,
     max_size: usize,
-    runtime_dir: impl AsRef<Path>,
+    spool: &Spool,
+    disk: &mut Reservation,
 ) -> Result<NamedTempFile, Response> {
     let mut len = 0;
-    let mut out = NamedTempFile::new_in(runtime_dir).map_err(|e| {
-        error!(error = ?e, "failed to create a new temporary file");
-        StatusCode::INTERNAL_SERVER_ERROR.into_response()
-    })?;
+    let mut out = spool.writer()?;
 
     while let Some(chunk) = field
         .chunk()
@@ -537,41 +1931,174 @@ async fn parse_file_field(
         if len > max_size {
             return Err(StatusCode::PAYLOAD_TOO_LARGE.into_response());
         }
+        disk.grow(chunk.len() as u64)?;
+        out.write(&chunk).await?;
+    }
+    out.finish().await
+}
 
-        out.write_all(&chunk).map_err(|e| {
-            error!(error = ?e, "failed to write chunk to temporary file");
+/// Reads a field of up to `max_size` bytes into memory.
+#[inline]
+async fn parse_bytes_field(mut field: Field<'_>, max_size: usize) -> Result<Vec<u8>, Response> {
+    let mut data = Vec::new();
+    while let Some(chunk) = field
+        .chunk()
+        .await
+        .map_err(|_| StatusCode::BAD_REQUEST.into_response())?
+    {
+        if data.len() + chunk.len() > max_size {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE.into_response());
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(data)
+}
+
+/// Decodes a text file, e.g. an Enarx.toml.
+fn text_file(data: Vec<u8>) -> Result<String, Response> {
+    String::from_utf8(data)
+        .map_err(|_| (StatusCode::BAD_REQUEST, "The file is not valid UTF-8").into_response())
+}
+
+/// Requests the URL in a field, returning the response if it is at most `max_size` bytes,
+/// along with a function reporting errors while reading it.
+async fn fetch_field(
+    field: Field<'_>,
+    max_size: usize,
+) -> Result<(reqwest::Response, impl Fn(reqwest::Error) -> Response), Response> {
+    let url: reqwest::Url = parse_string_field(field)
+        .await?
+        .parse()
+        .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid URL").into_response())?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err((StatusCode::BAD_REQUEST, "Only HTTP(S) URLs are supported").into_response());
+    }
+    // IPv6 addresses are enclosed in brackets.
+    let host = url
+        .host_str()
+        .map(|host| host.trim_matches(['[', ']']))
+        .ok_or_else(|| (StatusCode::BAD_REQUEST, "Invalid URL").into_response())?;
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| (StatusCode::BAD_REQUEST, "Invalid URL").into_response())?;
+    let addr = egress::resolve_public(host, port).await?;
+
+    // Redirects are not followed, as they could lead to internal hosts.
+    let client = reqwest::Client::builder()
+        .timeout(FETCH_TIMEOUT)
+        .redirect(reqwest::redirect::Policy::none())
+        .resolve(host, addr)
+        .build()
+        .map_err(|e| {
+            error!(error = ?e, "failed to build HTTP client");
             StatusCode::INTERNAL_SERVER_ERROR.into_response()
         })?;
+    let request = client.get(url.clone());
+    let fetch_error = move |e: reqwest::Error| {
+        error!(error = ?e, %url, "failed to fetch file");
+        (
+            StatusCode::BAD_GATEWAY,
+            format!("Failed to fetch `{url}`, make sure it is publicly accessible"),
+        )
+            .into_response()
+    };
+    let resp = request
+        .send()
+        .await
+        .and_then(|resp| resp.error_for_status())
+        .map_err(&fetch_error)?;
+    if resp.status().is_redirection() {
+        return Err((
+            StatusCode::BAD_GATEWAY,
+            format!(
+                "`{}` redirects elsewhere, give the final URL instead",
+                resp.url()
+            ),
+        )
+            .into_response());
     }
-    Ok(out)
+    if matches!(resp.content_length(), Some(len) if len > max_size as u64) {
+        return Err(StatusCode::PAYLOAD_TOO_LARGE.into_response());
+    }
+    Ok((resp, fetch_error))
+}
+
+/// Streams the file at the URL in a field, of up to `max_size` bytes, to a spooled file,
+/// accounting for it in `disk`.
+#[inline]
+async fn fetch_file_field(
+    field: Field<'_>,
+    max_size: usize,
+    spool: &Spool,
+    disk: &mut Reservation,
+) -> Result<NamedTempFile, Response> {
+    let (mut resp, fetch_error) = fetch_field(field, max_size).await?;
+
+    let mut len = 0;
+    let mut out = spool.writer()?;
+    while let Some(chunk) = resp.chunk().await.map_err(&fetch_error)? {
+        len += chunk.len();
+        if len > max_size {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE.into_response());
+        }
+        disk.grow(chunk.len() as u64)?;
+        out.write(&chunk).await?;
+    }
+    out.finish().await
 }
 
+/// Reads the file at the URL in a field, of up to `max_size` bytes, into memory.
 #[inline]
-fn listen_ports<T: FromIterator<(u16, String)>>(conf: Config, demo_fqdn: String) -> T {
+async fn fetch_bytes_field(field: Field<'_>, max_size: usize) -> Result<Vec<u8>, Response> {
+    let (mut resp, fetch_error) = fetch_field(field, max_size).await?;
+
+    let mut data = Vec::new();
+    while let Some(chunk) = resp.chunk().await.map_err(&fetch_error)? {
+        if data.len() + chunk.len() > max_size {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE.into_response());
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(data)
+}
+
+/// Builds a wasm module from the `delta` against the module with digest `base`,
+/// which `user` uploaded previously, accounting for it in `disk`.
+async fn apply_delta(
+    user: User,
+    base: &str,
+    delta: &[u8],
+    max_size: usize,
+    spool: &Spool,
+    disk: &mut Reservation,
+) -> Result<NamedTempFile, Response> {
+    let wasm = delta::apply(user, base, delta, max_size).await?;
+    disk.grow(wasm.len() as u64)?;
+    spool.write(&wasm).await
+}
+
+/// Returns the ports the workload listens on, along with the URL scheme used to access them.
+#[inline]
+fn listen_ports<T: FromIterator<(u16, &'static str)>>(conf: &Config) -> T {
     conf.files
-        .into_iter()
+        .iter()
         .filter_map(|file| match file {
             File::Null { .. }
             | File::Stdin { .. }
             | File::Stdout { .. }
             | File::Stderr { .. }
             | File::Connect { .. } => None,
-            File::Listen { port, prot, .. } => {
-                let prot = match prot {
+            File::Listen { port, prot, .. } => Some((
+                *port,
+                match prot {
                     Protocol::Tls => "https",
                     Protocol::Tcp => "http",
-                };
-                Some((port, format!("{}://{}:{}", prot, demo_fqdn, port)))
-            }
+                },
+            )),
         })
         .collect()
 }
 
-#[inline]
-async fn last_page(jar: &CookieJar) -> Option<&str> {
-    jar.get("LAST_PATH").map(|cookie| cookie.value())
-}
-
 #[derive(Debug)]
 pub enum Workload {
     Drawbridge {
@@ -580,27 +2107,20 @@ pub enum Workload {
     Upload {
         wasm: NamedTempFile,
         conf: NamedTempFile,
+        /// Disk budget of the files, released after they are deleted.
+        disk: Reservation,
     },
 }
 
 // TODO: create tests for endpoints: #38
-#[allow(clippy::too_many_arguments)]
 async fn root_post(
     user: Option<User>,
-    mut multipart: Multipart,
+    len: Option<TypedHeader<ContentLength>>,
+    upload_id: UploadId,
+    multipart: Multipart,
     limits: Limits,
-    port_range: Range<u16>,
-    listen_max: Option<u16>,
-    jobs_max: usize,
-    ss_command: impl AsRef<OsStr>,
-    oci_command: impl AsRef<OsStr>,
-    oci_image: impl AsRef<str>,
-    runtime_dir: impl AsRef<Path>,
-    devices: impl IntoIterator<Item = impl AsRef<Path>>,
-    paths: impl IntoIterator<Item = impl AsRef<Path>>,
-    privileged: bool,
-    demo_fqdn: String,
-) -> impl IntoResponse {
+    other: Other,
+) -> Result<Json<serde_json::Value>, Response> {
     let user = match user {
         None => {
             return Err((
@@ -612,73 +2132,308 @@ async fn root_post(
         Some(user) => user,
     };
 
-    let star = user.has_starred_enarx();
-    let ttl = limits.time_to_live(star);
-    let max_wasm_size = limits.size(star);
+    let events = uploads::Events::new(user, &upload_id);
+    let result = submit(user, len, &upload_id, &events, multipart, limits, other).await;
+    if matches!(&result, Err(resp) if resp.status() == StatusCode::PAYLOAD_TOO_LARGE) {
+        security::quota_exceeded(user, Quota::Size).await;
+    }
+    events.finish(&result);
+    result
+}
 
-    let mut workload_type = None;
-    let mut slug = None;
-    let mut wasm = None;
-    let mut conf = None;
+/// Receives a workload submitted by `user` and starts its job.
+async fn submit(
+    user: User,
+    len: Option<TypedHeader<ContentLength>>,
+    upload_id: &UploadId,
+    events: &uploads::Events,
+    mut multipart: Multipart,
+    limits: Limits,
+    other: Other,
+) -> Result<Json<serde_json::Value>, Response> {
+    if other.disk_budget.is_exhausted() {
+        return Err((
+            StatusCode::INSUFFICIENT_STORAGE,
+            "Not enough disk space is available right now, try again later",
+        )
+            .into_response());
+    }
 
-    while let Some(field) = multipart
-        .next_field()
-        .await
-        .map_err(|_| StatusCode::BAD_REQUEST.into_response())?
-    {
-        match field.name() {
-            Some("workloadType") if workload_type.is_none() => {
-                workload_type = parse_string_field(field).await?.into()
-            }
-            Some("slug") if slug.is_none() => slug = parse_string_field(field).await?.into(),
-            Some("wasm") if wasm.is_none() => match field.content_type() {
-                None => return Err(StatusCode::BAD_REQUEST.into_response()),
-                Some("application/wasm") => {
-                    wasm = parse_file_field(field, max_wasm_size, &runtime_dir)
+    let _permit = other
+        .upload_lanes
+        .enter(len.map(|TypedHeader(ContentLength(len))| len))
+        .await?;
+
+    let max_wasm_size = limits.decide(&policy::Context::new(user)).size;
+    // Slightly oversized modules are still accepted, so that they can be broken down
+    // to explain how to shrink them.
+    let max_wasm_analyzed = max_wasm_size.saturating_mul(OVERSIZE_FACTOR);
+
+    // Everything until the job is started may be aborted by the client.
+    let receive = async {
+        // The files are accounted as they are received, before they are written.
+        let mut disk = other.disk_budget.reservation();
+        let mut workload_type = None;
+        let mut slug = None;
+        let mut wasm = None;
+        let mut wasm_base = None;
+        let mut wasm_delta = None;
+        let mut wasm_sig = None;
+        let mut callback_url = None;
+        let mut conf = None;
+        let mut name = None;
+        let mut description = None;
+
+        while let Some(field) = multipart
+            .next_field()
+            .await
+            .map_err(|_| StatusCode::BAD_REQUEST.into_response())?
+        {
+            match field.name() {
+                Some("workloadType") if workload_type.is_none() => {
+                    workload_type = parse_string_field(field).await?.into()
+                }
+                Some("slug") if slug.is_none() => slug = parse_string_field(field).await?.into(),
+                Some("wasm") if wasm.is_none() => match field.content_type() {
+                    None => return Err(StatusCode::BAD_REQUEST.into_response()),
+                    Some("application/wasm") => {
+                        wasm = parse_file_field(field, max_wasm_analyzed, &other.spool, &mut disk)
+                            .await?
+                            .into()
+                    }
+                    _ => return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response()),
+                },
+                Some("wasm_url") if wasm.is_none() => {
+                    wasm = fetch_file_field(field, max_wasm_analyzed, &other.spool, &mut disk)
                         .await?
                         .into()
                 }
-                _ => return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response()),
+                Some("wasm_base") if wasm_base.is_none() => {
+                    wasm_base = parse_string_field(field).await?.into()
+                }
+                Some("wasm_delta") if wasm_delta.is_none() => {
+                    wasm_delta = parse_bytes_field(field, max_wasm_size).await?.into()
+                }
+                Some("wasm_sig") if wasm_sig.is_none() => {
+                    wasm_sig = parse_string_field(field).await?.into()
+                }
+                Some("callback_url") if callback_url.is_none() => {
+                    callback_url = parse_string_field(field).await?.into()
+                }
+                Some("toml") if conf.is_none() && field.content_type().is_none() => {
+                    conf = text_file(parse_bytes_field(field, MAX_CONF_SIZE).await?)?.into()
+                }
+                Some("toml_url") if conf.is_none() => {
+                    conf = text_file(fetch_bytes_field(field, MAX_CONF_SIZE).await?)?.into()
+                }
+                Some("name") if name.is_none() => name = parse_string_field(field).await?.into(),
+                Some("description") if description.is_none() => {
+                    description = parse_string_field(field).await?.into()
+                }
+                _ => return Err(StatusCode::BAD_REQUEST.into_response()),
+            }
+        }
+
+        if let Some(delta) = wasm_delta {
+            let base = match (wasm, wasm_base) {
+                (None, Some(base)) => base,
+                _ => return Err(StatusCode::BAD_REQUEST.into_response()),
+            };
+            wasm = apply_delta(user, &base, &delta, max_wasm_size, &other.spool, &mut disk)
+                .await?
+                .into();
+        }
+
+        let mut breakdown = None;
+        let mut parsed = None;
+        let workload = match workload_type
+            .ok_or_else(|| StatusCode::BAD_REQUEST.into_response())?
+            .as_str()
+        {
+            "upload" => {
+                features::require(Feature::Uploads).await?;
+                let wasm = wasm.ok_or_else(|| StatusCode::BAD_REQUEST.into_response())?;
+                breakdown = Some(wasm::validate(wasm.path(), max_wasm_size).await?);
+                if !other.signing.signers.is_empty() {
+                    let data = tokio::fs::read(wasm.path()).await.map_err(|e| {
+                        error!(error = ?e, "failed to read uploaded wasm");
+                        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+                    })?;
+                    _ = other.signing.verify(&data, wasm_sig.as_deref())?;
+                }
+                // The Enarx.toml is parsed from memory and only written to disk to be mounted.
+                let conf = conf.ok_or_else(|| StatusCode::BAD_REQUEST.into_response())?;
+                parsed = Some(config::parse(&conf)?);
+                disk.grow(conf.len() as u64)?;
+                let conf = other.spool.write(conf.as_bytes()).await?;
+                Workload::Upload { wasm, conf, disk }
+            }
+            "drawbridge" => Workload::Drawbridge {
+                slug: slug.ok_or_else(|| StatusCode::BAD_REQUEST.into_response())?,
             },
-            Some("toml") if conf.is_none() && field.content_type().is_none() => {
-                conf = parse_file_field(field, MAX_CONF_SIZE, &runtime_dir)
-                    .await?
-                    .into()
+            typ => {
+                error!(typ, "Unknown workload type");
+                return Err(StatusCode::BAD_REQUEST.into_response());
             }
-            _ => return Err(StatusCode::BAD_REQUEST.into_response()),
+        };
+
+        let callback = match callback_url {
+            Some(url) => Some(webhooks::parse(&url, &other.egress).await?),
+            None => None,
+        };
+        let meta = Metadata::new(name, description);
+        Ok::<_, Response>((workload, meta, parsed, breakdown, callback))
+    };
+    let (workload, meta, parsed, breakdown, callback) =
+        uploads::abortable(user, upload_id, events, receive).await?;
+    events.send(uploads::Event::Validated {
+        wasm: breakdown.as_ref().map(|breakdown| json!(breakdown)),
+    });
+
+    let mut resp = start_job(user, workload, meta, parsed, callback, limits, other).await?;
+    if let Some(breakdown) = breakdown {
+        resp.0["wasm"] = json!(breakdown);
+    }
+    Ok(resp)
+}
+
+#[derive(Debug, Deserialize)]
+struct Deploy {
+    /// Drawbridge slug of the workload, for example `user/my-repo:0.1.0`.
+    slug: String,
+    /// URL called once the job has ended.
+    callback_url: Option<String>,
+    /// Name of the job.
+    name: Option<String>,
+    description: Option<String>,
+}
+
+/// Deploys a workload from Drawbridge without going through a multipart upload.
+async fn deploy_post(
+    user: User,
+    Deploy {
+        slug,
+        callback_url,
+        name,
+        description,
+    }: Deploy,
+    limits: Limits,
+    other: Other,
+) -> Result<Json<serde_json::Value>, Response> {
+    let callback = match callback_url {
+        Some(url) => Some(webhooks::parse(&url, &other.egress).await?),
+        None => None,
+    };
+    let (workload, meta) = (
+        Workload::Drawbridge { slug },
+        Metadata::new(name, description),
+    );
+    start_job(user, workload, meta, None, callback, limits, other).await
+}
+
+/// Runs a prebuilt example from the gallery.
+async fn gallery_run(
+    AxumPath(name): AxumPath<String>,
+    user: User,
+    limits: Limits,
+    other: Other,
+) -> Result<Json<serde_json::Value>, Response> {
+    features::require(Feature::Gallery).await?;
+
+    let settings = settings::load();
+    let example = settings
+        .gallery
+        .iter()
+        .find(|example| example.name == name)
+        .ok_or_else(|| StatusCode::NOT_FOUND.into_response())?;
+
+    // Copy the example, so that it is cleaned up with the job like an upload, accounting for
+    // the copies before making them.
+    let mut disk = other.disk_budget.reservation();
+    for src in [&example.wasm, &example.conf] {
+        let meta = tokio::fs::metadata(src).await.map_err(|e| {
+            error!(error = ?e, example = name, "failed to query size of example");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        })?;
+        disk.grow(meta.len())?;
+    }
+    let copy = |src: PathBuf| {
+        let runtime_dir = other.runtime_dir.clone();
+        async move {
+            let dst = NamedTempFile::new_in(runtime_dir)?;
+            _ = tokio::fs::copy(src, dst.path()).await?;
+            Ok::<_, std::io::Error>(dst)
         }
+    };
+    let workload = match tokio::try_join!(copy(example.wasm.clone()), copy(example.conf.clone())) {
+        Ok((wasm, conf)) => Workload::Upload { wasm, conf, disk },
+        Err(e) => {
+            error!(error = ?e, example = name, "failed to copy example");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+        }
+    };
+    let meta = Metadata::new(Some(name), None);
+    start_job(user, workload, meta, None, None, limits, other).await
+}
+
+/// Spawns a job running `workload` on behalf of `user`, replacing any job the user was running.
+/// The `parsed` Enarx.toml of an uploaded workload is read from its file if not given.
+/// The `callback` URL, if any, is called once the job has ended.
+async fn start_job(
+    user: User,
+    workload: Workload,
+    meta: Metadata,
+    parsed: Option<Config>,
+    callback: Option<reqwest::Url>,
+    limits: Limits,
+    other: Other,
+) -> Result<Json<serde_json::Value>, Response> {
+    let ctx = match &workload {
+        Workload::Drawbridge { slug } => policy::Context::new(user).slug(slug),
+        Workload::Upload { .. } => policy::Context::new(user),
+    };
+    let decision = limits.decide(&ctx);
+    let ttl = decision.time_to_live;
+    if let Some(per_hour) = decision.submissions {
+        ratelimit::check(user, per_hour).await?;
     }
+    daily::check(user, limits.daily).await?;
+    let id = Uuid::new_v4().to_string();
 
-    let workload = match workload_type
-        .ok_or_else(|| StatusCode::BAD_REQUEST.into_response())?
-        .as_str()
-    {
-        "upload" => Workload::Upload {
-            wasm: wasm.ok_or_else(|| StatusCode::BAD_REQUEST.into_response())?,
-            conf: conf.ok_or_else(|| StatusCode::BAD_REQUEST.into_response())?,
-        },
-        "drawbridge" => Workload::Drawbridge {
-            slug: slug.ok_or_else(|| StatusCode::BAD_REQUEST.into_response())?,
-        },
-        typ => {
-            error!(typ, "Unknown workload type");
-            return Err(StatusCode::BAD_REQUEST.into_response());
+    // The files of uploaded workloads are retained if a storage is configured.
+    let mut sha256 = None;
+    let retained = if let Workload::Upload { wasm, conf, .. } = &workload {
+        let wasm = tokio::fs::read(wasm.path()).await.map_err(|e| {
+            error!(error = ?e, "failed to read uploaded wasm");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        })?;
+        let digest = denylist::check(user, &wasm).await?;
+        sha256 = Some(digest.clone());
+        delta::remember(user, digest, &wasm).await;
+
+        if storage::get().is_some() {
+            let conf = tokio::fs::read(conf.path()).await.map_err(|e| {
+                error!(error = ?e, "failed to read uploaded Enarx.toml");
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            })?;
+            Some([(WORKLOAD_WASM, wasm), (WORKLOAD_CONF, conf)])
+        } else {
+            None
         }
+    } else {
+        None
     };
 
-    let ports: Vec<(u16, String)> = match &workload {
+    let conf: Option<Config> = match &workload {
+        Workload::Upload { .. } if parsed.is_some() => parsed,
         Workload::Upload { conf, .. } => read_to_string(conf)
             .await
             .map_err(|e| {
                 error!(error = ?e, "failed to read uploaded Enarx.toml");
                 StatusCode::INTERNAL_SERVER_ERROR.into_response()
             })
-            .map(|conf| toml::from_str(&conf))?
-            .map(|config| listen_ports(config, demo_fqdn))
-            .map_err(|e| {
-                error!(error = ?e, "failed to parse uploaded Enarx.toml");
-                StatusCode::BAD_REQUEST.into_response()
-            })?,
+            .and_then(|conf| config::parse(&conf))
+            .map(Some)?,
         Workload::Drawbridge { slug } => {
             let (repo, tag) = slug
                 .split_once(':')
@@ -696,12 +2451,12 @@ async fn root_post(
                         StatusCode::INTERNAL_SERVER_ERROR.into_response()
                     })
                     .map(|conf| toml::from_str(&conf))?
-                    .map(|config| listen_ports(config, demo_fqdn))
+                    .map(Some)
                     .map_err(|e| {
                         error!(slug, error = ?e, "failed to parse Enarx.toml");
                         StatusCode::BAD_REQUEST.into_response()
                     })?,
-                Err(e) if e.status() == Some(StatusCode::NOT_FOUND) => vec![],
+                Err(e) if e.status() == Some(StatusCode::NOT_FOUND) => None,
                 Err(e) => {
                     error!(slug, error = ?e, "failed to request Enarx.toml");
                     return Err(StatusCode::BAD_REQUEST.into_response());
@@ -710,9 +2465,18 @@ async fn root_post(
         }
     };
 
-    if let Some(listen_max) = listen_max {
+    let ports: HashMap<u16, &str> = match &conf {
+        Some(conf) => {
+            other.egress.check(conf).await?;
+            listen_ports(conf)
+        }
+        None => Default::default(),
+    };
+
+    if let Some(listen_max) = other.listen_max {
         // Check if the user is trying to listen on too many ports.
         if ports.len() > listen_max as _ {
+            security::quota_exceeded(user, Quota::Ports).await;
             return Err((
                 StatusCode::BAD_REQUEST,
                 format!(
@@ -724,70 +2488,257 @@ async fn root_post(
         }
     }
 
-    let mut jobs = JOBS.write().await;
+    // Once all slots are taken, submissions wait for one in order of arrival if queueing is
+    // enabled, and are rejected otherwise.
+    let mut place: Option<queue::Place> = None;
+    let mut jobs = loop {
+        let jobs = JOBS.write().await;
 
-    if jobs.len() >= jobs_max
-        && stream::iter(jobs.values())
-            .filter(|job| async { matches!(job.write().await.exec.try_wait(), Ok(None)) })
-            .count()
-            .await
-            >= jobs_max
-    {
-        error!(num_jobs = jobs.len(), "too many jobs running");
-        // TODO: Queue the workload for execution in FIFO fashion
-        return Err((
-            StatusCode::SERVICE_UNAVAILABLE,
-            "Too many workloads are running right now, try again later",
-        )
-            .into_response());
-    }
+        // Runs of comparisons occupy slots as well.
+        let jobs_max = other.jobs_max.saturating_sub(compare::running());
+        let (running, eta) = if jobs.len() >= jobs_max {
+            eta::next_slot(jobs.values(), jobs_max).await
+        } else {
+            (jobs.len(), Duration::ZERO)
+        };
+        // Submissions do not overtake the ones waiting before them.
+        let ahead = match &place {
+            Some(place) => place.position() - 1,
+            None => queue::len(),
+        };
+        if running < jobs_max && ahead == 0 {
+            break jobs;
+        }
+        drop(jobs);
+
+        let minutes = (eta.as_secs() + 59) / 60;
+        let retry_after = [(RETRY_AFTER, eta.as_secs().max(1).to_string())];
+        let max_wait = match other.queue_max_wait {
+            Some(max_wait) => max_wait,
+            None => {
+                error!(num_jobs = running, "too many jobs running");
+                return Err((
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    retry_after,
+                    format!(
+                        "Too many workloads are running right now, \
+                        a slot is expected to free up in about {minutes} minute(s)"
+                    ),
+                )
+                    .into_response());
+            }
+        };
+        let place = match &mut place {
+            Some(place) => place,
+            None => {
+                let joined = queue::join(user).ok_or_else(|| {
+                    (
+                        StatusCode::CONFLICT,
+                        "You already have a workload waiting for a slot",
+                    )
+                        .into_response()
+                })?;
+                info!(%user, position = joined.position(), "queued submission");
+                place.insert(joined)
+            }
+        };
+        if place.waited() >= max_wait {
+            warn!(%user, position = place.position(), "queued submission timed out");
+            return Err((
+                StatusCode::SERVICE_UNAVAILABLE,
+                retry_after,
+                format!(
+                    "No slot freed up within {} minute(s), \
+                    the next one is expected to free up in about {minutes} minute(s)",
+                    (max_wait.as_secs() + 59) / 60
+                ),
+            )
+                .into_response());
+        }
+        sleep(queue::POLL_INTERVAL).await;
+    };
+    drop(place);
+
+    devices::check(&other.devices).await?;
+
+    let slug = match &workload {
+        Workload::Drawbridge { slug } => Some(slug.clone()),
+        Workload::Upload { .. } => None,
+    };
+
+    let public_url = {
+        let (id, ports, other) = (id.clone(), &ports, &other);
+        move |host: u16, port: u16| match (ports[&port], &other.proxy) {
+            // Only plain HTTP can be proxied.
+            ("http", Some(proxy)) => proxy.url(&id, port),
+            (scheme, _) => format!("{scheme}://{}:{host}", other.demo_fqdn),
+        }
+    };
 
     // Spawn a new job.
-    let id = Uuid::new_v4().to_string();
-    let job = Job::spawn(
+    let mut job = Job::spawn(
         id.clone(),
+        user,
         workload,
-        ss_command,
-        oci_command,
-        oci_image.as_ref(),
-        port_range,
-        ports,
-        devices,
-        paths,
-        privileged,
-        // Ensure job is killed after a timeout.
+        &other.ss_command,
+        &other.oci_command,
+        &other.oci_image,
+        other.port_range,
+        // All listen files use TCP, including TLS ones.
+        ports.keys().map(|port| (Transport::Tcp, *port)),
+        public_url,
+        &other.devices,
+        &other.paths,
+        other.privileged,
+        other.host_network,
+        None,
+        other.wasi_report,
+        other.memory_limit,
+        other.cpu_limit,
+        other.cgroup_slice.as_deref(),
+        other.spawn_retries,
+        ttl,
+        // Ensure job is killed after a timeout, which may be extended while the job runs.
         async move {
-            sleep(ttl).await;
-
-            let mut jobs = JOBS.write().await;
-            match jobs.get(&user) {
-                Some(job) if job.read().await.id == id => {
-                    error!(job_id = id, "killing job after timeout");
-                    jobs.remove(&user).unwrap().into_inner().kill().await;
+            let mut deadline = clock::now() + ttl;
+            loop {
+                sleep_until(deadline).await;
+
+                let mut jobs = JOBS.write().await;
+                match jobs.get(&user) {
+                    Some(job) if job.read().await.id == id => {
+                        let current = job.read().await.deadline();
+                        if current > clock::now() {
+                            deadline = current;
+                            continue;
+                        }
+                        error!(job_id = id, "killing job after timeout");
+                        let job = jobs.remove(&user).unwrap();
+                        drop(jobs);
+                        job.into_inner().kill(Reason::Timeout).await;
+                        audit::Event::new(user.uid(), audit::Action::Timeout)
+                            .job(&id)
+                            .record()
+                            .await;
+                    }
+                    _ => {}
                 }
-                _ => {}
+                return;
             }
         },
     )
     .await?;
+    job.capture_output(other.log_limit, other.output_cap);
+    if let Some(per_hour) = decision.submissions {
+        ratelimit::take(user, per_hour);
+    }
+    daily::record(user, &job.id).await;
+    job.sha256 = sha256.clone();
+    job.meta = meta.clone();
     let resp = Json(json!({
         "id": job.id,
+        "name": meta.name,
         "ports": job.mapped_ports
     }));
-    info!(job_id = job.id, %user, "job started");
+    info!(job_id = job.id, job_name = meta.name.as_deref(), %user, "job started");
+    audit::Event::new(user.uid(), audit::Action::Submit)
+        .job(&job.id)
+        .name(meta.name.clone())
+        .sha256(sha256)
+        .ports(job.mapped_ports.keys().copied())
+        .record()
+        .await;
+    if let Some(callback) = callback {
+        webhooks::register(job.id.clone(), callback, other.url.clone()).await;
+    }
+    state::add(state::Entry {
+        id: job.id.clone(),
+        pid: job.exec.id(),
+        ports: job.mapped_ports.keys().copied().collect(),
+        owner: user.to_string(),
+        name: meta.name.clone(),
+        deadline: clock::unix_secs(clock::now() + ttl),
+    })
+    .await;
+    history::record(
+        user,
+        history::Run {
+            id: job.id.clone(),
+            slug,
+            meta,
+            started: SystemTime::now(),
+            retained: retained.is_some(),
+            outcome: None,
+            wasi: None,
+            annotations: vec![],
+        },
+    )
+    .await;
+    if let (Some(storage), Some(files)) = (storage::get(), retained) {
+        let id = job.id.clone();
+        _ = tokio::spawn(async move {
+            for (name, data) in files {
+                if let Err(e) = storage.put(&format!("workloads/{id}/{name}"), data).await {
+                    error!(error = ?e, job_id = id, "failed to retain workload file");
+                }
+            }
+        });
+    }
 
-    if let Some(old) = jobs.insert(user, RwLock::new(job)) {
+    let old = jobs.insert(user, RwLock::new(job));
+    drop(jobs);
+    if let Some(old) = old {
         let old = old.into_inner();
         info!(old_job_id = old.id, %user, "killing old job");
-        old.kill().await;
+        let event = audit::Event::new(user.uid(), audit::Action::Kill).job(&old.id);
+        old.kill(Reason::Killed).await;
+        event.record().await;
     }
     Ok(resp)
 }
 
+/// Downloads a retained file of a workload recently run by the user.
+async fn job_file(
+    AxumPath((id, name)): AxumPath<(String, String)>,
+    user: User,
+) -> Result<Response, Response> {
+    let storage = storage::get().ok_or_else(|| StatusCode::NOT_FOUND.into_response())?;
+    if ![WORKLOAD_WASM, WORKLOAD_CONF].contains(&name.as_str())
+        || !history::recent(&user)
+            .await
+            .iter()
+            .any(|run| run.id == id && run.retained)
+    {
+        return Err(StatusCode::NOT_FOUND.into_response());
+    }
+    storage.download(&format!("workloads/{id}/{name}")).await
+}
+
+/// Kills the job of the user with `uid`, if they are running one.
+async fn kill_job_of(uid: u64) {
+    let mut jobs = JOBS.write().await;
+    let user = match jobs.keys().find(|user| user.uid() == uid) {
+        Some(user) => *user,
+        None => return,
+    };
+    let job = jobs.remove(&user);
+    drop(jobs);
+    if let Some(job) = job {
+        let job = job.into_inner();
+        info!(%user, job_id = job.id, "killing job of logged out user");
+        let event = audit::Event::new(user.uid(), audit::Action::Kill).job(&job.id);
+        job.kill(Reason::Killed).await;
+        event.record().await;
+    }
+}
+
 async fn root_delete(user: User) {
-    if let Some(job) = JOBS.write().await.remove(&user) {
+    let job = JOBS.write().await.remove(&user);
+    if let Some(job) = job {
         let job = job.into_inner();
         info!(%user, job_id = job.id, "explicitly killing job");
-        job.kill().await;
+        let event = audit::Event::new(user.uid(), audit::Action::Kill).job(&job.id);
+        job.kill(Reason::Killed).await;
+        event.record().await;
     }
 }
@@ -0,0 +1,141 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Networks in CIDR notation, as used by egress rules and bans.
+
+use std::fmt::{self, Display};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use anyhow::{bail, Context};
+
+/// A network in CIDR notation, or a single address.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Net {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for Net {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (
+                addr,
+                Some(
+                    prefix
+                        .parse()
+                        .with_context(|| format!("invalid prefix length in `{s}`"))?,
+                ),
+            ),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .with_context(|| format!("invalid address in `{s}`"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max);
+        if prefix > max {
+            bail!("prefix length of `{s}` exceeds {max}");
+        }
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl Display for Net {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl Net {
+    /// Returns whether `ip` belongs to the network. IPv4 addresses mapped into IPv6 match
+    /// IPv4 networks.
+    pub(crate) fn contains(&self, ip: IpAddr) -> bool {
+        let prefix = u32::from(self.prefix);
+        match (self.addr, canonical(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Unwraps IPv4 addresses mapped into IPv6.
+pub(crate) fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(..) => ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn net(s: &str) -> Net {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn single_addresses_have_a_full_prefix() {
+        assert_eq!(net("192.0.2.1").to_string(), "192.0.2.1/32");
+        assert_eq!(net("2001:db8::1").to_string(), "2001:db8::1/128");
+        assert_eq!(net("192.0.2.0/24").to_string(), "192.0.2.0/24");
+    }
+
+    #[test]
+    fn invalid_networks_are_rejected() {
+        for s in [
+            "",
+            "example.com",
+            "192.0.2.0/",
+            "192.0.2.0/x",
+            "192.0.2.0/33",
+        ] {
+            assert!(s.parse::<Net>().is_err(), "{s}");
+        }
+        assert!("2001:db8::/129".parse::<Net>().is_err());
+        assert!("2001:db8::/128".parse::<Net>().is_ok());
+    }
+
+    #[test]
+    fn v4_networks_contain_their_addresses() {
+        let docs = net("192.0.2.0/24");
+        assert!(docs.contains(ip("192.0.2.0")));
+        assert!(docs.contains(ip("192.0.2.255")));
+        assert!(!docs.contains(ip("192.0.3.0")));
+        assert!(!docs.contains(ip("2001:db8::1")));
+
+        assert!(net("192.0.2.1").contains(ip("192.0.2.1")));
+        assert!(!net("192.0.2.1").contains(ip("192.0.2.2")));
+        assert!(net("0.0.0.0/0").contains(ip("203.0.113.7")));
+    }
+
+    #[test]
+    fn v6_networks_contain_their_addresses() {
+        let docs = net("2001:db8::/32");
+        assert!(docs.contains(ip("2001:db8::1")));
+        assert!(docs.contains(ip("2001:db8:ffff::1")));
+        assert!(!docs.contains(ip("2001:db9::1")));
+        assert!(!docs.contains(ip("192.0.2.1")));
+        assert!(net("::/0").contains(ip("2001:db9::1")));
+    }
+
+    #[test]
+    fn mapped_addresses_match_v4_networks() {
+        assert!(net("192.0.2.0/24").contains(ip("::ffff:192.0.2.1")));
+        assert!(!net("192.0.2.0/24").contains(ip("::ffff:192.0.3.1")));
+    }
+}
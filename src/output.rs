@@ -0,0 +1,638 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Output of jobs, drained from their pipes by a task per stream into a bounded ring buffer.
+//!
+//! Clients read the output from an offset in the stream, so that reconnecting clients neither
+//! miss nor duplicate any of it, or continue after the output read last. Output which has not
+//! been read is never evicted, the job is throttled until it is read instead, while output
+//! which has been read is retained for replay up to the capacity of the buffer.
+//!
+//! The output of both streams is also merged in order of arrival, with each line tagged with
+//! its stream, since the order cannot be reconstructed from the streams. Reading the merged
+//! output counts as reading the streams, while output of the merged stream which has not been
+//! read is evicted rather than throttling the job, as the streams may be read separately.
+//!
+//! The output of a job may be capped, in which case the output beyond the cap is discarded,
+//! or the job is killed, and a notice is appended to each stream in its place.
+//!
+//! Notices of this server, e.g. why a job was paused, are not part of the output and thus
+//! not replayed.
+
+use crate::auth::User;
+use crate::clock::{self, Instant};
+use crate::job::{OutputExceeded, OutputLimits, Reason};
+use crate::lifecycle::{Annotation, Annotator};
+use crate::logs::{Log, Stream};
+use crate::redact::Redactor;
+use crate::security::{self, Quota};
+use crate::wasi::{Report, Tracer};
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::response::{IntoResponse, Response};
+use clap::ValueEnum;
+use humansize::{file_size_opts as options, FileSize};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::sync::Notify;
+use tokio::time::timeout;
+use tracing::{error, warn};
+
+/// Capacity of the buffer of each stream in bytes.
+const CAPACITY: usize = 256 * 1024;
+
+/// Maximum size of a chunk read by a client in bytes.
+const CHUNK_MAX: usize = 64 * 1024;
+
+/// Time without output after which the output held back by the filters, e.g. a prompt
+/// without a trailing newline, is passed on.
+const FLUSH_DELAY: Duration = Duration::from_millis(250);
+
+/// Header holding the offset of the first byte of a chunk.
+const OFFSET_HEADER: &str = "x-output-offset";
+
+/// Header holding the offset to read the next chunk from.
+const NEXT_HEADER: &str = "x-output-next";
+
+#[derive(Debug, Default)]
+struct Buffer {
+    data: VecDeque<u8>,
+    /// Offset of the first byte of `data` in the stream.
+    start: u64,
+    /// Offset up to which the output has been read.
+    read: u64,
+    /// Whether the stream has been closed, i.e. the job has exited.
+    closed: bool,
+    /// Whether the output is no longer read, e.g. because the job was removed.
+    detached: bool,
+}
+
+impl Buffer {
+    fn end(&self) -> u64 {
+        self.start + self.data.len() as u64
+    }
+
+    /// Reads a chunk of output from `offset`, or from the oldest output buffered, without
+    /// marking it as read.
+    fn peek(&self, offset: Option<u64>) -> Chunk {
+        let from = offset.unwrap_or(self.start).clamp(self.start, self.end());
+        let skip = (from - self.start) as usize;
+        let len = (self.data.len() - skip).min(CHUNK_MAX);
+        Chunk {
+            offset: from,
+            data: self.data.range(skip..skip + len).copied().collect(),
+            next: from + len as u64,
+            fresh: 0,
+        }
+    }
+
+    /// Reads a chunk of output from `offset`, or after the output read last.
+    fn read(&mut self, offset: Option<u64>) -> Chunk {
+        let mut chunk = self.peek(Some(offset.unwrap_or(self.read)));
+        chunk.fresh = chunk.next.saturating_sub(self.read.max(chunk.offset)) as usize;
+        self.read = self.read.max(chunk.next);
+        chunk
+    }
+}
+
+/// Output of a stream of a job.
+#[derive(Debug, Default)]
+struct Output {
+    buffer: Mutex<Buffer>,
+    /// Notifies the task draining the stream that output has been read.
+    consumed: Notify,
+    /// Filters the trace of WASI calls out of the stream, if enabled.
+    tracer: Mutex<Option<Tracer>>,
+    /// Annotates the job with the stages of its Keep logged on the stream, if it is the
+    /// standard error.
+    annotator: Mutex<Option<Annotator>>,
+}
+
+/// Chunk of output read by a client.
+#[derive(Debug)]
+pub(crate) struct Chunk {
+    /// Offset of the first byte of `data`, which is later than the requested one if the
+    /// output in between has been evicted.
+    pub(crate) offset: u64,
+    pub(crate) data: Vec<u8>,
+    /// Offset to read the next chunk from.
+    next: u64,
+    /// Number of bytes of output in `data` which had not been read before.
+    fresh: usize,
+}
+
+impl Chunk {
+    /// Returns the output which had not been read before.
+    pub(crate) fn fresh(&self) -> &[u8] {
+        let end = (self.next - self.offset) as usize;
+        &self.data[end - self.fresh..end]
+    }
+}
+
+impl IntoResponse for Chunk {
+    fn into_response(self) -> Response {
+        (
+            [
+                (OFFSET_HEADER, self.offset.to_string()),
+                (NEXT_HEADER, self.next.to_string()),
+            ],
+            self.data,
+        )
+            .into_response()
+    }
+}
+
+impl Output {
+    /// Reads a chunk of output from `offset`, or after the output read last.
+    fn read(&self, offset: Option<u64>) -> Chunk {
+        let chunk = self.buffer.lock().unwrap().read(offset);
+        if chunk.fresh > 0 {
+            self.consumed.notify_one();
+        }
+        chunk
+    }
+
+    /// Marks the output up to `end` as read, returning the number of bytes not read before.
+    fn consume(&self, end: u64) -> u64 {
+        let mut buffer = self.buffer.lock().unwrap();
+        let end = end.min(buffer.end());
+        let fresh = end.saturating_sub(buffer.read);
+        if fresh > 0 {
+            buffer.read = end;
+            drop(buffer);
+            self.consumed.notify_one();
+        }
+        fresh
+    }
+
+    /// Returns the WASI calls recorded so far, if the trace is filtered out of the stream.
+    fn report(&self) -> Option<Report> {
+        self.tracer.lock().unwrap().as_ref().map(Tracer::report)
+    }
+
+    /// Returns the annotations recorded so far.
+    fn annotations(&self) -> Vec<Annotation> {
+        self.annotator
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map_or_else(Vec::new, Annotator::annotations)
+    }
+
+    /// Stops buffering the output once it is no longer read.
+    fn detach(&self) {
+        let mut buffer = self.buffer.lock().unwrap();
+        buffer.detached = true;
+        buffer.data.clear();
+        drop(buffer);
+        self.consumed.notify_one();
+    }
+
+    /// Appends a `chunk` to the buffer, waiting until there is room for it, and returns the
+    /// offset of its end in the stream.
+    async fn push(&self, chunk: &[u8]) -> u64 {
+        loop {
+            {
+                let mut buffer = self.buffer.lock().unwrap();
+                if buffer.detached {
+                    return buffer.end();
+                }
+                if buffer.end() - buffer.read < CAPACITY as u64 {
+                    buffer.data.extend(chunk);
+                    // Evict output which has been read beyond the capacity.
+                    let excess = buffer.data.len().saturating_sub(CAPACITY);
+                    let evict = excess.min((buffer.read - buffer.start) as usize);
+                    drop(buffer.data.drain(..evict));
+                    buffer.start += evict as u64;
+                    return buffer.end();
+                }
+            }
+            self.consumed.notified().await;
+        }
+    }
+
+    /// Marks the stream as closed once all of its output has been buffered.
+    fn close(&self) {
+        self.buffer.lock().unwrap().closed = true;
+    }
+
+    /// Filters a `chunk` read from the pipe, returning the output which may be passed on.
+    /// An empty chunk flushes the held back output.
+    fn filter(&self, redactor: &mut Redactor, chunk: Vec<u8>) -> Vec<u8> {
+        if let Some(annotator) = self.annotator.lock().unwrap().as_mut() {
+            annotator.scan(&chunk);
+        }
+        let chunk = match self.tracer.lock().unwrap().as_mut() {
+            Some(tracer) => tracer.filter(chunk),
+            None => chunk,
+        };
+        redactor.redact(chunk)
+    }
+}
+
+/// Returns the tag of the lines of `stream` in the merged output.
+fn tag(stream: Stream) -> &'static [u8] {
+    match stream {
+        Stream::Out => b"out: ",
+        Stream::Err => b"err: ",
+    }
+}
+
+/// Output of both streams of a job in order of arrival, with each line tagged with its stream.
+#[derive(Debug, Default)]
+struct Merged {
+    buffer: Buffer,
+    /// Stream of the last line and whether the line is complete.
+    last: Option<(Stream, bool)>,
+    /// End of each chunk of output in the merged output and in its stream, oldest first.
+    chunks: VecDeque<(u64, Stream, u64)>,
+}
+
+impl Merged {
+    /// Appends a `chunk` of `stream`, which ends at offset `end` of the stream, evicting the
+    /// oldest output beyond the capacity whether it has been read or not.
+    fn push(&mut self, stream: Stream, chunk: &[u8], end: u64) {
+        if self.buffer.detached || chunk.is_empty() {
+            return;
+        }
+        for line in chunk.split_inclusive(|b| *b == b'\n') {
+            match self.last {
+                Some((last, false)) if last == stream => {}
+                // Break the incomplete line of the other stream.
+                Some((_, false)) => {
+                    self.buffer.data.push_back(b'\n');
+                    self.buffer.data.extend(tag(stream));
+                }
+                _ => self.buffer.data.extend(tag(stream)),
+            }
+            self.buffer.data.extend(line);
+            self.last = Some((stream, line.ends_with(b"\n")));
+        }
+        self.chunks.push_back((self.buffer.end(), stream, end));
+
+        let evict = self.buffer.data.len().saturating_sub(CAPACITY);
+        drop(self.buffer.data.drain(..evict));
+        self.buffer.start += evict as u64;
+        self.buffer.read = self.buffer.read.max(self.buffer.start);
+        while matches!(self.chunks.front(), Some((end, ..)) if *end <= self.buffer.start) {
+            _ = self.chunks.pop_front();
+        }
+    }
+
+    /// Reads a chunk of `stream` from `offset`, or from the oldest output buffered, on behalf
+    /// of someone else than the owner of the job. The output does not count as read, so the
+    /// job keeps being throttled by the reads of its owner.
+    pub(crate) fn peek(&self, stream: Stream, offset: Option<u64>) -> Chunk {
+        self.get(stream).buffer.lock().unwrap().peek(offset)
+    }
+
+    /// Reads a chunk of the merged output from `offset`, or after the output read last,
+    /// returning the offsets in the standard output and the standard error up to which the
+    /// chunk covers them.
+    fn read(&mut self, offset: Option<u64>) -> (Chunk, Option<u64>, Option<u64>) {
+        let chunk = self.buffer.read(offset);
+        let (mut stdout, mut stderr) = (None, None);
+        for (end, stream, stream_end) in &self.chunks {
+            if *end > chunk.next {
+                break;
+            }
+            match stream {
+                Stream::Out => stdout = Some(*stream_end),
+                Stream::Err => stderr = Some(*stream_end),
+            }
+        }
+        (chunk, stdout, stderr)
+    }
+
+    fn detach(&mut self) {
+        self.buffer.detached = true;
+        self.buffer.data.clear();
+        self.chunks.clear();
+    }
+}
+
+/// What happens once a job exceeds the output cap.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub(crate) enum CapAction {
+    /// The output beyond the cap is discarded, while the job keeps running.
+    Truncate,
+    /// The job is killed.
+    Kill,
+}
+
+/// Cap of the output of both streams of a job combined, which is enforced as the output is
+/// captured, whether it is read or not.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Cap {
+    pub(crate) bytes: u64,
+    pub(crate) action: CapAction,
+}
+
+impl Cap {
+    /// Returns the notice appended to each stream in place of the output beyond the cap.
+    fn notice(self) -> String {
+        let max = self
+            .bytes
+            .file_size(options::CONVENTIONAL)
+            .unwrap_or_else(|_| format!("{} B", self.bytes));
+        match self.action {
+            CapAction::Truncate => format!("\n[The output was truncated at {max}]\n"),
+            CapAction::Kill => {
+                format!("\n[Your workload exceeded the output cap of {max} and was killed]\n")
+            }
+        }
+    }
+}
+
+/// State shared by the tasks draining the streams of a job.
+#[derive(Debug)]
+struct Shared {
+    owner: User,
+    merged: Mutex<Merged>,
+    cap: Option<Cap>,
+    /// Number of bytes of output captured from both streams.
+    captured: AtomicU64,
+    /// Whether the job has exceeded the cap.
+    exceeded: AtomicBool,
+}
+
+impl Shared {
+    /// Returns as much of a `chunk` as the cap allows, and the cap if the chunk exceeds it.
+    fn cap(&self, mut chunk: Vec<u8>) -> (Vec<u8>, Option<Cap>) {
+        let cap = match self.cap {
+            Some(cap) => cap,
+            None => return (chunk, None),
+        };
+        let captured = self
+            .captured
+            .fetch_add(chunk.len() as u64, Ordering::Relaxed);
+        let room = cap.bytes.saturating_sub(captured);
+        if chunk.len() as u64 <= room {
+            return (chunk, None);
+        }
+        chunk.truncate(room as usize);
+        (chunk, Some(cap))
+    }
+
+    /// Enforces the `cap` on the job with `id` once it exceeded it on either stream.
+    async fn enforce(&self, id: &str, cap: Cap) {
+        if self.exceeded.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let user = self.owner;
+        match cap.action {
+            CapAction::Truncate => warn!(job_id = id, %user, "truncated job output at its cap"),
+            CapAction::Kill => {
+                warn!(job_id = id, %user, "killing job exceeding its output cap");
+                crate::kill_job(user, id, Reason::OutputLimit).await;
+            }
+        }
+        security::quota_exceeded(user, Quota::Output).await;
+    }
+}
+
+/// Output read from the streams of a job, which its output limits apply to.
+#[derive(Debug)]
+struct Usage {
+    /// Number of bytes read from the standard output and the standard error.
+    bytes: (u64, u64),
+    /// Start of the current one second window of output and the lines output within it.
+    window: (Instant, u64),
+}
+
+/// Output of both streams of a job, which is read without locking the job, so that the output
+/// can be read while the job is queried or controlled.
+#[derive(Debug)]
+pub(crate) struct Streams {
+    stdout: Arc<Output>,
+    stderr: Arc<Output>,
+    shared: Arc<Shared>,
+    usage: Mutex<Usage>,
+}
+
+impl Streams {
+    /// Drains the `stdout` and `stderr` pipes of the job with `id` of `owner` in the background,
+    /// logging up to `limit` bytes of each and enforcing the output `cap`, if any. The WASI call
+    /// trace is filtered out of the standard error if `trace` is set.
+    pub(crate) fn capture(
+        id: &str,
+        owner: User,
+        stdout: impl AsyncRead + Unpin + Send + 'static,
+        stderr: impl AsyncRead + Unpin + Send + 'static,
+        trace: bool,
+        limit: u64,
+        cap: Option<Cap>,
+    ) -> Self {
+        let shared = Arc::new(Shared {
+            owner,
+            merged: Mutex::default(),
+            cap,
+            captured: AtomicU64::new(0),
+            exceeded: AtomicBool::new(false),
+        });
+        Self {
+            stdout: capture(id, Stream::Out, stdout, false, limit, shared.clone()),
+            stderr: capture(id, Stream::Err, stderr, trace, limit, shared.clone()),
+            shared,
+            usage: Mutex::new(Usage {
+                bytes: (0, 0),
+                window: (clock::now(), 0),
+            }),
+        }
+    }
+
+    fn get(&self, stream: Stream) -> &Output {
+        match stream {
+            Stream::Out => &self.stdout,
+            Stream::Err => &self.stderr,
+        }
+    }
+
+    /// Reads a chunk of `stream` from `offset`, or after the output read last, returning which
+    /// of the `limits` the job has exceeded with the output not read before, if any.
+    pub(crate) fn read(
+        &self,
+        stream: Stream,
+        offset: Option<u64>,
+        limits: OutputLimits,
+    ) -> (Chunk, Option<OutputExceeded>) {
+        let chunk = self.get(stream).read(offset);
+        let fresh = chunk.fresh().len() as u64;
+        let bytes = match stream {
+            Stream::Out => (fresh, 0),
+            Stream::Err => (0, fresh),
+        };
+        let exceeded = self.account(bytes, chunk.fresh(), limits);
+        (chunk, exceeded)
+    }
+
+    /// Reads a chunk of `stream` from `offset`, or from the oldest output buffered, on behalf
+    /// of someone else than the owner of the job. The output does not count as read, so the
+    /// job keeps being throttled by the reads of its owner.
+    pub(crate) fn peek(&self, stream: Stream, offset: Option<u64>) -> Chunk {
+        self.get(stream).buffer.lock().unwrap().peek(offset)
+    }
+
+    /// Reads a chunk of the merged output from `offset`, or after the output read last, like
+    /// `read`. The output of the streams in the chunk counts as read.
+    pub(crate) fn read_merged(
+        &self,
+        offset: Option<u64>,
+        limits: OutputLimits,
+    ) -> (Chunk, Option<OutputExceeded>) {
+        let (chunk, stdout, stderr) = self.shared.merged.lock().unwrap().read(offset);
+        let bytes = (
+            stdout.map_or(0, |end| self.stdout.consume(end)),
+            stderr.map_or(0, |end| self.stderr.consume(end)),
+        );
+        let exceeded = self.account(bytes, chunk.fresh(), limits);
+        (chunk, exceeded)
+    }
+
+    /// Accounts for the `bytes` of the standard output and the standard error read, with the
+    /// `fresh` output, returning which of the `limits` the job has exceeded, if any.
+    fn account(
+        &self,
+        bytes: (u64, u64),
+        fresh: &[u8],
+        limits: OutputLimits,
+    ) -> Option<OutputExceeded> {
+        let mut usage = self.usage.lock().unwrap();
+        usage.bytes.0 += bytes.0;
+        usage.bytes.1 += bytes.1;
+        if let Some(max) = limits.bytes {
+            if usage.bytes.0 + usage.bytes.1 > max {
+                return Some(OutputExceeded::Bytes(max));
+            }
+        }
+
+        let (start, lines) = &mut usage.window;
+        if start.elapsed() >= Duration::from_secs(1) {
+            *start = clock::now();
+            *lines = 0;
+        }
+        *lines += fresh.iter().filter(|b| **b == b'\n').count() as u64;
+        match limits.lines_per_sec {
+            Some(max) if *lines > max => Some(OutputExceeded::Rate(max)),
+            _ => None,
+        }
+    }
+
+    /// Returns whether `stream` has been closed and all of its output has been read.
+    pub(crate) fn is_drained(&self, stream: Stream) -> bool {
+        let buffer = self.get(stream).buffer.lock().unwrap();
+        buffer.closed && buffer.read == buffer.end()
+    }
+
+    /// Returns the number of bytes read from the standard output and the standard error.
+    pub(crate) fn bytes(&self) -> (u64, u64) {
+        self.usage.lock().unwrap().bytes
+    }
+
+    /// Returns the WASI calls recorded so far, if the trace is filtered out of the standard error.
+    pub(crate) fn report(&self) -> Option<Report> {
+        self.stderr.report()
+    }
+
+    /// Returns the stages of the Keep of the job recognized so far.
+    pub(crate) fn annotations(&self) -> Vec<Annotation> {
+        self.stderr.annotations()
+    }
+
+    /// Stops buffering the output once it is no longer read, e.g. because the job was removed.
+    pub(crate) fn detach(&self) {
+        self.stdout.detach();
+        self.stderr.detach();
+        self.shared.merged.lock().unwrap().detach();
+    }
+}
+
+/// Drains `pipe`, an output stream of the job with `id`, in the background, logging up to
+/// `limit` bytes of it. The WASI call trace is filtered out of the output if `trace` is set.
+fn capture(
+    id: &str,
+    stream: Stream,
+    pipe: impl AsyncRead + Unpin + Send + 'static,
+    trace: bool,
+    limit: u64,
+    shared: Arc<Shared>,
+) -> Arc<Output> {
+    let output = Arc::new(Output {
+        tracer: Mutex::new(trace.then(Tracer::default)),
+        annotator: Mutex::new((stream == Stream::Err).then(Annotator::default)),
+        ..Default::default()
+    });
+    let id = id.to_string();
+    _ = tokio::spawn({
+        let output = output.clone();
+        async move {
+            let log = Log::create(&id, stream, limit).await;
+            pump(&id, stream, pipe, &output, &shared, log).await;
+        }
+    });
+    output
+}
+
+/// Passes the output of `stream` read from `pipe` on to `output`, the merged output and `log`,
+/// until the job exits, and then finishes the log. Once the job exceeds its output cap, the pipe is still drained, so that
+/// the job does not block on it, but the output is discarded.
+async fn pump(
+    id: &str,
+    stream: Stream,
+    mut pipe: impl AsyncRead + Unpin,
+    output: &Output,
+    shared: &Shared,
+    mut log: Log,
+) {
+    let mut redactor = Redactor::default();
+    let mut buf = [0; 4096];
+    let mut capped = false;
+    loop {
+        let chunk = match timeout(FLUSH_DELAY, pipe.read(&mut buf)).await {
+            Ok(Ok(0)) => break,
+            Ok(Ok(size)) => buf[..size].to_vec(),
+            Ok(Err(e)) => {
+                error!(error = ?e, job_id = id, "failed to read job output");
+                break;
+            }
+            // Flush the output held back by the filters.
+            Err(..) => Vec::new(),
+        };
+        let chunk = output.filter(&mut redactor, chunk);
+        if !capped && !chunk.is_empty() {
+            capped = pass(id, stream, chunk, output, shared, &mut log).await;
+        }
+    }
+    let chunk = output.filter(&mut redactor, Vec::new());
+    if !capped {
+        _ = pass(id, stream, chunk, output, shared, &mut log).await;
+    }
+    output.close();
+    log.finish().await;
+}
+
+/// Passes a `chunk` of `stream` on as far as the output cap allows, returning whether the cap
+/// was exceeded.
+async fn pass(
+    id: &str,
+    stream: Stream,
+    chunk: Vec<u8>,
+    output: &Output,
+    shared: &Shared,
+    log: &mut Log,
+) -> bool {
+    let (mut chunk, exceeded) = shared.cap(chunk);
+    if let Some(cap) = exceeded {
+        chunk.extend_from_slice(cap.notice().as_bytes());
+    }
+    log.append(&chunk).await;
+    let end = output.push(&chunk).await;
+    shared.merged.lock().unwrap().push(stream, &chunk, end);
+    if let Some(cap) = exceeded {
+        shared.enforce(id, cap).await;
+    }
+    exceeded.is_some()
+}
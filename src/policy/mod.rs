@@ -0,0 +1,212 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Decisions of the limits which apply to a request, made by a policy operators can replace.
+//!
+//! The default policy grants the limits of the tier of the user, which depends on their roles,
+//! e.g. whether they have starred the Enarx repository. Besides the default and starred tiers,
+//! operators can define tiers of their own with `--tiers`. Instances built with the `scripting`
+//! feature can instead be passed a Rhai script with `--limit-policy`, which decides with the
+//! full context of each request.
+
+#[cfg(feature = "scripting")]
+mod script;
+
+use crate::auth::{Roles, User};
+use crate::Limits;
+
+use std::fmt::Debug;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use humansize::{file_size_opts as options, FileSize};
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+use tracing::error;
+
+/// Configured limit policy.
+static POLICY: OnceCell<Box<dyn Policy>> = OnceCell::new();
+
+/// Context of a request limits are decided for.
+#[derive(Clone, Debug)]
+pub(crate) struct Context {
+    /// The user making the request, `None` if they are not logged in.
+    pub(crate) user: Option<User>,
+    /// Drawbridge namespace of the workload, if it is deployed from Drawbridge.
+    // Only scripted policies tell namespaces and times apart.
+    #[cfg_attr(not(feature = "scripting"), allow(dead_code))]
+    pub(crate) namespace: Option<String>,
+    #[cfg_attr(not(feature = "scripting"), allow(dead_code))]
+    pub(crate) now: DateTime<Utc>,
+}
+
+impl Context {
+    pub(crate) fn new(user: User) -> Self {
+        Self {
+            user: Some(user),
+            namespace: None,
+            now: Utc::now(),
+        }
+    }
+
+    /// Returns the context of a request by a user who is not logged in.
+    pub(crate) fn anonymous() -> Self {
+        Self {
+            user: None,
+            namespace: None,
+            now: Utc::now(),
+        }
+    }
+
+    /// Sets the namespace of the workload from its Drawbridge `slug`, e.g. `user/repo:0.1.0`.
+    pub(crate) fn slug(mut self, slug: &str) -> Self {
+        self.namespace = slug.split_once('/').map(|(namespace, _)| namespace.into());
+        self
+    }
+
+    /// Returns the roles of the user, none if they are not logged in.
+    fn roles(&self) -> Roles {
+        self.user.as_ref().map_or_else(Roles::default, User::roles)
+    }
+}
+
+/// Limits which apply to a request.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Decision {
+    /// Maximum size of a workload in bytes.
+    pub(crate) size: usize,
+    /// Timeout of a job.
+    pub(crate) time_to_live: Duration,
+    /// Maximum total runtime a job may be extended to, which is never less than its timeout.
+    pub(crate) max_time_to_live: Duration,
+    /// Number of jobs the user may submit per hour, if limited.
+    pub(crate) submissions: Option<u32>,
+}
+
+impl Decision {
+    pub(crate) fn size_human(&self) -> String {
+        self.size
+            .file_size(options::CONVENTIONAL)
+            .unwrap_or_else(|e| {
+                error!(error = ?e, "Failed to get human readable size string");
+                "?".to_string()
+            })
+    }
+}
+
+/// Tier of limits defined by the operators, granted to users with any of its roles.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct Tier {
+    pub(crate) name: String,
+    /// Roles granting the tier.
+    pub(crate) roles: Roles,
+    /// Maximum size of a workload in megabytes.
+    size: usize,
+    /// Timeout of a job in seconds.
+    timeout: u64,
+    /// Maximum total runtime a job may be extended to in seconds, its timeout if not given.
+    timeout_max: Option<u64>,
+    /// Number of jobs a user may submit per hour, unlimited if not given.
+    submissions: Option<u32>,
+}
+
+impl Tier {
+    fn decision(&self) -> Decision {
+        let time_to_live = Duration::from_secs(self.timeout);
+        Decision {
+            size: self.size * 1024 * 1024,
+            time_to_live,
+            max_time_to_live: self
+                .timeout_max
+                .map_or(time_to_live, Duration::from_secs)
+                .max(time_to_live),
+            submissions: self.submissions.filter(|submissions| *submissions > 0),
+        }
+    }
+}
+
+/// Tiers defined by the operators, ranked above the starred tier from lowest to highest.
+/// Users get the highest tier granted by any of their roles.
+///
+/// For example:
+/// ```toml
+/// [[tiers]]
+/// name = "maintainer"
+/// roles = ["member"]
+/// size = 100
+/// timeout = 3600
+/// timeout-max = 14400
+/// ```
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ExtraTiers {
+    pub(crate) tiers: Vec<Tier>,
+}
+
+impl FromStr for ExtraTiers {
+    type Err = toml::de::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        toml::from_str(s)
+    }
+}
+
+/// Decides the limits which apply to requests.
+pub(crate) trait Policy: Debug + Send + Sync {
+    /// Decides the limits which apply in `ctx`, given the `limits` configured for the tiers.
+    fn decide(&self, limits: &Limits, ctx: &Context) -> Decision;
+
+    /// Returns whether the policy grants the limits of the tiers alone, so that the limits of
+    /// a user are explained by their tier.
+    fn is_tiered(&self) -> bool {
+        false
+    }
+}
+
+/// The default policy, granting the limits of the tier of the user.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Tiers;
+
+impl Policy for Tiers {
+    fn decide(&self, limits: &Limits, ctx: &Context) -> Decision {
+        if let Some(tier) = limits.tier(ctx.roles()) {
+            return tier.decision();
+        }
+        let star = limits.is_starred(ctx.roles());
+        Decision {
+            size: limits.size(star),
+            time_to_live: limits.time_to_live(star),
+            max_time_to_live: limits.max_time_to_live(star),
+            submissions: limits.submissions(star),
+        }
+    }
+
+    fn is_tiered(&self) -> bool {
+        true
+    }
+}
+
+/// Loads the limit policy script at `path`, or sets up the default policy if `None`.
+pub(crate) fn init(path: Option<&Path>) -> anyhow::Result<()> {
+    let policy: Box<dyn Policy> = match path {
+        None => Box::new(Tiers),
+        #[cfg(feature = "scripting")]
+        Some(path) => Box::new(script::Script::load(path)?),
+        #[cfg(not(feature = "scripting"))]
+        Some(path) => anyhow::bail!(
+            "cannot load limit policy `{}` without the `scripting` feature",
+            path.display()
+        ),
+    };
+    POLICY
+        .set(policy)
+        .map_err(|_| anyhow::anyhow!("limit policy already initialized"))
+}
+
+/// Returns the limit policy of this instance.
+pub(crate) fn get() -> &'static dyn Policy {
+    POLICY.get_or_init(|| Box::new(Tiers)).as_ref()
+}
@@ -0,0 +1,170 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Limit policy scripted in Rhai.
+//!
+//! The script defines `fn decide(ctx, limits)`, which is called with the context of a request
+//! and the limits the tiers grant, and returns the limits to apply. The context has the keys
+//! `uid` (`()` if the user is not logged in), `roles` (e.g. `["starred", "default"]`),
+//! `starred`, `guest`, `namespace` (`()` unless the workload is deployed from Drawbridge),
+//! `hour` (0-23, UTC) and `weekday` (0 for Monday). The limits have the keys `size` (in
+//! bytes), `ttl` and `max_ttl` (in seconds), and `submissions` (per hour, `()` if unlimited).
+//! Keys missing from the returned limits keep the limits of the tiers.
+//!
+//! ```rhai
+//! fn decide(ctx, limits) {
+//!     // Jobs run longer at night, when this instance is quiet.
+//!     if ctx.hour < 6 {
+//!         limits.ttl *= 2;
+//!         limits.max_ttl *= 2;
+//!     }
+//!     limits
+//! }
+//! ```
+//!
+//! If the script fails, the limits of the tiers apply.
+
+use super::{Context, Decision, Policy, Tiers};
+use crate::auth::Role;
+use crate::Limits;
+
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context as _};
+use chrono::{Datelike, Timelike};
+use rhai::{Dynamic, Engine, Map, Scope, AST};
+use tracing::{error, info};
+
+/// Name of the function of the script deciding the limits.
+const DECIDE: &str = "decide";
+
+/// Maximum number of operations of a decision, so that a faulty script cannot hang requests.
+const MAX_OPERATIONS: u64 = 100_000;
+
+#[derive(Debug)]
+pub(super) struct Script {
+    engine: Engine,
+    ast: AST,
+}
+
+impl Script {
+    /// Compiles the script at `path`.
+    pub(super) fn load(path: &Path) -> anyhow::Result<Self> {
+        let mut engine = Engine::new();
+        _ = engine.set_max_operations(MAX_OPERATIONS);
+        let ast = engine
+            .compile_file(path.into())
+            .map_err(|e| anyhow!("{e}"))
+            .with_context(|| format!("failed to compile limit policy `{}`", path.display()))?;
+        if !ast
+            .iter_functions()
+            .any(|f| f.name == DECIDE && f.params.len() == 2)
+        {
+            bail!(
+                "limit policy `{}` does not define `fn {DECIDE}(ctx, limits)`",
+                path.display()
+            );
+        }
+        info!(path = %path.display(), "loaded limit policy");
+        Ok(Self { engine, ast })
+    }
+
+    fn eval(&self, ctx: &Context, tiers: Decision) -> anyhow::Result<Decision> {
+        let mut context = Map::new();
+        let user = ctx.user.as_ref();
+        _ = context.insert(
+            "uid".into(),
+            user.map_or(Dynamic::UNIT, |user| Dynamic::from(user.uid() as i64)),
+        );
+        let roles = ctx.roles();
+        _ = context.insert(
+            "roles".into(),
+            roles
+                .iter()
+                .map(|role| Dynamic::from(role.to_string()))
+                .collect::<Vec<_>>()
+                .into(),
+        );
+        _ = context.insert("starred".into(), roles.contains(Role::Starred).into());
+        _ = context.insert(
+            "guest".into(),
+            user.map_or(false, |user| user.expires().is_some()).into(),
+        );
+        _ = context.insert(
+            "namespace".into(),
+            ctx.namespace.clone().map_or(Dynamic::UNIT, Dynamic::from),
+        );
+        _ = context.insert("hour".into(), Dynamic::from(ctx.now.hour() as i64));
+        _ = context.insert(
+            "weekday".into(),
+            Dynamic::from(ctx.now.weekday().num_days_from_monday() as i64),
+        );
+
+        let mut limits = Map::new();
+        _ = limits.insert("size".into(), Dynamic::from(tiers.size as i64));
+        _ = limits.insert(
+            "ttl".into(),
+            Dynamic::from(tiers.time_to_live.as_secs() as i64),
+        );
+        _ = limits.insert(
+            "max_ttl".into(),
+            Dynamic::from(tiers.max_time_to_live.as_secs() as i64),
+        );
+        _ = limits.insert(
+            "submissions".into(),
+            tiers.submissions.map_or(Dynamic::UNIT, |submissions| {
+                Dynamic::from(i64::from(submissions))
+            }),
+        );
+
+        let decided = self
+            .engine
+            .call_fn::<Map>(
+                &mut Scope::new(),
+                &self.ast,
+                DECIDE,
+                (Dynamic::from(context), Dynamic::from(limits)),
+            )
+            .map_err(|e| anyhow!("{e}"))?;
+        let get = |key: &str| -> anyhow::Result<Option<u64>> {
+            decided
+                .get(key)
+                .map(|value| {
+                    value
+                        .as_int()
+                        .ok()
+                        .and_then(|value| u64::try_from(value).ok())
+                        .ok_or_else(|| anyhow!("`{key}` is not a non-negative integer"))
+                })
+                .transpose()
+        };
+
+        let time_to_live = get("ttl")?.map_or(tiers.time_to_live, Duration::from_secs);
+        let submissions = match decided.get("submissions") {
+            None => tiers.submissions,
+            Some(submissions) if submissions.is_unit() => None,
+            Some(_) => get("submissions")?
+                .filter(|submissions| *submissions > 0)
+                .map(|submissions| u32::try_from(submissions).unwrap_or(u32::MAX)),
+        };
+        Ok(Decision {
+            size: get("size")?.map_or(tiers.size, |size| size as usize),
+            time_to_live,
+            max_time_to_live: get("max_ttl")?
+                .map_or(tiers.max_time_to_live, Duration::from_secs)
+                .max(time_to_live),
+            submissions,
+        })
+    }
+}
+
+impl Policy for Script {
+    fn decide(&self, limits: &Limits, ctx: &Context) -> Decision {
+        let tiers = Tiers.decide(limits, ctx);
+        self.eval(ctx, tiers).unwrap_or_else(|e| {
+            error!(error = ?e, "limit policy failed, applying the limits of the tiers");
+            tiers
+        })
+    }
+}
@@ -0,0 +1,102 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Routing of requests for `<job ID>-<port>.<proxy domain>` to the ports of running jobs.
+
+use crate::features::{self, Feature};
+use crate::JOBS;
+
+use axum::body::Body;
+use axum::http::{header, Request, StatusCode, Uri};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use tracing::{debug, error};
+
+#[derive(Clone, Debug)]
+pub(crate) struct Proxy {
+    /// Domain, whose subdomains are routed to jobs.
+    domain: String,
+    /// Scheme of the public URLs of the subdomains.
+    scheme: String,
+}
+
+impl Proxy {
+    pub(crate) fn new(domain: String, scheme: String) -> Self {
+        Self {
+            domain: domain.trim_matches('.').to_ascii_lowercase(),
+            scheme,
+        }
+    }
+
+    /// Returns the public URL of `port` of the job with `id`.
+    pub(crate) fn url(&self, id: &str, port: u16) -> String {
+        format!("{}://{id}-{port}.{}", self.scheme, self.domain)
+    }
+
+    /// Parses the job ID and port out of a `Host` header.
+    fn parse(&self, host: &str) -> Option<(String, u16)> {
+        let host = host.to_ascii_lowercase();
+        let host = host
+            .rsplit_once(':')
+            .map_or(host.as_str(), |(host, _)| host);
+        let sub = host.strip_suffix(&self.domain)?.strip_suffix('.')?;
+        let (id, port) = sub.rsplit_once('-')?;
+        Some((id.into(), port.parse().ok()?))
+    }
+
+    /// Forwards requests for subdomains of the proxy domain to the jobs, passing other
+    /// requests on to the benefice routes.
+    pub(crate) async fn route(self, req: Request<Body>, next: Next<Body>) -> Response {
+        let target = req
+            .headers()
+            .get(header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .and_then(|host| self.parse(host));
+        let (id, port) = match target {
+            Some(target) => target,
+            None => return next.run(req).await,
+        };
+        if let Err(resp) = features::require(Feature::Proxy).await {
+            return resp;
+        }
+
+        let mut host_port = None;
+        for job in JOBS.read().await.values() {
+            let job = job.read().await;
+            if job.id == id {
+                host_port = job
+                    .mapped_ports
+                    .iter()
+                    .find(|(_, (cont, url))| *cont == port && *url == self.url(&id, port))
+                    .map(|(host, _)| *host);
+                break;
+            }
+        }
+        let host_port = match host_port {
+            Some(host_port) => host_port,
+            None => return (StatusCode::NOT_FOUND, "No such workload port").into_response(),
+        };
+
+        let (mut parts, body) = req.into_parts();
+        let path = parts
+            .uri
+            .path_and_query()
+            .map_or("/", |path| path.as_str())
+            .to_string();
+        parts.uri = match Uri::try_from(format!("http://127.0.0.1:{host_port}{path}")) {
+            Ok(uri) => uri,
+            Err(_) => return StatusCode::BAD_REQUEST.into_response(),
+        };
+        debug!(job_id = id, port, host_port, "proxying request");
+        match hyper::Client::new()
+            .request(Request::from_parts(parts, body))
+            .await
+        {
+            Ok(resp) => resp.into_response(),
+            Err(e) => {
+                error!(error = ?e, job_id = id, port, "failed to proxy request");
+                (StatusCode::BAD_GATEWAY, "The workload did not respond").into_response()
+            }
+        }
+    }
+}
@@ -0,0 +1,94 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Queue of submissions waiting for a slot while all slots are taken, so that users are not
+//! bounced when this instance is busy.
+//!
+//! Waiting submissions hold their request open and are admitted in order of arrival as slots
+//! free up. They leave the queue once admitted, once they have waited too long, or once their
+//! client disconnects.
+
+use crate::auth::User;
+use crate::clock::{self, Instant};
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use axum::Json;
+use once_cell::sync::Lazy;
+use serde_json::json;
+
+/// Interval at which waiting submissions check whether a slot has freed up.
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Tickets of the waiting submissions and their users, in order of arrival.
+static QUEUE: Lazy<Mutex<VecDeque<(u64, User)>>> = Lazy::new(Default::default);
+
+static NEXT_TICKET: AtomicU64 = AtomicU64::new(0);
+
+/// Place of a submission in the queue, which it leaves once dropped.
+#[derive(Debug)]
+pub(crate) struct Place {
+    ticket: u64,
+    joined: Instant,
+}
+
+impl Place {
+    /// Returns the position of the submission in the queue, starting at 1.
+    pub(crate) fn position(&self) -> usize {
+        let queue = QUEUE.lock().unwrap();
+        queue
+            .iter()
+            .position(|(ticket, _)| *ticket == self.ticket)
+            .map_or(queue.len(), |index| index + 1)
+    }
+
+    /// Returns how long the submission has been waiting.
+    pub(crate) fn waited(&self) -> Duration {
+        self.joined.elapsed()
+    }
+}
+
+impl Drop for Place {
+    fn drop(&mut self) {
+        QUEUE
+            .lock()
+            .unwrap()
+            .retain(|(ticket, _)| *ticket != self.ticket);
+    }
+}
+
+/// Queues a submission of `user`, unless they already have one waiting.
+pub(crate) fn join(user: User) -> Option<Place> {
+    let mut queue = QUEUE.lock().unwrap();
+    if queue.iter().any(|(_, queued)| *queued == user) {
+        return None;
+    }
+    let ticket = NEXT_TICKET.fetch_add(1, Ordering::Relaxed);
+    queue.push_back((ticket, user));
+    Some(Place {
+        ticket,
+        joined: clock::now(),
+    })
+}
+
+/// Returns the number of waiting submissions.
+pub(crate) fn len() -> usize {
+    QUEUE.lock().unwrap().len()
+}
+
+/// Returns the position of the waiting submission of `user`, if any, and the length of the
+/// queue, so that pages can report progress while the submission waits.
+pub(crate) async fn status(user: User) -> Json<serde_json::Value> {
+    let queue = QUEUE.lock().unwrap();
+    let position = queue
+        .iter()
+        .position(|(_, queued)| *queued == user)
+        .map(|index| index + 1);
+    Json(json!({
+        "position": position,
+        "length": queue.len(),
+    }))
+}
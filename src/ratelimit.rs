@@ -0,0 +1,101 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Rate limit of the submissions of each user, so that a single user cannot monopolize this
+//! instance by submitting job after job.
+//!
+//! Each user has a bucket holding up to their hourly allowance of submissions, which refills
+//! steadily over the hour. Starting a job takes one submission from the bucket.
+
+use crate::auth::User;
+use crate::clock::{self, Instant};
+use crate::security::{self, Quota};
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use axum::http::header::RETRY_AFTER;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use once_cell::sync::Lazy;
+use tracing::info;
+
+/// Period the allowance of submissions applies to.
+const PERIOD: Duration = Duration::from_secs(60 * 60);
+
+/// Bucket of the submissions of each user, by their user ID.
+static BUCKETS: Lazy<Mutex<HashMap<u64, Bucket>>> = Lazy::new(Default::default);
+
+#[derive(Debug)]
+struct Bucket {
+    /// Submissions left, including fractions refilled since.
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// Refills the bucket with the submissions of an allowance of `per_hour` accrued since it
+    /// was updated last.
+    fn refill(&mut self, per_hour: u32) {
+        let now = clock::now();
+        let accrued = (now - self.updated).as_secs_f64() / PERIOD.as_secs_f64();
+        self.tokens = (self.tokens + accrued * f64::from(per_hour)).min(f64::from(per_hour));
+        self.updated = now;
+    }
+}
+
+/// Applies `f` to the refilled bucket of `user` with an allowance of `per_hour`.
+fn with_bucket<T>(user: User, per_hour: u32, f: impl FnOnce(&mut Bucket) -> T) -> T {
+    let mut buckets = BUCKETS.lock().unwrap();
+    let bucket = buckets.entry(user.uid()).or_insert_with(|| Bucket {
+        tokens: f64::from(per_hour),
+        updated: clock::now(),
+    });
+    bucket.refill(per_hour);
+    f(bucket)
+}
+
+/// Checks whether `user` may submit a job with an allowance of `per_hour` submissions,
+/// telling them when to try again otherwise.
+pub(crate) async fn check(user: User, per_hour: u32) -> Result<(), Response> {
+    let wait = with_bucket(user, per_hour, |bucket| {
+        (bucket.tokens < 1.0).then(|| {
+            let missing = 1.0 - bucket.tokens;
+            PERIOD.mul_f64(missing / f64::from(per_hour.max(1)))
+        })
+    });
+    let wait = match wait {
+        Some(wait) => wait,
+        None => return Ok(()),
+    };
+
+    info!(%user, per_hour, wait = wait.as_secs(), "rate limiting submission");
+    security::quota_exceeded(user, Quota::Submissions).await;
+    let minutes = (wait.as_secs() + 59) / 60;
+    Err((
+        StatusCode::TOO_MANY_REQUESTS,
+        [(RETRY_AFTER, wait.as_secs().max(1).to_string())],
+        format!(
+            "You may submit {per_hour} workloads per hour, \
+            try again in {minutes} minute(s)"
+        ),
+    )
+        .into_response())
+}
+
+/// Takes a submission of `user` with an allowance of `per_hour` from their bucket.
+pub(crate) fn take(user: User, per_hour: u32) {
+    with_bucket(user, per_hour, |bucket| {
+        bucket.tokens = (bucket.tokens - 1.0).max(0.0);
+    });
+}
+
+/// Forgets the buckets which have refilled completely.
+pub(crate) fn gc() {
+    let now = clock::now();
+    BUCKETS
+        .lock()
+        .unwrap()
+        .retain(|_, bucket| now - bucket.updated < PERIOD);
+}
@@ -0,0 +1,80 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Redaction of the output of jobs, e.g. to mask IP addresses or tokens printed by workloads.
+
+use std::str::FromStr;
+
+use anyhow::Context;
+use once_cell::sync::OnceCell;
+use regex::bytes::Regex;
+
+/// Replacement of redacted output.
+const MASK: &[u8] = b"[REDACTED]";
+
+/// Longest incomplete line held back until it is complete.
+const MAX_PENDING: usize = 4096;
+
+/// Rules of this instance.
+static RULES: OnceCell<Vec<Rule>> = OnceCell::new();
+
+/// A regular expression whose matches are masked in the output of jobs.
+#[derive(Clone, Debug)]
+pub(crate) struct Rule(Regex);
+
+impl FromStr for Rule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Regex::new(s)
+            .map(Self)
+            .with_context(|| format!("invalid redaction pattern `{s}`"))
+    }
+}
+
+/// Sets the redaction rules on startup.
+pub(crate) fn init(rules: Vec<Rule>) -> anyhow::Result<()> {
+    RULES
+        .set(rules)
+        .map_err(|_| anyhow::anyhow!("redaction rules already initialized"))
+}
+
+fn rules() -> &'static [Rule] {
+    RULES.get().map_or(&[], Vec::as_slice)
+}
+
+/// Redacts an output stream of a job.
+#[derive(Debug, Default)]
+pub(crate) struct Redactor {
+    /// Output of an incomplete line, which is held back until the line is complete,
+    /// so that matches spanning several chunks are redacted as well.
+    pending: Vec<u8>,
+}
+
+impl Redactor {
+    /// Redacts a `chunk` read from the stream, returning the output which may be passed on.
+    /// An empty chunk, i.e. no output within the read timeout, flushes the held back output.
+    pub(crate) fn redact(&mut self, chunk: Vec<u8>) -> Vec<u8> {
+        let rules = rules();
+        if rules.is_empty() {
+            return chunk;
+        }
+
+        let flush = chunk.is_empty();
+        self.pending.extend(chunk);
+        let end = if flush || self.pending.len() > MAX_PENDING {
+            self.pending.len()
+        } else {
+            match self.pending.iter().rposition(|b| *b == b'\n') {
+                Some(pos) => pos + 1,
+                None => return Vec::new(),
+            }
+        };
+
+        let mut output: Vec<u8> = self.pending.drain(..end).collect();
+        for Rule(regex) in rules {
+            output = regex.replace_all(&output, MASK).into_owned();
+        }
+        output
+    }
+}
@@ -0,0 +1,84 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Redirects to pages of this server, whose targets are validated against the external `--url`.
+//!
+//! Targets may come from clients, such as the page to return to after logging in, which is
+//! remembered in a cookie set by the browser. Targets which would lead away from this server,
+//! e.g. `//evil.example.com` or `https:\evil.example.com`, are replaced by the home page,
+//! so that links to this server cannot be abused as open redirects.
+
+use crate::auth::Url;
+
+use axum::response::Redirect;
+use axum_extra::extract::cookie::Cookie;
+use axum_extra::extract::CookieJar;
+use once_cell::sync::OnceCell;
+use tracing::warn;
+
+/// Cookie holding the page to return to after logging in, which is also set by the browser.
+const RETURN_COOKIE: &str = "LAST_PATH";
+
+/// Page redirected to instead of rejected targets.
+const HOME: &str = "/";
+
+/// External URL of this server, set by `init`.
+static BASE: OnceCell<Url> = OnceCell::new();
+
+/// Sets the external URL of this server on startup.
+pub(crate) fn init(url: Url) -> anyhow::Result<()> {
+    BASE.set(url)
+        .map_err(|_| anyhow::anyhow!("redirects already initialized"))
+}
+
+/// Returns the path and query of `target` if it is a page of this server, resolving it
+/// relative to the external URL.
+pub(crate) fn local(target: &str) -> Option<String> {
+    let base = BASE.get().expect("redirects not initialized");
+
+    // Browsers treat backslashes like slashes and ignore some control characters, so that
+    // targets containing them may be resolved differently than here.
+    if target.is_empty() || target.contains('\\') || target.chars().any(char::is_control) {
+        return None;
+    }
+    let url = base.join(target).ok()?;
+    let prefix = base.path().trim_end_matches('/');
+    let path = url.path();
+    if url.origin() != base.origin() || (path != prefix && !path.starts_with(&format!("{prefix}/")))
+    {
+        return None;
+    }
+
+    Some(match url.query() {
+        Some(query) => format!("{path}?{query}"),
+        None => path.into(),
+    })
+}
+
+/// Redirects to `target` if it is a page of this server, or to the home page otherwise.
+pub(crate) fn to(target: &str) -> Redirect {
+    match local(target) {
+        Some(target) => Redirect::to(&target),
+        None => {
+            warn!(target, "rejected redirect away from this server");
+            Redirect::to(HOME)
+        }
+    }
+}
+
+/// Remembers `target` as the page to return to after logging in.
+pub(crate) fn remember(jar: CookieJar, target: impl Into<String>) -> CookieJar {
+    jar.add(
+        Cookie::build(RETURN_COOKIE, target.into())
+            .path("/")
+            .finish(),
+    )
+}
+
+/// Redirects back to the page remembered in `jar`, or to the home page.
+pub(crate) fn back(jar: &CookieJar) -> Redirect {
+    match jar.get(RETURN_COOKIE) {
+        Some(cookie) => to(cookie.value()),
+        None => Redirect::to(HOME),
+    }
+}
@@ -0,0 +1,65 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Workloads of exited jobs, which are kept for a while so that they can be run again
+//! without uploading them again.
+//!
+//! Only the workload of the last job of each user is kept. Kept files remain accounted in the
+//! disk budget until they are removed, which is why they are only kept briefly.
+
+use crate::auth::User;
+use crate::Workload;
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use tokio::sync::Mutex;
+use tokio::time::sleep;
+use tracing::debug;
+
+/// How long the workload of an exited job is kept.
+const GRACE: Duration = Duration::from_secs(10 * 60);
+
+/// Interval at which expired workloads are removed.
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+struct Kept {
+    id: String,
+    workload: Workload,
+    since: Instant,
+}
+
+/// Kept workloads keyed by their owner.
+static KEPT: Lazy<Mutex<HashMap<User, Kept>>> = Lazy::new(Default::default);
+
+/// Keeps the `workload` of the job with `id` of `owner`, which has exited.
+pub(crate) async fn keep(owner: User, id: String, workload: Workload) {
+    debug!(job_id = id, %owner, "keeping workload of exited job");
+    let kept = Kept {
+        id,
+        workload,
+        since: Instant::now(),
+    };
+    _ = KEPT.lock().await.insert(owner, kept);
+}
+
+/// Takes the kept workload of the job with `id` of `owner`, if it has not expired yet.
+pub(crate) async fn take(owner: User, id: &str) -> Option<Workload> {
+    let mut kept = KEPT.lock().await;
+    match kept.get(&owner) {
+        Some(k) if k.id == id && k.since.elapsed() < GRACE => {
+            kept.remove(&owner).map(|k| k.workload)
+        }
+        _ => None,
+    }
+}
+
+/// Periodically removes the files of workloads kept for longer than the grace period.
+pub(crate) async fn expire() {
+    loop {
+        sleep(EXPIRE_INTERVAL).await;
+        KEPT.lock().await.retain(|_, k| k.since.elapsed() < GRACE);
+    }
+}
@@ -0,0 +1,136 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Security-relevant events, such as hits of the denylist, users repeatedly exceeding their
+//! quotas, administrative actions and failed attempts to administer this instance.
+//!
+//! Unlike the callbacks of jobs, these events are meant for the operators. They are recorded
+//! in the audit log and posted as JSON to the `--security-webhook`, if one is configured,
+//! so that security tooling can watch a public instance.
+
+use crate::audit::{Action, Event};
+use crate::auth::User;
+use crate::clock::{self, Instant};
+
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+use once_cell::sync::{Lazy, OnceCell};
+use reqwest::Url;
+use tokio::sync::Mutex;
+use tracing::{debug, warn};
+
+/// Maximum time allowed for delivering an event.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Number of times a user may exceed their quotas within `QUOTA_WINDOW` before it is reported.
+const QUOTA_REPEATS: usize = 3;
+
+/// Window in which repeatedly exceeded quotas are reported.
+const QUOTA_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// Webhook the events are posted to, if configured.
+static WEBHOOK: OnceCell<Url> = OnceCell::new();
+
+/// When each user recently exceeded a quota, oldest first.
+static EXCEEDED: Lazy<Mutex<HashMap<User, VecDeque<Instant>>>> = Lazy::new(Default::default);
+
+/// Sets the webhook the events are posted to on startup.
+pub(crate) fn init(url: Url) -> anyhow::Result<()> {
+    WEBHOOK
+        .set(url)
+        .map_err(|_| anyhow::anyhow!("security webhook already initialized"))
+}
+
+/// Quota of a user.
+#[derive(Copy, Clone, Debug)]
+pub(crate) enum Quota {
+    /// Size of the files of a workload.
+    Size,
+    /// Number of ports a workload listens on.
+    Ports,
+    /// Lines a workload prints per second.
+    OutputRate,
+    /// Total output of a workload.
+    Output,
+    /// Jobs a user submits per hour.
+    Submissions,
+    /// Jobs a user runs, and the minutes they run for, per day.
+    Daily,
+}
+
+impl Quota {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Size => "size",
+            Self::Ports => "ports",
+            Self::OutputRate => "output-rate",
+            Self::Output => "output",
+            Self::Submissions => "submissions",
+            Self::Daily => "daily",
+        }
+    }
+}
+
+/// Notes that `user` exceeded `quota`, reporting it once it happened repeatedly.
+pub(crate) async fn quota_exceeded(user: User, quota: Quota) {
+    let now = clock::now();
+    let repeated = {
+        let mut exceeded = EXCEEDED.lock().await;
+        let times = exceeded.entry(user).or_default();
+        while matches!(times.front(), Some(time) if now - *time > QUOTA_WINDOW) {
+            _ = times.pop_front();
+        }
+        times.push_back(now);
+        if times.len() >= QUOTA_REPEATS {
+            times.clear();
+            true
+        } else {
+            false
+        }
+    };
+    if repeated {
+        Event::new(user.uid(), Action::QuotaExceeded)
+            .detail(quota.as_str())
+            .record()
+            .await;
+    }
+}
+
+/// Forgets the quotas exceeded longer ago than the reporting window.
+pub(crate) async fn gc() {
+    let now = clock::now();
+    EXCEEDED
+        .lock()
+        .await
+        .retain(|_, times| matches!(times.back(), Some(time) if now - *time <= QUOTA_WINDOW));
+}
+
+/// Posts a security-relevant `event` to the webhook, if configured.
+pub(crate) fn alert(event: &Event) {
+    let url = match WEBHOOK.get() {
+        Some(url) => url.clone(),
+        None => return,
+    };
+    let body = match serde_json::to_vec(event) {
+        Ok(body) => body,
+        Err(e) => {
+            warn!(error = ?e, "failed to encode security event");
+            return;
+        }
+    };
+    _ = tokio::spawn(async move {
+        let result = reqwest::Client::new()
+            .post(url.clone())
+            .timeout(WEBHOOK_TIMEOUT)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status());
+        match result {
+            Ok(_) => debug!(%url, "delivered security event"),
+            Err(e) => warn!(%url, error = ?e, "failed to deliver security event"),
+        }
+    });
+}
@@ -0,0 +1,129 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Settings of this instance, which are reloaded from the `@config.toml` files on SIGHUP
+//! or by an administrator, without a restart.
+//!
+//! Limits, the job count, the port range and the examples take effect for new requests.
+//! Settings only read on startup, such as the listen address, OIDC, the storage, the garbage
+//! collection policy, disabled features and redaction rules, still require a restart.
+
+use crate::auth::Admin;
+use crate::examples::{Examples, LocalExample};
+use crate::{conffile, Args, Limits, Other};
+
+use std::sync::Arc;
+
+use anyhow::Context as _;
+use arc_swap::ArcSwap;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use clap::Parser;
+use once_cell::sync::OnceCell;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info};
+
+static SETTINGS: OnceCell<ArcSwap<Settings>> = OnceCell::new();
+
+#[derive(Debug)]
+pub(crate) struct Settings {
+    pub(crate) limits: Limits,
+    pub(crate) other: Other,
+    /// Examples
+    pub(crate) examples: Examples,
+    /// Prebuilt examples which can be run without uploading anything
+    pub(crate) gallery: Vec<LocalExample>,
+}
+
+impl Settings {
+    fn new(limits: Limits, other: Other) -> anyhow::Result<Self> {
+        // If no examples are provided the default examples will be used.
+        let examples = other.examples.clone().unwrap_or_default();
+        let gallery = match &other.examples_dir {
+            Some(dir) => LocalExample::load_dir(dir)?,
+            None => vec![],
+        };
+        Ok(Self {
+            limits,
+            other,
+            examples,
+            gallery,
+        })
+    }
+}
+
+/// Sets the settings parsed on startup.
+pub(crate) fn init(limits: Limits, other: Other) -> anyhow::Result<()> {
+    let settings = Settings::new(limits, other)?;
+    SETTINGS
+        .set(ArcSwap::from_pointee(settings))
+        .map_err(|_| anyhow::anyhow!("settings already initialized"))
+}
+
+/// Returns the current settings.
+pub(crate) fn load() -> Arc<Settings> {
+    SETTINGS
+        .get()
+        .expect("settings not initialized")
+        .load_full()
+}
+
+/// Returns the current limits.
+pub(crate) fn limits() -> Limits {
+    load().limits.clone()
+}
+
+/// Returns the current settings other than limits.
+pub(crate) fn other() -> Other {
+    load().other.clone()
+}
+
+/// Parses the arguments and `@config.toml` files again.
+fn parse() -> anyhow::Result<Settings> {
+    let (limits, _, other) = conffile::args()
+        .context("failed to parse config")
+        .and_then(|args| Args::try_parse_from(args).context("invalid config"))?
+        .split()?;
+    Settings::new(limits, other)
+}
+
+/// Replaces the current settings with the ones parsed again.
+async fn reload() -> anyhow::Result<()> {
+    let settings = tokio::task::spawn_blocking(parse)
+        .await
+        .context("failed to parse config")??;
+    SETTINGS
+        .get()
+        .context("settings not initialized")?
+        .store(Arc::new(settings));
+    info!("reloaded settings");
+    Ok(())
+}
+
+/// Reloads the settings whenever the process receives SIGHUP.
+pub(crate) async fn watch() {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            error!(error = ?e, "failed to listen for SIGHUP, settings are only reloaded on request");
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        if let Err(e) = reload().await {
+            error!(error = ?e, "failed to reload settings, keeping the current ones");
+        }
+    }
+}
+
+/// Reloads the settings on behalf of an administrator.
+pub(crate) async fn reload_admin(Admin(admin): Admin) -> Result<StatusCode, Response> {
+    info!(%admin, "reloading settings");
+    reload()
+        .await
+        .map(|()| StatusCode::NO_CONTENT)
+        .map_err(|e| {
+            error!(error = ?e, "failed to reload settings, keeping the current ones");
+            (StatusCode::BAD_REQUEST, format!("{e:#}")).into_response()
+        })
+}
@@ -0,0 +1,95 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Verification of cosign signatures of uploaded workloads.
+//!
+//! Signatures are created with `cosign sign-blob --key cosign.key main.wasm`
+//! and checked against the public keys of the trusted signers.
+
+use std::str::FromStr;
+
+use anyhow::Context;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use p256::ecdsa::signature::Verifier;
+use p256::ecdsa::{Signature, VerifyingKey};
+use p256::pkcs8::DecodePublicKey;
+use tracing::{debug, info};
+
+/// A signer whose signatures are trusted.
+#[derive(Clone, Debug)]
+pub(crate) struct Signer {
+    name: String,
+    key: VerifyingKey,
+}
+
+impl FromStr for Signer {
+    type Err = anyhow::Error;
+
+    /// Parses `<name>=<path to PEM encoded public key>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, path) = s
+            .split_once('=')
+            .with_context(|| format!("expected `<name>=<path>`, got `{s}`"))?;
+        let pem = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read public key at `{path}`"))?;
+        let key = VerifyingKey::from_public_key_pem(&pem)
+            .map_err(|e| anyhow::anyhow!("{e}"))
+            .with_context(|| format!("invalid ECDSA P-256 public key at `{path}`"))?;
+        Ok(Self {
+            name: name.into(),
+            key,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Policy {
+    pub(crate) signers: Vec<Signer>,
+    /// Whether unsigned workloads are rejected.
+    pub(crate) required: bool,
+}
+
+impl Policy {
+    /// Checks the base64 encoded `signature` of `wasm`, returning the name of the signer.
+    pub(crate) fn verify(
+        &self,
+        wasm: &[u8],
+        signature: Option<&str>,
+    ) -> Result<Option<String>, Response> {
+        let signature = match signature.map(str::trim) {
+            Some(signature) if !signature.is_empty() => signature,
+            _ if self.required => return Err((
+                StatusCode::FORBIDDEN,
+                "This instance only runs signed workloads, upload a cosign signature of the module",
+            )
+                .into_response()),
+            _ => return Ok(None),
+        };
+
+        let signature = base64::decode(signature)
+            .ok()
+            .and_then(|der| Signature::from_der(&der).ok())
+            .ok_or_else(|| {
+                (StatusCode::BAD_REQUEST, "The signature is malformed").into_response()
+            })?;
+        match self
+            .signers
+            .iter()
+            .find(|signer| signer.key.verify(wasm, &signature).is_ok())
+        {
+            Some(signer) => {
+                info!(signer = signer.name, "verified workload signature");
+                Ok(Some(signer.name.clone()))
+            }
+            None => {
+                debug!("workload signature does not match any trusted signer");
+                Err((
+                    StatusCode::FORBIDDEN,
+                    "The workload is not signed by a trusted signer",
+                )
+                    .into_response())
+            }
+        }
+    }
+}
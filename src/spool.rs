@@ -0,0 +1,137 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Spooling of uploaded files, which are kept in memory while small and spill to disk beyond
+//! a threshold.
+//!
+//! Workloads are mounted into their container by path, so files kept in memory are written
+//! to a memory-backed directory such as `/dev/shm` once complete.
+
+use std::path::{Path, PathBuf};
+
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use tempfile::NamedTempFile;
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, error, warn};
+
+#[derive(Clone, Debug)]
+pub(crate) struct Spool {
+    /// Size in bytes up to which files are kept in memory, if spooling is enabled.
+    threshold: Option<usize>,
+    /// Memory-backed directory for files kept in memory.
+    memory_dir: PathBuf,
+    /// Directory for files spilled to disk.
+    disk_dir: PathBuf,
+}
+
+impl Spool {
+    pub(crate) fn new(threshold: Option<usize>, memory_dir: PathBuf, disk_dir: PathBuf) -> Self {
+        Self {
+            threshold,
+            memory_dir,
+            disk_dir,
+        }
+    }
+
+    /// Starts spooling a new file.
+    pub(crate) fn writer(&self) -> Result<Writer<'_>, Response> {
+        let state = match self.threshold {
+            Some(_) => State::Memory(Vec::new()),
+            None => temp_file(&self.disk_dir).map(State::Disk)?,
+        };
+        Ok(Writer { spool: self, state })
+    }
+
+    /// Spools a complete file.
+    pub(crate) async fn write(&self, data: &[u8]) -> Result<NamedTempFile, Response> {
+        let mut writer = self.writer()?;
+        writer.write(data).await?;
+        writer.finish().await
+    }
+}
+
+#[derive(Debug)]
+enum State {
+    Memory(Vec<u8>),
+    Disk((NamedTempFile, File)),
+}
+
+/// A file being spooled.
+#[derive(Debug)]
+pub(crate) struct Writer<'a> {
+    spool: &'a Spool,
+    state: State,
+}
+
+impl Writer<'_> {
+    /// Appends a `chunk` to the file, spilling it to disk once it exceeds the threshold.
+    pub(crate) async fn write(&mut self, chunk: &[u8]) -> Result<(), Response> {
+        if let State::Memory(data) = &mut self.state {
+            if data.len() + chunk.len() <= self.spool.threshold.unwrap_or_default() {
+                data.extend_from_slice(chunk);
+                return Ok(());
+            }
+
+            debug!(len = data.len() + chunk.len(), "spilling upload to disk");
+            let (out, mut file) = temp_file(&self.spool.disk_dir)?;
+            write_chunk(&mut file, data).await?;
+            self.state = State::Disk((out, file));
+        }
+        match &mut self.state {
+            State::Disk((_, file)) => write_chunk(file, chunk).await,
+            State::Memory(_) => unreachable!("spooled file was spilled to disk"),
+        }
+    }
+
+    /// Completes the file, returning it.
+    pub(crate) async fn finish(self) -> Result<NamedTempFile, Response> {
+        match self.state {
+            State::Memory(data) => {
+                let (out, mut file) = temp_file(&self.spool.memory_dir).or_else(|_| {
+                    warn!(
+                        dir = %self.spool.memory_dir.display(),
+                        "failed to spool upload in memory, falling back to disk"
+                    );
+                    temp_file(&self.spool.disk_dir)
+                })?;
+                write_chunk(&mut file, &data).await?;
+                flush(file).await?;
+                Ok(out)
+            }
+            State::Disk((out, file)) => {
+                flush(file).await?;
+                Ok(out)
+            }
+        }
+    }
+}
+
+/// Creates a temporary file in `dir`, along with a handle to write to it asynchronously.
+fn temp_file(dir: &Path) -> Result<(NamedTempFile, File), Response> {
+    NamedTempFile::new_in(dir)
+        .and_then(|out| {
+            let file = out.as_file().try_clone()?;
+            Ok((out, File::from_std(file)))
+        })
+        .map_err(|e| {
+            error!(error = ?e, "failed to create a new temporary file");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        })
+}
+
+async fn write_chunk(file: &mut File, chunk: &[u8]) -> Result<(), Response> {
+    file.write_all(chunk).await.map_err(|e| {
+        error!(error = ?e, "failed to write chunk to temporary file");
+        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    })
+}
+
+/// Waits for all writes to a temporary file to complete.
+async fn flush(mut file: File) -> Result<(), Response> {
+    file.flush().await.map_err(|e| {
+        error!(error = ?e, "failed to write temporary file");
+        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    })
+}
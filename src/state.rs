@@ -0,0 +1,267 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Disk-backed record of running jobs, used to clean up after a crash.
+//!
+//! The host ports of a job are leased until its deadline, so that they are not handed out
+//! again before the job is cleaned up, even if the job was left behind by a crash.
+
+use crate::auth::Admin;
+use crate::clock::{self, Instant};
+use crate::job::{used_ports, Transport};
+use crate::settings;
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context as _;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use once_cell::sync::{Lazy, OnceCell};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tempfile::NamedTempFile;
+use tokio::process::Command;
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
+
+/// Name of the state file within the runtime directory.
+const STATE_FILE: &str = "benefice-jobs.json";
+
+/// Time after the deadline of a job until its port lease expires,
+/// which allows for the job to be cleaned up.
+const LEASE_GRACE: Duration = Duration::from_secs(5 * 60);
+
+/// Path to the state file, set by `recover`.
+static PATH: OnceCell<PathBuf> = OnceCell::new();
+
+/// Running jobs keyed by their ID.
+static ENTRIES: Lazy<Mutex<HashMap<String, Entry>>> = Lazy::new(Default::default);
+
+/// Minimal runtime state of a running job.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Entry {
+    pub(crate) id: String,
+    /// PID of the OCI engine client process.
+    pub(crate) pid: Option<u32>,
+    /// Reserved host ports.
+    pub(crate) ports: Vec<u16>,
+    pub(crate) owner: String,
+    /// Name given to the job by its owner.
+    #[serde(default)]
+    pub(crate) name: Option<String>,
+    /// Deadline of the job in seconds since the Unix epoch.
+    pub(crate) deadline: u64,
+}
+
+impl Entry {
+    /// Returns whether the port lease of the job has expired.
+    fn is_expired(&self) -> bool {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Duration::from_secs(self.deadline) + LEASE_GRACE < now
+    }
+}
+
+fn write(path: &Path, entries: &HashMap<String, Entry>) -> anyhow::Result<()> {
+    let dir = path
+        .parent()
+        .context("state file has no parent directory")?;
+    let mut file = NamedTempFile::new_in(dir).context("failed to create state file")?;
+    serde_json::to_writer(&mut file, &entries.values().collect::<Vec<_>>())
+        .context("failed to encode state")?;
+    file.flush().context("failed to write state")?;
+    _ = file.persist(path).context("failed to persist state file")?;
+    Ok(())
+}
+
+async fn update(f: impl FnOnce(&mut HashMap<String, Entry>)) {
+    let mut entries = ENTRIES.lock().await;
+    f(&mut entries);
+    entries.retain(|_, entry| !entry.is_expired());
+    if let Some(path) = PATH.get() {
+        if let Err(e) = write(path, &entries) {
+            error!(error = ?e, "failed to save job state");
+        }
+    }
+}
+
+/// Records a newly spawned job.
+pub(crate) async fn add(entry: Entry) {
+    update(|entries| _ = entries.insert(entry.id.clone(), entry)).await
+}
+
+/// Forgets a job which has ended.
+pub(crate) async fn remove(id: &str) {
+    update(|entries| _ = entries.remove(id)).await
+}
+
+/// Moves the deadline of a job whose timeout was extended.
+pub(crate) async fn extend(id: &str, deadline: Instant) {
+    let deadline = clock::unix_secs(deadline);
+    update(|entries| {
+        if let Some(entry) = entries.get_mut(id) {
+            entry.deadline = deadline;
+        }
+    })
+    .await
+}
+
+/// Releases the ports of a job which died, before it is cleaned up.
+pub(crate) async fn release_ports(id: &str) {
+    let mut released = false;
+    update(|entries| {
+        if let Some(entry) = entries.get_mut(id) {
+            released = !entry.ports.is_empty();
+            entry.ports.clear();
+        }
+    })
+    .await;
+    if released {
+        info!(job_id = id, "released ports of exited job");
+    }
+}
+
+/// Returns the host ports leased to jobs.
+pub(crate) async fn leased_ports<T: FromIterator<u16>>() -> T {
+    ENTRIES
+        .lock()
+        .await
+        .values()
+        .filter(|entry| !entry.is_expired())
+        .flat_map(|entry| entry.ports.iter().copied())
+        .collect()
+}
+
+/// Lists the leased ports along with the jobs owning them, and the remaining capacity
+/// of the port range currently configured.
+pub(crate) async fn ports(_: Admin) -> Result<impl IntoResponse, StatusCode> {
+    let settings = settings::load();
+    let port_range = settings.other.port_range.clone();
+    let used: HashSet<(Transport, u16)> =
+        used_ports(&settings.other.ss_command).await.map_err(|e| {
+            error!(error = ?e, "failed to lookup used ports");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let entries = ENTRIES.lock().await;
+    let mut leases: Vec<_> = entries
+        .values()
+        .filter(|entry| !entry.is_expired())
+        .flat_map(|entry| {
+            entry.ports.iter().map(move |port| {
+                json!({
+                    "port": port,
+                    "job": entry.id,
+                    "owner": entry.owner,
+                    "name": entry.name,
+                    "deadline": entry.deadline,
+                })
+            })
+        })
+        .collect();
+    leases.sort_by_key(|lease| lease["port"].as_u64());
+
+    let leased: HashSet<_> = entries
+        .values()
+        .filter(|entry| !entry.is_expired())
+        .flat_map(|entry| entry.ports.iter().copied())
+        .collect();
+    let available = port_range
+        .clone()
+        .filter(|port| {
+            !leased.contains(port)
+                && !used.contains(&(Transport::Tcp, *port))
+                && !used.contains(&(Transport::Udp, *port))
+        })
+        .count();
+
+    Ok(Json(json!({
+        "range": { "start": port_range.start, "end": port_range.end },
+        "capacity": port_range.len(),
+        "available": available,
+        "leases": leases,
+    })))
+}
+
+/// Returns whether the container of the job with `id` exists.
+async fn exists(oci_command: &OsStr, id: &str) -> anyhow::Result<bool> {
+    let out = Command::new(oci_command)
+        .args(["inspect", "--format", "{{.Id}}", id])
+        .output()
+        .await
+        .context("failed to inspect container")?;
+    Ok(out.status.success())
+}
+
+/// Cleans up jobs left behind by a previous instance using the state file in `runtime_dir`.
+///
+/// The output pipes of such jobs are gone, so they cannot be re-adopted and are killed instead.
+pub(crate) async fn recover(
+    runtime_dir: impl AsRef<Path>,
+    oci_command: impl AsRef<OsStr>,
+) -> anyhow::Result<()> {
+    let path = runtime_dir.as_ref().join(STATE_FILE);
+    PATH.set(path.clone())
+        .map_err(|_| anyhow::anyhow!("job state already initialized"))?;
+
+    let entries: Vec<Entry> = match std::fs::read(&path) {
+        Ok(data) => serde_json::from_slice(&data)
+            .with_context(|| format!("failed to parse `{}`", path.display()))?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("failed to read `{}`", path.display())),
+    };
+
+    let mut leased = HashMap::new();
+    for entry in entries {
+        match exists(oci_command.as_ref(), &entry.id).await {
+            Ok(false) => {
+                info!(
+                    job_id = entry.id,
+                    ports = ?entry.ports,
+                    "job left behind by a previous instance is gone, releasing its ports"
+                );
+                continue;
+            }
+            Ok(true) => {}
+            Err(e) => error!(error = ?e, job_id = entry.id, "failed to look up stale job"),
+        }
+
+        warn!(
+            job_id = entry.id,
+            pid = entry.pid,
+            owner = entry.owner,
+            ports = ?entry.ports,
+            "killing job left behind by a previous instance"
+        );
+        match Command::new(oci_command.as_ref())
+            .args(["rm", "--force", entry.id.as_str()])
+            .output()
+            .await
+        {
+            Ok(out) if out.status.success() => {
+                info!(job_id = entry.id, "killed stale job");
+                continue;
+            }
+            Ok(out) => warn!(
+                job_id = entry.id,
+                stderr = %String::from_utf8_lossy(&out.stderr),
+                "failed to kill stale job"
+            ),
+            Err(e) => error!(error = ?e, job_id = entry.id, "failed to kill stale job"),
+        }
+        // The ports may still be in use, so keep them leased until the lease expires.
+        if !entry.is_expired() {
+            _ = leased.insert(entry.id.clone(), entry);
+        }
+    }
+
+    write(&path, &leased)?;
+    *ENTRIES.lock().await = leased;
+    Ok(())
+}
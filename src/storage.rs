@@ -0,0 +1,300 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Storage of user-visible files, such as retained workloads and job logs, either on local disk
+//! or in an S3-compatible object store shared by all instances.
+//!
+//! Objects stored in S3 by previous instances are not tracked by the retention policy,
+//! so the bucket should have a lifecycle rule expiring them.
+
+use crate::gc::Rule;
+
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::Context as _;
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Redirect, Response};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use once_cell::sync::{Lazy, OnceCell};
+use reqwest::{Method, Url};
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+use tracing::{debug, error};
+
+/// Validity of presigned download URLs.
+const DOWNLOAD_URL_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// Validity of presigned URLs used by benefice itself.
+const REQUEST_URL_TTL: Duration = Duration::from_secs(60);
+
+/// Configured storage, if any.
+static STORAGE: OnceCell<Storage> = OnceCell::new();
+
+/// Objects stored by this instance, newest first.
+static OBJECTS: Lazy<Mutex<VecDeque<Object>>> = Lazy::new(Default::default);
+
+#[derive(Debug)]
+struct Object {
+    key: String,
+    size: u64,
+    created: SystemTime,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) enum Storage {
+    /// Files are stored in a local directory and served by benefice.
+    Local(PathBuf),
+    /// Files are stored in an S3 bucket and downloaded via presigned URLs.
+    S3(S3),
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct S3 {
+    /// Path-style URL of the bucket, for example `https://s3.us-east-1.amazonaws.com/my-bucket`.
+    pub(crate) url: Url,
+    pub(crate) region: String,
+    pub(crate) access_key_id: String,
+    pub(crate) secret_access_key: String,
+}
+
+/// Collects the objects stored in the local directory `root`.
+fn walk(root: &Path, dir: &Path, objects: &mut Vec<Object>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let meta = std::fs::metadata(&path)?;
+        if meta.is_dir() {
+            walk(root, &path, objects)?;
+        } else if let Ok(key) = path.strip_prefix(root) {
+            objects.push(Object {
+                key: key.to_string_lossy().into(),
+                size: meta.len(),
+                created: meta.modified()?,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Sets the storage used by this instance.
+pub(crate) fn init(storage: Storage) -> anyhow::Result<()> {
+    if let Storage::Local(dir) = &storage {
+        let mut objects = vec![];
+        match walk(dir, dir, &mut objects) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(e).with_context(|| format!("failed to read `{}`", dir.display()))
+            }
+            _ => {}
+        }
+        objects.sort_by(|a, b| b.created.cmp(&a.created));
+        *OBJECTS.try_lock().context("stored objects are in use")? = objects.into();
+    }
+    STORAGE
+        .set(storage)
+        .map_err(|_| anyhow::anyhow!("storage already initialized"))
+}
+
+/// Returns the storage used by this instance, if one is configured.
+pub(crate) fn get() -> Option<&'static Storage> {
+    STORAGE.get()
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Percent-encodes `s` as required by AWS Signature Version 4.
+fn encode(s: &str, encode_slash: bool) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                char::from(b).to_string()
+            }
+            b'/' if !encode_slash => "/".into(),
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+impl S3 {
+    /// Returns a URL to perform `method` on the object at `key`, signed using
+    /// AWS Signature Version 4 and valid for `ttl`.
+    fn presign(&self, method: &Method, key: &str, ttl: Duration) -> anyhow::Result<Url> {
+        let now = Utc::now();
+        let date = now.format("%Y%m%d").to_string();
+        let timestamp = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let scope = format!("{date}/{}/s3/aws4_request", self.region);
+
+        let host = self.url.host_str().context("S3 URL has no host")?;
+        let host = match self.url.port() {
+            Some(port) => format!("{host}:{port}"),
+            None => host.into(),
+        };
+        let path = format!(
+            "{}/{}",
+            self.url.path().trim_end_matches('/'),
+            encode(key, false)
+        );
+        let query = format!(
+            "X-Amz-Algorithm=AWS4-HMAC-SHA256\
+             &X-Amz-Credential={}\
+             &X-Amz-Date={timestamp}\
+             &X-Amz-Expires={}\
+             &X-Amz-SignedHeaders=host",
+            encode(&format!("{}/{scope}", self.access_key_id), true),
+            ttl.as_secs(),
+        );
+
+        let request = format!("{method}\n{path}\n{query}\nhost:{host}\n\nhost\nUNSIGNED-PAYLOAD");
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{timestamp}\n{scope}\n{:x}",
+            Sha256::digest(request)
+        );
+        let key = [self.region.as_str(), "s3", "aws4_request"].iter().fold(
+            hmac(
+                format!("AWS4{}", self.secret_access_key).as_bytes(),
+                date.as_bytes(),
+            ),
+            |key, part| hmac(&key, part.as_bytes()),
+        );
+        let signature: String = hmac(&key, string_to_sign.as_bytes())
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+
+        let mut url = self.url.clone();
+        url.set_path(&path);
+        url.set_query(Some(&format!("{query}&X-Amz-Signature={signature}")));
+        Ok(url)
+    }
+
+    fn request(&self, method: Method, key: &str) -> anyhow::Result<reqwest::RequestBuilder> {
+        let url = self.presign(&method, key, REQUEST_URL_TTL)?;
+        Ok(reqwest::Client::new().request(method, url))
+    }
+}
+
+impl Storage {
+    /// Stores `data` at `key`, replacing any previous contents.
+    pub(crate) async fn put(&self, key: &str, data: Vec<u8>) -> anyhow::Result<()> {
+        let size = data.len() as u64;
+        match self {
+            Self::Local(dir) => {
+                let path = dir.join(key);
+                if let Some(parent) = path.parent() {
+                    tokio::fs::create_dir_all(parent)
+                        .await
+                        .with_context(|| format!("failed to create `{}`", parent.display()))?;
+                }
+                tokio::fs::write(&path, data)
+                    .await
+                    .with_context(|| format!("failed to write `{}`", path.display()))?;
+            }
+            Self::S3(s3) => {
+                _ = s3
+                    .request(Method::PUT, key)?
+                    .body(data)
+                    .send()
+                    .await
+                    .and_then(|resp| resp.error_for_status())
+                    .with_context(|| format!("failed to upload `{key}`"))?;
+            }
+        }
+
+        let mut objects = OBJECTS.lock().await;
+        objects.retain(|object| object.key != key);
+        objects.push_front(Object {
+            key: key.into(),
+            size,
+            created: SystemTime::now(),
+        });
+        Ok(())
+    }
+
+    /// Removes the object at `key`, if it exists.
+    async fn delete(&self, key: &str) -> anyhow::Result<()> {
+        match self {
+            Self::Local(dir) => {
+                let path = dir.join(key);
+                match tokio::fs::remove_file(&path).await {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                        return Err(e)
+                            .with_context(|| format!("failed to remove `{}`", path.display()))
+                    }
+                    _ => {}
+                }
+                // Remove the parent directory as well, once it is empty.
+                if let Some(parent) = path.parent().filter(|parent| parent != dir) {
+                    _ = tokio::fs::remove_dir(parent).await;
+                }
+            }
+            Self::S3(s3) => {
+                _ = s3
+                    .request(Method::DELETE, key)?
+                    .send()
+                    .await
+                    .and_then(|resp| resp.error_for_status())
+                    .with_context(|| format!("failed to delete `{key}`"))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the objects with keys starting with `prefix` which are no longer retained by
+    /// `rule`.
+    pub(crate) async fn gc(&self, prefix: &str, rule: &Rule) {
+        let expired = {
+            let mut objects = OBJECTS.lock().await;
+            let (mut matching, other): (Vec<_>, Vec<_>) = objects
+                .drain(..)
+                .partition(|object| object.key.starts_with(prefix));
+            let retained = rule.retain(
+                matching.iter(),
+                |object| object.created.elapsed().unwrap_or_default(),
+                |object| object.size,
+            );
+            let expired = matching.split_off(retained);
+            objects.extend(matching);
+            objects.extend(other);
+            objects
+                .make_contiguous()
+                .sort_by(|a, b| b.created.cmp(&a.created));
+            expired
+        };
+        for object in expired {
+            match self.delete(&object.key).await {
+                Ok(()) => debug!(key = object.key, "removed expired object"),
+                Err(e) => error!(error = ?e, key = object.key, "failed to remove expired object"),
+            }
+        }
+    }
+
+    /// Responds with the object at `key`, either directly or by redirecting to a presigned URL.
+    pub(crate) async fn download(&self, key: &str) -> Result<Response, Response> {
+        match self {
+            Self::Local(dir) => match tokio::fs::read(dir.join(key)).await {
+                Ok(data) => Ok(
+                    ([(header::CONTENT_TYPE, "application/octet-stream")], data).into_response()
+                ),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    Err(StatusCode::NOT_FOUND.into_response())
+                }
+                Err(e) => {
+                    error!(error = ?e, key, "failed to read stored file");
+                    Err(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+                }
+            },
+            Self::S3(s3) => match s3.presign(&Method::GET, key, DOWNLOAD_URL_TTL) {
+                Ok(url) => Ok(Redirect::temporary(url.as_str()).into_response()),
+                Err(e) => {
+                    error!(error = ?e, key, "failed to sign download URL");
+                    Err(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+                }
+            },
+        }
+    }
+}
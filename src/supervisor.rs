@@ -0,0 +1,125 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Supervision of long-running background tasks.
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use once_cell::sync::Lazy;
+use serde_json::json;
+use tokio::sync::RwLock;
+use tokio::time::sleep;
+use tracing::{error, info};
+
+/// Delay before restarting a task which panicked.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// A task which panicked within this window is reported as unhealthy.
+const HEALTH_WINDOW: Duration = Duration::from_secs(5 * 60);
+
+/// Health of the supervised tasks keyed by their name.
+static TASKS: Lazy<RwLock<BTreeMap<&'static str, Health>>> = Lazy::new(Default::default);
+
+#[derive(Debug, Default)]
+struct Health {
+    running: bool,
+    restarts: u64,
+    last_panic: Option<(Instant, String)>,
+}
+
+impl Health {
+    fn is_healthy(&self) -> bool {
+        self.running && !matches!(&self.last_panic, Some((at, _)) if at.elapsed() < HEALTH_WINDOW)
+    }
+}
+
+/// Spawns a background task, which is restarted whenever it panics.
+pub(crate) fn spawn<F, Fut>(name: &'static str, task: F)
+where
+    F: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    _ = tokio::spawn(async move {
+        loop {
+            TASKS.write().await.entry(name).or_default().running = true;
+            let result = tokio::spawn(task()).await;
+
+            let mut tasks = TASKS.write().await;
+            let health = tasks.entry(name).or_default();
+            health.running = false;
+            match result {
+                Ok(()) => {
+                    info!(task = name, "background task finished");
+                    return;
+                }
+                Err(e) if e.is_panic() => {
+                    let panic = e.into_panic();
+                    let msg = panic
+                        .downcast_ref::<&str>()
+                        .map(|msg| msg.to_string())
+                        .or_else(|| panic.downcast_ref::<String>().cloned())
+                        .unwrap_or_default();
+                    error!(
+                        task = name,
+                        panic = msg,
+                        "background task panicked, restarting"
+                    );
+                    health.restarts += 1;
+                    health.last_panic = Some((Instant::now(), msg));
+                }
+                Err(e) => {
+                    error!(task = name, error = ?e, "background task was cancelled");
+                    return;
+                }
+            }
+            drop(tasks);
+            sleep(RESTART_DELAY).await;
+        }
+    });
+}
+
+/// Reports the health of all supervised tasks and of the devices required to run jobs.
+pub(crate) async fn healthz() -> impl IntoResponse {
+    let tasks = TASKS.read().await;
+    let devices = crate::devices::unavailable().await;
+    let healthy = tasks.values().all(Health::is_healthy) && devices.is_none();
+    let report: BTreeMap<_, _> = tasks
+        .iter()
+        .map(|(name, health)| {
+            (
+                *name,
+                json!({
+                    "healthy": health.is_healthy(),
+                    "running": health.running,
+                    "restarts": health.restarts,
+                    "last_panic": health.last_panic.as_ref().map(|(at, msg)| json!({
+                        "seconds_ago": at.elapsed().as_secs(),
+                        "message": msg,
+                    })),
+                }),
+            )
+        })
+        .collect();
+
+    let status = if healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        status,
+        Json(json!({
+            "healthy": healthy,
+            "tasks": report,
+            "devices": {
+                "available": devices.is_none(),
+                "reason": devices,
+            },
+        })),
+    )
+}
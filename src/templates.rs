@@ -1,7 +1,11 @@
 // SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
 // SPDX-License-Identifier: AGPL-3.0-only
 
-use crate::examples::Example;
+use crate::auth::github::Repo;
+use crate::daily::Allowance;
+use crate::examples::{Example, LocalExample};
+use crate::history::Run;
+use crate::limits::Limit;
 
 use askama::Template;
 use axum::http::StatusCode;
@@ -20,11 +24,76 @@ pub(crate) struct IdxTemplate<'a> {
     pub(crate) page: Page,
     pub(crate) toml: &'static str,
     pub(crate) examples: &'a [Example],
+    pub(crate) gallery: &'a [LocalExample],
     pub(crate) user: bool,
     pub(crate) star: bool,
+    /// Repositories whose stargazers get the `starred` role.
+    pub(crate) star_repos: &'a [Repo],
+    /// ID of the job the user is currently running.
+    pub(crate) running: Option<String>,
+    pub(crate) history: Vec<Run>,
     pub(crate) _size: usize,
     pub(crate) size_human: String,
     pub(crate) ttl: u64,
+    /// What is left of the daily quota of the user, if they have one.
+    pub(crate) allowance: Option<Allowance>,
+}
+
+#[derive(Template)]
+#[template(path = "job.html")]
+pub(crate) struct JobTemplate {
+    pub(crate) demo_fqdn: String,
+    pub(crate) user: bool,
+    pub(crate) id: String,
+    /// JSON-encoded port mappings of the job.
+    pub(crate) ports: String,
+    /// How the job ended, `None` while it is running.
+    pub(crate) outcome: Option<String>,
+}
+
+#[derive(Template)]
+#[template(path = "share.html")]
+pub(crate) struct ShareTemplate {
+    pub(crate) demo_fqdn: String,
+    pub(crate) user: bool,
+    pub(crate) id: String,
+    /// Token of the share link the job is viewed through.
+    pub(crate) token: String,
+}
+
+#[derive(Template)]
+#[template(path = "limits.html")]
+pub(crate) struct LimitsTemplate {
+    pub(crate) demo_fqdn: String,
+    pub(crate) user: bool,
+    /// Tier of the user, which determines most of their limits.
+    pub(crate) tier: String,
+    pub(crate) limits: Vec<Limit>,
+}
+
+#[derive(Template)]
+#[template(path = "compare.html")]
+pub(crate) struct CompareTemplate {
+    pub(crate) demo_fqdn: String,
+    pub(crate) user: bool,
+    /// Backends workloads are compared across.
+    pub(crate) backends: Vec<String>,
+}
+
+#[derive(Template)]
+#[template(path = "login.html")]
+pub(crate) struct LoginTemplate {
+    pub(crate) demo_fqdn: String,
+    pub(crate) user: bool,
+    /// Names and titles of the OpenID Connect providers users can log in with.
+    pub(crate) providers: Vec<(String, String)>,
+}
+
+#[derive(Template)]
+#[template(path = "tokens.html")]
+pub(crate) struct TokensTemplate {
+    pub(crate) demo_fqdn: String,
+    pub(crate) user: bool,
 }
 
 pub(crate) struct HtmlTemplate<T>(pub(crate) T);
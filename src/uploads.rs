@@ -0,0 +1,345 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Uploads in progress, which their clients identify by the ID they pass in the
+//! `X-Upload-Id` header.
+//!
+//! Clients can abort an upload, e.g. after picking a large file by accident. Aborting an upload
+//! stops receiving it and releases its temporary files and its upload lane. Uploads can only be
+//! aborted until their job is started, after which the job can be killed.
+//!
+//! Clients can also watch an upload over a WebSocket, which reports its progress, the validation
+//! of the workload, the start of its job and, on request, the first output of the job as JSON
+//! events. The first output is then no longer returned by the output endpoints of the job.
+
+use crate::auth::User;
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::async_trait;
+use axum::body::Body;
+use axum::extract::ws::{Message, WebSocketUpgrade};
+use axum::extract::{FromRequest, Path as AxumPath, Query, RequestParts};
+use axum::http::header::CONTENT_LENGTH;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use futures_util::StreamExt;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, oneshot};
+use tokio::time::{interval, timeout};
+use tracing::info;
+
+/// Header carrying the ID of an upload chosen by the client.
+const HEADER: &str = "x-upload-id";
+
+/// Maximum length of the ID of an upload.
+const MAX_ID_LEN: usize = 64;
+
+/// Interval between progress events of an upload.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Time a watcher waits for the next event of an upload before giving up.
+const WATCH_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Events buffered for slow watchers.
+const EVENTS_CAPACITY: usize = 16;
+
+/// Uploads in progress keyed by their owner and ID, along with a way to abort them.
+static UPLOADS: Lazy<Mutex<HashMap<(User, String), oneshot::Sender<()>>>> =
+    Lazy::new(Default::default);
+
+/// Channels of the events of uploads keyed by their owner and ID, which are created by
+/// whichever of the upload and its first watcher comes first.
+static CHANNELS: Lazy<Mutex<HashMap<(User, String), Channel>>> = Lazy::new(Default::default);
+
+#[derive(Debug)]
+struct Channel {
+    events: broadcast::Sender<Event>,
+    /// Whether the upload has started, after which the channel is removed along with it.
+    uploading: bool,
+}
+
+/// Event of an upload, sent to its watchers.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub(crate) enum Event {
+    /// Bytes of the request received so far, out of its length if known.
+    Progress { received: u64, total: Option<u64> },
+    /// The workload was received and, if uploaded, validated.
+    Validated { wasm: Option<serde_json::Value> },
+    /// The job was started.
+    Started {
+        id: serde_json::Value,
+        ports: serde_json::Value,
+    },
+    /// First output of the job, on `stdout` or `stderr`.
+    Output { stream: &'static str, data: String },
+    /// The upload was rejected or aborted.
+    Failed { status: u16 },
+}
+
+/// ID of an upload, if the client passed one, along with the number of bytes received.
+#[derive(Clone, Debug)]
+pub(crate) struct UploadId {
+    id: Option<String>,
+    received: Arc<AtomicU64>,
+    total: Option<u64>,
+}
+
+#[async_trait]
+impl FromRequest<Body> for UploadId {
+    type Rejection = Response;
+
+    async fn from_request(req: &mut RequestParts<Body>) -> Result<Self, Self::Rejection> {
+        let id = match req.headers().get(HEADER) {
+            Some(id) => id,
+            None => {
+                return Ok(Self {
+                    id: None,
+                    received: Default::default(),
+                    total: None,
+                })
+            }
+        };
+        let id = match id.to_str() {
+            Ok(id) if !id.is_empty() && id.len() <= MAX_ID_LEN => id.to_string(),
+            _ => {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    format!("The upload ID must be 1 to {MAX_ID_LEN} visible ASCII characters"),
+                )
+                    .into_response())
+            }
+        };
+        let total = req
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|len| len.to_str().ok())
+            .and_then(|len| len.parse().ok());
+
+        // Count the bytes of the body as it is received, to report the progress.
+        let received = Arc::new(AtomicU64::new(0));
+        if let Some(body) = req.body_mut() {
+            let counter = received.clone();
+            let inner = std::mem::take(body);
+            *body = Body::wrap_stream(inner.inspect(move |chunk| {
+                if let Ok(chunk) = chunk {
+                    _ = counter.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+                }
+            }));
+        }
+        Ok(Self {
+            id: Some(id),
+            received,
+            total,
+        })
+    }
+}
+
+impl UploadId {
+    fn progress(&self) -> Event {
+        Event::Progress {
+            received: self.received.load(Ordering::Relaxed),
+            total: self.total,
+        }
+    }
+}
+
+/// Returns the channel of the events of an upload, creating it if needed.
+fn channel(key: &(User, String), uploading: bool) -> broadcast::Sender<Event> {
+    let mut channels = CHANNELS.lock().unwrap();
+    let channel = channels.entry(key.clone()).or_insert_with(|| Channel {
+        events: broadcast::channel(EVENTS_CAPACITY).0,
+        uploading,
+    });
+    channel.uploading |= uploading;
+    channel.events.clone()
+}
+
+/// Sends the events of an upload to its watchers, until it is dropped.
+#[derive(Debug)]
+pub(crate) struct Events(Option<((User, String), broadcast::Sender<Event>)>);
+
+impl Events {
+    pub(crate) fn new(user: User, upload: &UploadId) -> Self {
+        Self(upload.id.clone().map(|id| {
+            let key = (user, id);
+            let events = channel(&key, true);
+            (key, events)
+        }))
+    }
+
+    pub(crate) fn send(&self, event: Event) {
+        if let Some((_, events)) = &self.0 {
+            // There may be no watchers.
+            _ = events.send(event);
+        }
+    }
+
+    /// Reports how the job of the upload was started.
+    pub(crate) fn finish(&self, result: &Result<Json<serde_json::Value>, Response>) {
+        self.send(match result {
+            Ok(Json(resp)) => Event::Started {
+                id: resp["id"].clone(),
+                ports: resp["ports"].clone(),
+            },
+            Err(resp) => Event::Failed {
+                status: resp.status().as_u16(),
+            },
+        });
+    }
+}
+
+impl Drop for Events {
+    fn drop(&mut self) {
+        if let Some((key, _)) = &self.0 {
+            _ = CHANNELS.lock().unwrap().remove(key);
+        }
+    }
+}
+
+/// Unregisters an upload once it is done, however it ended.
+#[derive(Debug)]
+struct Registration((User, String));
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        _ = UPLOADS.lock().unwrap().remove(&self.0);
+    }
+}
+
+/// Receives an upload of `user`, unless it is aborted first, reporting its progress.
+pub(crate) async fn abortable<T>(
+    user: User,
+    upload: &UploadId,
+    events: &Events,
+    receive: impl Future<Output = Result<T, Response>>,
+) -> Result<T, Response> {
+    let id = match &upload.id {
+        Some(id) => id.clone(),
+        None => return receive.await,
+    };
+
+    let (tx, mut rx) = oneshot::channel();
+    let key = (user, id);
+    {
+        let mut uploads = UPLOADS.lock().unwrap();
+        if uploads.contains_key(&key) {
+            return Err((
+                StatusCode::CONFLICT,
+                "An upload with this ID is already in progress",
+            )
+                .into_response());
+        }
+        _ = uploads.insert(key.clone(), tx);
+    }
+    let registration = Registration(key);
+
+    tokio::pin!(receive);
+    let mut ticks = interval(PROGRESS_INTERVAL);
+    loop {
+        tokio::select! {
+            result = &mut receive => {
+                events.send(upload.progress());
+                return result;
+            }
+            Ok(()) = &mut rx => {
+                let (user, id) = &registration.0;
+                info!(%user, upload_id = id, "upload aborted");
+                return Err((StatusCode::BAD_REQUEST, "The upload was aborted").into_response());
+            }
+            _ = ticks.tick() => events.send(upload.progress()),
+        }
+    }
+}
+
+/// Aborts an upload of the user in progress.
+pub(crate) async fn abort(AxumPath(id): AxumPath<String>, user: User) -> StatusCode {
+    let abort = UPLOADS.lock().unwrap().remove(&(user, id));
+    match abort.map(|abort| abort.send(())) {
+        Some(Ok(())) => StatusCode::NO_CONTENT,
+        _ => StatusCode::NOT_FOUND,
+    }
+}
+
+/// Removes the channel of an upload which was never received once its watchers are gone.
+#[derive(Debug)]
+struct Watcher((User, String));
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        let mut channels = CHANNELS.lock().unwrap();
+        if matches!(
+            channels.get(&self.0),
+            Some(channel) if !channel.uploading && channel.events.receiver_count() == 0
+        ) {
+            _ = channels.remove(&self.0);
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub(crate) struct WatchQuery {
+    /// Whether to send the first output of the job once it is started.
+    output: bool,
+}
+
+/// Streams the events of an upload of the user over a WebSocket, which may be opened
+/// before the upload starts. The WebSocket is closed once the job is started,
+/// or once its first output was sent if requested.
+pub(crate) async fn watch(
+    AxumPath(id): AxumPath<String>,
+    Query(WatchQuery { output }): Query<WatchQuery>,
+    user: User,
+    ws: WebSocketUpgrade,
+) -> Response {
+    if id.is_empty() || id.len() > MAX_ID_LEN {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+
+    ws.on_upgrade(move |mut socket| async move {
+        let key = (user, id);
+        let mut events = channel(&key, false).subscribe();
+        let _watcher = Watcher(key);
+
+        loop {
+            let event = match timeout(WATCH_TIMEOUT, events.recv()).await {
+                Ok(Ok(event)) => event,
+                // Progress events which were missed are superseded by the next ones.
+                Ok(Err(broadcast::error::RecvError::Lagged(_))) => continue,
+                Ok(Err(broadcast::error::RecvError::Closed)) | Err(_) => break,
+            };
+            let done = matches!(event, Event::Started { .. } | Event::Failed { .. });
+            let job = match &event {
+                Event::Started { id, .. } if output => id.as_str().map(String::from),
+                _ => None,
+            };
+            let msg = serde_json::to_string(&event).unwrap();
+            if socket.send(Message::Text(msg)).await.is_err() {
+                return;
+            }
+
+            if let Some(job) = job {
+                if let Some((stream, data)) = crate::first_output(job, user).await {
+                    let event = Event::Output {
+                        stream,
+                        data: String::from_utf8_lossy(&data).into_owned(),
+                    };
+                    let msg = serde_json::to_string(&event).unwrap();
+                    _ = socket.send(Message::Text(msg)).await;
+                }
+            }
+            if done {
+                break;
+            }
+        }
+        _ = socket.send(Message::Close(None)).await;
+    })
+}
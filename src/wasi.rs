@@ -0,0 +1,128 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Report of the WASI capabilities exercised by a workload, e.g. whether it read the clock
+//! or requested entropy, to teach what a workload does inside a Keep.
+//!
+//! The WASI calls of the workload are traced by Enarx on the standard error of the job,
+//! from which the trace is filtered out before it is passed on.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use once_cell::sync::Lazy;
+use regex::bytes::Regex;
+use serde::{Deserialize, Deserializer, Serialize};
+
+/// Log filter enabling the trace of WASI calls by Enarx.
+pub(crate) const LOG_FILTER: &str = "wasi_common=trace,wiggle=trace";
+
+/// Longest incomplete line held back until it is complete.
+const MAX_PENDING: usize = 4096;
+
+/// Matches lines of the WASI call trace, capturing the name of the called function if present.
+static TRACE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"(?:wasi_snapshot_preview1|wasi_common)(?:.*function="?(\w+))?"#)
+        .expect("invalid WASI trace pattern")
+});
+
+/// Capabilities which WASI calls are grouped by.
+const CAPABILITIES: [&str; 9] = [
+    "clock",
+    "entropy",
+    "environment",
+    "input/output",
+    "filesystem",
+    "network",
+    "scheduling",
+    "process",
+    "other",
+];
+
+/// Returns the capability exercised by a call to the WASI `function`.
+fn capability(function: &str) -> &'static str {
+    match function {
+        "clock_time_get" | "clock_res_get" => "clock",
+        "random_get" => "entropy",
+        "args_get" | "args_sizes_get" | "environ_get" | "environ_sizes_get" => "environment",
+        "fd_read" | "fd_write" | "fd_pread" | "fd_pwrite" => "input/output",
+        f if f.starts_with("path_") || f.starts_with("fd_") => "filesystem",
+        f if f.starts_with("sock_") => "network",
+        "poll_oneoff" | "sched_yield" => "scheduling",
+        f if f.starts_with("proc_") => "process",
+        _ => "other",
+    }
+}
+
+/// WASI functions called by a workload, keyed by the capability they exercise.
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(transparent)]
+pub(crate) struct Report(BTreeMap<&'static str, BTreeSet<String>>);
+
+impl<'de> Deserialize<'de> for Report {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let calls = BTreeMap::<String, BTreeSet<String>>::deserialize(deserializer)?;
+        let mut report = Self::default();
+        for (capability, functions) in calls {
+            let capability = CAPABILITIES
+                .into_iter()
+                .find(|known| *known == capability)
+                .unwrap_or("other");
+            report.0.entry(capability).or_default().extend(functions);
+        }
+        Ok(report)
+    }
+}
+
+/// Filters the WASI call trace out of the standard error of a job.
+#[derive(Debug, Default)]
+pub(crate) struct Tracer {
+    /// Output of an incomplete line, which is held back until the line is complete.
+    pending: Vec<u8>,
+    report: Report,
+}
+
+impl Tracer {
+    /// Records the WASI calls in a `chunk` read from the standard error of the job, returning
+    /// the output which is not part of the trace. An empty chunk flushes the held back output.
+    pub(crate) fn filter(&mut self, chunk: Vec<u8>) -> Vec<u8> {
+        let flush = chunk.is_empty();
+        self.pending.extend(chunk);
+        let end = if flush || self.pending.len() > MAX_PENDING {
+            self.pending.len()
+        } else {
+            match self.pending.iter().rposition(|b| *b == b'\n') {
+                Some(pos) => pos + 1,
+                None => return Vec::new(),
+            }
+        };
+
+        let mut output = Vec::with_capacity(end);
+        for line in self
+            .pending
+            .drain(..end)
+            .as_slice()
+            .split_inclusive(|b| *b == b'\n')
+        {
+            match TRACE.captures(line) {
+                Some(captures) => {
+                    if let Some(function) = captures.get(1) {
+                        let function = String::from_utf8_lossy(function.as_bytes());
+                        _ = self
+                            .report
+                            .0
+                            .entry(capability(&function))
+                            .or_default()
+                            .insert(function.into_owned());
+                    }
+                }
+                None => output.extend_from_slice(line),
+            }
+        }
+        output
+    }
+
+    /// Returns the WASI calls recorded so far.
+    pub(crate) fn report(&self) -> Report {
+        self.report.clone()
+    }
+}
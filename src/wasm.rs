@@ -0,0 +1,164 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Validation of uploaded wasm modules.
+
+use crate::fingerprint::leb128;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use humansize::{file_size_opts as options, FileSize};
+use serde::Serialize;
+use tracing::{debug, error};
+use wasmparser::Validator;
+
+/// Magic number at the start of every wasm module.
+const MAGIC: &[u8] = b"\0asm";
+
+/// IDs of the wasm sections which are broken down.
+const CUSTOM_SECTION: u8 = 0;
+const CODE_SECTION: u8 = 10;
+const DATA_SECTION: u8 = 11;
+
+/// Sizes in bytes of the sections of a wasm module.
+#[derive(Clone, Debug, Default, Serialize)]
+pub(crate) struct Breakdown {
+    total: usize,
+    code: usize,
+    data: usize,
+    /// Custom sections, e.g. debug info, keyed by their name.
+    custom: BTreeMap<String, usize>,
+    /// All other sections, e.g. types, imports and exports.
+    other: usize,
+}
+
+fn human(size: usize) -> String {
+    size.file_size(options::CONVENTIONAL)
+        .unwrap_or_else(|_| format!("{size} B"))
+}
+
+impl Breakdown {
+    /// Breaks down the sections of `wasm`, returning `None` if it is malformed.
+    fn new(wasm: &[u8]) -> Option<Self> {
+        if !wasm.starts_with(MAGIC) {
+            return None;
+        }
+
+        let mut breakdown = Self {
+            total: wasm.len(),
+            other: 8,
+            ..Default::default()
+        };
+        let mut pos = 8;
+        while pos < wasm.len() {
+            let start = pos;
+            let id = wasm[pos];
+            pos += 1;
+            let size = leb128(wasm, &mut pos)?;
+            let section = wasm.get(pos..pos.checked_add(size)?)?;
+            pos += size;
+
+            let len = pos - start;
+            match id {
+                CODE_SECTION => breakdown.code += len,
+                DATA_SECTION => breakdown.data += len,
+                CUSTOM_SECTION => {
+                    let mut p = 0;
+                    let name = leb128(section, &mut p)
+                        .and_then(|n| section.get(p..p.checked_add(n)?))
+                        .map(String::from_utf8_lossy)
+                        .unwrap_or_default();
+                    *breakdown.custom.entry(name.into_owned()).or_default() += len;
+                }
+                _ => breakdown.other += len,
+            }
+        }
+        Some(breakdown)
+    }
+
+    fn custom_size(&self) -> usize {
+        self.custom.values().sum()
+    }
+
+    /// Returns advice on shrinking the module.
+    fn advice(&self) -> &'static str {
+        if self.custom_size() * 2 > self.total {
+            "Most of the module consists of custom sections like debug info, \
+            strip them with `wasm-strip` or `wasm-opt --strip-debug --strip-producers`, \
+            or build in release mode."
+        } else {
+            "Build in release mode and optimize the module for size with `wasm-opt -Oz`."
+        }
+    }
+}
+
+impl fmt::Display for Breakdown {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "code: {}", human(self.code))?;
+        writeln!(f, "data: {}", human(self.data))?;
+        writeln!(f, "custom: {}", human(self.custom_size()))?;
+        for (name, size) in &self.custom {
+            writeln!(f, "  {name}: {}", human(*size))?;
+        }
+        writeln!(f, "other: {}", human(self.other))
+    }
+}
+
+/// Rejects the wasm module at `path` if it exceeds `max_size` bytes or is malformed,
+/// so that users get an explanation up front instead of Enarx failing at runtime.
+/// Returns the breakdown of the sections of the module.
+pub(crate) async fn validate(path: &Path, max_size: usize) -> Result<Breakdown, Response> {
+    let wasm = tokio::fs::read(path).await.map_err(|e| {
+        error!(error = ?e, "failed to read uploaded wasm");
+        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    })?;
+    if !wasm.starts_with(MAGIC) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "The uploaded file is not a WebAssembly module, make sure to upload the compiled `.wasm` file",
+        )
+            .into_response());
+    }
+
+    let breakdown = Breakdown::new(&wasm);
+    if wasm.len() > max_size {
+        let mut message = format!(
+            "The uploaded module is {}, which exceeds the limit of {}.\n",
+            human(wasm.len()),
+            human(max_size),
+        );
+        if let Some(breakdown) = breakdown {
+            message += &format!("\n{breakdown}\n{}\n", breakdown.advice());
+        }
+        return Err((StatusCode::PAYLOAD_TOO_LARGE, message).into_response());
+    }
+
+    let result = tokio::task::spawn_blocking(move || {
+        Validator::new()
+            .validate_all(&wasm)
+            .map(|_| ())
+            .map_err(|e| (e.message().to_string(), e.offset()))
+    })
+    .await
+    .map_err(|e| {
+        error!(error = ?e, "failed to validate uploaded wasm");
+        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    })?;
+    result.map_err(|(message, offset)| {
+        debug!(message, offset, "rejecting invalid wasm");
+        (
+            StatusCode::BAD_REQUEST,
+            format!(
+                "The uploaded WebAssembly module is invalid: {message} (at offset {offset:#x})"
+            ),
+        )
+            .into_response()
+    })?;
+
+    // A valid module can always be broken down.
+    Ok(breakdown.unwrap_or_default())
+}
@@ -0,0 +1,130 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Callbacks notifying submitters when their jobs have ended.
+//!
+//! The body of callbacks is JSON by default. Operators may provide a template of the body
+//! instead, with the `{{uuid}}`, `{{status}}`, `{{outcome}}` and `{{link}}` placeholders.
+
+use crate::egress;
+use crate::job::Outcome;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::Context as _;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use once_cell::sync::{Lazy, OnceCell};
+use reqwest::Url;
+use serde_json::json;
+use tokio::sync::Mutex;
+use tracing::{debug, warn};
+
+/// Maximum time allowed for delivering a callback.
+const CALLBACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Placeholders of templates.
+const PLACEHOLDERS: [&str; 4] = ["{{uuid}}", "{{status}}", "{{outcome}}", "{{link}}"];
+
+/// Template of the body of callbacks, if provided by the operator.
+static TEMPLATE: OnceCell<String> = OnceCell::new();
+
+/// Loads the template of the body of callbacks from `path` on startup.
+pub(crate) fn init(path: &Path) -> anyhow::Result<()> {
+    let template = fs::read_to_string(path)
+        .with_context(|| format!("failed to read webhook template `{}`", path.display()))?;
+    if !PLACEHOLDERS
+        .iter()
+        .any(|placeholder| template.contains(placeholder))
+    {
+        warn!(path = %path.display(), "webhook template does not contain any placeholder");
+    }
+    TEMPLATE
+        .set(template)
+        .map_err(|_| anyhow::anyhow!("webhook template already initialized"))
+}
+
+#[derive(Debug)]
+struct Callback {
+    url: Url,
+    /// Base URL of this instance.
+    base: Url,
+}
+
+/// Callbacks of running jobs, keyed by their ID.
+static CALLBACKS: Lazy<Mutex<HashMap<String, Callback>>> = Lazy::new(Default::default);
+
+/// Parses a callback URL submitted with a job, rejecting URLs the egress policy disallows.
+pub(crate) async fn parse(url: &str, egress: &egress::Policy) -> Result<Url, Response> {
+    let invalid = || (StatusCode::BAD_REQUEST, "Invalid callback URL").into_response();
+    let url: Url = url.trim().parse().map_err(|_| invalid())?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(invalid());
+    }
+    // IPv6 addresses are enclosed in brackets.
+    let host = url.host_str().ok_or_else(invalid)?.trim_matches(['[', ']']);
+    let port = url.port_or_known_default().ok_or_else(invalid)?;
+    egress.check_host(host, port).await?;
+    Ok(url)
+}
+
+/// Registers `url` to be called once the job with `id` has ended.
+/// Links to the job are relative to `base`.
+pub(crate) async fn register(id: String, url: Url, base: Url) {
+    _ = CALLBACKS.lock().await.insert(id, Callback { url, base });
+}
+
+/// Calls the callback of the job with `id`, if any, with the `outcome` of the job.
+pub(crate) async fn notify(id: &str, outcome: Outcome) {
+    let Callback { url, base } = match CALLBACKS.lock().await.remove(id) {
+        Some(callback) => callback,
+        None => return,
+    };
+
+    let status = base.join(&format!("/api/v1/jobs/{id}")).ok();
+    let log = base.join(&format!("/job/{id}")).ok();
+    let (body, content_type) = match TEMPLATE.get() {
+        Some(template) => {
+            let reason = serde_json::to_value(outcome.reason).unwrap_or_default();
+            let body = template
+                .replace("{{uuid}}", id)
+                .replace("{{status}}", reason.as_str().unwrap_or_default())
+                .replace("{{outcome}}", &outcome.to_string())
+                .replace("{{link}}", log.as_ref().map_or("", Url::as_str));
+            // Templates producing JSON are delivered as such.
+            let content_type = match serde_json::from_str::<serde_json::Value>(&body) {
+                Ok(_) => "application/json",
+                Err(_) => "text/plain; charset=utf-8",
+            };
+            (body, content_type)
+        }
+        None => {
+            let body = json!({
+                "id": id,
+                "outcome": outcome,
+                "status": status,
+                "log": log,
+            });
+            (body.to_string(), "application/json")
+        }
+    };
+    let id = id.to_string();
+    _ = tokio::spawn(async move {
+        let result = reqwest::Client::new()
+            .post(url.clone())
+            .timeout(CALLBACK_TIMEOUT)
+            .header(CONTENT_TYPE, content_type)
+            .body(body)
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status());
+        match result {
+            Ok(_) => debug!(job_id = id, %url, "delivered job callback"),
+            Err(e) => warn!(job_id = id, %url, error = ?e, "failed to deliver job callback"),
+        }
+    });
+}